@@ -0,0 +1,216 @@
+//! Baseline numbers for the compiler stages, so the interning/dataflow rewrites
+//! have something to be compared against.
+//!
+//! Run with `cargo bench --bench pipeline`. Save a baseline before a perf change
+//! with `-- --save-baseline before` and compare with `-- --baseline before`.
+
+use bril_frontend::Program;
+use bril_frontend::Symbol;
+use bril_ir::{destruct_ssa, BlockID, DominatorInfo, IrModule, SSAFormation};
+use bril_passes::{
+    ConstantFoldPass, ConstantPropagationPass, DeadCodeRemovalPass, PassManager, SimplifyCfgPass,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use riscv_backend::{select_instructions, LinearScan};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Fixtures from this repo and from the vendored bril checkout. Missing files and
+/// programs using extensions we can't compile yet are skipped, not failed.
+const FIXTURES: &[&str] = &[
+    "tests/add.json",
+    "tests/palindrome.json",
+    "bril-extern/bril/test/print/eight-queens.json",
+];
+
+/// Number of if/else diamonds in each synthetic program
+const SYNTHETIC_SIZES: &[usize] = &[16, 256, 1024];
+
+fn load_fixture(path: &str) -> Option<(String, Program)> {
+    let full = Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+    let Ok(text) = std::fs::read_to_string(&full) else {
+        eprintln!("skipping {}: not found", path);
+        return None;
+    };
+
+    let program: Program = match serde_json::from_str(&text) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("skipping {}: {}", path, e);
+            return None;
+        }
+    };
+
+    // only keep fixtures that make it through the whole pipeline
+    if let Err(e) = build_ssa(&program) {
+        eprintln!("skipping {}: {}", path, e);
+        return None;
+    }
+
+    let name = Path::new(path).file_stem()?.to_string_lossy().to_string();
+    Some((name, program))
+}
+
+/// A chain of `diamonds` if/else diamonds that all redefine `x`,
+/// so every join block gets a phi
+fn synthetic_program(diamonds: usize) -> Program {
+    let mut instrs = vec![
+        json!({"op": "const", "dest": "x", "type": "int", "value": 0}),
+        json!({"op": "const", "dest": "one", "type": "int", "value": 1}),
+        json!({"op": "const", "dest": "limit", "type": "int", "value": 100}),
+    ];
+
+    for k in 0..diamonds {
+        instrs.extend([
+            json!({"op": "lt", "dest": "c", "type": "bool", "args": ["x", "limit"]}),
+            json!({"op": "br", "args": ["c"], "labels": [format!("t{}", k), format!("f{}", k)]}),
+            json!({"label": format!("t{}", k)}),
+            json!({"op": "add", "dest": "x", "type": "int", "args": ["x", "one"]}),
+            json!({"op": "jmp", "labels": [format!("j{}", k)]}),
+            json!({"label": format!("f{}", k)}),
+            json!({"op": "sub", "dest": "x", "type": "int", "args": ["x", "one"]}),
+            json!({"op": "jmp", "labels": [format!("j{}", k)]}),
+            json!({"label": format!("j{}", k)}),
+            json!({"op": "mul", "dest": "y", "type": "int", "args": ["x", "x"]}),
+            json!({"op": "print", "args": ["y"]}),
+        ]);
+    }
+    instrs.push(json!({"op": "ret", "args": []}));
+
+    let program: Value = json!({ "functions": [{ "name": "main", "instrs": instrs }] });
+    serde_json::from_value(program).expect("synthetic program is well formed")
+}
+
+fn inputs() -> Vec<(String, Program)> {
+    let mut out: Vec<(String, Program)> = FIXTURES.iter().filter_map(|p| load_fixture(p)).collect();
+    for &n in SYNTHETIC_SIZES {
+        out.push((format!("synthetic-{}", n), synthetic_program(n)));
+    }
+    out
+}
+
+fn build_ssa(program: &Program) -> anyhow::Result<IrModule> {
+    let mut module = IrModule::try_from(program)?;
+    SSAFormation::try_from(&mut module)?;
+    Ok(module)
+}
+
+/// Same pipeline the CLI runs
+fn o1_pipeline() -> PassManager {
+    let mut pm = PassManager::new();
+    pm.add_pass(ConstantPropagationPass {});
+    pm.add_pass(ConstantFoldPass::default());
+    pm.add_pass(DeadCodeRemovalPass {});
+    pm.add_pass(SimplifyCfgPass {});
+    pm
+}
+
+fn bench_ir_construction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ir_construction");
+    for (name, program) in inputs() {
+        group.bench_with_input(BenchmarkId::from_parameter(&name), &program, |b, p| {
+            b.iter(|| IrModule::try_from(p).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_ssa_construction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ssa_construction");
+    for (name, program) in inputs() {
+        let module = IrModule::try_from(&program).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(&name), &module, |b, m| {
+            b.iter_batched(
+                || m.clone(),
+                |mut m| SSAFormation::try_from(&mut m).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_o1_pipeline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("o1_pipeline");
+    for (name, program) in inputs() {
+        let module = build_ssa(&program).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(&name), &module, |b, m| {
+            b.iter_batched(
+                || m.clone(),
+                |mut m| o1_pipeline().run(&mut m).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_isel_regalloc(c: &mut Criterion) {
+    let mut group = c.benchmark_group("isel_regalloc");
+    for (name, program) in inputs() {
+        let mut module = build_ssa(&program).unwrap();
+        o1_pipeline().run(&mut module).unwrap();
+        for func in &mut module.functions {
+            destruct_ssa(func);
+        }
+        group.bench_with_input(BenchmarkId::from_parameter(&name), &module, |b, m| {
+            b.iter(|| {
+                let machine_funcs: Vec<_> = m.functions.iter().map(select_instructions).collect();
+                LinearScan::new().run(&machine_funcs)
+            })
+        });
+    }
+    group.finish();
+}
+
+/// DF+ the obvious way: keep adding the frontier of everything so far
+/// until it stops growing. What `iterated_df` is measured against
+fn naive_iterated_df(info: &DominatorInfo, blocks: &[BlockID]) -> BTreeSet<BlockID> {
+    let mut df_plus = BTreeSet::new();
+    loop {
+        let next: BTreeSet<BlockID> = blocks
+            .iter()
+            .chain(&df_plus)
+            .filter_map(|b| info.dom_frontier.get(b))
+            .flatten()
+            .copied()
+            .collect();
+        if next == df_plus {
+            return df_plus;
+        }
+        df_plus = next;
+    }
+}
+
+fn bench_iterated_df(c: &mut Criterion) {
+    let mut group = c.benchmark_group("iterated_df");
+    // 3 blocks a diamond, about a thousand in all
+    let module = IrModule::try_from(&synthetic_program(333)).unwrap();
+    let func = &module.functions[0];
+    let info = DominatorInfo::compute(func).unwrap();
+    let x = Symbol::from("x");
+    let def_blocks: Vec<BlockID> = (0..func.blocks.len())
+        .filter(|&b| func.blocks[b].instrs.iter().any(|i| i.defs().contains(&x)))
+        .collect();
+    assert!(info
+        .iterated_df(&def_blocks)
+        .iter()
+        .eq(naive_iterated_df(&info, &def_blocks)));
+
+    group.bench_function("worklist", |b| b.iter(|| info.iterated_df(&def_blocks)));
+    group.bench_function("naive", |b| {
+        b.iter(|| naive_iterated_df(&info, &def_blocks))
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_ir_construction,
+    bench_ssa_construction,
+    bench_o1_pipeline,
+    bench_isel_regalloc,
+    bench_iterated_df
+);
+criterion_main!(benches);
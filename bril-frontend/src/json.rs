@@ -1,138 +1,693 @@
-use serde::Deserialize;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize)]
+use crate::symbol::Symbol;
+use crate::types::Type;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Program {
     pub functions: Vec<Function>,
+    // the import extension, resolved by `Program::link`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub imports: Vec<Import>,
+}
+
+/// Why a JSON program couldn't be read
+#[derive(Debug)]
+pub enum ParseError {
+    /// Not JSON, or the program/function layout around the instructions is off
+    Json(serde_json::Error),
+    /// The input couldn't be read at all
+    Io(std::io::Error),
+    /// A field strict mode doesn't know, outside of any instruction
+    UnknownField {
+        /// The function it's in, `None` at the top level
+        func: Option<String>,
+        field: String,
+    },
+    /// Any of the others, for a program read from `path`
+    File {
+        path: PathBuf,
+        error: Box<ParseError>,
+    },
+    /// One instruction didn't match any Bril op
+    Instruction {
+        func: String,
+        /// Index into the function's `instrs`, labels included
+        index: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Json(e) => write!(f, "{}", e),
+            ParseError::Io(e) => write!(f, "{}", e),
+            ParseError::UnknownField {
+                func: Some(func),
+                field,
+            } => write!(f, "in function `{}`: unknown field `{}`", func, field),
+            ParseError::UnknownField { func: None, field } => {
+                write!(f, "unknown field `{}`", field)
+            }
+            ParseError::File { path, error } => write!(f, "{}: {}", path.display(), error),
+            ParseError::Instruction {
+                func,
+                index,
+                message,
+            } => write!(
+                f,
+                "in function `{}`, instruction {}: {}",
+                func, index, message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Json(e) => Some(e),
+            ParseError::Io(e) => Some(e),
+            ParseError::File { error, .. } => error.source(),
+            ParseError::Instruction { .. } | ParseError::UnknownField { .. } => None,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+impl From<serde_json::Error> for ParseError {
+    fn from(e: serde_json::Error) -> Self {
+        // serde_json wraps read errors too, keep those apart
+        if e.is_io() {
+            ParseError::Io(e.into())
+        } else {
+            ParseError::Json(e)
+        }
+    }
+}
+
+// `Program` with the instructions left as plain JSON, so a bad one can be
+// pinned to its function and index. Deserializing `Instruction` directly only
+// gets serde's "did not match any variant of untagged enum" with no location
+#[derive(Deserialize)]
+struct RawProgram {
+    functions: Vec<RawFunction>,
+    #[serde(default)]
+    imports: Vec<Import>,
+}
+
+#[derive(Deserialize)]
+struct RawFunction {
+    name: String,
+    #[serde(default)]
+    args: Vec<ValueDef>,
+    instrs: Vec<serde_json::Value>,
+    #[serde(rename = "type", default)]
+    ret_typ: Option<Type>,
+}
+
+/// How forgiving reading JSON is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Reject fields and ops this crate doesn't know about instead of
+    /// ignoring them, a misspelled `labels` is otherwise just dropped
+    pub strict: bool,
+}
+
+impl ParseOptions {
+    /// Read a program from Bril JSON, reporting which instruction is broken
+    /// when one is
+    pub fn parse_str(&self, json: &str) -> Result<Program, ParseError> {
+        if self.strict {
+            self.parse_value(serde_json::from_str(json)?)
+        } else {
+            serde_json::from_str::<RawProgram>(json)?.convert(self)
+        }
+    }
+
+    /// Like [`ParseOptions::parse_str`], straight from `reader` (e.g. stdin)
+    /// without reading it into a string first. Buffer it if it isn't already
+    pub fn parse_reader<R: Read>(&self, reader: R) -> Result<Program, ParseError> {
+        if self.strict {
+            self.parse_value(serde_json::from_reader(reader)?)
+        } else {
+            serde_json::from_reader::<_, RawProgram>(reader)?.convert(self)
+        }
+    }
+
+    /// Read a JSON program from a file, errors name the file
+    pub fn parse_path(&self, path: impl AsRef<Path>) -> Result<Program, ParseError> {
+        let path = path.as_ref();
+        File::open(path)
+            .map_err(ParseError::Io)
+            .and_then(|file| self.parse_reader(BufReader::new(file)))
+            .map_err(|error| ParseError::File {
+                path: path.to_path_buf(),
+                error: Box::new(error),
+            })
+    }
+
+    // strict mode looks at the keys before serde drops the ones it doesn't know
+    pub(crate) fn parse_value(&self, value: serde_json::Value) -> Result<Program, ParseError> {
+        if self.strict {
+            check_fields(&value, None, &["functions", "imports"])?;
+            for func in value["functions"].as_array().into_iter().flatten() {
+                let name = func["name"].as_str().unwrap_or_default();
+                check_fields(func, Some(name), &["name", "args", "instrs", "type"])?;
+            }
+        }
+        serde_json::from_value::<RawProgram>(value)?.convert(self)
+    }
+}
+
+fn check_fields(
+    value: &serde_json::Value,
+    func: Option<&str>,
+    known: &[&str],
+) -> Result<(), ParseError> {
+    let fields = value.as_object().into_iter().flat_map(|o| o.keys());
+    match fields.into_iter().find(|k| !known.contains(&k.as_str())) {
+        Some(field) => Err(ParseError::UnknownField {
+            func: func.map(String::from),
+            field: field.clone(),
+        }),
+        None => Ok(()),
+    }
+}
+
+impl Program {
+    /// [`ParseOptions::parse_str`] with the default, lenient options
+    pub fn from_json(json: &str) -> Result<Program, ParseError> {
+        ParseOptions::default().parse_str(json)
+    }
+
+    /// [`ParseOptions::parse_reader`] with the default, lenient options
+    pub fn from_reader<R: Read>(reader: R) -> Result<Program, ParseError> {
+        ParseOptions::default().parse_reader(reader)
+    }
+
+    /// [`ParseOptions::parse_path`] with the default, lenient options
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Program, ParseError> {
+        ParseOptions::default().parse_path(path)
+    }
+
+    /// Fix up what the parser can't know without types: `x: float = const 1`
+    /// comes in as an `Int` literal and is turned into `1.0` here, like
+    /// brilirs does when it runs it
+    pub fn normalize(&mut self) {
+        for func in &mut self.functions {
+            for instr in &mut func.instrs {
+                if let Instruction::Op {
+                    op: Op::Const { typ, value, .. },
+                    ..
+                } = instr
+                {
+                    *value = value.as_type(typ);
+                }
+            }
+        }
+    }
+}
+
+impl RawProgram {
+    fn convert(self, options: &ParseOptions) -> Result<Program, ParseError> {
+        let functions = self
+            .functions
+            .into_iter()
+            .map(|func| func.convert(options))
+            .collect::<Result<_, _>>()?;
+        Ok(Program {
+            functions,
+            imports: self.imports,
+        })
+    }
+}
+
+impl RawFunction {
+    fn convert(self, options: &ParseOptions) -> Result<Function, ParseError> {
+        let instrs = self
+            .instrs
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| {
+                convert_instr(value, options).map_err(|message| ParseError::Instruction {
+                    func: self.name.clone(),
+                    index,
+                    message,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Function {
+            name: self.name,
+            args: self.args,
+            instrs,
+            ret_typ: self.ret_typ,
+        })
+    }
+}
+
+fn convert_instr(value: serde_json::Value, options: &ParseOptions) -> Result<Instruction, String> {
+    let fields: Vec<String> = match &value {
+        serde_json::Value::Object(o) if options.strict => o.keys().cloned().collect(),
+        _ => Vec::new(),
+    };
+    let instr = instr_from_value(value)?;
+
+    if options.strict {
+        if let Instruction::Op {
+            op: Op::Other { op, .. },
+            ..
+        } = &instr
+        {
+            return Err(format!("unknown op `{}`", op));
+        }
+        // anything that doesn't come back out wasn't understood going in
+        let known = serde_json::to_value(&instr).map_err(|e| e.to_string())?;
+        if let Some(field) = fields.iter().find(|f| known.get(f.as_str()).is_none()) {
+            return Err(format!("unknown field `{}`", field));
+        }
+    }
+    Ok(instr)
+}
+
+fn instr_from_value(value: serde_json::Value) -> Result<Instruction, String> {
+    let opcode = match value.get("op") {
+        Some(serde_json::Value::String(op)) => op.clone(),
+        Some(other) => return Err(format!("`op` should be a string, found {}", other)),
+        None if value.get("label").is_some() => {
+            return Instruction::deserialize(value).map_err(|e| e.to_string());
+        }
+        None => return Err("neither an op nor a label".to_string()),
+    };
+    let pos = match value.get("pos") {
+        Some(pos) => Some(Position::deserialize(pos).map_err(|e| format!("bad `pos`: {}", e))?),
+        None => None,
+    };
+    // going through `Op` rather than `Instruction` keeps serde's message about
+    // which field is missing or wrong
+    let op = <Op as Deserialize>::deserialize(value).map_err(|e| format!("`{}`: {}", opcode, e))?;
+    Ok(Instruction::Op { op, pos })
+}
+
+/// `from "path" import @name as @alias;`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Import {
+    pub path: String,
+    pub functions: Vec<ImportedFunction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportedFunction {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Function {
     pub name: String,
     #[serde(default)]
     pub args: Vec<ValueDef>,
     pub instrs: Vec<Instruction>,
-    #[serde(rename = "type", default)]
-    pub ret_typ: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub ret_typ: Option<Type>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Function {
+    /// Every instruction along with its [`InstrId`]
+    pub fn instrs_with_ids(&self) -> impl Iterator<Item = (InstrId, &Instruction)> {
+        self.instrs
+            .iter()
+            .enumerate()
+            .map(|(i, instr)| (InstrId(i), instr))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ValueDef {
-    pub name: String,
+    pub name: Symbol,
     #[serde(rename = "type")]
-    pub typ: String,
+    pub typ: Type,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Instruction {
     /// A bare label instruction without an "op" field
-    Label { label: String },
+    Label {
+        label: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pos: Option<Position>,
+    },
 
     /// All other instructions, identified by the "op" tag
-    //#[serde(tag = "op", rename_all = "snake_case")]
-    Op(Op),
+    Op {
+        #[serde(flatten)]
+        op: Op,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pos: Option<Position>,
+    },
+}
+
+/// Where an instruction came from, `bril2json -p` fills these in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub row: u64,
+    pub col: u64,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.row, self.col)
+    }
+}
+
+/// Which instruction of its function something was lowered from. It's the
+/// index into the parsed `instrs`, labels included, so it's the same number
+/// however the program was read and survives every pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InstrId(pub usize);
+
+impl fmt::Display for InstrId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 /// Specicially made for const opcode
-#[derive(Clone, Debug, Deserialize)]
-#[serde(untagged)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Literal {
     Int(i64),
     Bool(bool),
+    Float(f64),
+    Char(char),
+}
+
+impl Literal {
+    /// Integer literals on a `float` const are meant as floats (`1` vs `1.0`)
+    pub fn as_type(&self, typ: &Type) -> Literal {
+        match (self, typ) {
+            (Literal::Int(i), Type::Float) => Literal::Float(*i as f64),
+            _ => self.clone(),
+        }
+    }
+}
+
+/// What a `value` can look like on the wire
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawLiteral {
+    Int(i64),
+    Bool(bool),
+    Float(f64),
+    // chars, plus the infinities JSON can't spell that other Bril tools write as strings
+    Str(String),
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "op", rename_all = "snake_case")]
+impl Serialize for Literal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Literal::Int(i) => serializer.serialize_i64(*i),
+            Literal::Bool(b) => serializer.serialize_bool(*b),
+            Literal::Float(f) if f.is_nan() => serializer.serialize_str("NaN"),
+            Literal::Float(f) if f.is_infinite() => {
+                serializer.serialize_str(if *f > 0.0 { "Infinity" } else { "-Infinity" })
+            }
+            Literal::Float(f) => serializer.serialize_f64(*f),
+            Literal::Char(c) => serializer.serialize_char(*c),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Literal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match RawLiteral::deserialize(deserializer)? {
+            RawLiteral::Int(i) => Ok(Literal::Int(i)),
+            RawLiteral::Bool(b) => Ok(Literal::Bool(b)),
+            RawLiteral::Float(f) => Ok(Literal::Float(f)),
+            RawLiteral::Str(s) => match s.as_str() {
+                "Infinity" => Ok(Literal::Float(f64::INFINITY)),
+                "-Infinity" => Ok(Literal::Float(f64::NEG_INFINITY)),
+                "NaN" => Ok(Literal::Float(f64::NAN)),
+                other => {
+                    let mut chars = other.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => Ok(Literal::Char(c)),
+                        _ => Err(de::Error::custom(format!(
+                            "invalid literal {:?}, a char literal must be exactly one character",
+                            other
+                        ))),
+                    }
+                }
+            },
+        }
+    }
+}
+
+fn bool_type() -> Type {
+    Type::Bool
+}
+
+// `remote = "Self"` makes the derives inherent functions, the trait impls
+// below wrap them to fall back on `Op::Other`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(remote = "Self", tag = "op", rename_all = "snake_case")]
 pub enum Op {
     // Arithmetic operations
     Add {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
     },
     Sub {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
     },
     Mul {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
     },
     Div {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
     },
 
     // Comparison operations
     Eq {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
     },
     Lt {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
     },
     Gt {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
     },
     Le {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
     },
     Ge {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
     },
 
-    // Logical operations
+    // Floating point arithmetic and comparison
+    #[serde(rename = "fadd")]
+    FAdd {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "fsub")]
+    FSub {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "fmul")]
+    FMul {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "fdiv")]
+    FDiv {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "feq")]
+    FEq {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "flt")]
+    FLt {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "fgt")]
+    FGt {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "fle")]
+    FLe {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "fge")]
+    FGe {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+
+    // Bit casts between a float and its IEEE 754 bits as an int
+    #[serde(rename = "float2bits")]
+    Float2Bits {
+        dest: Symbol,
+        args: Vec<Symbol>,
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "bits2float")]
+    Bits2Float {
+        dest: Symbol,
+        args: Vec<Symbol>,
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+
+    // Conversions between a char and its codepoint
+    #[serde(rename = "char2int")]
+    Char2Int {
+        dest: Symbol,
+        args: Vec<Symbol>,
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "int2char")]
+    Int2Char {
+        dest: Symbol,
+        args: Vec<Symbol>,
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+
+    // Character comparison
+    #[serde(rename = "ceq")]
+    CEq {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "clt")]
+    CLt {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "cgt")]
+    CGt {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "cle")]
+    CLe {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    #[serde(rename = "cge")]
+    CGe {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+
+    // Logical operations, always bools. The type is written out for
+    // interpreters that check it, but can be left out on the way in
     Not {
-        dest: String,
-        args: Vec<String>,
+        dest: Symbol,
+        args: Vec<Symbol>,
+        #[serde(rename = "type", default = "bool_type")]
+        typ: Type,
     },
     And {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type", default = "bool_type")]
+        typ: Type,
     },
     Or {
-        dest: String,
-        args: [String; 2],
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type", default = "bool_type")]
+        typ: Type,
     },
 
     // Constants and identity
     Const {
-        dest: String,
+        dest: Symbol,
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
         value: Literal,
     },
     Id {
-        dest: String,
-        args: Vec<String>,
+        dest: Symbol,
+        args: Vec<Symbol>,
         #[serde(rename = "type")]
-        typ: String,
+        typ: Type,
     },
 
     // Control flow
     Br {
-        args: Vec<String>,
+        args: Vec<Symbol>,
         labels: [String; 2],
     },
     Jmp {
@@ -141,23 +696,275 @@ pub enum Op {
 
     // Function call and return
     Call {
-        #[serde(default)]
-        dest: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dest: Option<Symbol>,
         funcs: Vec<String>,
         #[serde(default)]
-        args: Vec<String>,
-        #[serde(rename = "type")]
-        typ: String,
+        args: Vec<Symbol>,
+        // left out for calls in effect position
+        #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+        typ: Option<Type>,
     },
     Ret {
         #[serde(default)]
-        args: Vec<String>,
+        args: Vec<Symbol>,
+    },
+
+    // Memory
+    Alloc {
+        dest: Symbol,
+        args: Vec<Symbol>,
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    Load {
+        dest: Symbol,
+        args: Vec<Symbol>,
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    Store {
+        args: [Symbol; 2],
+    },
+    Free {
+        args: Vec<Symbol>,
+    },
+    #[serde(rename = "ptradd")]
+    PtrAdd {
+        dest: Symbol,
+        args: [Symbol; 2],
+        #[serde(rename = "type")]
+        typ: Type,
     },
 
     // Miscellaneous
     Print {
         #[serde(default)]
-        args: Vec<String>,
+        args: Vec<Symbol>,
     },
     Nop,
+
+    // SSA form, read straight into `IrInstruction::Phi` and what it comes back out as
+    Phi {
+        dest: Symbol,
+        args: Vec<Symbol>,
+        labels: Vec<String>,
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    // the newer SSA form, `set x v` writes the shadow of `x` and `x = get` reads it
+    Get {
+        dest: Symbol,
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+    Set {
+        args: [Symbol; 2],
+    },
+    Undef {
+        dest: Symbol,
+        #[serde(rename = "type")]
+        typ: Type,
+    },
+
+    // Speculative execution
+    Speculate,
+    Commit,
+    Guard {
+        args: Vec<Symbol>,
+        labels: Vec<String>,
+    },
+
+    /// An op from an extension we don't know, kept with whatever operands it
+    /// spells the usual way
+    #[serde(skip)]
+    Other {
+        op: String,
+        dest: Option<Symbol>,
+        args: Vec<Symbol>,
+        funcs: Vec<String>,
+        labels: Vec<String>,
+        typ: Option<Type>,
+    },
+}
+
+impl Op {
+    /// The variable the op writes and its declared type. `and`/`or`/`not`
+    /// don't spell one out, they're always `bool`
+    pub fn dest_type(&self) -> Option<(Symbol, Type)> {
+        match self {
+            Op::Add { dest, typ, .. }
+            | Op::Sub { dest, typ, .. }
+            | Op::Mul { dest, typ, .. }
+            | Op::Div { dest, typ, .. }
+            | Op::Eq { dest, typ, .. }
+            | Op::Lt { dest, typ, .. }
+            | Op::Gt { dest, typ, .. }
+            | Op::Le { dest, typ, .. }
+            | Op::Ge { dest, typ, .. }
+            | Op::FAdd { dest, typ, .. }
+            | Op::FSub { dest, typ, .. }
+            | Op::FMul { dest, typ, .. }
+            | Op::FDiv { dest, typ, .. }
+            | Op::FEq { dest, typ, .. }
+            | Op::FLt { dest, typ, .. }
+            | Op::FGt { dest, typ, .. }
+            | Op::FLe { dest, typ, .. }
+            | Op::FGe { dest, typ, .. }
+            | Op::Float2Bits { dest, typ, .. }
+            | Op::Bits2Float { dest, typ, .. }
+            | Op::Char2Int { dest, typ, .. }
+            | Op::Int2Char { dest, typ, .. }
+            | Op::CEq { dest, typ, .. }
+            | Op::CLt { dest, typ, .. }
+            | Op::CGt { dest, typ, .. }
+            | Op::CLe { dest, typ, .. }
+            | Op::CGe { dest, typ, .. }
+            | Op::Const { dest, typ, .. }
+            | Op::Id { dest, typ, .. }
+            | Op::Alloc { dest, typ, .. }
+            | Op::Load { dest, typ, .. }
+            | Op::PtrAdd { dest, typ, .. }
+            | Op::Phi { dest, typ, .. }
+            | Op::Get { dest, typ }
+            | Op::Undef { dest, typ } => Some((*dest, typ.clone())),
+
+            Op::Not { dest, .. } | Op::And { dest, .. } | Op::Or { dest, .. } => {
+                Some((*dest, Type::Bool))
+            }
+
+            Op::Call {
+                dest: Some(dest),
+                typ: Some(typ),
+                ..
+            }
+            | Op::Other {
+                dest: Some(dest),
+                typ: Some(typ),
+                ..
+            } => Some((*dest, typ.clone())),
+
+            _ => None,
+        }
+    }
+
+    /// The op's name, as it's spelled in Bril
+    pub fn opcode(&self) -> &str {
+        match self {
+            Op::Add { .. } => "add",
+            Op::Sub { .. } => "sub",
+            Op::Mul { .. } => "mul",
+            Op::Div { .. } => "div",
+            Op::Eq { .. } => "eq",
+            Op::Lt { .. } => "lt",
+            Op::Gt { .. } => "gt",
+            Op::Le { .. } => "le",
+            Op::Ge { .. } => "ge",
+            Op::FAdd { .. } => "fadd",
+            Op::FSub { .. } => "fsub",
+            Op::FMul { .. } => "fmul",
+            Op::FDiv { .. } => "fdiv",
+            Op::FEq { .. } => "feq",
+            Op::FLt { .. } => "flt",
+            Op::FGt { .. } => "fgt",
+            Op::FLe { .. } => "fle",
+            Op::FGe { .. } => "fge",
+            Op::Float2Bits { .. } => "float2bits",
+            Op::Bits2Float { .. } => "bits2float",
+            Op::Char2Int { .. } => "char2int",
+            Op::Int2Char { .. } => "int2char",
+            Op::CEq { .. } => "ceq",
+            Op::CLt { .. } => "clt",
+            Op::CGt { .. } => "cgt",
+            Op::CLe { .. } => "cle",
+            Op::CGe { .. } => "cge",
+            Op::Not { .. } => "not",
+            Op::And { .. } => "and",
+            Op::Or { .. } => "or",
+            Op::Const { .. } => "const",
+            Op::Id { .. } => "id",
+            Op::Br { .. } => "br",
+            Op::Jmp { .. } => "jmp",
+            Op::Call { .. } => "call",
+            Op::Ret { .. } => "ret",
+            Op::Alloc { .. } => "alloc",
+            Op::Load { .. } => "load",
+            Op::Store { .. } => "store",
+            Op::Free { .. } => "free",
+            Op::PtrAdd { .. } => "ptradd",
+            Op::Print { .. } => "print",
+            Op::Nop => "nop",
+            Op::Phi { .. } => "phi",
+            Op::Get { .. } => "get",
+            Op::Set { .. } => "set",
+            Op::Undef { .. } => "undef",
+            Op::Speculate => "speculate",
+            Op::Commit => "commit",
+            Op::Guard { .. } => "guard",
+            Op::Other { op, .. } => op,
+        }
+    }
+}
+
+/// What `Op::Other` looks like in JSON
+#[derive(Serialize, Deserialize)]
+struct OtherOp {
+    op: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dest: Option<Symbol>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    args: Vec<Symbol>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    funcs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    typ: Option<Type>,
+}
+
+impl Serialize for Op {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Op::Other {
+                op,
+                dest,
+                args,
+                funcs,
+                labels,
+                typ,
+            } => OtherOp {
+                op: op.clone(),
+                dest: *dest,
+                args: args.clone(),
+                funcs: funcs.clone(),
+                labels: labels.clone(),
+                typ: typ.clone(),
+            }
+            .serialize(serializer),
+            _ => Op::serialize(self, serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Op {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        match Op::deserialize(&value) {
+            Ok(op) => Ok(op),
+            // only an op we've never heard of, a known one with bad operands
+            // is still an error
+            Err(e) if e.to_string().starts_with("unknown variant") => {
+                let other = OtherOp::deserialize(&value).map_err(de::Error::custom)?;
+                Ok(Op::Other {
+                    op: other.op,
+                    dest: other.dest,
+                    args: other.args,
+                    funcs: other.funcs,
+                    labels: other.labels,
+                    typ: other.typ,
+                })
+            }
+            Err(e) => Err(de::Error::custom(e)),
+        }
+    }
 }
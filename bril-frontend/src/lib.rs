@@ -1,14 +1,30 @@
 pub mod json;
+pub mod link;
+pub mod symbol;
+pub mod text;
+pub mod types;
+pub mod upstream;
+pub mod validate;
 pub use json::Function;
+pub use json::Import;
+pub use json::ImportedFunction;
+pub use json::InstrId;
 pub use json::Instruction;
 pub use json::Literal;
 pub use json::Op;
+pub use json::ParseError;
+pub use json::ParseOptions;
+pub use json::Position;
 pub use json::Program;
+pub use json::ValueDef;
+pub use symbol::Symbol;
+pub use types::Type;
+pub use upstream::ConversionError;
+pub use validate::ValidationError;
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json;
 
     #[test]
     fn print_add_json() {
@@ -25,4 +41,277 @@ mod tests {
         // Basic sanity checks
         assert_eq!(program.functions.len(), 1);
     }
+
+    #[test]
+    fn parse_errors_name_the_instruction() {
+        let json = include_str!("../../tests/add.json");
+        assert_eq!(
+            Program::from_json(json).unwrap(),
+            serde_json::from_str(json).unwrap()
+        );
+
+        let typo = json.replace(r#""dest": "v2""#, r#""dest": 2"#);
+        let err = Program::from_json(&typo).unwrap_err();
+        assert!(
+            matches!(&err, ParseError::Instruction { func, index: 2, .. } if func == "add_test")
+        );
+        assert_eq!(
+            err.to_string(),
+            "in function `add_test`, instruction 2: `add`: invalid type: integer `2`, expected a string"
+        );
+
+        let missing = json.replace(r#""dest": "v2","#, "");
+        let err = Program::from_json(&missing).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "in function `add_test`, instruction 2: `add`: missing field `dest`"
+        );
+
+        let err = Program::from_json("{\"functions\": 3}").unwrap_err();
+        assert!(matches!(err, ParseError::Json(_)));
+    }
+
+    #[test]
+    fn reading_from_readers_and_files() {
+        let bytes = include_bytes!("../../tests/add.json");
+        let program = Program::from_reader(std::io::Cursor::new(&bytes[..])).unwrap();
+        assert_eq!(program.functions[0].name, "add_test");
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../tests/add.json");
+        assert_eq!(Program::from_path(path).unwrap(), program);
+
+        let err = Program::from_path("no/such/file.json").unwrap_err();
+        assert!(
+            matches!(&err, ParseError::File { error, .. } if matches!(**error, ParseError::Io(_)))
+        );
+        assert!(
+            err.to_string().starts_with("no/such/file.json: "),
+            "{}",
+            err
+        );
+
+        // a broken instruction still says which one, after the file name
+        let typo = String::from_utf8_lossy(bytes).replace(r#""dest": "v2""#, r#""dest": 2"#);
+        let err = Program::from_reader(typo.as_bytes()).unwrap_err();
+        assert!(matches!(err, ParseError::Instruction { index: 2, .. }));
+    }
+
+    #[test]
+    fn strict_mode_rejects_unknown_fields() {
+        let strict = ParseOptions { strict: true };
+        for json in [
+            include_str!("../../tests/add.json"),
+            include_str!("../../tests/palindrome.json"),
+            include_str!("../../tests/char.json"),
+            include_str!("../../tests/float.json"),
+        ] {
+            assert_eq!(
+                strict.parse_str(json).unwrap(),
+                Program::from_json(json).unwrap()
+            );
+        }
+
+        let json = r#"{"functions": [{"name": "main", "instrs": [
+            {"op": "const", "dest": "x", "type": "int", "value": 1},
+            {"label": "done"},
+            {"op": "ret", "args": [], "lables": ["done"]}
+        ]}]}"#;
+        assert!(Program::from_json(json).is_ok());
+        let err = strict.parse_str(json).unwrap_err();
+        assert!(matches!(&err, ParseError::Instruction { index: 2, .. }));
+        assert_eq!(
+            err.to_string(),
+            "in function `main`, instruction 2: unknown field `lables`"
+        );
+
+        let err = strict
+            .parse_str(r#"{"functions": [{"name": "main", "instrs": [], "retyrn": "int"}]}"#)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "in function `main`: unknown field `retyrn`"
+        );
+        let err = strict
+            .parse_str(r#"{"functions": [], "imprts": []}"#)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unknown field `imprts`");
+
+        let err = strict
+            .parse_str(r#"{"functions": [{"name": "main", "instrs": [{"op": "vecadd"}]}]}"#)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "in function `main`, instruction 0: unknown op `vecadd`"
+        );
+    }
+
+    #[test]
+    fn unknown_ops_are_kept() {
+        let json = r#"{"op": "vecadd", "dest": "v", "type": "int", "args": ["a", "b"]}"#;
+        let op: Op = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            op,
+            Op::Other {
+                op: "vecadd".to_string(),
+                dest: Some("v".into()),
+                args: vec!["a".into(), "b".into()],
+                funcs: Vec::new(),
+                labels: Vec::new(),
+                typ: Some(Type::Int),
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&op).unwrap(),
+            r#"{"op":"vecadd","dest":"v","args":["a","b"],"type":"int"}"#
+        );
+
+        // a known op with bad operands doesn't get to hide in there
+        let err = serde_json::from_str::<Op>(r#"{"op": "add", "args": ["a", "b"]}"#).unwrap_err();
+        assert!(err.to_string().contains("missing field `dest`"), "{}", err);
+    }
+
+    #[test]
+    fn float_literals() {
+        let lit = |v: &str| serde_json::from_str::<Literal>(v);
+
+        assert_eq!(lit("1").unwrap(), Literal::Int(1));
+        assert_eq!(lit("1.0").unwrap(), Literal::Float(1.0));
+        assert_eq!(lit("-2.5e3").unwrap(), Literal::Float(-2500.0));
+        assert_eq!(lit("true").unwrap(), Literal::Bool(true));
+        assert_eq!(lit(r#""Infinity""#).unwrap(), Literal::Float(f64::INFINITY));
+        assert_eq!(
+            lit(r#""-Infinity""#).unwrap(),
+            Literal::Float(f64::NEG_INFINITY)
+        );
+        assert!(matches!(lit(r#""NaN""#).unwrap(), Literal::Float(f) if f.is_nan()));
+        assert!(lit(r#""pi""#).is_err());
+
+        assert_eq!(Literal::Int(1).as_type(&Type::Float), Literal::Float(1.0));
+        assert_eq!(Literal::Int(1).as_type(&Type::Int), Literal::Int(1));
+    }
+
+    #[test]
+    fn normalize_promotes_float_consts() {
+        let mut program = Program::from_json(include_str!("../../tests/float.json")).unwrap();
+        let first = program.functions[0].instrs[0].to_string();
+        assert_eq!(first, "whole: float = const 1;");
+
+        program.normalize();
+        assert_eq!(
+            program.functions[0].instrs[0].to_string(),
+            "whole: float = const 1.0;"
+        );
+        // ints on int consts are left alone
+        let mut program = Program::from_json(include_str!("../../tests/add.json")).unwrap();
+        let before = program.clone();
+        program.normalize();
+        assert_eq!(program, before);
+    }
+
+    #[test]
+    fn float_bit_casts() {
+        let program = text::parse_program(include_str!("../../tests/float.bril")).unwrap();
+        let ops: Vec<&Op> = program.functions[0]
+            .instrs
+            .iter()
+            .filter_map(|instr| match instr {
+                Instruction::Op { op, .. } => Some(op),
+                _ => None,
+            })
+            .collect();
+
+        assert!(ops.contains(&&Op::Float2Bits {
+            dest: "bits".into(),
+            args: vec!["half".into()],
+            typ: Type::Int,
+        }));
+        assert!(ops.contains(&&Op::Bits2Float {
+            dest: "back".into(),
+            args: vec!["bits".into()],
+            typ: Type::Float,
+        }));
+    }
+
+    #[test]
+    fn char_literals() {
+        let lit = |v: &str| serde_json::from_str::<Literal>(v);
+
+        assert_eq!(lit(r#""a""#).unwrap(), Literal::Char('a'));
+        assert_eq!(lit(r#""\n""#).unwrap(), Literal::Char('\n'));
+        assert_eq!(lit(r#""🐦""#).unwrap(), Literal::Char('🐦'));
+
+        let err = lit(r#""ab""#).unwrap_err().to_string();
+        assert!(err.contains("exactly one character"), "{}", err);
+        assert!(lit(r#""""#).is_err());
+
+        let program: Program = serde_json::from_str(include_str!("../../tests/char.json")).unwrap();
+        assert_eq!(program.functions[0].args[0].typ, Type::Char);
+    }
+
+    #[test]
+    fn memory_ops_and_pointer_types() {
+        let json = r#"{"functions": [{
+            "name": "main",
+            "args": [{"name": "pp", "type": {"ptr": {"ptr": "int"}}}],
+            "type": {"ptr": "int"},
+            "instrs": [
+                {"op": "load", "dest": "p", "type": {"ptr": "int"}, "args": ["pp"]},
+                {"op": "ptradd", "dest": "q", "type": {"ptr": "int"}, "args": ["p", "p"]},
+                {"op": "store", "args": ["q", "p"]},
+                {"op": "free", "args": ["q"]},
+                {"op": "ret", "args": ["p"]}
+            ]
+        }]}"#;
+        let program: Program = serde_json::from_str(json).unwrap();
+        let func = &program.functions[0];
+
+        let ptr_int = Type::Ptr(Box::new(Type::Int));
+        assert_eq!(func.args[0].typ, Type::Ptr(Box::new(ptr_int.clone())));
+        assert_eq!(func.ret_typ, Some(ptr_int.clone()));
+        match &func.instrs[1] {
+            Instruction::Op {
+                op: Op::PtrAdd { typ, .. },
+                ..
+            } => assert_eq!(typ, &ptr_int),
+            other => panic!("expected a ptradd, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn type_forms_and_display() {
+        let ty = |v: &str| serde_json::from_str::<Type>(v);
+
+        assert_eq!(ty(r#""int""#).unwrap(), Type::Int);
+        assert_eq!(ty(r#""bool""#).unwrap(), Type::Bool);
+        assert_eq!(
+            ty(r#"{"ptr": {"ptr": "float"}}"#).unwrap(),
+            Type::Ptr(Box::new(Type::Ptr(Box::new(Type::Float))))
+        );
+        assert!(ty(r#""string""#).is_err());
+        assert!(ty(r#"{"box": "int"}"#).is_err());
+        assert!(ty(r#"{"ptr": "int", "box": "int"}"#).is_err());
+
+        let nested = ty(r#"{"ptr": {"ptr": "char"}}"#).unwrap();
+        assert_eq!(nested.to_string(), "ptr<ptr<char>>");
+        assert_eq!("ptr<ptr<char>>".parse::<Type>().unwrap(), nested);
+
+        let any = ty(r#"{"ptr": "any"}"#).unwrap();
+        assert_eq!(any.to_string(), "ptr<any>");
+        assert!(any.is_compatible(&Type::Ptr(Box::new(Type::Int))));
+        assert!(!any.is_compatible(&Type::Int));
+    }
+
+    #[test]
+    fn positions_round_trip() {
+        let json = r#"{"functions":[{"name":"main","args":[],"instrs":[{"label":"l","pos":{"row":1,"col":2}},{"op":"nop","pos":{"row":2,"col":3}},{"op":"nop"}]}]}"#;
+        let program: Program = serde_json::from_str(json).unwrap();
+        match &program.functions[0].instrs[1] {
+            Instruction::Op { op, pos } => {
+                assert_eq!(op, &Op::Nop);
+                assert_eq!(pos, &Some(Position { row: 2, col: 3 }));
+            }
+            other => panic!("expected an op, got {:?}", other),
+        }
+        assert_eq!(serde_json::to_string(&program).unwrap(), json);
+    }
 }
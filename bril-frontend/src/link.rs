@@ -0,0 +1,141 @@
+//! Resolving the import extension: every file's functions end up in one
+//! program, calls through an alias are pointed at the real name.
+
+use crate::json::{Instruction, Op, Program};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+impl Program {
+    /// Merge `programs` into a single program with no imports left.
+    ///
+    /// Imports are matched by function name, not by path, so it doesn't
+    /// matter which file they were read from. Two definitions of the same
+    /// function, or an alias that shadows one, are errors.
+    pub fn link(programs: &[Program]) -> Result<Program> {
+        let mut defined: HashMap<&str, usize> = HashMap::new();
+        for (i, program) in programs.iter().enumerate() {
+            for func in &program.functions {
+                if defined.insert(&func.name, i).is_some() {
+                    bail!("@{} is defined in more than one file", func.name);
+                }
+            }
+        }
+
+        let mut functions = Vec::new();
+        for program in programs {
+            let mut aliases: HashMap<&str, &str> = HashMap::new();
+            for import in &program.imports {
+                for imported in &import.functions {
+                    if !defined.contains_key(imported.name.as_str()) {
+                        bail!(
+                            "@{} is imported from \"{}\" but none of the files define it",
+                            imported.name,
+                            import.path
+                        );
+                    }
+                    if let Some(alias) = &imported.alias {
+                        if defined.contains_key(alias.as_str()) {
+                            bail!("import alias @{} collides with a function", alias);
+                        }
+                        aliases.insert(alias, &imported.name);
+                    }
+                }
+            }
+
+            for func in &program.functions {
+                let mut func = func.clone();
+                for instr in &mut func.instrs {
+                    if let Instruction::Op {
+                        op: Op::Call { funcs, .. },
+                        ..
+                    } = instr
+                    {
+                        for callee in funcs.iter_mut() {
+                            if let Some(name) = aliases.get(callee.as_str()) {
+                                *callee = name.to_string();
+                            }
+                        }
+                    }
+                }
+                functions.push(func);
+            }
+        }
+
+        Ok(Program {
+            functions,
+            imports: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::text::parse_program;
+    use crate::{Instruction, Op, Program};
+
+    fn callees(program: &Program) -> Vec<&str> {
+        program
+            .functions
+            .iter()
+            .flat_map(|f| &f.instrs)
+            .filter_map(|instr| match instr {
+                Instruction::Op {
+                    op: Op::Call { funcs, .. },
+                    ..
+                } => Some(funcs[0].as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn imports_and_aliases() {
+        let main = parse_program(
+            r#"from "lib.bril" import @double, @triple as @thrice;
+@main {
+  x: int = const 2;
+  y: int = call @double x;
+  z: int = call @thrice y;
+  print z;
+}"#,
+        )
+        .unwrap();
+        assert_eq!(main.imports[0].path, "lib.bril");
+        assert_eq!(
+            main.imports[0].functions[1].alias.as_deref(),
+            Some("thrice")
+        );
+
+        let lib = parse_program(
+            "@double(n: int): int {
+  r: int = add n n;
+  ret r;
+}
+@triple(n: int): int {
+  d: int = call @double n;
+  r: int = add d n;
+  ret r;
+}",
+        )
+        .unwrap();
+
+        let linked = Program::link(&[main.clone(), lib.clone()]).unwrap();
+        assert!(linked.imports.is_empty());
+        let names: Vec<&str> = linked.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["main", "double", "triple"]);
+        assert_eq!(callees(&linked), ["double", "triple", "double"]);
+
+        // imports survive a trip through JSON
+        let json = serde_json::to_string(&main).unwrap();
+        assert!(json.contains(r#""imports":[{"path":"lib.bril","functions":[{"name":"double"},{"name":"triple","alias":"thrice"}]}]"#));
+        assert_eq!(serde_json::from_str::<Program>(&json).unwrap(), main);
+
+        let err = Program::link(std::slice::from_ref(&main)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@double is imported from \"lib.bril\" but none of the files define it"
+        );
+        let err = Program::link(&[lib.clone(), lib]).unwrap_err();
+        assert_eq!(err.to_string(), "@double is defined in more than one file");
+    }
+}
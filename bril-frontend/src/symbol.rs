@@ -0,0 +1,164 @@
+//! Interned variable names. A [`Symbol`] is a `u32` into a process-wide
+//! table, so copying, hashing and comparing names doesn't touch the heap.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Mutex, OnceLock};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Interner {
+    // names are leaked so `as_str` can hand out `&'static str`, there's
+    // only ever as many of them as distinct names in the programs we compile
+    names: Vec<&'static str>,
+    ids: HashMap<&'static str, Symbol>,
+}
+
+fn interner() -> &'static Mutex<Interner> {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(Default::default)
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        let mut interner = interner().lock().unwrap();
+        if let Some(&sym) = interner.ids.get(name) {
+            return sym;
+        }
+        let name: &'static str = Box::leak(name.into());
+        let sym = Symbol(interner.names.len() as u32);
+        interner.names.push(name);
+        interner.ids.insert(name, sym);
+        sym
+    }
+
+    pub fn as_str(self) -> &'static str {
+        interner().lock().unwrap().names[self.0 as usize]
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Symbol {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::intern(&name)
+    }
+}
+
+impl From<Symbol> for String {
+    fn from(sym: Symbol) -> Self {
+        sym.as_str().to_string()
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for Symbol {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+// by name, not by id, so sorting doesn't depend on interning order
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        if self == other {
+            return std::cmp::Ordering::Equal;
+        }
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// same as a String, so `{:#?}` dumps of the IR read the same as before
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Symbol::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Symbol;
+
+    #[test]
+    fn interning() {
+        let a = Symbol::from("x");
+        let b = Symbol::from("x".to_string());
+        assert_eq!(a, b);
+        assert_ne!(a, Symbol::from("y"));
+        assert_eq!(a, "x");
+        assert_eq!(a.to_string(), "x");
+        assert_eq!(format!("{:?}", a), "\"x\"");
+
+        // ordered by name, whatever order they were interned in
+        let mut names: Vec<Symbol> = ["b$2", "a", "b$10"].map(Symbol::from).to_vec();
+        names.sort();
+        assert_eq!(names, ["a", "b$10", "b$2"]);
+
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(json, "\"x\"");
+        assert_eq!(serde_json::from_str::<Symbol>(&json).unwrap(), a);
+    }
+}
@@ -0,0 +1,768 @@
+//! Parser for the Bril text syntax, so `.bril` files don't have to go through
+//! `bril2json` first. Produces the same `Program` as the JSON path.
+//!
+//! `Display` goes the other way, printing a `Program` like `bril2txt` does.
+//!
+//! The grammar follows the reference `bril2json`:
+//!
+//! ```text
+//! @name(arg: type, ...): type {
+//! .label:
+//!   dest: type = const 5;
+//!   dest: type = op args... @funcs... .labels...;
+//!   op args... @funcs... .labels...;
+//! }
+//! ```
+
+use crate::json::{
+    Function, Import, ImportedFunction, Instruction, Literal, Op, Position, Program, ValueDef,
+};
+use crate::types::Type;
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::fmt;
+
+/// Parse a whole Bril text program
+pub fn parse_program(src: &str) -> Result<Program> {
+    parse(src, false)
+}
+
+/// Like [`parse_program`], but records each instruction's `pos` the way
+/// `bril2json -p` does
+pub fn parse_program_with_positions(src: &str) -> Result<Program> {
+    parse(src, true)
+}
+
+fn parse(src: &str, positions: bool) -> Result<Program> {
+    let tokens = Lexer::new(src).tokenize()?;
+    Parser {
+        tokens,
+        pos: 0,
+        positions,
+    }
+    .program()
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for import in &self.imports {
+            write!(f, "from \"{}\" import ", import.path)?;
+            for (i, func) in import.functions.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "@{}", func.name)?;
+                if let Some(alias) = &func.alias {
+                    write!(f, " as @{}", alias)?;
+                }
+            }
+            writeln!(f, ";")?;
+        }
+        for (i, func) in self.functions.iter().enumerate() {
+            if i > 0 || !self.imports.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "{}", func)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}", self.name)?;
+        if !self.args.is_empty() {
+            let args: Vec<String> = self
+                .args
+                .iter()
+                .map(|a| format!("{}: {}", a.name, a.typ))
+                .collect();
+            write!(f, "({})", args.join(", "))?;
+        }
+        if let Some(typ) = &self.ret_typ {
+            write!(f, ": {}", typ)?;
+        }
+        writeln!(f, " {{")?;
+        for instr in &self.instrs {
+            match instr {
+                Instruction::Label { .. } => writeln!(f, "{}", instr)?,
+                Instruction::Op { .. } => writeln!(f, "  {}", instr)?,
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Label { label, .. } => write!(f, ".{}:", label),
+            Instruction::Op { op, .. } => write!(f, "{};", op),
+        }
+    }
+}
+
+/// `dest: type = op args... @funcs... .labels...`, without the `;`
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // same trick as the parser, every variant spells its operands the
+        // same way in JSON
+        let fields = serde_json::to_value(self).unwrap_or(Value::Null);
+        if let Some(dest) = fields["dest"].as_str() {
+            write!(f, "{}", dest)?;
+            if let Ok(typ) = Type::deserialize(&fields["type"]) {
+                write!(f, ": {}", typ)?;
+            }
+            write!(f, " = ")?;
+        }
+        write!(f, "{}", fields["op"].as_str().unwrap_or_default())?;
+
+        if let Op::Const { value, .. } = self {
+            return write!(f, " {}", fmt_literal(value));
+        }
+        let list = |key: &str| -> Vec<&str> {
+            fields[key]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect()
+        };
+        for arg in list("args") {
+            write!(f, " {}", arg)?;
+        }
+        for func in list("funcs") {
+            write!(f, " @{}", func)?;
+        }
+        for label in list("labels") {
+            write!(f, " .{}", label)?;
+        }
+        Ok(())
+    }
+}
+
+fn fmt_literal(value: &Literal) -> String {
+    match value {
+        Literal::Int(i) => i.to_string(),
+        Literal::Bool(b) => b.to_string(),
+        // Debug keeps the `.0` on whole floats so they don't read back as ints
+        Literal::Float(x) => format!("{:?}", x),
+        Literal::Char(c) => {
+            let escaped = match c {
+                '\0' => "\\0".to_string(),
+                '\u{07}' => "\\a".to_string(),
+                '\u{08}' => "\\b".to_string(),
+                '\t' => "\\t".to_string(),
+                '\n' => "\\n".to_string(),
+                '\u{0b}' => "\\v".to_string(),
+                '\u{0c}' => "\\f".to_string(),
+                '\r' => "\\r".to_string(),
+                c => c.to_string(),
+            };
+            format!("'{}'", escaped)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    /// `@name`
+    Func(String),
+    /// `.name`
+    Label(String),
+    Int(i64),
+    Float(f64),
+    Char(char),
+    /// `"path"`, only used by imports
+    Str(String),
+    Sym(char),
+    Eof,
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tok::Ident(s) => write!(f, "`{}`", s),
+            Tok::Func(s) => write!(f, "`@{}`", s),
+            Tok::Label(s) => write!(f, "`.{}`", s),
+            Tok::Int(i) => write!(f, "`{}`", i),
+            Tok::Float(x) => write!(f, "`{}`", x),
+            Tok::Char(c) => write!(f, "`{:?}`", c),
+            Tok::Str(s) => write!(f, "`\"{}\"`", s),
+            Tok::Sym(c) => write!(f, "`{}`", c),
+            Tok::Eof => write!(f, "end of input"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    tok: Tok,
+    line: usize,
+    col: usize,
+}
+
+struct Lexer<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    col: usize,
+}
+
+fn is_ident_start(c: char) -> bool {
+    c == '_' || c == '%' || c.is_alphabetic()
+}
+
+fn is_ident_char(c: char) -> bool {
+    c == '_' || c == '%' || c == '.' || c.is_alphanumeric()
+}
+
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str) -> Self {
+        Self {
+            chars: src.chars().peekable(),
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> String {
+        let mut s = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !pred(c) {
+                break;
+            }
+            s.push(c);
+            self.bump();
+        }
+        s
+    }
+
+    fn tokenize(mut self) -> Result<Vec<Token>> {
+        let mut tokens = Vec::new();
+
+        loop {
+            let (line, col) = (self.line, self.col);
+            let Some(&c) = self.chars.peek() else {
+                tokens.push(Token {
+                    tok: Tok::Eof,
+                    line,
+                    col,
+                });
+                return Ok(tokens);
+            };
+
+            let tok = match c {
+                _ if c.is_whitespace() => {
+                    self.bump();
+                    continue;
+                }
+                '#' => {
+                    self.take_while(|c| c != '\n');
+                    continue;
+                }
+                '@' => {
+                    self.bump();
+                    Tok::Func(self.ident(line, col)?)
+                }
+                '\'' => self.char_literal(line, col)?,
+                '"' => {
+                    self.bump();
+                    let s = self.take_while(|c| c != '"' && c != '\n');
+                    if self.bump() != Some('"') {
+                        bail!("{}:{}: unterminated string", line, col);
+                    }
+                    Tok::Str(s)
+                }
+                '.' | '+' | '-' | '0'..='9' => {
+                    self.bump();
+                    let next = self.chars.peek().copied();
+                    match (c, next) {
+                        ('.', Some(n)) if is_ident_start(n) => Tok::Label(self.ident(line, col)?),
+                        ('+' | '-', Some(n)) if !(n.is_ascii_digit() || n == '.') => {
+                            bail!("{}:{}: expected a number after `{}`", line, col, c)
+                        }
+                        _ => self.number(c, line, col)?,
+                    }
+                }
+                '{' | '}' | '(' | ')' | ':' | ';' | '=' | ',' | '<' | '>' => {
+                    self.bump();
+                    Tok::Sym(c)
+                }
+                _ if is_ident_start(c) => Tok::Ident(self.ident(line, col)?),
+                _ => bail!("{}:{}: unexpected character `{}`", line, col, c),
+            };
+
+            tokens.push(Token { tok, line, col });
+        }
+    }
+
+    fn ident(&mut self, line: usize, col: usize) -> Result<String> {
+        match self.chars.peek() {
+            Some(&c) if is_ident_start(c) => Ok(self.take_while(is_ident_char)),
+            _ => bail!("{}:{}: expected a name", line, col),
+        }
+    }
+
+    /// `first` is already consumed, it's a sign, a digit or the `.` of `.5`
+    fn number(&mut self, first: char, line: usize, col: usize) -> Result<Tok> {
+        let mut text = first.to_string();
+        text += &self.take_while(|c| c.is_ascii_digit() || c == '.');
+        if let Some('e' | 'E') = self.chars.peek() {
+            text.push(self.bump().unwrap_or('e'));
+            if let Some(&sign @ ('+' | '-')) = self.chars.peek() {
+                text.push(sign);
+                self.bump();
+            }
+            text += &self.take_while(|c| c.is_ascii_digit());
+        }
+
+        let is_float = text.contains(['.', 'e', 'E']);
+        let parsed = if is_float {
+            text.parse::<f64>().ok().map(Tok::Float)
+        } else {
+            text.parse::<i64>().ok().map(Tok::Int)
+        };
+        parsed.ok_or_else(|| anyhow!("{}:{}: invalid number `{}`", line, col, text))
+    }
+
+    /// `'c'` or one of the escapes `'\0' '\a' '\b' '\t' '\n' '\v' '\f' '\r'`
+    fn char_literal(&mut self, line: usize, col: usize) -> Result<Tok> {
+        self.bump();
+        let c = match self.bump() {
+            Some('\\') => match self.bump() {
+                Some('0') => '\0',
+                Some('a') => '\u{07}',
+                Some('b') => '\u{08}',
+                Some('t') => '\t',
+                Some('n') => '\n',
+                Some('v') => '\u{0b}',
+                Some('f') => '\u{0c}',
+                Some('r') => '\r',
+                other => bail!(
+                    "{}:{}: unknown char escape `\\{}`",
+                    line,
+                    col,
+                    other.unwrap_or(' ')
+                ),
+            },
+            Some('\n') | None => bail!("{}:{}: unterminated char literal", line, col),
+            Some(c) => c,
+        };
+        match self.bump() {
+            Some('\'') => Ok(Tok::Char(c)),
+            _ => bail!(
+                "{}:{}: a char literal must be exactly one character",
+                line,
+                col
+            ),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    positions: bool,
+}
+
+impl Parser {
+    // the token list always ends in `Eof` and we never step past it
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos.min(self.tokens.len() - 1)]
+    }
+
+    fn peek_nth(&self, n: usize) -> &Tok {
+        &self.tokens[(self.pos + n).min(self.tokens.len() - 1)].tok
+    }
+
+    fn error<T>(&self, expected: &str) -> Result<T> {
+        let t = self.peek();
+        bail!(
+            "{}:{}: expected {}, found {}",
+            t.line,
+            t.col,
+            expected,
+            t.tok
+        )
+    }
+
+    fn eat(&mut self, sym: char) -> bool {
+        if self.peek().tok == Tok::Sym(sym) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, sym: char) -> Result<()> {
+        if !self.eat(sym) {
+            return self.error(&format!("`{}`", sym));
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        match &self.peek().tok {
+            Tok::Ident(name) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => self.error("a name"),
+        }
+    }
+
+    fn program(&mut self) -> Result<Program> {
+        let (mut functions, mut imports) = (Vec::new(), Vec::new());
+        while self.peek().tok != Tok::Eof {
+            if self.peek().tok == Tok::Ident("from".into()) {
+                imports.push(self.import()?);
+            } else {
+                functions.push(self.function()?);
+            }
+        }
+        Ok(Program { functions, imports })
+    }
+
+    fn func_name(&mut self) -> Result<String> {
+        match &self.peek().tok {
+            Tok::Func(name) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => self.error("a function name"),
+        }
+    }
+
+    /// `from "lib.bril" import @f, @g as @h;`
+    fn import(&mut self) -> Result<Import> {
+        self.pos += 1;
+        let path = match &self.peek().tok {
+            Tok::Str(path) => path.clone(),
+            _ => return self.error("a quoted path"),
+        };
+        self.pos += 1;
+        if self.ident()? != "import" {
+            self.pos -= 1;
+            return self.error("`import`");
+        }
+
+        let mut functions = Vec::new();
+        loop {
+            let name = self.func_name()?;
+            let alias = if self.peek().tok == Tok::Ident("as".into()) {
+                self.pos += 1;
+                Some(self.func_name()?)
+            } else {
+                None
+            };
+            functions.push(ImportedFunction { name, alias });
+            if !self.eat(',') {
+                break;
+            }
+        }
+        self.expect(';')?;
+        Ok(Import { path, functions })
+    }
+
+    fn function(&mut self) -> Result<Function> {
+        let name = match &self.peek().tok {
+            Tok::Func(name) => name.clone(),
+            _ => return self.error("a function like `@main`"),
+        };
+        self.pos += 1;
+
+        let mut args = Vec::new();
+        if self.eat('(') && !self.eat(')') {
+            loop {
+                let name = self.ident()?;
+                self.expect(':')?;
+                let typ = self.typ()?;
+                args.push(ValueDef {
+                    name: name.into(),
+                    typ,
+                });
+                if self.eat(')') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+
+        let ret_typ = if self.eat(':') {
+            Some(self.typ()?)
+        } else {
+            None
+        };
+
+        self.expect('{')?;
+        let mut instrs = Vec::new();
+        while !self.eat('}') {
+            instrs.push(self.instruction()?);
+        }
+
+        Ok(Function {
+            name,
+            args,
+            instrs,
+            ret_typ,
+        })
+    }
+
+    fn typ(&mut self) -> Result<crate::Type> {
+        let start = self.peek().clone();
+        let value = self.type_value()?;
+        serde_json::from_value(value).map_err(|e| anyhow!("{}:{}: {}", start.line, start.col, e))
+    }
+
+    /// Types are checked by the JSON deserializer, this only builds its input
+    fn type_value(&mut self) -> Result<Value> {
+        let name = self.ident()?;
+        if self.eat('<') {
+            let inner = self.type_value()?;
+            self.expect('>')?;
+            return Ok(json!({ name: inner }));
+        }
+        Ok(Value::String(name))
+    }
+
+    fn instruction(&mut self) -> Result<Instruction> {
+        let start = self.peek().clone();
+        let pos = self.positions.then_some(Position {
+            row: start.line as u64,
+            col: start.col as u64,
+        });
+
+        if let Tok::Label(label) = &start.tok
+            && self.peek_nth(1) == &Tok::Sym(':')
+        {
+            self.pos += 2;
+            return Ok(Instruction::Label {
+                label: label.clone(),
+                pos,
+            });
+        }
+
+        // the instruction is put together as JSON and handed to the `Op`
+        // deserializer, so both frontends agree on what's valid
+        let mut fields = Map::new();
+        let first = self.ident()?;
+
+        let is_value_op = matches!(self.peek_nth(0), Tok::Sym(':') | Tok::Sym('='));
+        let op = if is_value_op {
+            fields.insert("dest".into(), Value::String(first));
+            if self.eat(':') {
+                fields.insert("type".into(), self.type_value()?);
+            }
+            self.expect('=')?;
+            self.ident()?
+        } else {
+            first
+        };
+
+        if op == "const" {
+            fields.insert("value".into(), self.literal()?);
+        } else {
+            let (mut args, mut funcs, mut labels) = (Vec::new(), Vec::new(), Vec::new());
+            loop {
+                match self.peek().tok.clone() {
+                    Tok::Ident(a) => args.push(Value::String(a)),
+                    Tok::Func(f) => funcs.push(Value::String(f)),
+                    Tok::Label(l) => labels.push(Value::String(l)),
+                    _ => break,
+                }
+                self.pos += 1;
+            }
+            for (key, list) in [("args", args), ("funcs", funcs), ("labels", labels)] {
+                if !list.is_empty() {
+                    fields.insert(key.into(), Value::Array(list));
+                }
+            }
+        }
+        self.expect(';')?;
+
+        fields.insert("op".into(), Value::String(op));
+        let op: Op = serde_json::from_value(Value::Object(fields))
+            .map_err(|e| anyhow!("{}:{}: {}", start.line, start.col, e))?;
+        Ok(Instruction::Op { op, pos })
+    }
+
+    fn literal(&mut self) -> Result<Value> {
+        let value = match &self.peek().tok {
+            Tok::Int(i) => json!(i),
+            Tok::Float(x) => json!(x),
+            Tok::Char(c) => json!(c.to_string()),
+            Tok::Ident(b) if b == "true" => json!(true),
+            Tok::Ident(b) if b == "false" => json!(false),
+            _ => return self.error("a literal"),
+        };
+        self.pos += 1;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_json(json: &str) -> Program {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn text_and_json_agree() {
+        let pairs = [
+            (
+                include_str!("../../tests/add.bril"),
+                include_str!("../../tests/add.json"),
+            ),
+            (
+                include_str!("../../tests/palindrome.bril"),
+                include_str!("../../tests/palindrome.json"),
+            ),
+            (
+                include_str!("../../tests/char.bril"),
+                include_str!("../../tests/char.json"),
+            ),
+            (
+                include_str!("../../tests/char_convert.bril"),
+                include_str!("../../tests/char_convert.json"),
+            ),
+            (
+                include_str!("../../tests/loop.bril"),
+                include_str!("../../tests/loop.json"),
+            ),
+            (
+                include_str!("../../tests/factorial.bril"),
+                include_str!("../../tests/factorial.json"),
+            ),
+        ];
+
+        for (text, json) in pairs {
+            assert_eq!(parse_program(text).unwrap(), from_json(json));
+        }
+    }
+
+    #[test]
+    fn printing_matches_bril2txt() {
+        let program = from_json(include_str!("../../tests/add.json"));
+        assert_eq!(program.to_string(), include_str!("../../tests/add.bril"));
+
+        // everything else at least survives the round trip
+        for src in [
+            include_str!("../../tests/palindrome.bril"),
+            include_str!("../../tests/char.bril"),
+            include_str!("../../tests/char_convert.bril"),
+            include_str!("../../tests/float.bril"),
+            include_str!("../../tests/factorial.bril"),
+            r#"from "lib.bril" import @double, @triple as @thrice;
+@main(p: ptr<int>) {
+  c: char = const '\n';
+  x: int = call @thrice;
+  call @double x;
+  br x .a .b;
+.a:
+  speculate;
+.b:
+}"#,
+        ] {
+            let program = parse_program(src).unwrap();
+            assert_eq!(parse_program(&program.to_string()).unwrap(), program);
+        }
+    }
+
+    #[test]
+    fn positions_are_opt_in() {
+        let src = "@main {\n  x: int = const 1;\n.done:\n  print x;\n}\n";
+        let program = parse_program_with_positions(src).unwrap();
+        let pos: Vec<_> = program.functions[0]
+            .instrs
+            .iter()
+            .map(|instr| match instr {
+                Instruction::Label { pos, .. } | Instruction::Op { pos, .. } => {
+                    pos.unwrap().to_string()
+                }
+            })
+            .collect();
+        assert_eq!(pos, ["2:3", "3:1", "4:3"]);
+
+        let json = serde_json::to_string(&parse_program(src).unwrap()).unwrap();
+        assert!(!json.contains("pos"));
+    }
+
+    #[test]
+    fn literals() {
+        let program = parse_program(
+            "@main {
+               a: float = const .5;
+               b: float = const -1E+6;
+               c: float = const 1.;
+               d: int = const -7;
+               e: char = const '\\n';
+               f: char = const '#';
+               g: bool = const false;
+             }",
+        )
+        .unwrap();
+
+        let values: Vec<_> = program.functions[0]
+            .instrs
+            .iter()
+            .map(|instr| match instr {
+                Instruction::Op {
+                    op: Op::Const { value, .. },
+                    ..
+                } => value.clone(),
+                other => panic!("expected a const, got {:?}", other),
+            })
+            .collect();
+
+        use crate::Literal;
+        assert_eq!(
+            values,
+            vec![
+                Literal::Float(0.5),
+                Literal::Float(-1e6),
+                Literal::Float(1.0),
+                Literal::Int(-7),
+                Literal::Char('\n'),
+                Literal::Char('#'),
+                Literal::Bool(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_have_positions() {
+        let err = |src: &str| parse_program(src).unwrap_err().to_string();
+
+        assert_eq!(
+            err("@main {\n  x: int = const 1\n}"),
+            "3:1: expected `;`, found `}`"
+        );
+        assert_eq!(
+            err("@main {\n  v: int = add a;\n}"),
+            "2:3: invalid length 1, expected an array of length 2"
+        );
+        assert_eq!(err("@main(x int) {}"), "1:9: expected `:`, found `int`");
+        assert_eq!(
+            err("@main {\n  c: char = const 'ab';\n}"),
+            "2:19: a char literal must be exactly one character"
+        );
+    }
+}
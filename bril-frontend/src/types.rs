@@ -0,0 +1,113 @@
+use serde::de::{self, Deserializer};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Bril value types
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Type {
+    Int,
+    Bool,
+    Float,
+    Char,
+    Ptr(Box<Type>),
+    /// The dynamic extension's type, checked at run time if at all
+    Any,
+}
+
+impl Type {
+    pub fn is_ptr(&self) -> bool {
+        matches!(self, Type::Ptr(_))
+    }
+
+    /// Whether a value of one type can be used as the other, `any` goes
+    /// with everything, including behind a pointer
+    pub fn is_compatible(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Ptr(a), Type::Ptr(b)) => a.is_compatible(b),
+            _ => self == other,
+        }
+    }
+}
+
+/// Canonical Bril spelling, `ptr<int>` for pointers like the text format
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::Float => write!(f, "float"),
+            Type::Char => write!(f, "char"),
+            Type::Ptr(inner) => write!(f, "ptr<{}>", inner),
+            Type::Any => write!(f, "any"),
+        }
+    }
+}
+
+impl FromStr for Type {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "int" => Ok(Type::Int),
+            "bool" => Ok(Type::Bool),
+            "float" => Ok(Type::Float),
+            "char" => Ok(Type::Char),
+            "any" => Ok(Type::Any),
+            other => match other.strip_prefix("ptr<").and_then(|t| t.strip_suffix('>')) {
+                Some(inner) => Ok(Type::Ptr(Box::new(inner.parse()?))),
+                None => Err(format!("unknown type `{}`", other)),
+            },
+        }
+    }
+}
+
+/// Bril spells parametric types as objects (`{"ptr": "int"}`)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawType {
+    Name(String),
+    Param(BTreeMap<String, RawType>),
+}
+
+impl RawType {
+    fn into_type<E: de::Error>(self) -> Result<Type, E> {
+        match self {
+            RawType::Name(name) => name.parse().map_err(E::custom),
+            RawType::Param(map) => {
+                let mut entries = map.into_iter();
+                match (entries.next(), entries.next()) {
+                    (Some((ctor, inner)), None) if ctor == "ptr" => {
+                        Ok(Type::Ptr(Box::new(inner.into_type()?)))
+                    }
+                    (Some((ctor, _)), None) => {
+                        Err(E::custom(format!("unknown parametric type `{}`", ctor)))
+                    }
+                    _ => Err(E::custom("a parametric type needs exactly one constructor")),
+                }
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Type {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        RawType::deserialize(deserializer)?.into_type()
+    }
+}
+
+impl Serialize for Type {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Type::Ptr(inner) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("ptr", inner)?;
+                map.end()
+            }
+            other => serializer.collect_str(other),
+        }
+    }
+}
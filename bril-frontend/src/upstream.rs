@@ -0,0 +1,140 @@
+//! Conversions to and from the upstream `bril_rs` types, so programs can be
+//! passed to the other Bril tools written in Rust (brilirs, the basic-block
+//! builder, ...) and back.
+//!
+//! Both sides read and write the same JSON, so that's what the conversions go
+//! through instead of matching every op twice.
+
+use crate::json::{Instruction, Op, ParseError, ParseOptions, Program};
+use std::fmt;
+
+/// Why a program couldn't be turned into a `bril_rs::Program`
+#[derive(Debug)]
+pub enum ConversionError {
+    /// Instructions bril-rs has no form for, each with the function it's in
+    Unsupported(Vec<String>),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::Unsupported(found) => {
+                write!(f, "bril-rs has no equivalent for {}", found.join(", "))
+            }
+            ConversionError::Json(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConversionError::Json(e) => Some(e),
+            ConversionError::Unsupported(_) => None,
+        }
+    }
+}
+
+impl TryFrom<&bril_rs::Program> for Program {
+    type Error = ParseError;
+
+    fn try_from(program: &bril_rs::Program) -> Result<Self, ParseError> {
+        // bril-rs doesn't check arities, so this can still fail on e.g. an
+        // `add` with three args
+        ParseOptions::default().parse_value(serde_json::to_value(program)?)
+    }
+}
+
+impl TryFrom<&Program> for bril_rs::Program {
+    type Error = ConversionError;
+
+    fn try_from(program: &Program) -> Result<Self, ConversionError> {
+        let mut unsupported = Vec::new();
+        for func in &program.functions {
+            for instr in &func.instrs {
+                let Instruction::Op { op, .. } = instr else {
+                    continue;
+                };
+                let missing = match op {
+                    // bril-rs only has the `get`/`set` form of SSA
+                    Op::Phi { .. } | Op::Other { .. } => true,
+                    // JSON can't spell these as numbers and bril-rs won't take strings
+                    Op::Const {
+                        value: crate::Literal::Float(f),
+                        ..
+                    } => !f.is_finite(),
+                    _ => false,
+                };
+                if missing {
+                    unsupported.push(format!("`{}` in @{}", op, func.name));
+                }
+            }
+        }
+        if !unsupported.is_empty() {
+            return Err(ConversionError::Unsupported(unsupported));
+        }
+
+        let mut value = serde_json::to_value(program).map_err(ConversionError::Json)?;
+        // we don't keep the type of the logic ops, it can only be bool
+        let instrs = value["functions"]
+            .as_array_mut()
+            .into_iter()
+            .flatten()
+            .flat_map(|func| func["instrs"].as_array_mut().into_iter().flatten());
+        for instr in instrs {
+            if matches!(instr["op"].as_str(), Some("and" | "or" | "not")) {
+                instr["type"] = "bool".into();
+            }
+        }
+        serde_json::from_value(value).map_err(ConversionError::Json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConversionError;
+    use crate::Program;
+
+    const PROGRAMS: [&str; 5] = [
+        include_str!("../../tests/add.json"),
+        include_str!("../../tests/char_convert.json"),
+        include_str!("../../tests/palindrome.json"),
+        include_str!("../../bril-extern/bril/test/print/eight-queens.json"),
+        include_str!("../../bril-extern/bril/test/parse/mem.json"),
+    ];
+
+    #[test]
+    fn both_ways() {
+        for json in PROGRAMS {
+            let upstream = bril_rs::load_program_from_read(json.as_bytes());
+            let ours = Program::try_from(&upstream).unwrap();
+            assert_eq!(ours, Program::from_json(json).unwrap());
+
+            let back = bril_rs::Program::try_from(&ours).unwrap();
+            assert_eq!(back, upstream);
+        }
+    }
+
+    #[test]
+    fn what_bril_rs_cant_take_is_listed() {
+        let program = Program::from_json(
+            r#"{"functions": [{"name": "main", "instrs": [
+                {"op": "const", "dest": "one", "type": "int", "value": 1},
+                {"op": "const", "dest": "inf", "type": "float", "value": "Infinity"},
+                {"label": "next"},
+                {"op": "phi", "dest": "x", "type": "int", "args": ["one"], "labels": ["next"]},
+                {"op": "vecadd", "dest": "v", "type": "int", "args": ["one", "x"]},
+                {"op": "print", "args": ["x"]}
+            ]}]}"#,
+        )
+        .unwrap();
+        let err = bril_rs::Program::try_from(&program).unwrap_err();
+        assert!(matches!(&err, ConversionError::Unsupported(found) if found.len() == 3));
+        assert_eq!(
+            err.to_string(),
+            "bril-rs has no equivalent for `inf: float = const inf` in @main, \
+             `x: int = phi one .next` in @main, `v: int = vecadd one x` in @main"
+        );
+    }
+}
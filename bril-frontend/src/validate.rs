@@ -0,0 +1,627 @@
+//! Checks that catch malformed programs before they reach the CFG builder,
+//! where they'd otherwise show up as panics or confusing errors.
+
+use crate::json::{Function, Instruction, Op, Position, Program};
+use crate::types::Type;
+use crate::Symbol;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// One problem with one instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub func: String,
+    /// Index into the function's `instrs`, labels included
+    pub index: usize,
+    pub pos: Option<Position>,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}[{}]", self.func, self.index)?;
+        if let Some(pos) = self.pos {
+            write!(f, " {}", pos)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// The operand lists of an op, whatever variant it is
+struct Operands<'a> {
+    opcode: &'a str,
+    dest: Option<Symbol>,
+    args: &'a [Symbol],
+    labels: &'a [String],
+    funcs: &'a [String],
+    typ: Option<Type>,
+}
+
+impl<'a> Operands<'a> {
+    fn of(op: &'a Op) -> Self {
+        // no catch-all, a new op has to say where its operands are
+        let (dest, args, labels, funcs): (Option<Symbol>, &[Symbol], &[String], &[String]) =
+            match op {
+                Op::Add { dest, args, .. }
+                | Op::Sub { dest, args, .. }
+                | Op::Mul { dest, args, .. }
+                | Op::Div { dest, args, .. }
+                | Op::Eq { dest, args, .. }
+                | Op::Lt { dest, args, .. }
+                | Op::Gt { dest, args, .. }
+                | Op::Le { dest, args, .. }
+                | Op::Ge { dest, args, .. }
+                | Op::FAdd { dest, args, .. }
+                | Op::FSub { dest, args, .. }
+                | Op::FMul { dest, args, .. }
+                | Op::FDiv { dest, args, .. }
+                | Op::FEq { dest, args, .. }
+                | Op::FLt { dest, args, .. }
+                | Op::FGt { dest, args, .. }
+                | Op::FLe { dest, args, .. }
+                | Op::FGe { dest, args, .. }
+                | Op::CEq { dest, args, .. }
+                | Op::CLt { dest, args, .. }
+                | Op::CGt { dest, args, .. }
+                | Op::CLe { dest, args, .. }
+                | Op::CGe { dest, args, .. }
+                | Op::And { dest, args, .. }
+                | Op::Or { dest, args, .. }
+                | Op::PtrAdd { dest, args, .. } => (Some(*dest), args, &[], &[]),
+                Op::Float2Bits { dest, args, .. }
+                | Op::Bits2Float { dest, args, .. }
+                | Op::Char2Int { dest, args, .. }
+                | Op::Int2Char { dest, args, .. }
+                | Op::Not { dest, args, .. }
+                | Op::Id { dest, args, .. }
+                | Op::Alloc { dest, args, .. }
+                | Op::Load { dest, args, .. } => (Some(*dest), args, &[], &[]),
+                Op::Phi {
+                    dest, args, labels, ..
+                } => (Some(*dest), args, labels, &[]),
+                Op::Const { dest, .. } | Op::Get { dest, .. } | Op::Undef { dest, .. } => {
+                    (Some(*dest), &[], &[], &[])
+                }
+                Op::Store { args } | Op::Set { args } => (None, args, &[], &[]),
+                Op::Free { args } | Op::Ret { args } | Op::Print { args } => (None, args, &[], &[]),
+                Op::Br { args, labels } => (None, args, labels, &[]),
+                Op::Guard { args, labels } => (None, args, labels, &[]),
+                Op::Jmp { labels } => (None, &[], labels, &[]),
+                Op::Call {
+                    dest, args, funcs, ..
+                } => (*dest, args, &[], funcs),
+                Op::Other {
+                    dest,
+                    args,
+                    funcs,
+                    labels,
+                    ..
+                } => (*dest, args, labels, funcs),
+                Op::Nop | Op::Speculate | Op::Commit => (None, &[], &[], &[]),
+            };
+        // a call or an unknown op can have a type without a dest
+        let typ = match op {
+            Op::Call { typ, .. } | Op::Other { typ, .. } => typ.clone(),
+            _ => op.dest_type().map(|(_, typ)| typ),
+        };
+        Operands {
+            opcode: op.opcode(),
+            dest,
+            args,
+            labels,
+            funcs,
+            typ,
+        }
+    }
+}
+
+/// How many `args` an op takes, for the ones whose count the types don't pin down
+fn arg_count(opcode: &str) -> Option<(usize, usize)> {
+    match opcode {
+        "not" | "id" | "br" | "guard" | "alloc" | "load" | "free" | "float2bits" | "bits2float"
+        | "char2int" | "int2char" => Some((1, 1)),
+        "ret" => Some((0, 1)),
+        _ => None,
+    }
+}
+
+impl Program {
+    /// Check operand counts, label references and calls across the whole
+    /// program, returning every problem found rather than just the first
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let signatures: HashMap<&str, &Function> = self
+            .functions
+            .iter()
+            .map(|f| (f.name.as_str(), f))
+            .collect();
+        // functions from other files, nothing to check them against
+        let imported: HashSet<&str> = self
+            .imports
+            .iter()
+            .flat_map(|i| &i.functions)
+            .map(|f| f.alias.as_deref().unwrap_or(&f.name))
+            .collect();
+
+        let mut errors = Vec::new();
+        for func in &self.functions {
+            validate_function(func, &signatures, &imported, &mut errors);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn validate_function(
+    func: &Function,
+    signatures: &HashMap<&str, &Function>,
+    imported: &HashSet<&str>,
+    errors: &mut Vec<ValidationError>,
+) {
+    let mut labels = HashSet::new();
+    let mut defined: HashSet<Symbol> = func.args.iter().map(|a| a.name).collect();
+    // the declared types, for checking calls. A variable that's redefined
+    // with another type could be either, so it's `any`
+    let mut types: HashMap<Symbol, Type> =
+        func.args.iter().map(|a| (a.name, a.typ.clone())).collect();
+    for (index, instr) in func.instrs.iter().enumerate() {
+        if let Instruction::Op { op, .. } = instr
+            && let Operands {
+                dest: Some(dest),
+                typ: Some(typ),
+                ..
+            } = Operands::of(op)
+        {
+            types
+                .entry(dest)
+                .and_modify(|t| {
+                    if *t != typ {
+                        *t = Type::Any;
+                    }
+                })
+                .or_insert(typ);
+        }
+        match instr {
+            Instruction::Label { label, pos } => {
+                if !labels.insert(label.as_str()) {
+                    errors.push(ValidationError {
+                        func: func.name.clone(),
+                        index,
+                        pos: *pos,
+                        message: format!("label .{} is defined more than once", label),
+                    });
+                }
+            }
+            Instruction::Op { op, .. } => defined.extend(Operands::of(op).dest),
+        }
+    }
+
+    for (index, instr) in func.instrs.iter().enumerate() {
+        let Instruction::Op { op, pos } = instr else {
+            continue;
+        };
+        let mut error = |message: String| {
+            errors.push(ValidationError {
+                func: func.name.clone(),
+                index,
+                pos: *pos,
+                message,
+            })
+        };
+        let ops = Operands::of(op);
+
+        if let Some((min, max)) = arg_count(ops.opcode)
+            && !(min..=max).contains(&ops.args.len())
+        {
+            let expected = if min == max {
+                min.to_string()
+            } else {
+                format!("{} to {}", min, max)
+            };
+            error(format!(
+                "`{}` takes {} args, found {}",
+                ops.opcode,
+                expected,
+                ops.args.len()
+            ));
+        }
+        if matches!(ops.opcode, "jmp" | "guard") && ops.labels.len() != 1 {
+            error(format!(
+                "`{}` takes 1 label, found {}",
+                ops.opcode,
+                ops.labels.len()
+            ));
+        }
+        if ops.opcode == "phi" && ops.args.len() != ops.labels.len() {
+            error(format!(
+                "`phi` has {} args but {} labels",
+                ops.args.len(),
+                ops.labels.len()
+            ));
+        }
+
+        // phis spell a missing incoming value this way
+        let allowed = |arg: &str| ops.opcode == "phi" && arg == "__undefined";
+        let mut undefined: Vec<&Symbol> = ops
+            .args
+            .iter()
+            .filter(|arg| !defined.contains(*arg) && !allowed(arg))
+            .collect();
+        undefined.dedup();
+        for arg in undefined {
+            error(format!("`{}` is never defined", arg));
+        }
+        for label in ops.labels {
+            if !labels.contains(label.as_str()) {
+                error(format!("unknown label .{}", label));
+            }
+        }
+
+        if ops.opcode == "ret" {
+            match (&func.ret_typ, ops.args.len()) {
+                (Some(typ), 0) => error(format!("`ret` needs a value of type {}", typ)),
+                (None, n) if n > 0 => {
+                    error("`ret` has a value but the function returns nothing".to_string())
+                }
+                _ => {}
+            }
+        }
+
+        if ops.opcode == "call" {
+            match ops.funcs {
+                [callee] => match signatures.get(callee.as_str()) {
+                    Some(target) if target.args.len() != ops.args.len() => error(format!(
+                        "@{} takes {} args, found {}",
+                        callee,
+                        target.args.len(),
+                        ops.args.len()
+                    )),
+                    Some(target) => {
+                        for (arg, param) in ops.args.iter().zip(&target.args) {
+                            if let Some(typ) = types.get(arg)
+                                && !typ.is_compatible(&param.typ)
+                            {
+                                error(format!(
+                                    "`{}` is {} but @{} takes {} for `{}`",
+                                    arg, typ, callee, param.typ, param.name
+                                ));
+                            }
+                        }
+                        if let (Some(typ), Some(ret)) = (&ops.typ, &target.ret_typ)
+                            && !typ.is_compatible(ret)
+                        {
+                            error(format!("@{} returns {}, not {}", callee, ret, typ));
+                        }
+                    }
+                    None if imported.contains(callee.as_str()) => {}
+                    None => error(format!(
+                        "call to unknown function @{}{}",
+                        callee,
+                        did_you_mean(callee, signatures.keys().chain(imported).copied())
+                    )),
+                },
+                other => error(format!("`call` takes 1 function, found {}", other.len())),
+            }
+        }
+    }
+
+    if let Some(typ) = &func.ret_typ
+        && end_is_reachable(func)
+    {
+        errors.push(ValidationError {
+            func: func.name.clone(),
+            index: func.instrs.len(),
+            pos: None,
+            message: format!("can reach the end without returning a {}", typ),
+        });
+    }
+}
+
+/// `, did you mean @foo?` when some of `functions` are a typo away from
+/// `name`, nothing otherwise
+pub fn did_you_mean<'a>(name: &str, functions: impl IntoIterator<Item = &'a str>) -> String {
+    let limit = (name.chars().count() / 3).max(1);
+    let mut close: Vec<(usize, &str)> = functions
+        .into_iter()
+        .map(|f| (edit_distance(name, f), f))
+        .filter(|&(d, _)| d <= limit)
+        .collect();
+    close.sort();
+    close.dedup();
+
+    let names: Vec<String> = close
+        .iter()
+        .take(3)
+        .map(|(_, f)| format!("@{}", f))
+        .collect();
+    if names.is_empty() {
+        String::new()
+    } else {
+        format!(", did you mean {}?", names.join(" or "))
+    }
+}
+
+/// Levenshtein distance, in chars
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let sub = diag + usize::from(ca != cb);
+            diag = row[j + 1];
+            row[j + 1] = sub.min(row[j] + 1).min(diag + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Whether some path from the entry falls off the bottom of the function
+fn end_is_reachable(func: &Function) -> bool {
+    let targets: HashMap<&str, usize> = func
+        .instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, instr)| match instr {
+            Instruction::Label { label, .. } => Some((label.as_str(), i)),
+            Instruction::Op { .. } => None,
+        })
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut work = vec![0];
+    while let Some(i) = work.pop() {
+        if i == func.instrs.len() {
+            return true;
+        }
+        if !seen.insert(i) {
+            continue;
+        }
+        let ops = match &func.instrs[i] {
+            Instruction::Op { op, .. } => Operands::of(op),
+            Instruction::Label { .. } => {
+                work.push(i + 1);
+                continue;
+            }
+        };
+        match ops.opcode {
+            "ret" => {}
+            "jmp" | "br" | "guard" => {
+                if ops.opcode == "guard" {
+                    work.push(i + 1);
+                }
+                // unknown labels are reported on their own
+                work.extend(ops.labels.iter().filter_map(|l| targets.get(l.as_str())));
+            }
+            _ => work.push(i + 1),
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::parse_program;
+    use serde_json::Value;
+
+    #[test]
+    fn operands_are_the_ones_in_the_json() {
+        let src = "@f(p: ptr<int>, x: float): int {
+  i: int = const 3;
+  j: int = id i;
+  k: int = call @f p x;
+  y: float = fmul x x;
+  le: bool = fle x y;
+  b: int = float2bits y;
+  c: char = int2char i;
+  n: bool = not le;
+  q: ptr<int> = ptradd p i;
+  store q j;
+  v: int = load q;
+  speculate;
+  guard n .done;
+  commit;
+  set j k;
+  g: int = get;
+  u: int = undef;
+  z: int = phi i g .a .done;
+  vecadd v i @f .done;
+  print v b c;
+  br le .done .done;
+.done:
+  free p;
+  nop;
+  jmp .done;
+  ret k;
+}";
+        let program = parse_program(src).unwrap();
+        let strings = |v: &Value| -> Vec<String> {
+            v.as_array()
+                .into_iter()
+                .flatten()
+                .map(|s| s.as_str().unwrap().to_string())
+                .collect()
+        };
+        for instr in &program.functions[0].instrs {
+            let Instruction::Op { op, .. } = instr else {
+                continue;
+            };
+            let json = serde_json::to_value(op).unwrap();
+            let ops = Operands::of(op);
+            assert_eq!(ops.opcode, json["op"], "{}", json);
+            assert_eq!(
+                ops.dest.map(|d| d.to_string()),
+                json["dest"].as_str().map(String::from)
+            );
+            let args: Vec<String> = ops.args.iter().map(|a| a.to_string()).collect();
+            assert_eq!(args, strings(&json["args"]), "{}", json);
+            assert_eq!(ops.labels, strings(&json["labels"]), "{}", json);
+            assert_eq!(ops.funcs, strings(&json["funcs"]), "{}", json);
+        }
+    }
+
+    #[test]
+    fn well_formed_programs_pass() {
+        for src in [
+            include_str!("../../tests/add.bril"),
+            include_str!("../../tests/factorial.bril"),
+            include_str!("../../tests/loop.bril"),
+            include_str!("../../tests/palindrome.bril"),
+            include_str!("../../tests/float.bril"),
+        ] {
+            parse_program(src).unwrap().validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let src = "@inc(n: int): int {
+  one: int = const 1;
+  r: int = add n one;
+  ret r;
+}
+@main {
+  x: int = const 1;
+  y: int = add x ghost;
+  c: bool = lt x y;
+  br c .then .nowhere;
+.then:
+  z: int = call @inc x y;
+  w: int = call @missing;
+  v: int = call @ic x;
+  jmp .then;
+}";
+        let errors = parse_program(src).unwrap().validate().unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "@main[1]: `ghost` is never defined",
+                "@main[3]: unknown label .nowhere",
+                "@main[5]: @inc takes 1 args, found 2",
+                "@main[6]: call to unknown function @missing",
+                "@main[7]: call to unknown function @ic, did you mean @inc?",
+            ]
+        );
+    }
+
+    #[test]
+    fn return_types() {
+        let messages = |src: &str| -> Vec<String> {
+            match parse_program(src).unwrap().validate() {
+                Ok(()) => Vec::new(),
+                Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+            }
+        };
+
+        // falling off the end of a void function is fine
+        assert!(messages("@show(n: int) {\n  print n;\n}").is_empty());
+
+        let src = "@abs(n: int): int {
+  zero: int = const 0;
+  neg: bool = lt n zero;
+  br neg .flip .done;
+.flip:
+  r: int = sub zero n;
+  ret r;
+.done:
+}";
+        assert_eq!(
+            messages(src),
+            ["@abs[7]: can reach the end without returning a int"]
+        );
+
+        let src = "@show(n: int) {
+  print n;
+  ret n;
+}
+@get: int {
+  ret;
+}";
+        assert_eq!(
+            messages(src),
+            [
+                "@show[1]: `ret` has a value but the function returns nothing",
+                "@get[0]: `ret` needs a value of type int",
+            ]
+        );
+    }
+
+    #[test]
+    fn any_goes_with_everything() {
+        let src =
+            include_str!("../../bril-extern/bril/test/interp/dynamic/mem_dynamic_dispatch.bril");
+        parse_program(src).unwrap().validate().unwrap();
+
+        let src = "@get: any {
+  x: int = const 4;
+  ret x;
+}
+@num: int {
+  x: int = const 4;
+  ret x;
+}
+@show(b: bool) {
+  print b;
+}
+@main {
+  a: int = call @get;
+  b: bool = call @num;
+  call @show a;
+  d: any = id a;
+  call @show d;
+}";
+        let errors = parse_program(src).unwrap().validate().unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "@main[1]: @num returns int, not bool",
+                "@main[2]: `a` is int but @show takes bool for `b`",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_labels() {
+        let src = "@main {
+.top:
+  jmp .top;
+.top:
+  ret;
+}";
+        let errors = parse_program(src).unwrap().validate().unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "@main[2]: label .top is defined more than once"
+        );
+    }
+
+    #[test]
+    fn operand_counts() {
+        let json = r#"{"functions": [{"name": "main", "type": "int", "instrs": [
+            {"op": "const", "dest": "x", "type": "int", "value": 1},
+            {"op": "not", "dest": "y", "args": []},
+            {"op": "ret", "args": ["x", "x"]},
+            {"op": "jmp", "labels": []}
+        ]}]}"#;
+        let program: crate::Program = serde_json::from_str(json).unwrap();
+        let errors = program.validate().unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "@main[1]: `not` takes 1 args, found 0",
+                "@main[2]: `ret` takes 0 to 1 args, found 2",
+                "@main[3]: `jmp` takes 1 label, found 0",
+            ]
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Available expressions, forward over the CFG, on SSA
+//!
+//! Every distinct value a pure instruction computes, keyed by the
+//! [`ValueTable`], is an expression. One is available at a point if every
+//! path from the entry to it computes it. In SSA an operand is only ever
+//! defined once, so nothing kills an expression and a block just adds
+//! what it computes to what all of its preds have.
+
+use super::bitset::BitSet;
+use super::value_table::{Expr, ValueTable};
+use crate::cfg::{IrFunction, IrInstruction};
+use std::collections::HashMap;
+
+/// Index into `AvailableExprs::exprs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExprId(pub usize);
+
+#[derive(Debug)]
+pub struct AvailableExprs {
+    pub exprs: Vec<Expr>,
+    /// Available at the top of each block, by block index
+    pub avail_in: Vec<BitSet>,
+    /// and the bottom
+    pub avail_out: Vec<BitSet>,
+    ids: HashMap<Expr, ExprId>,
+    // knows every const, so operands holding one are keyed by it
+    table: ValueTable,
+}
+
+pub fn compute(func: &IrFunction) -> AvailableExprs {
+    let mut table = ValueTable::new();
+    for instr in func.blocks.iter().flat_map(|b| &b.instrs) {
+        if let IrInstruction::Const { .. } = instr {
+            table.insert(instr);
+        }
+    }
+    let mut out = AvailableExprs {
+        exprs: Vec::new(),
+        avail_in: Vec::new(),
+        avail_out: Vec::new(),
+        ids: HashMap::new(),
+        table,
+    };
+    for instr in func.blocks.iter().flat_map(|b| &b.instrs) {
+        if let Some(expr) = out.key(instr)
+            && !out.ids.contains_key(&expr)
+        {
+            out.ids.insert(expr.clone(), ExprId(out.exprs.len()));
+            out.exprs.push(expr);
+        }
+    }
+
+    let n = func.blocks.len();
+    let gen_sets: Vec<BitSet> = func
+        .blocks
+        .iter()
+        .map(|block| {
+            let mut gen_set = BitSet::new(out.exprs.len());
+            for id in block.instrs.iter().filter_map(|i| out.id_of(i)) {
+                gen_set.insert(id.0);
+            }
+            gen_set
+        })
+        .collect();
+
+    // the entry and the blocks it can't get to start with nothing, a block
+    // it can get to with everything, for the intersections to whittle down
+    let order = func.reverse_postorder();
+    out.avail_in = vec![BitSet::new(out.exprs.len()); n];
+    out.avail_out = vec![BitSet::new(out.exprs.len()); n];
+    for &b in order.iter().skip(1) {
+        out.avail_out[b] = BitSet::full(out.exprs.len());
+    }
+    if let Some(&entry) = order.first() {
+        out.avail_out[entry] = gen_sets[entry].clone();
+    }
+
+    // Fix-pointed iteration (forwards)
+    loop {
+        let mut changed = false;
+
+        for &b in order.iter().skip(1) {
+            let mut inn = BitSet::full(out.exprs.len());
+            for &p in &func.blocks[b].preds {
+                inn.intersect_with(&out.avail_out[p]);
+            }
+
+            // gen ∪ in
+            let mut set = inn.clone();
+            set.union_with(&gen_sets[b]);
+
+            if inn != out.avail_in[b] || set != out.avail_out[b] {
+                changed = true;
+                out.avail_in[b] = inn;
+                out.avail_out[b] = set;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    out
+}
+
+impl AvailableExprs {
+    /// The expression `instr` computes, None if it isn't a pure one
+    pub fn id_of(&self, instr: &IrInstruction) -> Option<ExprId> {
+        self.ids.get(&self.key(instr)?).copied()
+    }
+
+    pub fn expr(&self, id: ExprId) -> &Expr {
+        &self.exprs[id.0]
+    }
+
+    // consts are left to constant propagation, they'd only ever meet
+    // other consts
+    fn key(&self, instr: &IrInstruction) -> Option<Expr> {
+        match instr {
+            IrInstruction::Const { .. } => None,
+            _ => self.table.expr(instr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_available_only_when_every_path_computes_it() {
+        let func = IrFunction::parse(
+            "@main(a: int, b: int, c: bool) {
+.entry:
+  br c .left .right
+.left:
+  x = add a b
+  y = mul a b
+  jmp .join
+.right:
+  z = add b a
+  jmp .join
+.join:
+  print x
+  ret
+}",
+        )
+        .unwrap();
+        let avail = compute(&func);
+        let add = avail.id_of(&func.blocks[1].instrs[0]).unwrap();
+        let mul = avail.id_of(&func.blocks[1].instrs[1]).unwrap();
+        // `add b a` is the same expression
+        assert_eq!(avail.id_of(&func.blocks[2].instrs[0]), Some(add));
+
+        let join = func.block_index(&"join".to_string()).unwrap();
+        assert!(avail.avail_in[join].contains(add.0));
+        assert!(!avail.avail_in[join].contains(mul.0));
+        assert!(avail.avail_in[0].is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+//! Fixed-size set of small integers, one bit each, for dataflow facts that
+//! are numbered up front (definition sites and the like)
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BitSet {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl BitSet {
+    /// An empty set that can hold `0..len`
+    pub fn new(len: usize) -> Self {
+        BitSet {
+            words: vec![0; len.div_ceil(64)],
+            len,
+        }
+    }
+
+    /// A set already holding all of `0..len`
+    pub fn full(len: usize) -> Self {
+        let mut words = vec![!0; len.div_ceil(64)];
+        if !len.is_multiple_of(64) {
+            words[len / 64] = (1 << (len % 64)) - 1;
+        }
+        BitSet { words, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&w| w == 0)
+    }
+
+    pub fn contains(&self, i: usize) -> bool {
+        i < self.len && self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    /// Add `i`, true if it wasn't in already
+    pub fn insert(&mut self, i: usize) -> bool {
+        assert!(
+            i < self.len,
+            "{} out of range for a BitSet of {}",
+            i,
+            self.len
+        );
+        let had = self.contains(i);
+        self.words[i / 64] |= 1 << (i % 64);
+        !had
+    }
+
+    pub fn remove(&mut self, i: usize) {
+        if i < self.len {
+            self.words[i / 64] &= !(1 << (i % 64));
+        }
+    }
+
+    /// `self |= other`, true if that added anything
+    pub fn union_with(&mut self, other: &BitSet) -> bool {
+        let mut changed = false;
+        for (w, o) in self.words.iter_mut().zip(&other.words) {
+            let new = *w | o;
+            changed |= new != *w;
+            *w = new;
+        }
+        changed
+    }
+
+    /// `self &= other`, true if that took anything out
+    pub fn intersect_with(&mut self, other: &BitSet) -> bool {
+        let mut changed = false;
+        for (w, o) in self.words.iter_mut().zip(&other.words) {
+            let new = *w & o;
+            changed |= new != *w;
+            *w = new;
+        }
+        changed
+    }
+
+    /// `self -= other`
+    pub fn subtract(&mut self, other: &BitSet) {
+        for (w, o) in self.words.iter_mut().zip(&other.words) {
+            *w &= !o;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        // whole empty words are skipped
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+    }
+}
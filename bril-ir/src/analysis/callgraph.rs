@@ -0,0 +1,232 @@
+//! Who calls whom across a module
+//!
+//! Edges come from `call`s. An opaque op naming functions could call any of
+//! them, or something outside the module, so its function also gets marked
+//! as calling something unknown. The SCCs are Tarjan's, which finds a
+//! callee's SCC before any of its callers'.
+
+use crate::cfg::{IrInstruction, IrModule};
+use crate::FuncId;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default)]
+pub struct CallGraph {
+    /// What each function calls, by its index in `IrModule::functions`,
+    /// each callee once in the order first called
+    pub callees: Vec<Vec<FuncId>>,
+    pub callers: Vec<Vec<FuncId>>,
+    /// Calls out of the module, or through an op we don't know
+    pub calls_unknown: Vec<bool>,
+    /// Strongly connected components, callees before callers
+    pub sccs: Vec<Vec<FuncId>>,
+    // index into `sccs` of each function
+    scc_of: Vec<usize>,
+}
+
+impl CallGraph {
+    pub fn build(module: &IrModule) -> CallGraph {
+        let n = module.functions.len();
+        let ids: HashMap<&str, FuncId> = module
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.name.as_str(), FuncId(i)))
+            .collect();
+
+        let mut graph = CallGraph {
+            callees: vec![Vec::new(); n],
+            callers: vec![Vec::new(); n],
+            calls_unknown: vec![false; n],
+            ..CallGraph::default()
+        };
+        for (caller, func) in module.functions.iter().enumerate() {
+            for instr in func.blocks.iter().flat_map(|b| &b.instrs) {
+                let targets = match instr {
+                    IrInstruction::Call {
+                        target_func,
+                        callee,
+                        ..
+                    } => {
+                        // unresolved when the function was parsed on its own
+                        let id = callee.or_else(|| ids.get(target_func.as_str()).copied());
+                        if id.is_none() {
+                            graph.calls_unknown[caller] = true;
+                        }
+                        id.into_iter().collect()
+                    }
+                    IrInstruction::Opaque { funcs, .. } if !funcs.is_empty() => {
+                        graph.calls_unknown[caller] = true;
+                        funcs
+                            .iter()
+                            .filter_map(|f| ids.get(f.as_str()).copied())
+                            .collect()
+                    }
+                    _ => Vec::new(),
+                };
+                for callee in targets {
+                    graph.add_edge(FuncId(caller), callee);
+                }
+            }
+        }
+
+        graph.find_sccs();
+        graph
+    }
+
+    fn add_edge(&mut self, caller: FuncId, callee: FuncId) {
+        if !self.callees[caller.0].contains(&callee) {
+            self.callees[caller.0].push(callee);
+            self.callers[callee.0].push(caller);
+        }
+    }
+
+    /// Index into `sccs` of the component `func` is in
+    pub fn scc_of(&self, func: FuncId) -> usize {
+        self.scc_of[func.0]
+    }
+
+    /// Whether `func` can end up calling itself, directly or through others
+    pub fn is_recursive(&self, func: FuncId) -> bool {
+        self.sccs[self.scc_of(func)].len() > 1 || self.callees[func.0].contains(&func)
+    }
+
+    /// Every function after all the ones it calls, except for the calls
+    /// inside its own SCC. What an inliner or a clobber analysis walks
+    pub fn bottom_up(&self) -> Vec<FuncId> {
+        self.sccs.iter().flatten().copied().collect()
+    }
+
+    fn find_sccs(&mut self) {
+        let n = self.callees.len();
+        let mut tarjan = Tarjan {
+            callees: &self.callees,
+            index: vec![usize::MAX; n],
+            low: vec![0; n],
+            on_stack: vec![false; n],
+            stack: Vec::new(),
+            next: 0,
+            sccs: Vec::new(),
+        };
+        for f in 0..n {
+            if tarjan.index[f] == usize::MAX {
+                tarjan.visit(f);
+            }
+        }
+
+        let sccs = tarjan.sccs;
+        self.scc_of = vec![0; n];
+        for (i, scc) in sccs.iter().enumerate() {
+            for f in scc {
+                self.scc_of[f.0] = i;
+            }
+        }
+        self.sccs = sccs;
+    }
+}
+
+struct Tarjan<'a> {
+    callees: &'a [Vec<FuncId>],
+    // DFS number of each function, usize::MAX until it's visited
+    index: Vec<usize>,
+    low: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    next: usize,
+    sccs: Vec<Vec<FuncId>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, f: usize) {
+        self.index[f] = self.next;
+        self.low[f] = self.next;
+        self.next += 1;
+        self.stack.push(f);
+        self.on_stack[f] = true;
+
+        for &FuncId(g) in self.callees[f].iter() {
+            if self.index[g] == usize::MAX {
+                self.visit(g);
+                self.low[f] = self.low[f].min(self.low[g]);
+            } else if self.on_stack[g] {
+                self.low[f] = self.low[f].min(self.index[g]);
+            }
+        }
+
+        // `f` is the first of its SCC we got to, the rest are above it
+        if self.low[f] == self.index[f] {
+            let mut scc = Vec::new();
+            loop {
+                let g = self.stack.pop().unwrap();
+                self.on_stack[g] = false;
+                scc.push(FuncId(g));
+                if g == f {
+                    break;
+                }
+            }
+            scc.sort_by_key(|f| f.0);
+            self.sccs.push(scc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::parse_module;
+
+    #[test]
+    fn test_mutual_recursion_is_one_scc() {
+        let module = parse_module(
+            "@main() {
+.entry:
+  n = const 4
+  e = call @is_even n
+  print e
+  ret
+}
+
+@is_even(n: int): bool {
+.entry:
+  zero = const 0
+  done = eq n zero
+  br done .yes .no
+.yes:
+  t = const true
+  ret t
+.no:
+  one = const 1
+  m = sub n one
+  r = call @is_odd m
+  ret r
+}
+
+@is_odd(n: int): bool {
+.entry:
+  one = const 1
+  m = sub n one
+  r = call @is_even m
+  ret r
+}
+
+@leaf() {
+.entry:
+  opaque spawn @main @elsewhere
+  ret
+}",
+        )
+        .unwrap();
+        let graph = CallGraph::build(&module);
+        let (main, even, odd, leaf) = (FuncId(0), FuncId(1), FuncId(2), FuncId(3));
+
+        assert_eq!(graph.callees[main.0], [even]);
+        assert_eq!(graph.callers[even.0], [main, odd]);
+        assert_eq!(graph.scc_of(even), graph.scc_of(odd));
+        assert_eq!(graph.sccs[graph.scc_of(even)], [even, odd]);
+        assert!(graph.is_recursive(even) && graph.is_recursive(odd));
+        assert!(!graph.is_recursive(main));
+
+        // the pair comes before main, which comes before what calls it
+        assert_eq!(graph.bottom_up(), [even, odd, main, leaf]);
+        assert_eq!(graph.calls_unknown, [false, false, false, true]);
+    }
+}
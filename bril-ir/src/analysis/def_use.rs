@@ -0,0 +1,204 @@
+//! Def-use chains for a function in SSA form
+//!
+//! Every name has one def, so it's enough to know where that is and which
+//! instructions read it. Passes that change instructions go through
+//! [`DefUse`] to do it, that's what keeps the chains in step with the code.
+
+use crate::cfg::{IrFunction, IrInstruction};
+use crate::BlockID;
+use bril_frontend::Symbol;
+use std::collections::HashMap;
+
+/// Instruction `instr` of block `block`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Site {
+    pub block: BlockID,
+    pub instr: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DefUse {
+    // args have no site, they're defined before the entry
+    defs: HashMap<Symbol, Site>,
+    // once per instruction, however many operands it reads the name through
+    uses: HashMap<Symbol, Vec<Site>>,
+}
+
+impl DefUse {
+    pub fn compute(func: &IrFunction) -> DefUse {
+        let mut out = DefUse::default();
+        for (b, block) in func.blocks.iter().enumerate() {
+            for (i, instr) in block.instrs.iter().enumerate() {
+                out.add(Site { block: b, instr: i }, instr);
+            }
+        }
+        out
+    }
+
+    /// Where `var` is defined. None for an arg, or a name nothing defines
+    pub fn def(&self, var: Symbol) -> Option<Site> {
+        self.defs.get(&var).copied()
+    }
+
+    /// Every instruction reading `var`, phis included
+    pub fn uses(&self, var: Symbol) -> &[Site] {
+        self.uses.get(&var).map_or(&[], |sites| sites.as_slice())
+    }
+
+    pub fn is_unused(&self, var: Symbol) -> bool {
+        self.uses(var).is_empty()
+    }
+
+    /// What's defined somewhere in the function, in no particular order
+    pub fn defined(&self) -> impl Iterator<Item = Symbol> + '_ {
+        self.defs.keys().copied()
+    }
+
+    /// Make everything that reads `old` read `new` instead, how many
+    /// instructions changed
+    pub fn replace_all_uses_with(
+        &mut self,
+        func: &mut IrFunction,
+        old: Symbol,
+        new: Symbol,
+    ) -> usize {
+        if old == new {
+            return 0;
+        }
+        let sites = self.uses.remove(&old).unwrap_or_default();
+        for &site in &sites {
+            func.blocks[site.block].instrs[site.instr].replace_use(old, new);
+            let new_uses = self.uses.entry(new).or_default();
+            if !new_uses.contains(&site) {
+                new_uses.push(site);
+            }
+        }
+        sites.len()
+    }
+
+    /// Put `instr` where `site` is, the chains let go of the old one's def
+    /// and uses. Returns the old instruction
+    pub fn replace_instr(
+        &mut self,
+        func: &mut IrFunction,
+        site: Site,
+        instr: IrInstruction,
+    ) -> IrInstruction {
+        let slot = &mut func.blocks[site.block].instrs[site.instr];
+        self.add(site, &instr);
+        let old = std::mem::replace(slot, instr);
+        self.forget(site, &old);
+        old
+    }
+
+    /// Turn the def of `var` into a nop, None if there isn't one. Whatever
+    /// still reads `var` is left reading nothing, so check it's unused first
+    pub fn remove_def(&mut self, func: &mut IrFunction, var: Symbol) -> Option<IrInstruction> {
+        let site = self.def(var)?;
+        Some(self.replace_instr(func, site, IrInstruction::Nop))
+    }
+
+    fn add(&mut self, site: Site, instr: &IrInstruction) {
+        for &var in instr.defs() {
+            // a second def means this isn't SSA, the first one stands
+            self.defs.entry(var).or_insert(site);
+        }
+        for var in instr.uses() {
+            let sites = self.uses.entry(var).or_default();
+            if !sites.contains(&site) {
+                sites.push(site);
+            }
+        }
+    }
+
+    fn forget(&mut self, site: Site, instr: &IrInstruction) {
+        for var in instr.defs() {
+            if self.defs.get(var) == Some(&site) {
+                self.defs.remove(var);
+            }
+        }
+        for var in instr.uses() {
+            if let Some(sites) = self.uses.get_mut(&var) {
+                sites.retain(|&s| s != site);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FUNC: &str = "@main(c: bool) {
+.entry:
+  a = const 1
+  b = const 2
+  br c .left .right
+.left:
+  x = add a a
+  jmp .join
+.right:
+  y = add a b
+  jmp .join
+.join:
+  z = phi x a
+  print z a
+  ret
+}";
+
+    fn at(block: BlockID, instr: usize) -> Site {
+        Site { block, instr }
+    }
+
+    #[test]
+    fn test_chains_of_a_diamond() {
+        let func = IrFunction::parse(FUNC).unwrap();
+        let du = DefUse::compute(&func);
+        let a = Symbol::from("a");
+
+        assert_eq!(du.def(a), Some(at(0, 0)));
+        // `x = add a a` counts once
+        assert_eq!(du.uses(a), [at(1, 0), at(2, 0), at(3, 0), at(3, 1)]);
+        assert_eq!(du.uses("x".into()), [at(3, 0)]);
+        assert_eq!(du.def("c".into()), None);
+        assert!(du.is_unused("y".into()));
+    }
+
+    #[test]
+    fn test_replace_all_uses_with_across_blocks() {
+        let mut func = IrFunction::parse(FUNC).unwrap();
+        let mut du = DefUse::compute(&func);
+        let (a, b) = (Symbol::from("a"), Symbol::from("b"));
+
+        assert_eq!(du.replace_all_uses_with(&mut func, a, b), 4);
+        let line = |b: BlockID, i: usize| func.blocks[b].instrs[i].to_string();
+        assert_eq!(line(1, 0), "x = add b b");
+        assert_eq!(line(2, 0), "y = add b b");
+        assert_eq!(line(3, 0), "z = phi x b");
+        assert_eq!(line(3, 1), "print z b");
+
+        assert!(du.is_unused(a));
+        // .right already read b, it's still there once
+        assert_eq!(du.uses(b), [at(2, 0), at(1, 0), at(3, 0), at(3, 1)]);
+        // and the chains agree with starting over
+        let fresh = DefUse::compute(&func);
+        let mut sorted = du.uses(b).to_vec();
+        sorted.sort();
+        assert_eq!(sorted, fresh.uses(b));
+    }
+
+    #[test]
+    fn test_remove_def() {
+        let mut func = IrFunction::parse(FUNC).unwrap();
+        let mut du = DefUse::compute(&func);
+        let y = Symbol::from("y");
+
+        let removed = du.remove_def(&mut func, y).unwrap();
+        assert_eq!(removed.to_string(), "y = add a b");
+        assert_eq!(func.blocks[2].instrs[0], IrInstruction::Nop);
+        assert_eq!(du.def(y), None);
+        assert!(du.is_unused("b".into()));
+        assert!(!du.uses("a".into()).contains(&at(2, 0)));
+        assert!(du.remove_def(&mut func, y).is_none());
+    }
+}
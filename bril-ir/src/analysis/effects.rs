@@ -0,0 +1,147 @@
+//! What calling each function can do besides give back a value
+//!
+//! Worked out bottom-up over the call graph: a function does the worst of
+//! what its own instructions do and what everything it calls does. The
+//! functions of a recursive SCC call each other, so each gets the worst of
+//! any of them.
+
+use crate::analysis::callgraph::CallGraph;
+use crate::cfg::{IrInstruction, IrModule};
+use std::collections::HashMap;
+
+/// Best first, the worse of two is the `max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Effect {
+    /// Only works its result out from its args
+    Pure,
+    /// Reads memory too, but never changes it
+    ReadOnly,
+    /// Prints, allocates, writes or frees memory, or calls something we
+    /// know nothing about
+    Impure,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Effects {
+    // by function name, calls go by name until they're resolved
+    functions: HashMap<String, Effect>,
+}
+
+impl Effects {
+    pub fn compute(module: &IrModule, graph: &CallGraph) -> Effects {
+        let mut effects = Effects::default();
+        // callees first, so theirs are known by the time a caller needs them
+        for scc in &graph.sccs {
+            let members: Vec<&str> = scc
+                .iter()
+                .map(|f| module.function(*f).name.as_str())
+                .collect();
+            let mut effect = Effect::Pure;
+            for &f in scc {
+                if graph.calls_unknown[f.0] {
+                    effect = Effect::Impure;
+                }
+                let instrs = module.function(f).blocks.iter().flat_map(|b| &b.instrs);
+                for instr in instrs {
+                    let of = match instr {
+                        // a call within the SCC adds nothing the members
+                        // don't already do
+                        IrInstruction::Call { target_func, .. }
+                            if members.contains(&target_func.as_str()) =>
+                        {
+                            Effect::Pure
+                        }
+                        _ => effects.of_instr(instr),
+                    };
+                    effect = effect.max(of);
+                }
+            }
+            for name in members {
+                effects.functions.insert(name.to_string(), effect);
+            }
+        }
+        effects
+    }
+
+    /// What calling `name` can do, `Impure` for one not in the module
+    pub fn of(&self, name: &str) -> Effect {
+        self.functions.get(name).copied().unwrap_or(Effect::Impure)
+    }
+
+    /// What running `instr` can do besides define its dest, a call doing
+    /// what its callee does
+    pub fn of_instr(&self, instr: &IrInstruction) -> Effect {
+        match instr {
+            IrInstruction::Call { target_func, .. } => self.of(target_func),
+            IrInstruction::Load { .. } => Effect::ReadOnly,
+            IrInstruction::Print { .. }
+            | IrInstruction::Store { .. }
+            | IrInstruction::Alloc { .. }
+            | IrInstruction::Free { .. }
+            | IrInstruction::Opaque { .. } => Effect::Impure,
+            _ => Effect::Pure,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::parse_module;
+
+    #[test]
+    fn test_effects_go_up_to_the_callers() {
+        let module = parse_module(
+            "@main() {
+.entry:
+  n = const 4
+  s = call @square n
+  r = call @count n
+  print s r
+  ret
+}
+
+@square(n: int): int {
+.entry:
+  s = mul n n
+  ret s
+}
+
+@peek(p: ptr<int>): int {
+.entry:
+  v = load p
+  ret v
+}
+
+@count(n: int): int {
+.entry:
+  zero = const 0
+  done = eq n zero
+  br done .yes .no
+.yes:
+  ret zero
+.no:
+  one = const 1
+  m = sub n one
+  r = call @shout m
+  ret r
+}
+
+@shout(n: int): int {
+.entry:
+  print n
+  r = call @count n
+  ret r
+}",
+        )
+        .unwrap();
+        let effects = Effects::compute(&module, &CallGraph::build(&module));
+        assert_eq!(effects.of("square"), Effect::Pure);
+        assert_eq!(effects.of("peek"), Effect::ReadOnly);
+        // @count only prints by way of @shout, they're one SCC
+        assert_eq!(effects.of("count"), Effect::Impure);
+        assert_eq!(effects.of("shout"), Effect::Impure);
+        assert_eq!(effects.of("main"), Effect::Impure);
+        assert_eq!(effects.of("elsewhere"), Effect::Impure);
+    }
+}
@@ -0,0 +1,204 @@
+//! Block-level liveness, the usual backward fixpoint
+//!
+//! ```text
+//! LiveIn(b)  = PhiDefs(b) ∪ Uses(b) ∪ (LiveOut(b) \ Defs(b))
+//! LiveOut(b) = ∪ (LiveIn(s) \ PhiDefs(s)) for s in succs(b), ∪ PhiUses(b)
+//! ```
+//!
+//! A phi's sources aren't live into its own block, each one is live out of
+//! the pred it comes from. That's `PhiUses(b)`: the sources the phis of
+//! b's succs take from b.
+
+use crate::cfg::{IrFunction, IrInstruction};
+use crate::BlockID;
+use bril_frontend::Symbol;
+use std::collections::HashSet;
+use std::hash::Hash;
+
+/// What's live going into and coming out of each block, by block index
+#[derive(Debug, Clone)]
+pub struct Liveness<V = Symbol> {
+    pub live_in: Vec<HashSet<V>>,
+    pub live_out: Vec<HashSet<V>>,
+}
+
+/// The facts about one block the fixpoint works from
+#[derive(Debug, Clone)]
+pub struct BlockSummary<V> {
+    /// Read before anything in the block writes them
+    pub uses: HashSet<V>,
+    pub defs: HashSet<V>,
+    pub phi_defs: HashSet<V>,
+    pub phi_uses: HashSet<V>,
+    pub succs: Vec<BlockID>,
+}
+
+impl<V> Default for BlockSummary<V> {
+    fn default() -> Self {
+        BlockSummary {
+            uses: HashSet::new(),
+            defs: HashSet::new(),
+            phi_defs: HashSet::new(),
+            phi_uses: HashSet::new(),
+            succs: Vec::new(),
+        }
+    }
+}
+
+impl<V: Copy + Eq + Hash> BlockSummary<V> {
+    /// Add the next instruction of a straight-line block
+    pub fn step(&mut self, defs: impl IntoIterator<Item = V>, uses: impl IntoIterator<Item = V>) {
+        for u in uses {
+            if !self.defs.contains(&u) {
+                self.uses.insert(u);
+            }
+        }
+        self.defs.extend(defs);
+    }
+}
+
+/// Liveness of every block of `func`, phis handled as above
+pub fn compute(func: &IrFunction) -> Liveness {
+    let mut blocks: Vec<BlockSummary<Symbol>> = func
+        .blocks
+        .iter()
+        .map(|block| BlockSummary {
+            succs: block.succs.clone(),
+            ..BlockSummary::default()
+        })
+        .collect();
+
+    for (b, block) in func.blocks.iter().enumerate() {
+        for instr in &block.instrs {
+            let IrInstruction::Phi { dest, sources } = instr else {
+                blocks[b].step(instr.defs().iter().copied(), instr.uses());
+                continue;
+            };
+            blocks[b].phi_defs.insert(*dest);
+            blocks[b].defs.insert(*dest);
+            for src in sources {
+                if let (Some(p), Some(value)) = (func.block_index(&src.pred), src.value) {
+                    blocks[p].phi_uses.insert(value);
+                }
+            }
+        }
+    }
+
+    solve(&blocks)
+}
+
+/// Run the fixpoint over blocks already boiled down to their summaries
+pub fn solve<V: Copy + Eq + Hash>(blocks: &[BlockSummary<V>]) -> Liveness<V> {
+    let n = blocks.len();
+    let mut live_in: Vec<HashSet<V>> = vec![HashSet::new(); n];
+    let mut live_out: Vec<HashSet<V>> = vec![HashSet::new(); n];
+
+    // Fix-pointed iteration (backwards)
+    loop {
+        let mut changed = false;
+
+        for b in (0..n).rev() {
+            let mut out = blocks[b].phi_uses.clone();
+            for &s in &blocks[b].succs {
+                out.extend(
+                    live_in[s]
+                        .iter()
+                        .filter(|v| !blocks[s].phi_defs.contains(v)),
+                );
+            }
+
+            let mut inn: HashSet<V> = blocks[b].phi_defs.union(&blocks[b].uses).copied().collect();
+            inn.extend(out.iter().filter(|v| !blocks[b].defs.contains(v)));
+
+            // if we detected any changes
+            if inn != live_in[b] || out != live_out[b] {
+                changed = true;
+                live_in[b] = inn;
+                live_out[b] = out;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    Liveness { live_in, live_out }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(set: &HashSet<Symbol>) -> Vec<&'static str> {
+        let mut names: Vec<&str> = set.iter().map(|v| v.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_live_across_a_loop() {
+        let func = IrFunction::parse(
+            "@main(n: int) {
+.entry:
+  x = const 7
+  i = const 0
+  jmp .head
+.head:
+  c = lt i n
+  br c .body .done
+.body:
+  one = const 1
+  i = add i one
+  jmp .head
+.done:
+  print x
+  ret
+}",
+        )
+        .unwrap();
+        let live = compute(&func);
+        let x = Symbol::from("x");
+
+        for label in ["head", "body"] {
+            let b = func.block_index(&label.to_string()).unwrap();
+            assert!(live.live_in[b].contains(&x), ".{}", label);
+            assert!(live.live_out[b].contains(&x), ".{}", label);
+        }
+        assert!(live.live_out[0].contains(&x));
+        assert!(!live.live_in[0].contains(&x));
+        assert_eq!(names(&live.live_in[0]), ["n"]);
+        let done = func.block_index(&"done".to_string()).unwrap();
+        assert_eq!(names(&live.live_in[done]), ["x"]);
+        assert!(live.live_out[done].is_empty());
+    }
+
+    #[test]
+    fn test_phi_sources_are_live_out_of_their_pred() {
+        let func = IrFunction::parse(
+            "@main(c: bool) {
+.entry:
+  br c .left .right
+.left:
+  a = const 1
+  jmp .join
+.right:
+  b = const 2
+  jmp .join
+.join:
+  x = phi [a, .left] [b, .right]
+  print x
+  ret
+}",
+        )
+        .unwrap();
+        let live = compute(&func);
+        let idx = |label: &str| func.block_index(&label.to_string()).unwrap();
+
+        assert_eq!(names(&live.live_out[idx("left")]), ["a"]);
+        assert_eq!(names(&live.live_out[idx("right")]), ["b"]);
+        // the phi defines x on the way in, a and b are already consumed
+        assert_eq!(names(&live.live_in[idx("join")]), ["x"]);
+        assert_eq!(names(&live.live_in[0]), ["c"]);
+    }
+}
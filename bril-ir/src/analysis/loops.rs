@@ -0,0 +1,533 @@
+//! Natural loops and how they nest
+//!
+//! An edge `latch -> header` where the header dominates the latch is a back
+//! edge, and the loop is the header plus everything that reaches the latch
+//! without going through the header. Back edges into the same header make
+//! one loop. An edge that goes back up the DFS without its target dominating
+//! it means the CFG is irreducible there, those are listed instead.
+
+use crate::cfg::{IrBasicBlock, IrFunction, IrInstruction, PhiSource};
+use crate::ssa::DominatorInfo;
+use crate::BlockID;
+use std::collections::{BTreeSet, HashSet};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    pub header: BlockID,
+    /// Blocks with a back edge to `header`
+    pub latches: Vec<BlockID>,
+    /// Every block of the loop, nested loops' included
+    pub blocks: BTreeSet<BlockID>,
+    /// The only way in from outside, if there's a block that's just that
+    pub preheader: Option<BlockID>,
+    /// Index in `LoopInfo::loops` of the loop around this one
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    /// 1 for an outermost loop
+    pub depth: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoopInfo {
+    pub loops: Vec<Loop>,
+    /// `(from, to)` edges that retreat into a block not dominating `from`
+    pub irreducible: Vec<(BlockID, BlockID)>,
+    // innermost loop of each block
+    innermost: Vec<Option<usize>>,
+}
+
+pub fn compute(func: &IrFunction, doms: &DominatorInfo) -> LoopInfo {
+    let n = func.blocks.len();
+    let mut rpo_num = vec![usize::MAX; n];
+    for (i, b) in func.reverse_postorder().into_iter().enumerate() {
+        rpo_num[b] = i;
+    }
+
+    let mut info = LoopInfo {
+        innermost: vec![None; n],
+        ..LoopInfo::default()
+    };
+
+    // headers in RPO so outer loops come before the ones inside them
+    let mut headers: Vec<(BlockID, Vec<BlockID>)> = Vec::new();
+    for (b, block) in func.blocks.iter().enumerate() {
+        if rpo_num[b] == usize::MAX {
+            continue;
+        }
+        for &s in &block.succs {
+            if doms.dominates(s, b) {
+                match headers.iter_mut().find(|(h, _)| *h == s) {
+                    Some((_, latches)) => latches.push(b),
+                    None => headers.push((s, vec![b])),
+                }
+            } else if rpo_num[s] <= rpo_num[b] {
+                info.irreducible.push((b, s));
+            }
+        }
+    }
+    headers.sort_by_key(|(h, _)| rpo_num[*h]);
+
+    for (header, latches) in headers {
+        let mut blocks = BTreeSet::from([header]);
+        let mut worklist = latches.clone();
+        while let Some(b) = worklist.pop() {
+            if blocks.insert(b) {
+                worklist.extend(&func.blocks[b].preds);
+            }
+        }
+
+        // the enclosing loop is the innermost one already seen holding the header
+        let parent = info.innermost[header];
+        let idx = info.loops.len();
+        for &b in &blocks {
+            info.innermost[b] = Some(idx);
+        }
+        if let Some(p) = parent {
+            info.loops[p].children.push(idx);
+        }
+
+        let outside: Vec<BlockID> = func.blocks[header]
+            .preds
+            .iter()
+            .copied()
+            .filter(|p| !blocks.contains(p))
+            .collect();
+        let preheader = match outside[..] {
+            [p] if func.blocks[p].succs.len() == 1 => Some(p),
+            _ => None,
+        };
+
+        info.loops.push(Loop {
+            header,
+            latches,
+            blocks,
+            preheader,
+            parent,
+            children: Vec::new(),
+            depth: parent.map_or(1, |p| info.loops[p].depth + 1),
+        });
+    }
+
+    info
+}
+
+impl LoopInfo {
+    /// How many loops `block` is in, 0 outside of any
+    pub fn loop_depth(&self, block: BlockID) -> usize {
+        self.loop_of(block).map_or(0, |l| l.depth)
+    }
+
+    /// The innermost loop `block` is in
+    pub fn loop_of(&self, block: BlockID) -> Option<&Loop> {
+        let idx = self.innermost.get(block).copied().flatten()?;
+        Some(&self.loops[idx])
+    }
+
+    /// Inner loops before the loops around them, the order to optimize them in
+    pub fn loops_in_postorder(&self) -> Vec<&Loop> {
+        let mut out = Vec::with_capacity(self.loops.len());
+        let roots = (0..self.loops.len()).filter(|&l| self.loops[l].parent.is_none());
+        for root in roots {
+            self.postorder(root, &mut out);
+        }
+        out
+    }
+
+    fn postorder<'a>(&'a self, l: usize, out: &mut Vec<&'a Loop>) {
+        for &child in &self.loops[l].children {
+            self.postorder(child, out);
+        }
+        out.push(&self.loops[l]);
+    }
+
+    pub fn is_reducible(&self) -> bool {
+        self.irreducible.is_empty()
+    }
+}
+
+impl IrFunction {
+    /// Give every loop in `loops` a preheader: a block that only jumps to
+    /// the header and is the one way into the loop from outside. Phis in the
+    /// header that took different values from outside get a phi of their
+    /// own in the preheader. Returns the blocks it made, `loops` is stale
+    /// once there are any.
+    pub fn ensure_preheaders(&mut self, loops: &LoopInfo) -> Vec<BlockID> {
+        let mut made = Vec::new();
+        for l in &loops.loops {
+            if l.preheader.is_some() {
+                continue;
+            }
+            let header = l.header;
+            let pre = if header == 0 {
+                self.move_entry_out()
+            } else {
+                self.split_outside_edges(header, &l.blocks)
+            };
+            made.push(pre);
+        }
+        made
+    }
+
+    /// The function starts in the header, so the header's instructions move
+    /// to a new block and the entry is left just jumping there
+    fn move_entry_out(&mut self) -> BlockID {
+        let label = self.blocks[0].label.clone();
+        let pre_label = self.fresh_label(&format!("{}.preheader", label));
+        let header = self.blocks.len();
+
+        let mut moved = std::mem::replace(
+            &mut self.blocks[0],
+            IrBasicBlock {
+                label: pre_label.clone(),
+                instrs: Vec::new(),
+                positions: Vec::new(),
+                origins: Vec::new(),
+                preds: Vec::new(),
+                succs: Vec::new(),
+            },
+        );
+        // it fell through to block 1, now it's at the end
+        if !moved.instrs.last().is_some_and(|i| i.is_terminator())
+            && let Some(&next) = moved.succs.first()
+        {
+            let jmp = IrInstruction::Jmp {
+                label: self.blocks[next].label.clone(),
+            };
+            moved.instrs.push(jmp);
+            moved.positions.resize(moved.instrs.len(), None);
+            moved.origins.resize(moved.instrs.len(), None);
+        }
+        // a header that loops straight back to itself names itself too
+        for b in moved.succs.iter_mut().chain(moved.preds.iter_mut()) {
+            if *b == 0 {
+                *b = header;
+            }
+        }
+        for &s in moved.succs.iter().filter(|&&s| s != header) {
+            for p in self.blocks[s].preds.iter_mut().filter(|p| **p == 0) {
+                *p = header;
+            }
+        }
+        for &p in moved.preds.iter().filter(|&&p| p != header) {
+            for s in self.blocks[p].succs.iter_mut().filter(|s| **s == 0) {
+                *s = header;
+            }
+        }
+        self.blocks.push(moved);
+        self.label_to_idx.insert(label.clone(), header);
+        self.label_to_idx.insert(pre_label.clone(), 0);
+
+        // nothing flows in along the new edge, the phis have no value for it
+        for instr in &mut self.blocks[header].instrs {
+            if let IrInstruction::Phi { sources, .. } = instr {
+                sources.push(PhiSource::new(&pre_label, None));
+            }
+        }
+        self.append_instr(0, &IrInstruction::Jmp { label }, None, None);
+        self.add_edge(0, header);
+        0
+    }
+
+    /// A new block in front of `header` that every edge from outside
+    /// `blocks` goes through instead
+    fn split_outside_edges(&mut self, header: BlockID, blocks: &BTreeSet<BlockID>) -> BlockID {
+        let header_label = self.blocks[header].label.clone();
+        let pre_label = self.fresh_label(&format!("{}.preheader", header_label));
+        let pre = self
+            .add_block(&pre_label)
+            .expect("fresh_label picks a label that isn't taken");
+
+        let preds = self.blocks[header].preds.clone();
+        let outside: Vec<usize> = (0..preds.len())
+            .filter(|&j| !blocks.contains(&preds[j]))
+            .collect();
+
+        let outside_labels: HashSet<String> = outside
+            .iter()
+            .map(|&j| self.blocks[preds[j]].label.clone())
+            .collect();
+        let mut retargeted = HashSet::new();
+        for &j in &outside {
+            let p = preds[j];
+            self.blocks[pre].preds.push(p);
+            if !retargeted.insert(p) {
+                continue;
+            }
+            for s in self.blocks[p].succs.iter_mut().filter(|s| **s == header) {
+                *s = pre;
+            }
+            let block = &mut self.blocks[p];
+            match block.instrs.last_mut() {
+                Some(IrInstruction::Jmp { label }) => *label = pre_label.clone(),
+                Some(IrInstruction::Br {
+                    then_lbl, else_lbl, ..
+                }) => {
+                    for lbl in [then_lbl, else_lbl] {
+                        if *lbl == header_label {
+                            *lbl = pre_label.clone();
+                        }
+                    }
+                }
+                // a fall-through, the preheader isn't the next block
+                _ => {
+                    let at = block.instrs.len();
+                    let jmp = IrInstruction::Jmp {
+                        label: pre_label.clone(),
+                    };
+                    block.insert_instr(at, jmp, None, None);
+                }
+            }
+        }
+
+        // the header's phis take one value from the preheader now
+        for i in 0..self.blocks[header].instrs.len() {
+            let IrInstruction::Phi { dest, sources } = &self.blocks[header].instrs[i] else {
+                continue;
+            };
+            let dest = *dest;
+            // the sources from outside keep their preds, those go to the
+            // preheader now
+            let incoming: Vec<PhiSource> = sources
+                .iter()
+                .filter(|s| outside_labels.contains(&s.pred))
+                .cloned()
+                .collect();
+            let merged = if incoming.iter().all(|s| s.value == incoming[0].value) {
+                incoming[0].value
+            } else {
+                let var = self.fresh_var(&format!("{}.pre", dest));
+                if let Some(typ) = self.types.get(&dest).cloned() {
+                    self.types.insert(var, typ);
+                }
+                let phi = IrInstruction::Phi {
+                    dest: var,
+                    sources: incoming,
+                };
+                let at = self.blocks[pre].instrs.len();
+                self.blocks[pre].insert_instr(at, phi, None, None);
+                Some(var)
+            };
+
+            let IrInstruction::Phi { sources, .. } = &mut self.blocks[header].instrs[i] else {
+                unreachable!()
+            };
+            sources.retain(|s| !outside_labels.contains(&s.pred));
+            sources.push(PhiSource::new(&pre_label, merged));
+        }
+
+        let mut j = 0;
+        self.blocks[header].preds.retain(|_| {
+            j += 1;
+            !outside.contains(&(j - 1))
+        });
+        self.append_instr(
+            pre,
+            &IrInstruction::Jmp {
+                label: header_label,
+            },
+            None,
+            None,
+        );
+        self.add_edge(pre, header);
+        pre
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IrModule;
+    use bril_frontend::Program;
+
+    fn function(json: &str) -> IrFunction {
+        let program: Program = serde_json::from_str(json).unwrap();
+        IrModule::try_from(&program).unwrap().functions.remove(0)
+    }
+
+    #[test]
+    fn test_doubly_nested_loop() {
+        // for i in 0..n { for j in 0..n { print i j } }
+        let func = function(
+            r#"{"functions": [{"name": "main", "args": [{"name": "n", "type": "int"}], "instrs": [
+                {"op": "const", "dest": "one", "type": "int", "value": 1},
+                {"op": "const", "dest": "i", "type": "int", "value": 0},
+                {"label": "outer"},
+                {"op": "lt", "dest": "c", "type": "bool", "args": ["i", "n"]},
+                {"op": "br", "args": ["c"], "labels": ["outer.body", "done"]},
+                {"label": "outer.body"},
+                {"op": "const", "dest": "j", "type": "int", "value": 0},
+                {"label": "inner"},
+                {"op": "lt", "dest": "d", "type": "bool", "args": ["j", "n"]},
+                {"op": "br", "args": ["d"], "labels": ["inner.body", "outer.latch"]},
+                {"label": "inner.body"},
+                {"op": "print", "args": ["i", "j"]},
+                {"op": "add", "dest": "j", "type": "int", "args": ["j", "one"]},
+                {"op": "jmp", "labels": ["inner"]},
+                {"label": "outer.latch"},
+                {"op": "add", "dest": "i", "type": "int", "args": ["i", "one"]},
+                {"op": "jmp", "labels": ["outer"]},
+                {"label": "done"},
+                {"op": "ret", "args": []}
+            ]}]}"#,
+        );
+        let doms = DominatorInfo::compute(&func).unwrap();
+        let loops = compute(&func, &doms);
+        let idx = |label: &str| func.block_index(&label.to_string()).unwrap();
+
+        assert!(loops.is_reducible());
+        assert_eq!(loops.loops.len(), 2);
+        assert_eq!(loops.loop_depth(0), 0);
+        assert_eq!(loops.loop_depth(idx("outer")), 1);
+        assert_eq!(loops.loop_depth(idx("outer.body")), 1);
+        assert_eq!(loops.loop_depth(idx("inner")), 2);
+        assert_eq!(loops.loop_depth(idx("inner.body")), 2);
+        assert_eq!(loops.loop_depth(idx("outer.latch")), 1);
+        assert_eq!(loops.loop_depth(idx("done")), 0);
+
+        let order = loops.loops_in_postorder();
+        let (inner, outer) = (order[0], order[1]);
+        assert_eq!(inner.header, idx("inner"));
+        assert_eq!(inner.latches, [idx("inner.body")]);
+        assert_eq!(inner.preheader, Some(idx("outer.body")));
+        assert_eq!(outer.header, idx("outer"));
+        assert_eq!(outer.preheader, Some(0));
+        assert!(inner.blocks.is_subset(&outer.blocks));
+        assert_eq!(outer.blocks.len(), 5);
+        assert_eq!(loops.loops[inner.parent.unwrap()].header, idx("outer"));
+    }
+
+    /// Every edge shows up on both ends and every phi has a source per pred,
+    /// each one naming a pred
+    fn assert_consistent(func: &IrFunction) {
+        for (b, block) in func.blocks.iter().enumerate() {
+            assert_eq!(func.block_index(&block.label), Some(b));
+            for &s in &block.succs {
+                assert!(func.blocks[s].preds.contains(&b), "{}", func);
+            }
+            for &p in &block.preds {
+                assert!(func.blocks[p].succs.contains(&b), "{}", func);
+            }
+            for instr in &block.instrs {
+                if let IrInstruction::Phi { sources, .. } = instr {
+                    assert_eq!(sources.len(), block.preds.len(), "{}", func);
+                    for src in sources {
+                        let p = func.block_index(&src.pred).unwrap();
+                        assert!(block.preds.contains(&p), "{}", func);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_preheader_for_a_loop_at_the_entry() {
+        let mut func = IrFunction::parse(
+            "@main(n: int) {
+.loop:
+  n = sub n n
+  c = lt n n
+  br c .loop .done
+.done:
+  ret
+}",
+        )
+        .unwrap();
+        let doms = DominatorInfo::compute(&func).unwrap();
+        let loops = compute(&func, &doms);
+        assert_eq!(loops.loops[0].header, 0);
+        assert_eq!(loops.loops[0].preheader, None);
+
+        assert_eq!(func.ensure_preheaders(&loops), [0]);
+        assert_consistent(&func);
+        assert_eq!(func.blocks[0].label, "loop.preheader");
+        assert_eq!(func.blocks[0].instrs[0].to_string(), "jmp .loop");
+        let header = func.block_index(&"loop".to_string()).unwrap();
+        assert_eq!(func.blocks[header].preds, [header, 0]);
+        assert!(func.blocks[header].succs.contains(&header));
+
+        let doms = DominatorInfo::compute(&func).unwrap();
+        let loops = compute(&func, &doms);
+        assert_eq!(loops.loops[0].header, header);
+        assert_eq!(loops.loops[0].preheader, Some(0));
+    }
+
+    #[test]
+    fn test_preheader_for_three_ways_in() {
+        let mut func = IrFunction::parse(
+            "@main(a: int, b: bool) {
+.entry:
+  br b .one .two
+.one:
+  x1 = const 1
+  br b .head .three
+.two:
+  x2 = const 2
+  jmp .head
+.three:
+  x3 = const 3
+.head:
+  x = phi [x1, .one] [x2, .two] [x3, .three] [y, .head]
+  y = add x a
+  c = lt y a
+  br c .head .done
+.done:
+  ret
+}",
+        )
+        .unwrap();
+        let doms = DominatorInfo::compute(&func).unwrap();
+        let loops = compute(&func, &doms);
+        let head = func.block_index(&"head".to_string()).unwrap();
+        assert_eq!(func.blocks[head].preds.len(), 4);
+
+        let made = func.ensure_preheaders(&loops);
+        assert_eq!(made.len(), 1);
+        let pre = made[0];
+        assert_consistent(&func);
+        assert_eq!(func.blocks[pre].label, "head.preheader");
+        assert_eq!(func.blocks[head].preds, [head, pre]);
+        assert_eq!(func.blocks[head].instrs[0].to_string(), "x = phi y x.pre");
+        let lines: Vec<String> = func.blocks[pre]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(lines, ["x.pre = phi x1 x2 x3", "jmp .head"]);
+
+        // .three used to fall into .head
+        let three = func.block_index(&"three".to_string()).unwrap();
+        assert_eq!(
+            func.blocks[three].instrs.last().unwrap().to_string(),
+            "jmp .head.preheader"
+        );
+        assert_eq!(
+            func.blocks[1].instrs.last().unwrap().to_string(),
+            "br b .head.preheader .three"
+        );
+
+        let doms = DominatorInfo::compute(&func).unwrap();
+        let loops = compute(&func, &doms);
+        assert_eq!(loops.loops[0].preheader, Some(pre));
+        assert!(func.ensure_preheaders(&loops).is_empty());
+    }
+
+    #[test]
+    fn test_irreducible_loop_is_reported() {
+        // two ways into the a <-> b cycle, neither dominates the other
+        let func = IrFunction::parse(
+            "@main(c: bool) {
+.entry:
+  br c .a .b
+.a:
+  jmp .b
+.b:
+  jmp .a
+}",
+        )
+        .unwrap();
+        let doms = DominatorInfo::compute(&func).unwrap();
+        let loops = compute(&func, &doms);
+        assert!(loops.loops.is_empty());
+        assert_eq!(loops.irreducible.len(), 1);
+    }
+}
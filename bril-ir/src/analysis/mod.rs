@@ -0,0 +1,10 @@
+//! Analyses over the IR that passes and the backend share
+pub mod available;
+pub mod bitset;
+pub mod callgraph;
+pub mod def_use;
+pub mod effects;
+pub mod liveness;
+pub mod loops;
+pub mod reaching_defs;
+pub mod value_table;
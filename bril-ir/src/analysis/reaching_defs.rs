@@ -0,0 +1,232 @@
+//! Reaching definitions, forward over the CFG
+//!
+//! Every instruction that defines something is a def site, numbered in block
+//! order. A site reaches a point if some path gets there from it without
+//! another def of the same variable in between.
+
+use super::bitset::BitSet;
+use crate::cfg::{IrFunction, IrInstruction};
+use crate::BlockID;
+use bril_frontend::Symbol;
+use std::collections::HashMap;
+
+/// Index into `ReachingDefs::sites`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DefId(pub usize);
+
+/// An instruction defining `var`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DefSite {
+    pub block: BlockID,
+    pub instr: usize,
+    pub var: Symbol,
+}
+
+/// A use of `var` at (`block`, `instr`) and the defs that can reach it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UseChain {
+    pub block: BlockID,
+    pub instr: usize,
+    pub var: Symbol,
+    pub defs: Vec<DefId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReachingDefs {
+    pub sites: Vec<DefSite>,
+    /// Defs reaching the top of each block, by block index
+    pub reach_in: Vec<BitSet>,
+    /// and the bottom
+    pub reach_out: Vec<BitSet>,
+    // every site of each variable, for the kills
+    by_var: HashMap<Symbol, Vec<DefId>>,
+}
+
+pub fn compute(func: &IrFunction) -> ReachingDefs {
+    let mut sites = Vec::new();
+    let mut by_var: HashMap<Symbol, Vec<DefId>> = HashMap::new();
+    for (b, block) in func.blocks.iter().enumerate() {
+        for (i, instr) in block.instrs.iter().enumerate() {
+            for &var in instr.defs() {
+                by_var.entry(var).or_default().push(DefId(sites.len()));
+                sites.push(DefSite {
+                    block: b,
+                    instr: i,
+                    var,
+                });
+            }
+        }
+    }
+
+    let n = func.blocks.len();
+    let mut out = ReachingDefs {
+        reach_in: vec![BitSet::new(sites.len()); n],
+        reach_out: vec![BitSet::new(sites.len()); n],
+        sites,
+        by_var,
+    };
+
+    // gen: the defs a block leaves standing, kill: every def of what it defines
+    let mut gen_sets = Vec::with_capacity(n);
+    let mut kill_sets = Vec::with_capacity(n);
+    for (b, block) in func.blocks.iter().enumerate() {
+        let mut gen_set = BitSet::new(out.sites.len());
+        let mut kill = BitSet::new(out.sites.len());
+        for i in 0..block.instrs.len() {
+            out.transfer(&mut gen_set, func, b, i);
+            for var in block.instrs[i].defs() {
+                for id in &out.by_var[var] {
+                    kill.insert(id.0);
+                }
+            }
+        }
+        gen_sets.push(gen_set);
+        kill_sets.push(kill);
+    }
+
+    // Fix-pointed iteration (forwards)
+    loop {
+        let mut changed = false;
+
+        for b in 0..n {
+            let mut inn = BitSet::new(out.sites.len());
+            for &p in &func.blocks[b].preds {
+                inn.union_with(&out.reach_out[p]);
+            }
+
+            // gen ∪ (in \ kill)
+            let mut set = inn.clone();
+            set.subtract(&kill_sets[b]);
+            set.union_with(&gen_sets[b]);
+
+            if inn != out.reach_in[b] || set != out.reach_out[b] {
+                changed = true;
+                out.reach_in[b] = inn;
+                out.reach_out[b] = set;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    out
+}
+
+impl ReachingDefs {
+    pub fn site(&self, id: DefId) -> &DefSite {
+        &self.sites[id.0]
+    }
+
+    /// Every use in `func` with the defs reaching it, in program order. A
+    /// phi's source is used at the end of the pred it comes from.
+    pub fn def_use_chains(&self, func: &IrFunction) -> Vec<UseChain> {
+        let mut chains = Vec::new();
+
+        for (b, block) in func.blocks.iter().enumerate() {
+            let mut set = self.reach_in[b].clone();
+            for (i, instr) in block.instrs.iter().enumerate() {
+                if let IrInstruction::Phi { sources, .. } = instr {
+                    for src in sources {
+                        let (Some(p), Some(var)) = (func.block_index(&src.pred), src.value) else {
+                            continue;
+                        };
+                        chains.push(UseChain {
+                            block: b,
+                            instr: i,
+                            var,
+                            defs: self.reaching(&self.reach_out[p], var),
+                        });
+                    }
+                } else {
+                    for var in instr.uses() {
+                        chains.push(UseChain {
+                            block: b,
+                            instr: i,
+                            var,
+                            defs: self.reaching(&set, var),
+                        });
+                    }
+                }
+                self.transfer(&mut set, func, b, i);
+            }
+        }
+
+        chains
+    }
+
+    /// The defs of `var` in `set`
+    pub fn reaching(&self, set: &BitSet, var: Symbol) -> Vec<DefId> {
+        let Some(ids) = self.by_var.get(&var) else {
+            return Vec::new();
+        };
+        ids.iter()
+            .copied()
+            .filter(|id| set.contains(id.0))
+            .collect()
+    }
+
+    /// Step `set` over instruction `i` of block `b`
+    fn transfer(&self, set: &mut BitSet, func: &IrFunction, b: BlockID, i: usize) {
+        for var in func.blocks[b].instrs[i].defs() {
+            for id in &self.by_var[var] {
+                let site = self.site(*id);
+                if site.block == b && site.instr == i {
+                    set.insert(id.0);
+                } else {
+                    set.remove(id.0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_both_defs_reach_the_join() {
+        let func = IrFunction::parse(
+            "@diamond(c: bool) {
+.entry:
+  x = const 1
+  jmp .A
+.A:
+  br c .B .C
+.B:
+  x = const 2
+  jmp .D
+.C:
+  jmp .D
+.D:
+  print x
+  ret
+}",
+        )
+        .unwrap();
+        let reaching = compute(&func);
+        let idx = |label: &str| func.block_index(&label.to_string()).unwrap();
+        let x = Symbol::from("x");
+
+        let at_join = reaching.reaching(&reaching.reach_in[idx("D")], x);
+        let blocks: Vec<BlockID> = at_join.iter().map(|&d| reaching.site(d).block).collect();
+        assert_eq!(blocks, [0, idx("B")]);
+
+        // B's own def kills the one from the entry
+        let out_b = reaching.reaching(&reaching.reach_out[idx("B")], x);
+        assert_eq!(out_b.len(), 1);
+        assert_eq!(reaching.site(out_b[0]).block, idx("B"));
+
+        let chains = reaching.def_use_chains(&func);
+        let print = chains
+            .iter()
+            .find(|c| c.block == idx("D") && c.var == x)
+            .unwrap();
+        assert_eq!(print.defs, at_join);
+        // c is an argument, no def site for it
+        let branch = chains.iter().find(|c| c.block == idx("A")).unwrap();
+        assert!(branch.defs.is_empty());
+    }
+}
@@ -0,0 +1,363 @@
+//! Hash table of the values instructions compute, for value numbering
+//!
+//! Two instructions get the same [`Expr`] when they're the same op on the
+//! same operands. Commutative ops have their operands sorted, and an
+//! operand known to hold a constant is keyed by the constant rather than
+//! its name, so `add a b`, `add b a` and `add b two` (with `a` and `two`
+//! both `const 2`) all meet.
+//!
+//! Scopes follow a walk down the dominator tree: what a block records is
+//! forgotten once its subtree is done, so a lookup only ever finds a value
+//! computed in a block that dominates the one asking.
+
+use crate::cfg::IrInstruction;
+use bril_frontend::{Literal, Symbol};
+use std::collections::HashMap;
+
+/// A literal as a hash key, floats by their bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ConstKey {
+    Int(i64),
+    Bool(bool),
+    Float(u64),
+    Char(char),
+}
+
+impl From<&Literal> for ConstKey {
+    fn from(lit: &Literal) -> Self {
+        match lit {
+            Literal::Int(i) => ConstKey::Int(*i),
+            Literal::Bool(b) => ConstKey::Bool(*b),
+            Literal::Float(f) => ConstKey::Float(f.to_bits()),
+            Literal::Char(c) => ConstKey::Char(*c),
+        }
+    }
+}
+
+impl From<ConstKey> for Literal {
+    fn from(key: ConstKey) -> Self {
+        match key {
+            ConstKey::Int(i) => Literal::Int(i),
+            ConstKey::Bool(b) => Literal::Bool(b),
+            ConstKey::Float(bits) => Literal::Float(f64::from_bits(bits)),
+            ConstKey::Char(c) => Literal::Char(c),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Operand {
+    Var(Symbol),
+    Const(ConstKey),
+}
+
+/// What an instruction computes, its dest left out
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Expr {
+    pub op: &'static str,
+    pub operands: Vec<Operand>,
+}
+
+// how to put the table back when a scope is popped
+#[derive(Debug)]
+enum Undo {
+    Expr(Expr, Option<Symbol>),
+    Const(Symbol, Option<ConstKey>),
+}
+
+#[derive(Debug, Default)]
+pub struct ValueTable {
+    exprs: HashMap<Expr, Symbol>,
+    consts: HashMap<Symbol, ConstKey>,
+    // one log per open scope, what was there before each insert in it
+    scopes: Vec<Vec<Undo>>,
+}
+
+impl ValueTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a scope, e.g. on entering a block of the dominator tree
+    pub fn push_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    /// Forget everything recorded since the matching `push_scope`,
+    /// bringing back what it shadowed
+    pub fn pop_scope(&mut self) {
+        let Some(log) = self.scopes.pop() else {
+            return;
+        };
+        for undo in log.into_iter().rev() {
+            match undo {
+                Undo::Expr(expr, Some(name)) => {
+                    self.exprs.insert(expr, name);
+                }
+                Undo::Expr(expr, None) => {
+                    self.exprs.remove(&expr);
+                }
+                Undo::Const(var, Some(key)) => {
+                    self.consts.insert(var, key);
+                }
+                Undo::Const(var, None) => {
+                    self.consts.remove(&var);
+                }
+            }
+        }
+    }
+
+    /// How deep in scopes the table is, 0 before the first `push_scope`
+    pub fn depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    /// The constant `var` is known to hold
+    pub fn constant(&self, var: Symbol) -> Option<ConstKey> {
+        self.consts.get(&var).copied()
+    }
+
+    /// The key of what `instr` computes. None for anything whose result
+    /// isn't a function of its operands alone: calls, memory, output,
+    /// phis, ops we don't know, and copies, which are left to copy
+    /// propagation
+    pub fn expr(&self, instr: &IrInstruction) -> Option<Expr> {
+        if let IrInstruction::Const { value, .. } = instr {
+            return Some(Expr {
+                op: "const",
+                operands: vec![Operand::Const(ConstKey::from(value))],
+            });
+        }
+        if !is_pure(instr) {
+            return None;
+        }
+        let mut operands: Vec<Operand> = instr
+            .uses()
+            .into_iter()
+            .map(|var| match self.constant(var) {
+                Some(key) => Operand::Const(key),
+                None => Operand::Var(var),
+            })
+            .collect();
+        if is_commutative(instr) {
+            operands.sort();
+        }
+        Some(Expr {
+            op: instr.opcode(),
+            operands,
+        })
+    }
+
+    /// The name that already holds what `instr` computes
+    pub fn lookup(&self, instr: &IrInstruction) -> Option<Symbol> {
+        self.exprs.get(&self.expr(instr)?).copied()
+    }
+
+    /// Record that `instr`'s dest holds its value, shadowing whatever held
+    /// the same value before until the current scope is popped. Returns
+    /// false, recording nothing, when `instr` has no key
+    pub fn insert(&mut self, instr: &IrInstruction) -> bool {
+        let (Some(expr), [dest]) = (self.expr(instr), instr.defs()) else {
+            return false;
+        };
+        let dest = *dest;
+        if let IrInstruction::Const { value, .. } = instr {
+            let old = self.consts.insert(dest, ConstKey::from(value));
+            self.log(Undo::Const(dest, old));
+        }
+        let old = self.exprs.insert(expr.clone(), dest);
+        self.log(Undo::Expr(expr, old));
+        true
+    }
+
+    /// The name already holding `instr`'s value if there is one, otherwise
+    /// `instr` is recorded as computing it
+    pub fn lookup_or_insert(&mut self, instr: &IrInstruction) -> Option<Symbol> {
+        let found = self.lookup(instr);
+        if found.is_none() {
+            self.insert(instr);
+        }
+        found
+    }
+
+    fn log(&mut self, undo: Undo) {
+        if let Some(log) = self.scopes.last_mut() {
+            log.push(undo);
+        }
+    }
+}
+
+/// Whether an instruction only computes its dest from its operands. An
+/// alloc is fresh memory each time and a load depends on the stores
+/// before it, so neither counts. Shifts are left out too, their amount
+/// isn't an operand so `shl x 1` and `shl x 2` would get the same key
+pub fn is_pure(instr: &IrInstruction) -> bool {
+    matches!(
+        instr,
+        IrInstruction::Add { .. }
+            | IrInstruction::Mul { .. }
+            | IrInstruction::Sub { .. }
+            | IrInstruction::Div { .. }
+            | IrInstruction::Eq { .. }
+            | IrInstruction::Lt { .. }
+            | IrInstruction::Gt { .. }
+            | IrInstruction::Ge { .. }
+            | IrInstruction::Le { .. }
+            | IrInstruction::FAdd { .. }
+            | IrInstruction::FSub { .. }
+            | IrInstruction::FMul { .. }
+            | IrInstruction::FDiv { .. }
+            | IrInstruction::FEq { .. }
+            | IrInstruction::FLt { .. }
+            | IrInstruction::FGt { .. }
+            | IrInstruction::FLe { .. }
+            | IrInstruction::FGe { .. }
+            | IrInstruction::CEq { .. }
+            | IrInstruction::CLt { .. }
+            | IrInstruction::CGt { .. }
+            | IrInstruction::CLe { .. }
+            | IrInstruction::CGe { .. }
+            | IrInstruction::Not { .. }
+            | IrInstruction::Or { .. }
+            | IrInstruction::And { .. }
+            | IrInstruction::Float2Bits { .. }
+            | IrInstruction::Bits2Float { .. }
+            | IrInstruction::Char2Int { .. }
+            | IrInstruction::Int2Char { .. }
+            | IrInstruction::PtrAdd { .. }
+    )
+}
+
+/// Whether swapping the two operands of `instr` gives the same value
+pub fn is_commutative(instr: &IrInstruction) -> bool {
+    matches!(
+        instr,
+        IrInstruction::Add { .. }
+            | IrInstruction::Mul { .. }
+            | IrInstruction::And { .. }
+            | IrInstruction::Or { .. }
+            | IrInstruction::Eq { .. }
+            | IrInstruction::CEq { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(dest: &str, lhs: &str, rhs: &str) -> IrInstruction {
+        IrInstruction::Add {
+            dest: dest.into(),
+            lhs: lhs.into(),
+            rhs: rhs.into(),
+        }
+    }
+
+    fn int(dest: &str, i: i64) -> IrInstruction {
+        IrInstruction::Const {
+            dest: dest.into(),
+            value: Literal::Int(i),
+        }
+    }
+
+    #[test]
+    fn test_commutative_ops_hash_the_same() {
+        let mut table = ValueTable::new();
+        assert_eq!(table.lookup_or_insert(&add("s", "a", "b")), None);
+        assert_eq!(table.lookup(&add("t", "b", "a")), Some("s".into()));
+
+        let sub = |dest: &str, lhs: &str, rhs: &str| IrInstruction::Sub {
+            dest: dest.into(),
+            lhs: lhs.into(),
+            rhs: rhs.into(),
+        };
+        table.insert(&sub("d", "a", "b"));
+        assert_eq!(table.lookup(&sub("e", "a", "b")), Some("d".into()));
+        assert_eq!(table.lookup(&sub("e", "b", "a")), None);
+        // same operands, different op
+        let mul = IrInstruction::Mul {
+            dest: "m".into(),
+            lhs: "a".into(),
+            rhs: "b".into(),
+        };
+        assert_eq!(table.lookup(&mul), None);
+    }
+
+    #[test]
+    fn test_constants_are_folded_into_the_key() {
+        let mut table = ValueTable::new();
+        table.insert(&int("two", 2));
+        assert_eq!(table.lookup(&int("also_two", 2)), Some("two".into()));
+        assert_eq!(table.lookup(&int("three", 3)), None);
+        table.insert(&int("deux", 2));
+
+        // both names are the constant, whichever one is read
+        table.insert(&add("s", "x", "two"));
+        assert_eq!(table.lookup(&add("t", "deux", "x")), Some("s".into()));
+        assert_eq!(table.constant("deux".into()), Some(ConstKey::Int(2)));
+        assert_eq!(table.constant("x".into()), None);
+    }
+
+    #[test]
+    fn test_side_effects_and_memory_have_no_key() {
+        let mut table = ValueTable::new();
+        let instrs = [
+            IrInstruction::Call {
+                target_func: "f".into(),
+                callee: None,
+                args: vec!["a".into()],
+                dest: Some("r".into()),
+            },
+            IrInstruction::Print {
+                values: vec!["a".into()],
+            },
+            IrInstruction::Store {
+                ptr: "p".into(),
+                value: "a".into(),
+            },
+            IrInstruction::Load {
+                dest: "v".into(),
+                ptr: "p".into(),
+            },
+            IrInstruction::Alloc {
+                dest: "p".into(),
+                size: "a".into(),
+            },
+            IrInstruction::Assign {
+                lhs: "b".into(),
+                rhs: "a".into(),
+            },
+        ];
+        for instr in &instrs {
+            assert_eq!(table.expr(instr), None, "{}", instr);
+            assert!(!table.insert(instr));
+            assert_eq!(table.lookup(instr), None);
+        }
+    }
+
+    #[test]
+    fn test_scopes_shadow_and_restore() {
+        let mut table = ValueTable::new();
+        table.insert(&add("outer", "a", "b"));
+
+        table.push_scope();
+        assert_eq!(table.lookup(&add("x", "b", "a")), Some("outer".into()));
+        table.insert(&add("inner", "a", "b"));
+        table.insert(&int("k", 7));
+        table.insert(&add("only_inner", "a", "k"));
+        assert_eq!(table.lookup(&add("x", "a", "b")), Some("inner".into()));
+        assert_eq!(table.depth(), 1);
+
+        table.push_scope();
+        table.insert(&add("innermost", "b", "a"));
+        table.pop_scope();
+        assert_eq!(table.lookup(&add("x", "a", "b")), Some("inner".into()));
+
+        table.pop_scope();
+        assert_eq!(table.depth(), 0);
+        assert_eq!(table.lookup(&add("x", "a", "b")), Some("outer".into()));
+        assert_eq!(table.constant("k".into()), None);
+        assert_eq!(table.lookup(&int("c", 7)), None);
+        assert_eq!(table.lookup(&add("x", "a", "k")), None);
+    }
+}
@@ -1,21 +1,77 @@
+use crate::to_bril::UNDEFINED;
 use crate::BlockID;
-use anyhow::Result;
+use crate::FuncId;
+use anyhow::{anyhow, bail, Result};
+use bril_frontend::validate::did_you_mean;
 use bril_frontend::Function as BrilFunction;
+use bril_frontend::InstrId;
 use bril_frontend::Instruction as BrilInstr;
 use bril_frontend::Literal;
 use bril_frontend::Op;
+use bril_frontend::Position;
 use bril_frontend::Program as BrilProgam;
-use std::collections::HashMap;
+use bril_frontend::Symbol;
+use bril_frontend::Type;
+use std::collections::{HashMap, HashSet};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct IrModule {
     pub functions: Vec<IrFunction>,
 }
 
-#[derive(Debug, Clone)]
+impl IrModule {
+    pub fn function(&self, id: FuncId) -> &IrFunction {
+        &self.functions[id.0]
+    }
+
+    /// Point every call at the function it names, so nothing later has to
+    /// look callees up by name (or trust that they exist)
+    pub fn resolve_calls(&mut self) -> Result<()> {
+        let ids: HashMap<String, FuncId> = self
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.name.clone(), FuncId(i)))
+            .collect();
+
+        for func in &mut self.functions {
+            let instrs = func.blocks.iter_mut().flat_map(|b| &mut b.instrs);
+            for instr in instrs {
+                if let IrInstruction::Call {
+                    target_func,
+                    callee,
+                    ..
+                } = instr
+                {
+                    let id = ids.get(target_func).ok_or_else(|| {
+                        anyhow!(
+                            "call to unknown function @{} in @{}{}",
+                            target_func,
+                            func.name,
+                            did_you_mean(target_func, ids.keys().map(String::as_str))
+                        )
+                    })?;
+                    *callee = Some(*id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct IrFunction {
     pub name: String,
-    pub args: Vec<String>,
+    pub args: Vec<Symbol>,
+    // same order as `args`
+    pub arg_types: Vec<Type>,
+    pub ret_typ: Option<Type>,
+    // type of every variable, args included, from the Bril type fields.
+    // Names the SSA renamer makes up get their original's
+    pub types: HashMap<Symbol, Type>,
+    // preds, succs and `label_to_idx` all index into this, so take blocks
+    // out with `remove_block`/`remove_unreachable_blocks` rather than
+    // splicing it
     pub blocks: Vec<IrBasicBlock>,
     pub label_to_idx: HashMap<String, usize>,
 }
@@ -24,28 +80,126 @@ pub struct IrFunction {
 pub struct IrBasicBlock {
     pub label: String,
     pub instrs: Vec<IrInstruction>,
+    // Bril `pos` of each instruction, parallel to `instrs`. None for
+    // instructions without one (phis, hand-built IR, JSON without -p)
+    pub positions: Vec<Option<Position>>,
+    // which Bril instruction each of `instrs` was lowered from, same deal.
+    // Lets a miscompile be traced back to the source
+    pub origins: Vec<Option<InstrId>>,
     pub preds: Vec<usize>,
     pub succs: Vec<usize>,
 }
 
+// where the instructions came from doesn't change what the block does, a
+// block read back from text or rebuilt from Bril is still the same block
+impl PartialEq for IrBasicBlock {
+    fn eq(&self, other: &Self) -> bool {
+        self.label == other.label
+            && self.instrs == other.instrs
+            && self.preds == other.preds
+            && self.succs == other.succs
+    }
+}
+
+impl IrBasicBlock {
+    /// Source position of `instrs[idx]`, if the frontend gave us one
+    pub fn pos(&self, idx: usize) -> Option<Position> {
+        self.positions.get(idx).copied().flatten()
+    }
+
+    /// The Bril instruction `instrs[idx]` came from, None for ones the
+    /// compiler made up
+    pub fn origin(&self, idx: usize) -> Option<InstrId> {
+        self.origins.get(idx).copied().flatten()
+    }
+
+    /// Insert `instr` at `idx`, keeping `positions` and `origins` lined up
+    pub fn insert_instr(
+        &mut self,
+        idx: usize,
+        instr: IrInstruction,
+        pos: Option<Position>,
+        origin: Option<InstrId>,
+    ) {
+        self.positions.resize(self.instrs.len(), None);
+        self.origins.resize(self.instrs.len(), None);
+        self.instrs.insert(idx, instr);
+        self.positions.insert(idx, pos);
+        self.origins.insert(idx, origin);
+    }
+
+    /// Drop every instruction `remove` says to, `positions` and `origins`
+    /// going with them. Returns what was dropped, in order
+    pub fn remove_instrs(
+        &mut self,
+        mut remove: impl FnMut(&IrInstruction) -> bool,
+    ) -> Vec<IrInstruction> {
+        self.positions.resize(self.instrs.len(), None);
+        self.origins.resize(self.instrs.len(), None);
+        let keep: Vec<bool> = self.instrs.iter().map(|i| !remove(i)).collect();
+
+        let mut removed = Vec::new();
+        let instrs = std::mem::take(&mut self.instrs);
+        for (instr, &keep) in instrs.into_iter().zip(&keep) {
+            if keep {
+                self.instrs.push(instr);
+            } else {
+                removed.push(instr);
+            }
+        }
+        let mut flags = keep.iter();
+        self.positions.retain(|_| *flags.next().unwrap());
+        let mut flags = keep.iter();
+        self.origins.retain(|_| *flags.next().unwrap());
+        removed
+    }
+}
+
 impl IrFunction {
     // just in case i was to do some testing
     pub fn new(func_name: &str) -> Self {
         Self {
             name: func_name.to_string(),
             args: Vec::new(),
+            arg_types: Vec::new(),
+            ret_typ: None,
+            types: HashMap::new(),
             blocks: Vec::new(),
             label_to_idx: HashMap::new(),
         }
     }
 
-    pub fn add_block(&mut self, label: &str) -> usize {
+    pub fn type_of(&self, var: &str) -> Option<&Type> {
+        self.types.get(&Symbol::from(var))
+    }
+
+    /// Record that `var` holds a `typ`. A variable that's redefined with
+    /// another type could be either, so it's `any`
+    pub fn declare(&mut self, var: Symbol, typ: Type) {
+        self.types
+            .entry(var)
+            .and_modify(|t| {
+                if *t != typ {
+                    *t = Type::Any;
+                }
+            })
+            .or_insert(typ);
+    }
+
+    pub fn add_block(&mut self, label: &str) -> Result<usize> {
+        // a second block with the same label would take over its branches
+        if self.label_to_idx.contains_key(label) {
+            bail!("label .{} is defined more than once", label);
+        }
+
         // current block we're on
         let idx = self.blocks.len();
 
         self.blocks.push(IrBasicBlock {
             label: label.to_string(),
             instrs: Vec::new(),
+            positions: Vec::new(),
+            origins: Vec::new(),
             preds: Vec::new(),
             succs: Vec::new(),
         });
@@ -55,7 +209,7 @@ impl IrFunction {
         self.label_to_idx.insert(label.to_string(), idx);
 
         // return index of newly added block index
-        idx
+        Ok(idx)
     }
 
     pub fn add_edge(&mut self, from: usize, to: usize) {
@@ -63,100 +217,535 @@ impl IrFunction {
         self.blocks[to].preds.push(from);
     }
 
-    pub fn append_instr(&mut self, idx: usize, instr: &IrInstruction) {
+    /// Take out the edges from `from` to `to`, both of them for a `br` with
+    /// the same label twice, and the phi sources in `to` they gave. The
+    /// branch itself is left for the caller to change
+    pub fn remove_edge(&mut self, from: usize, to: usize) {
+        self.blocks[from].succs.retain(|&s| s != to);
+        self.blocks[to].preds.retain(|&p| p != from);
+        let label = self.blocks[from].label.clone();
+        for instr in &mut self.blocks[to].instrs {
+            if let IrInstruction::Phi { sources, .. } = instr {
+                sources.retain(|s| s.pred != label);
+            }
+        }
+    }
+
+    pub fn append_instr(
+        &mut self,
+        idx: usize,
+        instr: &IrInstruction,
+        pos: Option<Position>,
+        origin: Option<InstrId>,
+    ) {
         self.blocks[idx].instrs.push(instr.clone());
+        self.blocks[idx].positions.push(pos);
+        self.blocks[idx].origins.push(origin);
     }
 
     pub fn block_index(&self, label: &String) -> Option<usize> {
         self.label_to_idx.get(label).copied()
     }
+
+    /// Blocks reachable from the entry, each after everything it leads to
+    /// (back edges aside). Siblings go by index, so the order only depends
+    /// on the CFG
+    pub fn postorder(&self) -> Vec<BlockID> {
+        let succs: Vec<Vec<BlockID>> = self.blocks.iter().map(|b| b.succs.clone()).collect();
+        if succs.is_empty() {
+            return Vec::new();
+        }
+        postorder_from(0, &succs)
+    }
+
+    /// The other way round, the entry first. What forward dataflow wants
+    pub fn reverse_postorder(&self) -> Vec<BlockID> {
+        let mut order = self.postorder();
+        order.reverse();
+        order
+    }
+
+    /// Drop every block the entry can't get to and renumber the rest,
+    /// returning how many went. Phis lose the sources from those blocks
+    pub fn remove_unreachable_blocks(&mut self) -> usize {
+        let n = self.blocks.len();
+        let mut reachable = vec![false; n];
+        let mut stack = Vec::new();
+        if n > 0 {
+            reachable[0] = true;
+            stack.push(0);
+        }
+        while let Some(b) = stack.pop() {
+            for &s in &self.blocks[b].succs {
+                if !reachable[s] {
+                    reachable[s] = true;
+                    stack.push(s);
+                }
+            }
+        }
+
+        let removed = reachable.iter().filter(|r| !**r).count();
+        if removed > 0 {
+            self.retain_blocks(&reachable);
+        }
+        removed
+    }
+
+    /// Take block `idx` out and renumber the ones after it. Edges to and
+    /// from it go, and so do the phi sources it gave. A branch naming its
+    /// label is left as it is, so retarget those first or the verifier
+    /// will have something to say
+    pub fn remove_block(&mut self, idx: BlockID) -> IrBasicBlock {
+        let block = self.blocks[idx].clone();
+        let mut keep = vec![true; self.blocks.len()];
+        keep[idx] = false;
+        self.retain_blocks(&keep);
+        block
+    }
+
+    /// Put the blocks in `order`, every block once and the entry first,
+    /// renumbering the edges. Only the order changes: a block that fell
+    /// through to a block no longer after it jumps there instead, and one
+    /// that fell off the end of the function returns
+    pub fn reorder_blocks(&mut self, order: &[BlockID]) {
+        assert_eq!(order.len(), self.blocks.len(), "every block goes somewhere");
+        assert_eq!(order.first(), Some(&0), "the entry stays first");
+        let mut new_idx = vec![usize::MAX; order.len()];
+        for (i, &b) in order.iter().enumerate() {
+            new_idx[b] = i;
+        }
+
+        for (i, &b) in order.iter().enumerate() {
+            let block = &self.blocks[b];
+            if block
+                .instrs
+                .last()
+                .is_some_and(|instr| instr.is_terminator())
+            {
+                continue;
+            }
+            let end = match block.succs[..] {
+                [] => IrInstruction::Ret { args: Vec::new() },
+                [next] if order.get(i + 1) != Some(&next) => IrInstruction::Jmp {
+                    label: self.blocks[next].label.clone(),
+                },
+                _ => continue,
+            };
+            let at = self.blocks[b].instrs.len();
+            self.blocks[b].insert_instr(at, end, None, None);
+        }
+
+        let mut blocks: Vec<Option<IrBasicBlock>> = std::mem::take(&mut self.blocks)
+            .into_iter()
+            .map(Some)
+            .collect();
+        for &b in order {
+            let mut block = blocks[b].take().expect("a block is put in twice");
+            for p in block.preds.iter_mut().chain(block.succs.iter_mut()) {
+                *p = new_idx[*p];
+            }
+            self.blocks.push(block);
+        }
+        self.label_to_idx = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (b.label.clone(), i))
+            .collect();
+    }
+
+    /// Drop the instructions `remove` says to from every block. A name that
+    /// loses its only def loses its type too, unless it's an arg
+    pub fn remove_instrs(&mut self, mut remove: impl FnMut(&IrInstruction) -> bool) -> usize {
+        let mut count = 0;
+        let mut gone = HashSet::new();
+        for block in &mut self.blocks {
+            for instr in block.remove_instrs(&mut remove) {
+                gone.extend(instr.defs().iter().copied());
+                count += 1;
+            }
+        }
+        for instr in self.blocks.iter().flat_map(|b| &b.instrs) {
+            for var in instr.defs() {
+                gone.remove(var);
+            }
+        }
+        for var in gone.iter().filter(|v| !self.args.contains(v)) {
+            self.types.remove(var);
+        }
+        count
+    }
+
+    /// Give the phis in `block` that took a value from `from` that value
+    /// from `to` instead, for when the edge now comes in from there
+    pub fn retarget_phi_sources(&mut self, block: BlockID, from: &str, to: &str) {
+        for instr in &mut self.blocks[block].instrs {
+            if let IrInstruction::Phi { sources, .. } = instr {
+                for src in sources.iter_mut().filter(|s| s.pred == from) {
+                    src.pred = to.to_string();
+                }
+            }
+        }
+    }
+
+    /// For phis written the old way, sources lined up with the preds: a
+    /// source with no `pred` gets the label of the pred at its position.
+    /// Then every phi's sources are put in pred order
+    pub fn pair_phi_sources(&mut self) {
+        for b in 0..self.blocks.len() {
+            let labels: Vec<String> = self.blocks[b]
+                .preds
+                .iter()
+                .map(|&p| self.blocks[p].label.clone())
+                .collect();
+            for instr in &mut self.blocks[b].instrs {
+                let IrInstruction::Phi { sources, .. } = instr else {
+                    continue;
+                };
+                for (src, label) in sources.iter_mut().zip(&labels) {
+                    if src.pred.is_empty() {
+                        src.pred = label.clone();
+                    }
+                }
+                // ones for blocks that aren't preds go last
+                sources.sort_by_key(|s| {
+                    labels
+                        .iter()
+                        .position(|l| *l == s.pred)
+                        .unwrap_or(usize::MAX)
+                });
+            }
+        }
+    }
+
+    /// Keep the blocks `keep` is true for, fixing up every index that
+    /// points at one: preds, succs and `label_to_idx`. Phi sources from
+    /// the dropped blocks go
+    fn retain_blocks(&mut self, keep: &[bool]) {
+        // old index -> new index
+        let mut new_idx = vec![usize::MAX; keep.len()];
+        let mut next = 0;
+        for b in 0..keep.len() {
+            if keep[b] {
+                new_idx[b] = next;
+                next += 1;
+            }
+        }
+
+        let removed: HashSet<String> = (0..keep.len())
+            .filter(|&b| !keep[b])
+            .map(|b| self.blocks[b].label.clone())
+            .collect();
+        let blocks = std::mem::take(&mut self.blocks);
+        for (b, mut block) in blocks.into_iter().enumerate() {
+            if !keep[b] {
+                continue;
+            }
+            for instr in &mut block.instrs {
+                if let IrInstruction::Phi { sources, .. } = instr {
+                    sources.retain(|s| !removed.contains(&s.pred));
+                }
+            }
+            block.preds = block
+                .preds
+                .iter()
+                .filter(|&&p| keep[p])
+                .map(|&p| new_idx[p])
+                .collect();
+            block.succs = block
+                .succs
+                .iter()
+                .filter(|&&s| keep[s])
+                .map(|&s| new_idx[s])
+                .collect();
+            self.blocks.push(block);
+        }
+
+        self.label_to_idx = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (b.label.clone(), i))
+            .collect();
+    }
+
+    /// `base`, or `base.1`, `base.2`... whichever the function doesn't use yet
+    pub fn fresh_label(&self, base: &str) -> String {
+        (0..)
+            .map(|n| match n {
+                0 => base.to_string(),
+                n => format!("{}.{}", base, n),
+            })
+            .find(|label| self.block_index(label).is_none())
+            .unwrap()
+    }
+
+    /// Same for a variable name, against the args and every def
+    pub fn fresh_var(&self, base: &str) -> Symbol {
+        let taken: HashSet<Symbol> = self
+            .blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .flat_map(|i| i.defs().iter().copied())
+            .chain(self.args.iter().copied())
+            .collect();
+        (0..)
+            .map(|n| match n {
+                0 => Symbol::from(base),
+                n => Symbol::from(format!("{}.{}", base, n)),
+            })
+            .find(|var| !taken.contains(var) && !self.types.contains_key(var))
+            .unwrap()
+    }
 }
 
-#[derive(Debug, Clone)]
+/// What a phi takes coming in from the block labelled `pred`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhiSource {
+    pub pred: String,
+    /// None when nothing's defined along that edge
+    pub value: Option<Symbol>,
+}
+
+impl PhiSource {
+    pub fn new(pred: &str, value: Option<Symbol>) -> Self {
+        PhiSource {
+            pred: pred.to_string(),
+            value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum IrInstruction {
     // == Arithematic ==
     Add {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 
     Mul {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 
     Sub {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 
     Div {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    // == Shifts ==
+    // Bril has none, these only come from strength reduction. The amount
+    // is part of the instruction, like in `slli`
+    Shl {
+        dest: Symbol,
+        src: Symbol,
+        amount: u32,
+    },
+
+    // logical, zeros come in at the top
+    Shr {
+        dest: Symbol,
+        src: Symbol,
+        amount: u32,
+    },
+
+    // arithmetic, copies of the sign bit come in at the top
+    Sra {
+        dest: Symbol,
+        src: Symbol,
+        amount: u32,
     },
 
     // == Comparsion ==
     Eq {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 
     Lt {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 
     Gt {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 
     Ge {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 
     Le {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    // == Floating Point ==
+    FAdd {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    FSub {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    FMul {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    FDiv {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    FEq {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    FLt {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    FGt {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    FLe {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    FGe {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    // reinterpret the bits, no conversion
+    Float2Bits {
+        dest: Symbol,
+        src: Symbol,
+    },
+
+    Bits2Float {
+        dest: Symbol,
+        src: Symbol,
+    },
+
+    /// An extension op the compiler doesn't know, kept so analyses can still
+    /// run over the function. It uses all its args and defines its dest,
+    /// nothing else is assumed, and its labels don't become CFG edges
+    Opaque {
+        op: String,
+        dest: Option<Symbol>,
+        args: Vec<Symbol>,
+        funcs: Vec<String>,
+        labels: Vec<String>,
+        typ: Option<Type>,
+    },
+
+    // a char is just its codepoint
+    Char2Int {
+        dest: Symbol,
+        src: Symbol,
+    },
+
+    Int2Char {
+        dest: Symbol,
+        src: Symbol,
+    },
+
+    // == Character Comparison ==
+    CEq {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    CLt {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    CGt {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    CLe {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
+    },
+
+    CGe {
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 
     // == Logical Operator ==
     Not {
-        dest: String,
-        args: String,
+        dest: Symbol,
+        args: Symbol,
     },
 
     Or {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 
     And {
-        dest: String,
-        lhs: String,
-        rhs: String,
+        dest: Symbol,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 
     // == Control Flow ==
     Call {
         target_func: String,
-        args: Vec<String>,
-        dest: Option<String>,
+        // filled in by `IrModule::resolve_calls`, `None` until then
+        callee: Option<FuncId>,
+        args: Vec<Symbol>,
+        dest: Option<Symbol>,
     },
 
     Br {
-        cond: String,
+        cond: Symbol,
         then_lbl: String,
         else_lbl: String,
     },
@@ -166,55 +755,189 @@ pub enum IrInstruction {
     },
 
     Ret {
-        args: Vec<String>,
+        args: Vec<Symbol>,
     },
 
     Phi {
-        dest: String,                 // value the be dictated by previous values
-        sources: Vec<Option<String>>, // this will store the blocks id of preds for blocks
+        dest: Symbol,
+        // one per pred, each saying which pred it's for, so reordering or
+        // dropping preds can't mix them up
+        sources: Vec<PhiSource>,
     },
 
     // == Literals ==
     Const {
-        dest: String,
+        dest: Symbol,
         value: Literal,
     },
 
+    // == Memory ==
+    Alloc {
+        dest: Symbol,
+        size: Symbol,
+    },
+
+    Load {
+        dest: Symbol,
+        ptr: Symbol,
+    },
+
+    Store {
+        ptr: Symbol,
+        value: Symbol,
+    },
+
+    Free {
+        ptr: Symbol,
+    },
+
+    PtrAdd {
+        dest: Symbol,
+        ptr: Symbol,
+        offset: Symbol,
+    },
+
     // == Misc ==
     Print {
-        values: Vec<String>,
+        values: Vec<Symbol>,
     },
 
+    /// Does nothing, kept so tools that sprinkle them in round-trip. DCE
+    /// drops them and isel skips them
+    Nop,
+
     Assign {
-        lhs: String,
-        rhs: String,
+        lhs: Symbol,
+        rhs: Symbol,
     },
 }
 
 impl IrInstruction {
+    /// Whether this ends a block
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            IrInstruction::Br { .. } | IrInstruction::Jmp { .. } | IrInstruction::Ret { .. }
+        )
+    }
+
+    /// Whether running this does something besides defining its dest, so
+    /// it has to stay even when nothing reads the dest. Memory writes,
+    /// output, calls (nothing is known about the callee) and ops we don't
+    /// know
+    pub fn has_side_effects(&self) -> bool {
+        matches!(
+            self,
+            IrInstruction::Store { .. }
+                | IrInstruction::Free { .. }
+                | IrInstruction::Print { .. }
+                | IrInstruction::Call { .. }
+                | IrInstruction::Opaque { .. }
+        ) || self.is_terminator()
+    }
+
+    /// The Bril name of the op, `id` for a copy and `opaque` for any
+    /// extension op
+    pub fn opcode(&self) -> &'static str {
+        match self {
+            IrInstruction::Add { .. } => "add",
+            IrInstruction::Mul { .. } => "mul",
+            IrInstruction::Sub { .. } => "sub",
+            IrInstruction::Div { .. } => "div",
+            IrInstruction::Shl { .. } => "shl",
+            IrInstruction::Shr { .. } => "shr",
+            IrInstruction::Sra { .. } => "sra",
+            IrInstruction::Eq { .. } => "eq",
+            IrInstruction::Lt { .. } => "lt",
+            IrInstruction::Gt { .. } => "gt",
+            IrInstruction::Ge { .. } => "ge",
+            IrInstruction::Le { .. } => "le",
+            IrInstruction::FAdd { .. } => "fadd",
+            IrInstruction::FSub { .. } => "fsub",
+            IrInstruction::FMul { .. } => "fmul",
+            IrInstruction::FDiv { .. } => "fdiv",
+            IrInstruction::FEq { .. } => "feq",
+            IrInstruction::FLt { .. } => "flt",
+            IrInstruction::FGt { .. } => "fgt",
+            IrInstruction::FLe { .. } => "fle",
+            IrInstruction::FGe { .. } => "fge",
+            IrInstruction::CEq { .. } => "ceq",
+            IrInstruction::CLt { .. } => "clt",
+            IrInstruction::CGt { .. } => "cgt",
+            IrInstruction::CLe { .. } => "cle",
+            IrInstruction::CGe { .. } => "cge",
+            IrInstruction::Not { .. } => "not",
+            IrInstruction::Or { .. } => "or",
+            IrInstruction::And { .. } => "and",
+            IrInstruction::Float2Bits { .. } => "float2bits",
+            IrInstruction::Bits2Float { .. } => "bits2float",
+            IrInstruction::Char2Int { .. } => "char2int",
+            IrInstruction::Int2Char { .. } => "int2char",
+            IrInstruction::Opaque { .. } => "opaque",
+            IrInstruction::Call { .. } => "call",
+            IrInstruction::Br { .. } => "br",
+            IrInstruction::Jmp { .. } => "jmp",
+            IrInstruction::Ret { .. } => "ret",
+            IrInstruction::Phi { .. } => "phi",
+            IrInstruction::Const { .. } => "const",
+            IrInstruction::Alloc { .. } => "alloc",
+            IrInstruction::Load { .. } => "load",
+            IrInstruction::Store { .. } => "store",
+            IrInstruction::Free { .. } => "free",
+            IrInstruction::PtrAdd { .. } => "ptradd",
+            IrInstruction::Print { .. } => "print",
+            IrInstruction::Nop => "nop",
+            IrInstruction::Assign { .. } => "id",
+        }
+    }
+
     // Returns a slice of a defined variable
     // describes what name does this instruction *write*
-    pub fn defs(&self) -> &[String] {
+    pub fn defs(&self) -> &[Symbol] {
         match self {
             IrInstruction::Add { dest, .. }
             | IrInstruction::Sub { dest, .. }
             | IrInstruction::Mul { dest, .. }
             | IrInstruction::Div { dest, .. }
+            | IrInstruction::Shl { dest, .. }
+            | IrInstruction::Shr { dest, .. }
+            | IrInstruction::Sra { dest, .. }
             | IrInstruction::Eq { dest, .. }
             | IrInstruction::Lt { dest, .. }
             | IrInstruction::Gt { dest, .. }
             | IrInstruction::Le { dest, .. }
             | IrInstruction::Ge { dest, .. }
+            | IrInstruction::FAdd { dest, .. }
+            | IrInstruction::FSub { dest, .. }
+            | IrInstruction::FMul { dest, .. }
+            | IrInstruction::FDiv { dest, .. }
+            | IrInstruction::FEq { dest, .. }
+            | IrInstruction::FLt { dest, .. }
+            | IrInstruction::FGt { dest, .. }
+            | IrInstruction::FLe { dest, .. }
+            | IrInstruction::FGe { dest, .. }
+            | IrInstruction::CEq { dest, .. }
+            | IrInstruction::CLt { dest, .. }
+            | IrInstruction::CGt { dest, .. }
+            | IrInstruction::CLe { dest, .. }
+            | IrInstruction::CGe { dest, .. }
             | IrInstruction::Or { dest, .. }
             | IrInstruction::And { dest, .. }
             | IrInstruction::Not { dest, .. }
+            | IrInstruction::Float2Bits { dest, .. }
+            | IrInstruction::Bits2Float { dest, .. }
+            | IrInstruction::Char2Int { dest, .. }
+            | IrInstruction::Int2Char { dest, .. }
             | IrInstruction::Const { dest, .. }
+            | IrInstruction::Alloc { dest, .. }
+            | IrInstruction::Load { dest, .. }
+            | IrInstruction::PtrAdd { dest, .. }
             // TODO: Maybe we should remove the assign?
             // Find something else to use
             | IrInstruction::Assign { lhs: dest, .. }
             | IrInstruction::Phi { dest, .. } => std::slice::from_ref(dest),
 
-            IrInstruction::Call { dest, .. } => {
+            IrInstruction::Call { dest, .. } | IrInstruction::Opaque { dest, .. } => {
                 if let Some(d) = dest {
                     std::slice::from_ref(d)
                 } else {
@@ -227,7 +950,7 @@ impl IrInstruction {
     }
 
     // describes what name does this instruction *reads*
-    pub fn uses(&self) -> Vec<String> {
+    pub fn uses(&self) -> Vec<Symbol> {
         match self {
             IrInstruction::Add { lhs, rhs, .. }
             | IrInstruction::Sub { lhs, rhs, .. }
@@ -238,30 +961,228 @@ impl IrInstruction {
             | IrInstruction::Gt { lhs, rhs, .. }
             | IrInstruction::Ge { lhs, rhs, .. }
             | IrInstruction::Le { lhs, rhs, .. }
+            | IrInstruction::FAdd { lhs, rhs, .. }
+            | IrInstruction::FSub { lhs, rhs, .. }
+            | IrInstruction::FMul { lhs, rhs, .. }
+            | IrInstruction::FDiv { lhs, rhs, .. }
+            | IrInstruction::FEq { lhs, rhs, .. }
+            | IrInstruction::FLt { lhs, rhs, .. }
+            | IrInstruction::FGt { lhs, rhs, .. }
+            | IrInstruction::FLe { lhs, rhs, .. }
+            | IrInstruction::FGe { lhs, rhs, .. }
+            | IrInstruction::CEq { lhs, rhs, .. }
+            | IrInstruction::CLt { lhs, rhs, .. }
+            | IrInstruction::CGt { lhs, rhs, .. }
+            | IrInstruction::CLe { lhs, rhs, .. }
+            | IrInstruction::CGe { lhs, rhs, .. }
             | IrInstruction::Or { lhs, rhs, .. }
-            | IrInstruction::And { lhs, rhs, .. } => vec![lhs.to_string(), rhs.to_string()],
-
-            IrInstruction::Not { args, .. } => vec![args.to_string()],
-
-            IrInstruction::Br { cond, .. } => vec![cond.to_string()],
-            IrInstruction::Call { args, .. } => args.to_vec(),
+            | IrInstruction::And { lhs, rhs, .. } => vec![*lhs, *rhs],
+
+            IrInstruction::Not { args, .. } => vec![*args],
+            IrInstruction::Shl { src, .. }
+            | IrInstruction::Shr { src, .. }
+            | IrInstruction::Sra { src, .. }
+            | IrInstruction::Float2Bits { src, .. }
+            | IrInstruction::Bits2Float { src, .. }
+            | IrInstruction::Char2Int { src, .. }
+            | IrInstruction::Int2Char { src, .. } => vec![*src],
+
+            IrInstruction::Alloc { size, .. } => vec![*size],
+            IrInstruction::Load { ptr, .. } | IrInstruction::Free { ptr } => vec![*ptr],
+            IrInstruction::Store { ptr, value } => vec![*ptr, *value],
+            IrInstruction::PtrAdd { ptr, offset, .. } => vec![*ptr, *offset],
+
+            IrInstruction::Br { cond, .. } => vec![*cond],
+            IrInstruction::Call { args, .. } | IrInstruction::Opaque { args, .. } => args.to_vec(),
             IrInstruction::Ret { args, .. } => args.to_vec(),
-            IrInstruction::Phi { sources, .. } => sources.iter().flatten().cloned().collect(),
+            IrInstruction::Phi { sources, .. } => sources.iter().filter_map(|s| s.value).collect(),
 
             IrInstruction::Print { values, .. } => values.to_vec(),
+            IrInstruction::Assign { rhs, .. } => vec![*rhs],
             _ => Vec::new(),
         }
     }
+
+    /// The operands of [`IrInstruction::uses`], in the same order, for
+    /// rewriting in place. No `_` arm here or in `defs_mut`, so a new
+    /// variant doesn't build until it's listed
+    pub fn uses_mut(&mut self) -> Vec<&mut Symbol> {
+        match self {
+            IrInstruction::Add { lhs, rhs, .. }
+            | IrInstruction::Sub { lhs, rhs, .. }
+            | IrInstruction::Mul { lhs, rhs, .. }
+            | IrInstruction::Div { lhs, rhs, .. }
+            | IrInstruction::Eq { lhs, rhs, .. }
+            | IrInstruction::Lt { lhs, rhs, .. }
+            | IrInstruction::Gt { lhs, rhs, .. }
+            | IrInstruction::Ge { lhs, rhs, .. }
+            | IrInstruction::Le { lhs, rhs, .. }
+            | IrInstruction::FAdd { lhs, rhs, .. }
+            | IrInstruction::FSub { lhs, rhs, .. }
+            | IrInstruction::FMul { lhs, rhs, .. }
+            | IrInstruction::FDiv { lhs, rhs, .. }
+            | IrInstruction::FEq { lhs, rhs, .. }
+            | IrInstruction::FLt { lhs, rhs, .. }
+            | IrInstruction::FGt { lhs, rhs, .. }
+            | IrInstruction::FLe { lhs, rhs, .. }
+            | IrInstruction::FGe { lhs, rhs, .. }
+            | IrInstruction::CEq { lhs, rhs, .. }
+            | IrInstruction::CLt { lhs, rhs, .. }
+            | IrInstruction::CGt { lhs, rhs, .. }
+            | IrInstruction::CLe { lhs, rhs, .. }
+            | IrInstruction::CGe { lhs, rhs, .. }
+            | IrInstruction::Or { lhs, rhs, .. }
+            | IrInstruction::And { lhs, rhs, .. } => vec![lhs, rhs],
+
+            IrInstruction::Not { args, .. } => vec![args],
+            IrInstruction::Shl { src, .. }
+            | IrInstruction::Shr { src, .. }
+            | IrInstruction::Sra { src, .. }
+            | IrInstruction::Float2Bits { src, .. }
+            | IrInstruction::Bits2Float { src, .. }
+            | IrInstruction::Char2Int { src, .. }
+            | IrInstruction::Int2Char { src, .. } => vec![src],
+
+            IrInstruction::Alloc { size, .. } => vec![size],
+            IrInstruction::Load { ptr, .. } | IrInstruction::Free { ptr } => vec![ptr],
+            IrInstruction::Store { ptr, value } => vec![ptr, value],
+            IrInstruction::PtrAdd { ptr, offset, .. } => vec![ptr, offset],
+
+            IrInstruction::Br { cond, .. } => vec![cond],
+            IrInstruction::Call { args, .. }
+            | IrInstruction::Opaque { args, .. }
+            | IrInstruction::Ret { args }
+            | IrInstruction::Print { values: args } => args.iter_mut().collect(),
+            IrInstruction::Phi { sources, .. } => sources
+                .iter_mut()
+                .filter_map(|s| s.value.as_mut())
+                .collect(),
+            IrInstruction::Assign { rhs, .. } => vec![rhs],
+
+            IrInstruction::Jmp { .. } | IrInstruction::Const { .. } | IrInstruction::Nop => {
+                Vec::new()
+            }
+        }
+    }
+
+    /// What [`IrInstruction::defs`] gives, for renaming in place
+    pub fn defs_mut(&mut self) -> Option<&mut Symbol> {
+        match self {
+            IrInstruction::Add { dest, .. }
+            | IrInstruction::Sub { dest, .. }
+            | IrInstruction::Mul { dest, .. }
+            | IrInstruction::Div { dest, .. }
+            | IrInstruction::Shl { dest, .. }
+            | IrInstruction::Shr { dest, .. }
+            | IrInstruction::Sra { dest, .. }
+            | IrInstruction::Eq { dest, .. }
+            | IrInstruction::Lt { dest, .. }
+            | IrInstruction::Gt { dest, .. }
+            | IrInstruction::Le { dest, .. }
+            | IrInstruction::Ge { dest, .. }
+            | IrInstruction::FAdd { dest, .. }
+            | IrInstruction::FSub { dest, .. }
+            | IrInstruction::FMul { dest, .. }
+            | IrInstruction::FDiv { dest, .. }
+            | IrInstruction::FEq { dest, .. }
+            | IrInstruction::FLt { dest, .. }
+            | IrInstruction::FGt { dest, .. }
+            | IrInstruction::FLe { dest, .. }
+            | IrInstruction::FGe { dest, .. }
+            | IrInstruction::CEq { dest, .. }
+            | IrInstruction::CLt { dest, .. }
+            | IrInstruction::CGt { dest, .. }
+            | IrInstruction::CLe { dest, .. }
+            | IrInstruction::CGe { dest, .. }
+            | IrInstruction::Or { dest, .. }
+            | IrInstruction::And { dest, .. }
+            | IrInstruction::Not { dest, .. }
+            | IrInstruction::Float2Bits { dest, .. }
+            | IrInstruction::Bits2Float { dest, .. }
+            | IrInstruction::Char2Int { dest, .. }
+            | IrInstruction::Int2Char { dest, .. }
+            | IrInstruction::Const { dest, .. }
+            | IrInstruction::Alloc { dest, .. }
+            | IrInstruction::Load { dest, .. }
+            | IrInstruction::PtrAdd { dest, .. }
+            | IrInstruction::Assign { lhs: dest, .. }
+            | IrInstruction::Phi { dest, .. } => Some(dest),
+
+            IrInstruction::Call { dest, .. } | IrInstruction::Opaque { dest, .. } => dest.as_mut(),
+
+            IrInstruction::Br { .. }
+            | IrInstruction::Jmp { .. }
+            | IrInstruction::Ret { .. }
+            | IrInstruction::Store { .. }
+            | IrInstruction::Free { .. }
+            | IrInstruction::Print { .. }
+            | IrInstruction::Nop => None,
+        }
+    }
+
+    /// Read `to` wherever this reads `from`, true if there was any
+    pub fn replace_use(&mut self, from: Symbol, to: Symbol) -> bool {
+        let mut replaced = false;
+        for var in self.uses_mut() {
+            if *var == from {
+                *var = to;
+                replaced = true;
+            }
+        }
+        replaced
+    }
 }
 
-/// For getting the mapping of each variable block(s) where variable might be defined
-pub fn collect_defs(func: &IrFunction) -> HashMap<String, Vec<BlockID>> {
-    let mut defs_map: HashMap<String, Vec<usize>> = HashMap::new();
+/// Postorder of what `entry` reaches in the graph given by `succs`. The
+/// succs are walked highest index first, so in reverse the lower one of
+/// two siblings comes first
+pub(crate) fn postorder_from(entry: BlockID, succs: &[Vec<BlockID>]) -> Vec<BlockID> {
+    let sorted: Vec<Vec<BlockID>> = succs
+        .iter()
+        .map(|s| {
+            let mut s = s.clone();
+            s.sort_unstable_by(|a, b| b.cmp(a));
+            s
+        })
+        .collect();
+    let mut visited = vec![false; succs.len()];
+    let mut order = Vec::with_capacity(succs.len());
+    // (block, next succ to look at), a loop instead of recursion so a long
+    // chain of blocks can't blow the stack
+    let mut stack = vec![(entry, 0)];
+    visited[entry] = true;
+
+    while let Some((b, i)) = stack.last_mut() {
+        match sorted[*b].get(*i) {
+            Some(&succ) => {
+                *i += 1;
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            }
+            None => {
+                order.push(*b);
+                stack.pop();
+            }
+        }
+    }
+
+    order
+}
+
+/// For getting the mapping of each variable block(s) where variable might be defined.
+/// The args count as defined in the entry block, before anything in it
+pub fn collect_defs(func: &IrFunction) -> HashMap<Symbol, Vec<BlockID>> {
+    let mut defs_map: HashMap<Symbol, Vec<usize>> = HashMap::new();
+    for &arg in &func.args {
+        defs_map.entry(arg).or_default().push(0);
+    }
 
     for (block_idx, block) in func.blocks.iter().enumerate() {
         for instr in &block.instrs {
             for var in instr.defs() {
-                defs_map.entry(var.clone()).or_default().push(block_idx);
+                defs_map.entry(*var).or_default().push(block_idx);
             }
         }
     }
@@ -279,51 +1200,97 @@ impl TryFrom<&BrilProgam> for IrModule {
             functions.push(convert_to_cfg(func)?);
         }
 
-        Ok(IrModule { functions })
+        let mut module = IrModule { functions };
+        module.resolve_calls()?;
+        Ok(module)
     }
 }
 
 /// Converting Flat Functions into CFG
 fn convert_to_cfg(func: &BrilFunction) -> Result<IrFunction> {
     let mut ir_func = IrFunction::new(&func.name);
-    split_into_blocks(&mut ir_func, func)?;
+    ir_func.args = func.args.iter().map(|a| a.name).collect();
+    ir_func.arg_types = func.args.iter().map(|a| a.typ.clone()).collect();
+    ir_func.ret_typ = func.ret_typ.clone();
+    for arg in &func.args {
+        ir_func.declare(arg.name, arg.typ.clone());
+    }
+    for instr in &func.instrs {
+        if let BrilInstr::Op { op, .. } = instr
+            && let Some((dest, typ)) = op.dest_type()
+        {
+            // `get` reads the shadow `set` wrote, same type
+            if let Op::Get { .. } = op {
+                ir_func.declare(shadow_name(&dest), typ.clone());
+            }
+            ir_func.declare(dest, typ);
+        }
+    }
+    let phis = split_into_blocks(&mut ir_func, func)?;
+
+    // a void function is allowed to fall off the end, spell that out
+    let last = ir_func.blocks.len() - 1;
+    if func.ret_typ.is_none()
+        && !ir_func.blocks[last]
+            .instrs
+            .last()
+            .is_some_and(IrInstruction::is_terminator)
+    {
+        ir_func.append_instr(last, &IrInstruction::Ret { args: Vec::new() }, None, None);
+    }
 
     wire_block_edges(&mut ir_func)?;
 
+    // phis can name blocks further down, so their sources wait for the edges
+    for phi in phis {
+        resolve_phi(&mut ir_func, phi)?;
+    }
+
     Ok(ir_func)
 }
 
 /// This functions deals with converting the IR into true
 /// Control-Flow Graphs by wiring up the blocks
-fn wire_block_edges(func: &mut IrFunction) -> Result<()> {
+pub(crate) fn wire_block_edges(func: &mut IrFunction) -> Result<()> {
     // Build up the list of Successors & Predecessors fork
     for curr_block_idx in 0..func.blocks.len() {
-        if let Some(terminator) = func.blocks[curr_block_idx].instrs.last() {
-            match terminator {
-                IrInstruction::Br {
-                    then_lbl, else_lbl, ..
-                } => {
-                    let then_idx = func.block_index(then_lbl).unwrap();
-                    let else_idx = func.block_index(else_lbl).unwrap();
-
-                    func.add_edge(curr_block_idx, then_idx);
-                    func.add_edge(curr_block_idx, else_idx);
-                }
+        let block = &func.blocks[curr_block_idx];
+        let last = block.instrs.len().checked_sub(1);
+        let at = locate(
+            &func.name,
+            last.and_then(|i| block.origin(i)),
+            last.and_then(|i| block.pos(i)),
+        );
+        match block.instrs.last() {
+            Some(IrInstruction::Br {
+                then_lbl, else_lbl, ..
+            }) => {
+                let then_idx = func
+                    .block_index(then_lbl)
+                    .ok_or_else(|| anyhow!("{}: br to unknown label .{}", at, then_lbl))?;
+                let else_idx = func
+                    .block_index(else_lbl)
+                    .ok_or_else(|| anyhow!("{}: br to unknown label .{}", at, else_lbl))?;
+
+                func.add_edge(curr_block_idx, then_idx);
+                func.add_edge(curr_block_idx, else_idx);
+            }
 
-                IrInstruction::Jmp { label } => {
-                    let target_idx = func.block_index(label).unwrap();
-                    func.add_edge(curr_block_idx, target_idx);
-                }
+            Some(IrInstruction::Jmp { label }) => {
+                let target_idx = func
+                    .block_index(label)
+                    .ok_or_else(|| anyhow!("{}: jmp to unknown label .{}", at, label))?;
+                func.add_edge(curr_block_idx, target_idx);
+            }
 
-                // TODO: I think I'll need to manage this later on?
-                IrInstruction::Ret { .. } => {}
+            // TODO: I think I'll need to manage this later on?
+            Some(IrInstruction::Ret { .. }) => {}
 
-                // Fall through the next label, if needed so
-                _ => {
-                    // check to see if we're still within the range of the blocks list
-                    if curr_block_idx + 1 < func.blocks.len() - 1 {
-                        func.add_edge(curr_block_idx, curr_block_idx + 1);
-                    }
+            // Fall through the next label, if there is one. An empty block
+            // (two labels in a row) falls through too
+            _ => {
+                if curr_block_idx + 1 < func.blocks.len() {
+                    func.add_edge(curr_block_idx, curr_block_idx + 1);
                 }
             }
         }
@@ -332,146 +1299,509 @@ fn wire_block_edges(func: &mut IrFunction) -> Result<()> {
     Ok(())
 }
 
-fn split_into_blocks(func: &mut IrFunction, bril_func: &BrilFunction) -> Result<()> {
-    // Pointer to current block we'll be indexing in
-    let mut current_idx = func.add_block("entry");
+/// A Bril phi waiting for the CFG edges, sources are in `labels` order
+struct PendingPhi<'a> {
+    block: BlockID,
+    idx: usize,
+    args: &'a [Symbol],
+    labels: &'a [String],
+    id: InstrId,
+    pos: Option<Position>,
+}
+
+/// Line the phi's sources up with its block's preds
+fn resolve_phi(func: &mut IrFunction, phi: PendingPhi) -> Result<()> {
+    let at = locate(&func.name, Some(phi.id), phi.pos);
+    if phi.args.len() != phi.labels.len() {
+        bail!(
+            "{}: phi has {} args but {} labels",
+            at,
+            phi.args.len(),
+            phi.labels.len()
+        );
+    }
+    if let Some(label) = phi.labels.iter().find(|l| func.block_index(l).is_none()) {
+        bail!("{}: phi names unknown label .{}", at, label);
+    }
+
+    let block = &func.blocks[phi.block];
+    let new_sources: Vec<PhiSource> = block
+        .preds
+        .iter()
+        .map(|&p| {
+            let pred = &func.blocks[p].label;
+            // a pred the phi doesn't mention leaves it undefined, like `__undefined`
+            let value = phi
+                .labels
+                .iter()
+                .position(|l| l == pred)
+                .map(|i| phi.args[i])
+                .filter(|arg| *arg != UNDEFINED);
+            PhiSource::new(pred, value)
+        })
+        .collect();
+
+    if let IrInstruction::Phi { sources, .. } = &mut func.blocks[phi.block].instrs[phi.idx] {
+        *sources = new_sources;
+    }
+    Ok(())
+}
+
+/// Where `set`/`get` keep a variable's incoming value
+pub fn shadow_name(var: &str) -> Symbol {
+    format!("__shadow_{}", var).into()
+}
+
+fn split_into_blocks<'a>(
+    func: &mut IrFunction,
+    bril_func: &'a BrilFunction,
+) -> Result<Vec<PendingPhi<'a>>> {
+    let mut phis = Vec::new();
+    let mut guards = 0;
+
+    // Pointer to current block we'll be indexing in, the implicit first
+    // block gets a name none of the function's own labels use
+    let labels: HashSet<&str> = bril_func
+        .instrs
+        .iter()
+        .filter_map(|instr| match instr {
+            BrilInstr::Label { label, .. } => Some(label.as_str()),
+            BrilInstr::Op { .. } => None,
+        })
+        .collect();
+    let entry = (0..)
+        .map(|n| match n {
+            0 => "entry".to_string(),
+            n => format!("entry.{}", n),
+        })
+        .find(|name| !labels.contains(name.as_str()))
+        .unwrap();
+
+    // a function that opens with a label can start right in that label's
+    // block (the loop adds it as block 0), as long as nothing jumps back to
+    // it: the entry mustn't have preds
+    let mut current_idx = match bril_func.instrs.first() {
+        Some(BrilInstr::Label { label, .. }) if !is_jump_target(bril_func, label) => 0,
+        _ => func.add_block(&entry)?,
+    };
 
     // 2) Now walk each Bril instruction in order:
-    let bril_instrs = &bril_func.instrs;
-    for instr in bril_instrs {
+    for (id, instr) in bril_func.instrs_with_ids() {
+        // nothing after a `ret` or a jump runs until the next label, and a
+        // block can only end in one terminator
+        if let BrilInstr::Op { .. } = instr
+            && func.blocks[current_idx]
+                .instrs
+                .last()
+                .is_some_and(IrInstruction::is_terminator)
+        {
+            continue;
+        }
         match instr {
-            BrilInstr::Label { label } => {
+            BrilInstr::Label { label, pos } => {
                 // Whenever we see a Bril label, start a new block with that name:
                 // (subsequent instructions go into this new block)
-                current_idx = func.add_block(&label);
+                current_idx = func
+                    .add_block(label)
+                    .map_err(|e| anyhow!("{}: {}", locate(&bril_func.name, Some(id), *pos), e))?;
             }
 
-            BrilInstr::Op(op) => {
-                // Translate each Bril “op” into an IrInstruction instance.
-                let ir_inst = match op {
-                    Op::Const { dest, value, .. } => IrInstruction::Const {
-                        dest: dest.clone(),
-                        value: value.clone(),
-                    },
-
-                    // == Arithmetic ==
-                    Op::Add { dest, args, .. } => IrInstruction::Add {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    Op::Mul { dest, args, .. } => IrInstruction::Mul {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    Op::Sub { dest, args, .. } => IrInstruction::Sub {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    Op::Div { dest, args, .. } => IrInstruction::Div {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    // == Comparison ==
-                    Op::Eq { dest, args, .. } => IrInstruction::Eq {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    Op::Lt { dest, args, .. } => IrInstruction::Lt {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    Op::Gt { dest, args, .. } => IrInstruction::Gt {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    Op::Ge { dest, args, .. } => IrInstruction::Ge {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    Op::Le { dest, args, .. } => IrInstruction::Le {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    // == Logical ==
-                    Op::Not { dest, args } => IrInstruction::Not {
-                        dest: dest.clone(),
-                        args: args[0].clone(),
-                    },
-
-                    Op::Or { dest, args } => IrInstruction::Or {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    Op::And { dest, args } => IrInstruction::And {
-                        dest: dest.clone(),
-                        lhs: args[0].clone(),
-                        rhs: args[1].clone(),
-                    },
-
-                    // == Control Flow ==
-                    Op::Call {
-                        dest, args, funcs, ..
-                    } => IrInstruction::Call {
-                        target_func: funcs[0].clone(),
-                        args: args.clone(),
-                        dest: Some(dest.as_ref().unwrap().clone()),
-                    },
-
-                    Op::Br { args, labels } => IrInstruction::Br {
-                        cond: args[0].clone(),
-                        then_lbl: labels[0].clone(),
-                        else_lbl: labels[1].clone(),
-                    },
-
-                    Op::Jmp { labels } => IrInstruction::Jmp {
-                        label: labels[0].clone(),
-                    },
-
-                    Op::Ret { args } => IrInstruction::Ret { args: args.clone() },
-
-                    // == Misc ==
-                    Op::Print { args } => IrInstruction::Print {
-                        values: args.to_vec(),
-                    },
-
-                    Op::Id { dest, args, .. } => IrInstruction::Assign {
-                        lhs: dest.clone(),
-                        rhs: args[0].clone(),
-                    },
-
-                    other => {
-                        panic!(
-                            "Unimplemented Bril opcode in split_into_blocks: {:?}",
-                            other
-                        );
-                    }
+            BrilInstr::Op {
+                op: Op::Phi {
+                    dest, args, labels, ..
+                },
+                pos,
+            } => {
+                phis.push(PendingPhi {
+                    block: current_idx,
+                    idx: func.blocks[current_idx].instrs.len(),
+                    args,
+                    labels,
+                    id,
+                    pos: *pos,
+                });
+                let phi = IrInstruction::Phi {
+                    dest: *dest,
+                    sources: Vec::new(),
+                };
+                func.append_instr(current_idx, &phi, *pos, Some(id));
+            }
+
+            // there's no rollback in the backend, so a speculative region
+            // just runs for real and only the guards' branches remain
+            BrilInstr::Op {
+                op: Op::Speculate | Op::Commit,
+                ..
+            } => {}
+
+            // `guard c .recover` ends the block, a false `c` takes the
+            // recovery edge and everything after it continues in a new block
+            BrilInstr::Op {
+                op: Op::Guard { args, labels },
+                pos,
+            } => {
+                let at = || locate(&bril_func.name, Some(id), *pos);
+                let cond = first_operand(args, "guard").map_err(|e| anyhow!("{}: {}", at(), e))?;
+                let recovery =
+                    first_operand(labels, "guard").map_err(|e| anyhow!("{}: {}", at(), e))?;
+
+                guards += 1;
+                let cont = format!("__guard_{}", guards);
+                let br = IrInstruction::Br {
+                    cond,
+                    then_lbl: cont.clone(),
+                    else_lbl: recovery,
                 };
+                func.append_instr(current_idx, &br, *pos, Some(id));
+                current_idx = func.add_block(&cont)?;
+            }
+
+            BrilInstr::Op { op, pos } => {
+                // Translate each Bril “op” into an IrInstruction instance.
+                let ir_inst = lower_op(op)
+                    .map_err(|e| anyhow!("{}: {}", locate(&bril_func.name, Some(id), *pos), e))?;
 
                 // 3) Append the newly created IR instruction into the “current” block
-                func.append_instr(current_idx, &ir_inst);
+                func.append_instr(current_idx, &ir_inst, *pos, Some(id));
             }
         }
     }
 
-    Ok(())
+    Ok(phis)
+}
+
+/// Whether a `jmp`, `br` or `guard` in the function goes to `.label`
+fn is_jump_target(func: &BrilFunction, label: &str) -> bool {
+    func.instrs.iter().any(|instr| {
+        let targets: &[String] = match instr {
+            BrilInstr::Op {
+                op: Op::Br { labels, .. },
+                ..
+            } => labels,
+            BrilInstr::Op {
+                op: Op::Jmp { labels } | Op::Guard { labels, .. },
+                ..
+            } => labels,
+            _ => &[],
+        };
+        targets.iter().any(|l| l == label)
+    })
+}
+
+/// Translate a single Bril op into its IR instruction
+fn lower_op(op: &Op) -> Result<IrInstruction> {
+    let ir_inst = match op {
+        Op::Const { dest, typ, value } => IrInstruction::Const {
+            dest: *dest,
+            value: value.as_type(typ),
+        },
+
+        // == Arithmetic ==
+        Op::Add { dest, args, .. } => IrInstruction::Add {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::Mul { dest, args, .. } => IrInstruction::Mul {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::Sub { dest, args, .. } => IrInstruction::Sub {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::Div { dest, args, .. } => IrInstruction::Div {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        // == Comparison ==
+        Op::Eq { dest, args, .. } => IrInstruction::Eq {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::Lt { dest, args, .. } => IrInstruction::Lt {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::Gt { dest, args, .. } => IrInstruction::Gt {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::Ge { dest, args, .. } => IrInstruction::Ge {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::Le { dest, args, .. } => IrInstruction::Le {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        // == Floating Point ==
+        Op::FAdd { dest, args, .. } => IrInstruction::FAdd {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::FSub { dest, args, .. } => IrInstruction::FSub {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::FMul { dest, args, .. } => IrInstruction::FMul {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::FDiv { dest, args, .. } => IrInstruction::FDiv {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::FEq { dest, args, .. } => IrInstruction::FEq {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::FLt { dest, args, .. } => IrInstruction::FLt {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::FGt { dest, args, .. } => IrInstruction::FGt {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::FLe { dest, args, .. } => IrInstruction::FLe {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::FGe { dest, args, .. } => IrInstruction::FGe {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::Float2Bits { dest, args, .. } => IrInstruction::Float2Bits {
+            dest: *dest,
+            src: first_operand(args, "float2bits")?,
+        },
+
+        Op::Bits2Float { dest, args, .. } => IrInstruction::Bits2Float {
+            dest: *dest,
+            src: first_operand(args, "bits2float")?,
+        },
+
+        Op::Char2Int { dest, args, .. } => IrInstruction::Char2Int {
+            dest: *dest,
+            src: first_operand(args, "char2int")?,
+        },
+
+        Op::Int2Char { dest, args, .. } => IrInstruction::Int2Char {
+            dest: *dest,
+            src: first_operand(args, "int2char")?,
+        },
+
+        // == Character Comparison ==
+        Op::CEq { dest, args, .. } => IrInstruction::CEq {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::CLt { dest, args, .. } => IrInstruction::CLt {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::CGt { dest, args, .. } => IrInstruction::CGt {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::CLe { dest, args, .. } => IrInstruction::CLe {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::CGe { dest, args, .. } => IrInstruction::CGe {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        // == Logical ==
+        Op::Not { dest, args, .. } => IrInstruction::Not {
+            dest: *dest,
+            args: first_operand(args, "not")?,
+        },
+
+        Op::Or { dest, args, .. } => IrInstruction::Or {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        Op::And { dest, args, .. } => IrInstruction::And {
+            dest: *dest,
+            lhs: args[0],
+            rhs: args[1],
+        },
+
+        // == Control Flow ==
+        Op::Call {
+            dest, args, funcs, ..
+        } => IrInstruction::Call {
+            target_func: first_operand(funcs, "call")?,
+            callee: None,
+            args: args.clone(),
+            dest: *dest,
+        },
+
+        Op::Br { args, labels } => IrInstruction::Br {
+            cond: first_operand(args, "br")?,
+            then_lbl: labels[0].clone(),
+            else_lbl: labels[1].clone(),
+        },
+
+        Op::Jmp { labels } => IrInstruction::Jmp {
+            label: first_operand(labels, "jmp")?,
+        },
+
+        Op::Ret { args } => IrInstruction::Ret { args: args.clone() },
+
+        // == Memory ==
+        Op::Alloc { dest, args, .. } => IrInstruction::Alloc {
+            dest: *dest,
+            size: first_operand(args, "alloc")?,
+        },
+
+        Op::Load { dest, args, .. } => IrInstruction::Load {
+            dest: *dest,
+            ptr: first_operand(args, "load")?,
+        },
+
+        Op::Store { args } => IrInstruction::Store {
+            ptr: args[0],
+            value: args[1],
+        },
+
+        Op::Free { args } => IrInstruction::Free {
+            ptr: first_operand(args, "free")?,
+        },
+
+        Op::PtrAdd { dest, args, .. } => IrInstruction::PtrAdd {
+            dest: *dest,
+            ptr: args[0],
+            offset: args[1],
+        },
+
+        // == Misc ==
+        Op::Print { args } => IrInstruction::Print {
+            values: args.to_vec(),
+        },
+        Op::Nop => IrInstruction::Nop,
+
+        Op::Id { dest, args, .. } => IrInstruction::Assign {
+            lhs: *dest,
+            rhs: first_operand(args, "id")?,
+        },
+
+        // == SSA ==
+        // the shadow is an ordinary variable, SSA construction turns the
+        // copies back into phis
+        Op::Set { args } => IrInstruction::Assign {
+            lhs: shadow_name(&args[0]),
+            rhs: args[1],
+        },
+
+        Op::Get { dest, .. } => IrInstruction::Assign {
+            lhs: *dest,
+            rhs: shadow_name(dest),
+        },
+
+        Op::Other {
+            op,
+            dest,
+            args,
+            funcs,
+            labels,
+            typ,
+        } => IrInstruction::Opaque {
+            op: op.clone(),
+            dest: *dest,
+            args: args.clone(),
+            funcs: funcs.clone(),
+            labels: labels.clone(),
+            typ: typ.clone(),
+        },
+
+        // any value will do, pick the zero of the type
+        Op::Undef { dest, typ } => IrInstruction::Const {
+            dest: *dest,
+            value: match typ {
+                Type::Bool => Literal::Bool(false),
+                Type::Float => Literal::Float(0.0),
+                Type::Char => Literal::Char('\0'),
+                _ => Literal::Int(0),
+            },
+        },
+
+        // split_into_blocks deals with these itself, they never get here
+        Op::Phi { .. } | Op::Speculate | Op::Commit | Op::Guard { .. } => {
+            unreachable!("`{}` is lowered while splitting into blocks", op.opcode())
+        }
+    };
+
+    Ok(ir_inst)
+}
+
+/// `@func[id]` like the validator prints it, plus `row:col` when the
+/// instruction carried a position
+fn locate(func: &str, id: Option<InstrId>, pos: Option<Position>) -> String {
+    let mut at = format!("@{}", func);
+    if let Some(id) = id {
+        at += &format!("[{}]", id);
+    }
+    if let Some(pos) = pos {
+        at += &format!(" {}", pos);
+    }
+    at
+}
+
+/// Bril keeps some operand lists as plain arrays (`args`, `labels`, `funcs`),
+/// so a malformed program can hand us an empty one. Error out instead of indexing.
+fn first_operand<T: Clone>(operands: &[T], opcode: &str) -> Result<T> {
+    operands
+        .first()
+        .cloned()
+        .ok_or_else(|| anyhow!("`{}` is missing an operand", opcode))
 }
@@ -0,0 +1,87 @@
+//! Graphviz output of the CFG, `dot -Tsvg cfg.dot -O` draws one picture per
+//! function. Each node is a block with its instructions listed inside, and
+//! the dominator tree can be drawn over it as dashed edges.
+
+use crate::cfg::IrFunction;
+use crate::ssa::SSAFormation;
+use std::fmt::Write;
+
+impl IrFunction {
+    /// The CFG as a Graphviz digraph
+    pub fn to_dot(&self) -> String {
+        self.dot(None)
+    }
+
+    /// Same as [`IrFunction::to_dot`], plus an edge from every block's idom
+    /// to it, dashed, if `ssa` has this function
+    pub fn to_dot_with_dominators(&self, ssa: &SSAFormation) -> String {
+        self.dot(Some(ssa))
+    }
+
+    fn dot(&self, ssa: Option<&SSAFormation>) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph {} {{", quote(&format!("@{}", self.name))).unwrap();
+        writeln!(out, "  node [shape=box, fontname=monospace];").unwrap();
+
+        for block in &self.blocks {
+            // `\l` ends a left-aligned line, so it's added after escaping
+            let mut label = escape(&format!(".{}:", block.label)) + "\\l";
+            for instr in &block.instrs {
+                label += &escape(&format!("  {}", instr));
+                label += "\\l";
+            }
+            writeln!(out, "  {} [label=\"{}\"];", quote(&block.label), label).unwrap();
+        }
+
+        for block in &self.blocks {
+            for &s in &block.succs {
+                let Some(succ) = self.blocks.get(s) else {
+                    continue;
+                };
+                writeln!(out, "  {} -> {};", quote(&block.label), quote(&succ.label)).unwrap();
+            }
+        }
+
+        if let Some(info) = ssa.and_then(|ssa| ssa.for_function(&self.name)) {
+            let mut tree: Vec<_> = info.dom_tree.iter().collect();
+            tree.sort();
+            for (&parent, children) in tree {
+                for &child in children {
+                    let (Some(p), Some(c)) = (self.blocks.get(parent), self.blocks.get(child))
+                    else {
+                        continue;
+                    };
+                    writeln!(
+                        out,
+                        "  {} -> {} [style=dashed, color=blue, constraint=false];",
+                        quote(&p.label),
+                        quote(&c.label)
+                    )
+                    .unwrap();
+                }
+            }
+        }
+
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+/// Make `s` safe inside a double-quoted dot string
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -1,14 +1,32 @@
+pub mod analysis;
 pub mod cfg;
+pub mod dot;
+pub mod out_of_ssa;
 pub mod ssa;
+pub mod stats;
+pub mod text;
+pub mod to_bril;
+pub mod verify;
 pub use cfg::IrBasicBlock;
 pub use cfg::IrFunction;
 pub use cfg::IrInstruction;
 pub use cfg::IrModule;
+pub use cfg::PhiSource;
+pub use out_of_ssa::destruct_ssa;
+pub use ssa::DominatorInfo;
 pub use ssa::SSAFormation;
+pub use ssa::SsaStyle;
+pub use stats::{FunctionStats, ModuleStats};
+pub use verify::{verify, verify_ssa, verify_ssa_with, VerifyError};
 
 /// Help with having more readable code
 pub type BlockID = usize;
 
+/// Index of a function in `IrModule::functions`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FuncId(pub usize);
+
+#[cfg(test)]
 macro_rules! function {
     () => {{
         fn f() {}
@@ -25,6 +43,7 @@ macro_rules! function {
 #[cfg(test)]
 mod tests {
     use crate::cfg::{collect_defs, IrBasicBlock};
+    use bril_frontend::{Literal, Symbol, Type};
 
     use super::*;
 
@@ -42,7 +61,7 @@ mod tests {
     fn diamond_cfg() -> IrFunction {
         let block_labels = ["entry", "A", "B", "C", "D", "Exit"];
 
-        let preds = vec![
+        let preds = [
             Vec::new(), // 0: entry
             vec![0],    // 1: A
             vec![1],    // 2: B
@@ -56,10 +75,18 @@ mod tests {
             blocks.push(IrBasicBlock {
                 label: label.to_string(),
                 instrs: Vec::new(),
+                positions: Vec::new(),
+                origins: Vec::new(),
                 preds: preds[i].clone(),
                 succs: Vec::new(),
             });
         }
+        // the other direction of the same edges
+        for (i, ps) in preds.iter().enumerate() {
+            for &p in ps {
+                blocks[p].succs.push(i);
+            }
+        }
 
         let mut label_to_idx = std::collections::HashMap::new();
         for (i, &label) in block_labels.iter().enumerate() {
@@ -69,6 +96,9 @@ mod tests {
         IrFunction {
             name: "diamond".to_string(),
             args: Vec::new(),
+            arg_types: Vec::new(),
+            ret_typ: None,
+            types: std::collections::HashMap::new(),
             blocks,
             label_to_idx,
         }
@@ -76,10 +106,30 @@ mod tests {
 
     #[test]
     fn test_idom_df_and_domtree_on_diamond() {
-        let func = diamond_cfg();
+        // the same CFG as `diamond_cfg`, from its text form
+        let func = IrFunction::parse(
+            "@diamond(c: bool) {
+.entry:
+  jmp .A
+.A:
+  br c .B .C
+.B:
+  jmp .D
+.C:
+  jmp .D
+.D:
+  jmp .Exit
+.Exit:
+  ret
+}",
+        )
+        .unwrap();
+        assert_eq!(func.blocks[4].preds, diamond_cfg().blocks[4].preds);
 
         let mut temp_funcs = vec![func];
-        let mut ssa = SSAFormation::new(&mut temp_funcs).unwrap();
+        let ssa = SSAFormation::new(&mut temp_funcs).unwrap();
+        assert!(ssa.for_function("nope").is_none());
+        let mut ssa = ssa.functions.into_values().next().unwrap();
 
         // IDOM Compute
         ssa.compute_idom(&temp_funcs[0]).unwrap();
@@ -109,19 +159,624 @@ mod tests {
         assert_eq!(kids, vec![2, 3, 4]);
     }
 
+    #[test]
+    fn test_ssa_styles_prune_dead_phis() {
+        // `x` is read on the left before it's redefined, `t` never leaves
+        // the arm it's in, neither is read after the join. Only `y` is
+        let src = "@main(c: bool) {
+.entry:
+  x = const 0
+  br c .left .right
+.left:
+  print x
+  x = const 1
+  t = const 3
+  y = const 5
+  print t
+  jmp .join
+.right:
+  x = const 2
+  t = const 4
+  y = const 6
+  print t
+  jmp .join
+.join:
+  print y
+  ret
+}";
+        let join_phis = |style: SsaStyle| {
+            let mut func = IrFunction::parse(src).unwrap();
+            SSAFormation::with_style(std::slice::from_mut(&mut func), style).unwrap();
+            crate::verify_ssa(&func).unwrap();
+            let join = func.block_index(&"join".to_string()).unwrap();
+            let mut vars: Vec<&str> = func.blocks[join]
+                .instrs
+                .iter()
+                .filter_map(|i| match i {
+                    IrInstruction::Phi { dest, .. } => dest.as_str().split('$').next(),
+                    _ => None,
+                })
+                .collect();
+            vars.sort();
+            vars
+        };
+
+        assert_eq!(join_phis(SsaStyle::Minimal), ["t", "x", "y"]);
+        assert_eq!(join_phis(SsaStyle::SemiPruned), ["x", "y"]);
+        assert_eq!(join_phis(SsaStyle::Pruned), ["y"]);
+        assert_eq!("semi-pruned".parse(), Ok(SsaStyle::SemiPruned));
+        assert!("pruney".parse::<SsaStyle>().is_err());
+    }
+
+    #[test]
+    fn test_iterated_df() {
+        let info = DominatorInfo::compute(&diamond_cfg()).unwrap();
+        let df_plus = |blocks: &[BlockID]| info.iterated_df(blocks).iter().collect::<Vec<_>>();
+        assert_eq!(df_plus(&[2, 3]), [4]);
+        assert!(df_plus(&[1, 5]).is_empty());
+
+        // a def in the body reaches the header, and the header's own
+        // frontier is the header again
+        let func = IrFunction::parse(
+            "@main(c: bool) {
+.entry:
+  x = const 0
+.head:
+  br c .body .done
+.body:
+  x = const 1
+  jmp .head
+.done:
+  ret
+}",
+        )
+        .unwrap();
+        let info = DominatorInfo::compute(&func).unwrap();
+        let block = |label: &str| func.block_index(&label.to_string()).unwrap();
+        let (head, body) = (block("head"), block("body"));
+        assert_eq!(
+            info.iterated_df(&[0, body]).iter().collect::<Vec<_>>(),
+            [head]
+        );
+    }
+
+    #[test]
+    fn test_post_dominators_on_diamond() {
+        let func = diamond_cfg();
+        let info = DominatorInfo::compute(&func).unwrap();
+        let exit = func.blocks.len();
+
+        // both ways out of A meet again at D
+        assert_eq!(info.post_idom[&1], 4);
+        assert_eq!(info.post_idom[&2], 4);
+        assert_eq!(info.post_idom[&5], exit);
+        assert_eq!(info.post_idom[&exit], exit);
+
+        // whether B or C runs depends on the branch in A
+        assert_eq!(info.post_dom_frontier[&2], vec![1]);
+        assert_eq!(info.post_dom_frontier[&3], vec![1]);
+        assert!(!info.post_dom_frontier.contains_key(&4));
+
+        assert_eq!(info.post_dom_tree[&4], vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_post_dominators_with_an_infinite_loop() {
+        let module = build_text(
+            "@main(cond: bool) {
+  br cond .spin .done;
+.spin:
+  jmp .spin;
+.done:
+  ret;
+}",
+        )
+        .unwrap();
+        let func = &module.functions[0];
+        let info = DominatorInfo::compute(func).unwrap();
+        let exit = func.blocks.len();
+        let spin = func.block_index(&"spin".to_string()).unwrap();
+        assert_eq!(info.post_idom[&spin], exit);
+        assert_eq!(info.post_idom[&0], exit);
+    }
+
+    #[test]
+    fn test_dominates_on_diamond() {
+        let func = diamond_cfg();
+        let info = DominatorInfo::compute(&func).unwrap();
+
+        for b in 0..func.blocks.len() {
+            assert!(info.dominates(0, b));
+            assert!(info.dominates(b, b));
+            assert!(!info.strictly_dominates(b, b));
+        }
+        assert!(info.strictly_dominates(1, 4));
+        assert!(info.dominates(4, 5));
+        assert!(!info.dominates(5, 4));
+        // neither side of the branch dominates the other, or the join
+        assert!(!info.dominates(2, 3));
+        assert!(!info.dominates(3, 2));
+        assert!(!info.dominates(2, 4));
+        assert!(!info.dominates(3, 5));
+    }
+
+    #[test]
+    fn test_remove_middle_block_of_diamond() {
+        let mut func = diamond_cfg();
+        let b = func.remove_block(2);
+        assert_eq!(b.label, "B");
+
+        // entry, A, C, D, Exit: everything after B moved up one
+        assert_eq!(func.block_index(&"C".to_string()), Some(2));
+        assert_eq!(func.block_index(&"B".to_string()), None);
+        assert_eq!(func.blocks[1].succs, [2]);
+        assert_eq!(func.blocks[3].preds, [2]);
+        assert_eq!(func.blocks[3].succs, [4]);
+
+        // with B gone the join is a straight line through C
+        let info = DominatorInfo::compute(&func).unwrap();
+        assert_eq!(info.idom[&2], 1);
+        assert_eq!(info.idom[&3], 2);
+        assert!(info.dominates(2, 4));
+    }
+
+    #[test]
+    fn test_phi_keeps_its_values_when_a_pred_goes() {
+        let mut func = IrFunction::parse(
+            "@main(c: bool, d: bool) {
+.entry:
+  br c .left .other
+.left:
+  x = const 1
+  jmp .join
+.other:
+  br d .mid .right
+.mid:
+  w = const 2
+  jmp .join
+.right:
+  y = const 3
+  jmp .join
+.join:
+  z = phi [x, .left] [w, .mid] [y, .right]
+  print z
+  ret
+}",
+        )
+        .unwrap();
+        let idx = |func: &IrFunction, label: &str| func.block_index(&label.to_string()).unwrap();
+
+        // .other stops going to .mid, which can then go
+        let other = idx(&func, "other");
+        func.blocks[other].instrs[0] = IrInstruction::Jmp {
+            label: "right".to_string(),
+        };
+        func.remove_block(idx(&func, "mid"));
+        // and the join's preds get shuffled for good measure
+        let join = idx(&func, "join");
+        func.blocks[join].preds.reverse();
+        verify_ssa(&func).unwrap();
+
+        let lines = func.to_string();
+        assert!(
+            lines.contains("z = phi [x, .left] [y, .right]"),
+            "{}",
+            lines
+        );
+        let live = analysis::liveness::compute(&func);
+        assert!(live.live_out[idx(&func, "right")].contains(&Symbol::from("y")));
+        assert!(live.live_out[idx(&func, "left")].contains(&Symbol::from("x")));
+
+        // each copy lands on the edge its value came in on
+        destruct_ssa(&mut func);
+        let right = &func.blocks[idx(&func, "right")].instrs;
+        assert_eq!(right[right.len() - 2].to_string(), "z = id y");
+        let left = &func.blocks[idx(&func, "left")].instrs;
+        assert_eq!(left[left.len() - 2].to_string(), "z = id x");
+    }
+
+    #[test]
+    fn test_removal_keeps_the_side_tables_lined_up() {
+        let mut func = build_text(
+            "@main(c: bool) {
+  x: int = const 1;
+  br c .then .join;
+.then:
+  y: int = const 2;
+  jmp .join;
+.join:
+  z: int = phi x y .entry .then;
+  print z;
+}",
+        )
+        .unwrap()
+        .functions
+        .remove(0);
+
+        // send the entry straight to the join and drop .then
+        let then = func.block_index(&"then".to_string()).unwrap();
+        let jmp = IrInstruction::Jmp {
+            label: "join".to_string(),
+        };
+        let last = func.blocks[0].instrs.len() - 1;
+        func.blocks[0].instrs[last] = jmp;
+        func.remove_block(then);
+        verify_ssa(&func).unwrap();
+        assert_eq!(func.blocks[1].instrs[0].to_string(), "z = phi x");
+
+        let origins: Vec<_> = (0..2).map(|i| func.blocks[1].origin(i)).collect();
+        assert_eq!(
+            func.remove_instrs(|i| matches!(i, IrInstruction::Phi { .. })),
+            1
+        );
+        assert_eq!(func.blocks[1].instrs[0].to_string(), "print z");
+        assert_eq!(func.blocks[1].origin(0), origins[1]);
+        assert_eq!(func.blocks[1].positions.len(), func.blocks[1].instrs.len());
+        assert!(func.type_of("z").is_none());
+        assert!(func.type_of("c").is_some());
+    }
+
+    #[test]
+    fn test_dominates_in_a_loop() {
+        let module = build_text(
+            "@main(n: int) {
+  i: int = const 0;
+.head:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  one: int = const 1;
+  i: int = add i one;
+  jmp .head;
+.done:
+  print i;
+}",
+        )
+        .unwrap();
+        let func = &module.functions[0];
+        let idx = |label: &str| func.block_index(&label.to_string()).unwrap();
+        let mut info = DominatorInfo::compute(func).unwrap();
+
+        assert!(info.strictly_dominates(idx("head"), idx("body")));
+        assert!(info.strictly_dominates(idx("head"), idx("done")));
+        // the back edge doesn't make the body dominate its header
+        assert!(!info.dominates(idx("body"), idx("head")));
+        assert!(!info.dominates(idx("body"), idx("done")));
+        assert!(!info.dominates(idx("done"), idx("body")));
+
+        // a rebuilt tree gets numbered again
+        info.idom.insert(idx("done"), idx("body"));
+        info.build_dom_tree().unwrap();
+        assert!(info.dominates(idx("body"), idx("done")));
+    }
+
+    /// One of every instruction, each operand named after its place
+    fn every_instruction() -> Vec<IrInstruction> {
+        let s = |name: &str| Symbol::from(name);
+        let (d, l, r) = (s("d"), s("l"), s("r"));
+        vec![
+            IrInstruction::Add {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Mul {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Sub {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Div {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Eq {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Lt {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Gt {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Ge {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Le {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::FAdd {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::FSub {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::FMul {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::FDiv {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::FEq {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::FLt {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::FGt {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::FLe {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::FGe {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Float2Bits { dest: d, src: l },
+            IrInstruction::Bits2Float { dest: d, src: l },
+            IrInstruction::Opaque {
+                op: "vecadd".to_string(),
+                dest: Some(d),
+                args: vec![l, r],
+                funcs: Vec::new(),
+                labels: Vec::new(),
+                typ: None,
+            },
+            IrInstruction::Char2Int { dest: d, src: l },
+            IrInstruction::Int2Char { dest: d, src: l },
+            IrInstruction::CEq {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::CLt {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::CGt {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::CLe {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::CGe {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Not { dest: d, args: l },
+            IrInstruction::Or {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::And {
+                dest: d,
+                lhs: l,
+                rhs: r,
+            },
+            IrInstruction::Call {
+                target_func: "f".to_string(),
+                callee: None,
+                args: vec![l, r],
+                dest: Some(d),
+            },
+            IrInstruction::Br {
+                cond: l,
+                then_lbl: "a".to_string(),
+                else_lbl: "b".to_string(),
+            },
+            IrInstruction::Jmp {
+                label: "a".to_string(),
+            },
+            IrInstruction::Ret { args: vec![l] },
+            IrInstruction::Phi {
+                dest: d,
+                sources: vec![
+                    PhiSource::new("a", Some(l)),
+                    PhiSource::new("b", None),
+                    PhiSource::new("c", Some(r)),
+                ],
+            },
+            IrInstruction::Const {
+                dest: d,
+                value: Literal::Int(1),
+            },
+            IrInstruction::Alloc { dest: d, size: l },
+            IrInstruction::Load { dest: d, ptr: l },
+            IrInstruction::Store { ptr: l, value: r },
+            IrInstruction::Free { ptr: l },
+            IrInstruction::PtrAdd {
+                dest: d,
+                ptr: l,
+                offset: r,
+            },
+            IrInstruction::Print { values: vec![l, r] },
+            IrInstruction::Assign { lhs: d, rhs: l },
+            IrInstruction::Nop,
+        ]
+    }
+
+    #[test]
+    fn test_every_operand_is_reachable_mutably() {
+        for mut instr in every_instruction() {
+            let uses = instr.uses();
+            let defs = instr.defs().to_vec();
+            let uses_mut: Vec<Symbol> = instr.uses_mut().into_iter().map(|v| *v).collect();
+            assert_eq!(uses_mut, uses, "{:?}", instr);
+            assert_eq!(
+                instr.defs_mut().map(|d| *d),
+                defs.first().copied(),
+                "{:?}",
+                instr
+            );
+
+            // rewritten through the references, seen by the read-only side
+            for var in instr.uses_mut() {
+                *var = Symbol::from(format!("{}.new", var));
+            }
+            if let Some(dest) = instr.defs_mut() {
+                *dest = Symbol::from("d.new");
+            }
+            let renamed: Vec<String> = uses.iter().map(|v| format!("{}.new", v)).collect();
+            assert_eq!(instr.uses(), renamed, "{:?}", instr);
+            assert!(instr.defs().iter().all(|d| *d == "d.new"), "{:?}", instr);
+        }
+    }
+
+    #[test]
+    fn test_replace_use() {
+        let mut add = IrInstruction::Add {
+            dest: "x".into(),
+            lhs: "y".into(),
+            rhs: "y".into(),
+        };
+        assert!(add.replace_use("y".into(), "z".into()));
+        assert_eq!(add.uses(), ["z", "z"]);
+        // the dest isn't a use
+        assert!(!add.replace_use("x".into(), "z".into()));
+        assert_eq!(add.defs(), ["x"]);
+
+        let mut phi = IrInstruction::Phi {
+            dest: "x".into(),
+            sources: vec![
+                PhiSource::new("p", Some("a".into())),
+                PhiSource::new("q", None),
+                PhiSource::new("r", Some("b".into())),
+            ],
+        };
+        assert!(phi.replace_use("b".into(), "c".into()));
+        let IrInstruction::Phi { sources, .. } = &phi else {
+            unreachable!()
+        };
+        let values: Vec<_> = sources.iter().map(|s| s.value).collect();
+        assert_eq!(values, [Some("a".into()), None, Some("c".into())]);
+        // the preds stay where they were
+        assert_eq!(sources[2].pred, "r");
+    }
+
+    #[test]
+    fn test_traversal_orders_on_diamond() {
+        let func = diamond_cfg();
+        // B before C either way, they tie and go by index
+        assert_eq!(func.reverse_postorder(), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(func.postorder(), [5, 4, 3, 2, 1, 0]);
+
+        let ssa = SSAFormation::analyze(std::slice::from_ref(&func)).unwrap();
+        // D hangs off A, after both sides of the branch
+        assert_eq!(ssa.dom_tree_preorder(&func), [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_traversal_orders_skip_what_cant_be_reached() {
+        let module = build_text(
+            "@main(cond: bool) {
+  jmp .b;
+.c:
+  jmp .exit;
+.b:
+  br cond .d .c;
+.d:
+  jmp .b;
+.exit:
+  ret;
+}",
+        )
+        .unwrap();
+        let mut func = module.functions[0].clone();
+        let idx = |label: &str| func.block_index(&label.to_string()).unwrap();
+        let (b, c, d, exit) = (idx("b"), idx("c"), idx("d"), idx("exit"));
+
+        // .c has the lower index, so it's first after the header
+        assert_eq!(func.reverse_postorder(), [0, b, c, exit, d]);
+        let ssa = SSAFormation::analyze(std::slice::from_ref(&func)).unwrap();
+        assert_eq!(ssa.dom_tree_preorder(&func), [0, b, c, exit, d]);
+
+        // a block nothing jumps to is left out
+        func.blocks[0].succs = vec![c];
+        func.blocks[b].preds.retain(|&p| p != 0);
+        func.blocks[c].preds.push(0);
+        assert_eq!(func.reverse_postorder(), [0, c, exit]);
+        assert_eq!(func.postorder(), [exit, c, 0]);
+    }
+
+    #[test]
+    fn test_diamond_to_dot() {
+        let mut func = diamond_cfg();
+        func.blocks[2].instrs.push(IrInstruction::Const {
+            dest: "q".into(),
+            value: Literal::Char('"'),
+        });
+        let dot = func.to_dot();
+
+        let nodes: Vec<&str> = dot.lines().filter(|l| l.contains("[label=")).collect();
+        assert_eq!(nodes.len(), 6);
+        assert!(nodes.iter().any(|l| l.starts_with("  \"Exit\" ")));
+        let edges: Vec<&str> = dot.lines().filter(|l| l.contains(" -> ")).collect();
+        // 0->1, 1->2, 1->3, 2->4, 3->4, 4->5
+        assert_eq!(edges.len(), 6);
+        assert!(edges.contains(&"  \"A\" -> \"C\";"));
+        // the quote in the char is escaped
+        assert!(dot.contains(r#"  q = const '\"'\l"#), "{}", dot);
+
+        let ssa = SSAFormation::analyze(std::slice::from_ref(&func)).unwrap();
+        let overlaid = func.to_dot_with_dominators(&ssa);
+        let dashed = overlaid.lines().filter(|l| l.contains("dashed")).count();
+        assert_eq!(dashed, 5);
+        assert!(overlaid.contains("  \"A\" -> \"D\" [style=dashed"));
+    }
+
     /// Helper function for creating multiple definitions for further testing
     fn create_def_sites(func: &mut IrFunction) -> anyhow::Result<()> {
         // Set of instrs that we'll be using for definitions sites
         // both block B & C are going to be a definition of var X that will then be managed
         // by block D (maybe)
         let def_x_b = IrInstruction::Assign {
-            lhs: "x".to_string(),
-            rhs: "5".to_string(),
+            lhs: "x".into(),
+            rhs: "5".into(),
         };
 
         let def_x_c = IrInstruction::Assign {
-            lhs: "x".to_string(),
-            rhs: "10".to_string(),
+            lhs: "x".into(),
+            rhs: "10".into(),
         };
 
         // index 2 is block B
@@ -148,7 +803,7 @@ mod tests {
         let defs_map = collect_defs(&func);
 
         println!("Test Function: {}", function!());
-        let x_defintion_sites = defs_map.get("x").unwrap();
+        let x_defintion_sites = defs_map.get(&"x".into()).unwrap();
         println!("  DefintionMap: {:?}", defs_map);
         assert_eq!(x_defintion_sites.len(), 2);
         assert!(x_defintion_sites.contains(&2));
@@ -160,19 +815,868 @@ mod tests {
     fn test_simple_phi_testing() {
         let mut func = diamond_cfg();
         create_def_sites(&mut func).unwrap();
-        let defs_map = collect_defs(&func);
+        // defined once, nothing to merge
+        func.blocks[2].instrs.push(IrInstruction::Assign {
+            lhs: "y".into(),
+            rhs: "1".into(),
+        });
         let mut temp_funcs = vec![func];
-        let ssa = SSAFormation::new(&mut temp_funcs).unwrap();
+        let _ssa = SSAFormation::new(&mut temp_funcs).unwrap();
 
-        println!("Test Function: {}", function!());
-        //let x_defintion_sites = defs_map.get("x").unwrap();
+        let phis: Vec<(usize, &IrInstruction)> = temp_funcs[0]
+            .blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(b, block)| block.instrs.iter().map(move |i| (b, i)))
+            .filter(|(_, i)| matches!(i, IrInstruction::Phi { .. }))
+            .collect();
+        assert_eq!(phis.len(), 1, "{:?}", phis);
+        let (block, IrInstruction::Phi { dest, sources }) = phis[0] else {
+            unreachable!()
+        };
+        // at the top of D, one source per pred
+        assert_eq!(block, 4);
+        assert_eq!(temp_funcs[0].blocks[4].instrs[0], *phis[0].1);
+        assert!(dest.starts_with("x$"));
+        assert_eq!(sources.len(), 2);
+    }
 
-        println!("  DefintionMap: {:?}", defs_map);
+    #[test]
+    fn test_phi_in_a_block_that_defines_the_variable() {
+        // the loop header redefines `i`, it still needs a phi for the
+        // value coming around the back edge
+        let mut module = build_text(
+            "@main {
+  i: int = const 0;
+.loop:
+  one: int = const 1;
+  i: int = add i one;
+  c: bool = lt i one;
+  br c .loop .done;
+.done:
+  print i;
+}",
+        )
+        .unwrap();
+        SSAFormation::try_from(&mut module).unwrap();
+        let func = &module.functions[0];
+        assert!(matches!(
+            &func.blocks[1].instrs[0],
+            IrInstruction::Phi { dest, sources } if dest.starts_with("i$") && sources.len() == 2
+        ));
+        assert_ssa(func);
+    }
+
+    #[test]
+    fn test_reassigned_arg_gets_one_phi() {
+        // `x` comes in defined, one side of the branch writes it again
+        let mut module = build_text(
+            "@main(x: int, c: bool) {
+  br c .then .join;
+.then:
+  x: int = const 5;
+.join:
+  print x;
+}",
+        )
+        .unwrap();
+        let defs = collect_defs(&module.functions[0]);
+        assert_eq!(defs[&Symbol::from("x")], [0, 1]);
+
+        SSAFormation::try_from(&mut module).unwrap();
+        let func = &module.functions[0];
+        crate::verify_ssa(func).unwrap();
+        let phis: Vec<String> = func
+            .blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .filter(|i| matches!(i, IrInstruction::Phi { .. }))
+            .map(|i| i.to_string())
+            .collect();
+        // the entry's value is still the arg itself
+        assert_eq!(phis, ["x$2 = phi x x$1"]);
+        assert_eq!(func.args, ["x", "c"]);
+    }
+
+    /// Regression inputs found by the `fuzz/` targets, these all used to panic
+    fn build_module(json: &str) -> anyhow::Result<IrModule> {
+        let program: bril_frontend::Program = serde_json::from_str(json)?;
+        IrModule::try_from(&program)
+    }
+
+    #[test]
+    fn test_fuzz_br_to_unknown_label_is_an_error() {
+        let json = r#"{"functions":[{"name":"main","instrs":[
+            {"op":"const","dest":"c","type":"bool","value":true},
+            {"op":"br","args":["c"],"labels":["nowhere","entry"]}
+        ]}]}"#;
+        let err = build_module(json).unwrap_err();
+        assert_eq!(err.to_string(), "@main[1]: br to unknown label .nowhere");
+    }
+
+    #[test]
+    fn test_fuzz_jmp_without_labels_is_an_error() {
+        let json = r#"{"functions":[{"name":"main","instrs":[{"op":"jmp","labels":[]}]}]}"#;
+        let err = build_module(json).unwrap_err();
+        assert!(err.to_string().starts_with("@main[0]: "), "{}", err);
+    }
+
+    #[test]
+    fn test_fuzz_empty_operand_lists_are_errors() {
+        let not = r#"{"functions":[{"name":"main","instrs":[
+            {"op":"not","dest":"x","args":[]}
+        ]}]}"#;
+        let id = r#"{"functions":[{"name":"main","instrs":[
+            {"op":"id","dest":"x","type":"int","args":[]}
+        ]}]}"#;
+        let call = r#"{"functions":[{"name":"main","instrs":[
+            {"op":"call","dest":"x","type":"int","funcs":[]}
+        ]}]}"#;
+        assert!(build_module(not).is_err());
+        assert!(build_module(id).is_err());
+        assert!(build_module(call).is_err());
+    }
+
+    #[test]
+    fn test_fuzz_call_without_dest() {
+        let json = r#"{"functions":[
+            {"name":"main","instrs":[{"op":"call","type":"int","funcs":["f"],"args":[]}]},
+            {"name":"f","instrs":[]}
+        ]}"#;
+        let module = build_module(json).unwrap();
+        match &module.functions[0].blocks[0].instrs[0] {
+            IrInstruction::Call { dest, .. } => assert!(dest.is_none()),
+            other => panic!("expected a call, got {:?}", other),
+        }
+    }
 
-        for block in &temp_funcs[0].blocks {
-            for instr in block.instrs.clone() {
-                println!("    {:?}", instr);
+    #[test]
+    fn test_fuzz_unknown_opcode_is_kept_opaque() {
+        let json = r#"{"functions":[{"name":"main","instrs":[
+            {"op":"const","dest":"x","type":"int","value":1},
+            {"op":"nop"},
+            {"op":"vecadd","dest":"v","type":"int","args":["x","x"],"labels":["out"]}
+        ]}]}"#;
+        let mut module = build_module(json).unwrap();
+        SSAFormation::try_from(&mut module).unwrap();
+
+        let func = &module.functions[0];
+        // the nop is kept, DCE is what drops it
+        assert_eq!(func.blocks[0].instrs[1], IrInstruction::Nop);
+        let vecadd = &func.blocks[0].instrs[2];
+        assert!(matches!(vecadd, IrInstruction::Opaque { op, .. } if op == "vecadd"));
+        let x = &func.blocks[0].instrs[0].defs()[0];
+        assert_eq!(vecadd.uses(), [*x, *x]);
+        let v = &vecadd.defs()[0];
+        assert!(v.starts_with('v'));
+
+        // and back through the textual form
+        let text = func.to_string();
+        let line = format!("{}: int = opaque vecadd {} {} .out", v, x, x);
+        assert!(text.contains(&line), "{}", text);
+        let reparsed = text::parse_function(&text).unwrap();
+        assert_eq!(&reparsed, func);
+    }
+
+    #[test]
+    fn test_fuzz_unreachable_block_is_an_error_in_ssa() {
+        let json = r#"{"functions":[{"name":"main","instrs":[
+            {"op":"ret","args":[]},
+            {"label":"dead"},
+            {"op":"ret","args":[]}
+        ]}]}"#;
+        let mut module = build_module(json).unwrap();
+        assert!(SSAFormation::try_from(&mut module).is_err());
+    }
+
+    #[test]
+    fn test_unreachable_blocks_are_removed() {
+        // the branch in .A was folded to a jmp, so .C is dead
+        let mut func = IrFunction::parse(
+            "@diamond(c: bool) {
+.entry:
+  jmp .A
+.A:
+  jmp .B
+.B:
+  x1 = const 1
+  jmp .D
+.C:
+  x2 = const 2
+  jmp .D
+.D:
+  x = phi [x1, .B] [x2, .C]
+  print x
+  jmp .Exit
+.Exit:
+  ret
+}",
+        )
+        .unwrap();
+        assert_eq!(func.remove_unreachable_blocks(), 1);
+
+        let labels: Vec<&str> = func.blocks.iter().map(|b| b.label.as_str()).collect();
+        assert_eq!(labels, ["entry", "A", "B", "D", "Exit"]);
+        assert_eq!(func.block_index(&"C".to_string()), None);
+        assert_eq!(func.block_index(&"D".to_string()), Some(3));
+        assert_eq!(func.blocks[3].preds, [2]);
+        assert_eq!(func.blocks[2].succs, [3]);
+        assert_eq!(func.blocks[3].succs, [4]);
+        assert_eq!(func.blocks[3].instrs[0].to_string(), "x = phi x1");
+
+        // nothing left to remove, and SSA works on it now
+        assert_eq!(func.remove_unreachable_blocks(), 0);
+        let mut funcs = vec![func];
+        SSAFormation::new(&mut funcs).unwrap();
+    }
+
+    #[test]
+    fn test_float_consts_reach_the_ir() {
+        let module = build_module(include_str!("../../tests/float.json")).unwrap();
+        let values: Vec<Literal> = module.functions[0].blocks[0]
+            .instrs
+            .iter()
+            .filter_map(|instr| match instr {
+                IrInstruction::Const { value, .. } => Some(value.clone()),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            values,
+            vec![
+                // `1` on a float const is still a float
+                Literal::Float(1.0),
+                Literal::Float(1.0),
+                Literal::Float(2.5),
+                Literal::Float(f64::INFINITY),
+                Literal::Float(f64::NEG_INFINITY),
+                Literal::Float(1e6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_array_sum_cfg() {
+        let module = build_text(
+            "@main {
+  n: int = const 3;
+  arr: ptr<int> = alloc n;
+  i: int = const 0;
+  one: int = const 1;
+.fill:
+  p: ptr<int> = ptradd arr i;
+  store p i;
+  i: int = add i one;
+  more: bool = lt i n;
+  br more .fill .sum;
+.sum:
+  i: int = const 0;
+  total: int = const 0;
+.loop:
+  q: ptr<int> = ptradd arr i;
+  x: int = load q;
+  total: int = add total x;
+  i: int = add i one;
+  again: bool = lt i n;
+  br again .loop .done;
+.done:
+  print total;
+  free arr;
+}",
+        )
+        .unwrap();
+        let mut func = module.functions[0].clone();
+        let idx = |label: &str| func.block_index(&label.to_string()).unwrap();
+        assert_eq!(func.blocks[idx("fill")].succs, [idx("fill"), idx("sum")]);
+        assert_eq!(func.blocks[idx("loop")].preds, [idx("sum"), idx("loop")]);
+
+        // memory isn't a value: only the pointers and what's loaded get defs
+        let defs = collect_defs(&func);
+        assert_eq!(defs[&Symbol::from("arr")], [0]);
+        assert_eq!(defs[&Symbol::from("x")], [idx("loop")]);
+        let store = &func.blocks[idx("fill")].instrs[1];
+        assert!(store.defs().is_empty());
+        assert_eq!(store.uses(), ["p", "i"]);
+
+        let loop_head = idx("loop");
+        SSAFormation::new(std::slice::from_mut(&mut func)).unwrap();
+        crate::verify_ssa(&func).unwrap();
+        // so the stores don't need a phi, only the counter and the sum do
+        let phis: Vec<&str> = func.blocks[loop_head]
+            .instrs
+            .iter()
+            .filter_map(|i| match i {
+                IrInstruction::Phi { dest, .. } => Some(dest.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(phis, ["total$2", "i$5"]);
+        // the pointer is defined once, so it goes through SSA untouched
+        let free = func
+            .blocks
+            .last()
+            .unwrap()
+            .instrs
+            .iter()
+            .find_map(|i| match i {
+                IrInstruction::Free { ptr } => Some(*ptr),
+                _ => None,
+            });
+        assert_eq!(free, Some(Symbol::from("arr")));
+    }
+
+    #[test]
+    fn test_memory_ops_from_bril_benchmarks() {
+        let programs = [
+            include_str!("../../bril-extern/bril/test/parse/mem.json"),
+            include_str!("../../bril-extern/bril/test/print/ptr.json"),
+            include_str!("../../bril-extern/bril/test/print/eight-queens.json"),
+        ];
+        for json in programs {
+            let mut module = build_module(json).unwrap();
+            SSAFormation::try_from(&mut module).unwrap();
+        }
+
+        let module = build_module(programs[0]).unwrap();
+        let instrs = &module.functions[0].blocks[0].instrs;
+        let store = instrs
+            .iter()
+            .find(|i| matches!(i, IrInstruction::Store { .. }))
+            .unwrap();
+        assert!(store.defs().is_empty());
+        assert_eq!(store.uses(), vec!["v0".to_string(), "x1".to_string()]);
+
+        let ptradd = IrInstruction::PtrAdd {
+            dest: "q".into(),
+            ptr: "p".into(),
+            offset: "i".into(),
+        };
+        assert_eq!(ptradd.defs(), ["q".to_string()]);
+        assert_eq!(ptradd.uses(), vec!["p".to_string(), "i".to_string()]);
+    }
+
+    #[test]
+    fn test_positions_survive_into_the_ir() {
+        let json = r#"{"functions": [{"name": "main", "instrs": [
+            {"op": "const", "dest": "x", "type": "int", "value": 1,
+             "pos": {"row": 2, "col": 3}},
+            {"label": "next", "pos": {"row": 3, "col": 1}},
+            {"op": "print", "args": ["x"], "pos": {"row": 4, "col": 3}},
+            {"op": "jmp", "labels": ["nowhere"], "pos": {"row": 5, "col": 3}}
+        ]}]}"#;
+        let program: bril_frontend::Program = serde_json::from_str(json).unwrap();
+        let err = IrModule::try_from(&program).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@main[3] 5:3: jmp to unknown label .nowhere"
+        );
+
+        let fixed = json.replace("nowhere", "next");
+        let module = build_module(&fixed).unwrap();
+        let dump = format!("{:?}", module);
+        assert!(dump.contains("Position { row: 2, col: 3 }"));
+        assert!(dump.contains("Position { row: 4, col: 3 }"));
+        assert_eq!(
+            module.functions[0].blocks[1].pos(1).unwrap().to_string(),
+            "5:3"
+        );
+        // ids count the label too
+        assert_eq!(
+            module.functions[0].blocks[1].origin(1),
+            Some(bril_frontend::InstrId(3))
+        );
+
+        let bad = json.replace(
+            r#""op": "print", "args": ["x"]"#,
+            r#""op": "call", "funcs": []"#,
+        );
+        let err = build_module(&bad).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@main[2] 4:3: `call` is missing an operand"
+        );
+    }
+
+    fn build_text(src: &str) -> anyhow::Result<IrModule> {
+        let program = bril_frontend::text::parse_program_with_positions(src)?;
+        IrModule::try_from(&program)
+    }
+
+    /// Every name is defined at most once and every phi has a source per pred
+    fn assert_ssa(func: &IrFunction) {
+        let mut seen = std::collections::HashSet::new();
+        for block in &func.blocks {
+            for instr in &block.instrs {
+                for d in instr.defs() {
+                    assert!(seen.insert(*d), "{} defined twice", d);
+                }
+                if let IrInstruction::Phi { sources, .. } = instr {
+                    assert_eq!(sources.len(), block.preds.len());
+                }
             }
         }
     }
+
+    #[test]
+    fn test_input_phis_build_a_cfg() {
+        let diamond = "@main(cond: bool) {
+  a: int = const 1;
+  br cond .left .right;
+.left:
+  b: int = const 2;
+  jmp .join;
+.right:
+  c: int = const 3;
+  jmp .join;
+.join:
+  x: int = phi c b .right .left;
+  print x;
+}";
+        let module = build_text(diamond).unwrap();
+        let func = &module.functions[0];
+        let join = &func.blocks[func.block_index(&"join".to_string()).unwrap()];
+        assert_eq!(join.preds, vec![1, 2]);
+        assert_eq!(
+            join.instrs[0],
+            IrInstruction::Phi {
+                dest: "x".into(),
+                sources: vec![
+                    PhiSource::new("left", Some("b".into())),
+                    PhiSource::new("right", Some("c".into())),
+                ],
+            }
+        );
+
+        // `.body` comes after the phi that names it
+        let looped = "@main {
+  zero: int = const 0;
+  jmp .pre;
+.pre:
+  one: int = const 1;
+  jmp .head;
+.head:
+  i: int = phi next zero .body .pre;
+  cond: bool = lt i one;
+  br cond .body .done;
+.body:
+  next: int = add i one;
+  jmp .head;
+.done:
+  print i;
+}";
+        let mut module = build_text(looped).unwrap();
+        let head = &module.functions[0].blocks[2];
+        assert_eq!(
+            head.instrs[0],
+            IrInstruction::Phi {
+                dest: "i".into(),
+                sources: vec![
+                    PhiSource::new("pre", Some("zero".into())),
+                    PhiSource::new("body", Some("next".into())),
+                ],
+            }
+        );
+
+        SSAFormation::try_from(&mut module).unwrap();
+        assert_ssa(&module.functions[0]);
+
+        let err = build_text(&looped.replace(".body .pre;", ".nowhere .pre;")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@main[6] 8:3: phi names unknown label .nowhere"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let err = build_text(
+            "@main {
+  x: int = const 1;
+.again:
+  print x;
+.again:
+  jmp .again;
+}",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@main[3] 5:1: label .again is defined more than once"
+        );
+
+        // a label called `entry` pushes the implicit block's name aside
+        let module = build_text(
+            "@main {
+  x: int = const 1;
+.entry:
+  print x;
+  jmp .entry;
+}",
+        )
+        .unwrap();
+        let func = &module.functions[0];
+        let labels: Vec<&str> = func.blocks.iter().map(|b| b.label.as_str()).collect();
+        assert_eq!(labels, ["entry.1", "entry"]);
+        assert_eq!(func.block_index(&"entry".to_string()), Some(1));
+    }
+
+    #[test]
+    fn test_void_functions_get_an_implicit_ret() {
+        let module = build_text(
+            "@main {
+  x: int = const 1;
+  jmp .end;
+.end:
+  print x;
+}
+@id(n: int): int {
+  ret n;
+}",
+        )
+        .unwrap();
+
+        let end = module.functions[0].blocks.last().unwrap();
+        assert_eq!(
+            end.instrs.last(),
+            Some(&IrInstruction::Ret { args: vec![] })
+        );
+        // one that already ends in a terminator is left alone
+        let id = &module.functions[1].blocks[0];
+        assert_eq!(id.instrs.len(), 1);
+    }
+
+    #[test]
+    fn test_void_helper_that_only_prints() {
+        let module = build_text(
+            "@show(n: int) {
+  print n;
+}
+@answer: int {
+  x: int = const 42;
+  print x;
+}",
+        )
+        .unwrap();
+
+        let show = &module.functions[0];
+        assert_eq!(show.blocks.len(), 1);
+        assert_eq!(show.to_string().lines().nth(3), Some("  ret"));
+        crate::verify(show).unwrap();
+
+        // falling off the end is only fine without a value to return, the
+        // CFG is left as it is and the verifier complains
+        let answer = &module.functions[1];
+        assert_eq!(answer.blocks[0].instrs.len(), 2);
+        let errors = crate::verify(answer).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].kind,
+            crate::verify::VerifyErrorKind::MissingTerminator
+        );
+    }
+
+    #[test]
+    fn test_get_set_undef() {
+        let src = "@main(cond: bool) {
+  br cond .left .right;
+.left:
+  b: int = const 2;
+  set x b;
+  jmp .join;
+.right:
+  u: int = undef;
+  set x u;
+  jmp .join;
+.join:
+  x: int = get;
+  print x;
+}";
+        let mut module = build_text(src).unwrap();
+        let func = &module.functions[0];
+        assert_eq!(
+            func.blocks[1].instrs[1],
+            IrInstruction::Assign {
+                lhs: "__shadow_x".into(),
+                rhs: "b".into(),
+            }
+        );
+        assert_eq!(
+            func.blocks[2].instrs[0],
+            IrInstruction::Const {
+                dest: "u".into(),
+                value: Literal::Int(0),
+            }
+        );
+
+        SSAFormation::try_from(&mut module).unwrap();
+        let func = &module.functions[0];
+        assert_ssa(func);
+        assert!(func.blocks[3].instrs.iter().any(|instr| matches!(
+            instr,
+            IrInstruction::Phi { dest, sources }
+                if dest.starts_with("__shadow_x") && sources.iter().all(|s| s.value.is_some())
+        )));
+    }
+
+    #[test]
+    fn test_guard_wires_the_recovery_edge() {
+        let trace = "@main(x: int) {
+  speculate;
+  zero: int = const 0;
+  ok: bool = gt x zero;
+  guard ok .recover;
+  print x;
+  commit;
+  ret;
+.recover:
+  print zero;
+}";
+        let mut module = build_text(trace).unwrap();
+        let func = &module.functions[0];
+        let labels: Vec<&str> = func.blocks.iter().map(|b| b.label.as_str()).collect();
+        assert_eq!(labels, ["entry", "__guard_1", "recover"]);
+        assert_eq!(func.blocks[0].succs, vec![1, 2]);
+        assert_eq!(
+            func.blocks[0].instrs.last(),
+            Some(&IrInstruction::Br {
+                cond: "ok".into(),
+                then_lbl: "__guard_1".to_string(),
+                else_lbl: "recover".to_string(),
+            })
+        );
+        assert_eq!(
+            func.blocks[1].instrs,
+            vec![
+                IrInstruction::Print {
+                    values: vec!["x".into()],
+                },
+                IrInstruction::Ret { args: Vec::new() },
+            ]
+        );
+        SSAFormation::try_from(&mut module).unwrap();
+
+        let err = build_text(&trace.replace("guard ok .recover", "guard ok")).unwrap_err();
+        assert_eq!(err.to_string(), "5:3: missing field `labels`");
+    }
+
+    #[test]
+    fn test_float_bit_casts_are_live() {
+        let module = build_module(include_str!("../../tests/float.json")).unwrap();
+        let instrs = &module.functions[0].blocks[0].instrs;
+        let cast = instrs
+            .iter()
+            .find(|i| matches!(i, IrInstruction::Float2Bits { .. }))
+            .unwrap();
+        assert_eq!(cast.defs(), ["bits".to_string()]);
+        assert_eq!(cast.uses(), vec!["half".to_string()]);
+
+        let back = instrs
+            .iter()
+            .find(|i| matches!(i, IrInstruction::Bits2Float { .. }))
+            .unwrap();
+        assert_eq!(back.defs(), ["back".to_string()]);
+        assert_eq!(back.uses(), vec!["bits".to_string()]);
+    }
+
+    #[test]
+    fn test_float_benchmarks() {
+        let programs = [
+            include_str!("../../bril-extern/bril/benchmarks/float/euler.bril"),
+            include_str!("../../bril-extern/bril/benchmarks/float/leibniz.bril"),
+            include_str!("../../bril-extern/bril/benchmarks/float/mandelbrot.bril"),
+            include_str!("../../bril-extern/bril/benchmarks/float/newton.bril"),
+            include_str!("../../bril-extern/bril/benchmarks/float/norm.bril"),
+            include_str!("../../bril-extern/bril/benchmarks/float/sqrt.bril"),
+        ];
+        for src in programs {
+            let mut module = build_text(src).unwrap();
+            SSAFormation::try_from(&mut module).unwrap();
+        }
+
+        let module = build_text(programs[4]).unwrap();
+        let fmul = module.functions[0]
+            .blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .find(|i| matches!(i, IrInstruction::FMul { .. }))
+            .unwrap();
+        assert_eq!(fmul.defs().len(), 1);
+        assert_eq!(fmul.uses().len(), 2);
+    }
+
+    #[test]
+    fn test_calls_resolve_to_their_callee() {
+        let mut module = build_text(
+            "@main { one: int = const 1; x: int = call @inc one; print x; }
+             @inc(n: int): int { one: int = const 1; r: int = add n one; ret r; }",
+        )
+        .unwrap();
+        let call = module.functions[0]
+            .blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .find_map(|i| match i {
+                IrInstruction::Call { callee, .. } => Some(*callee),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(call, Some(FuncId(1)));
+        assert_eq!(module.function(FuncId(1)).name, "inc");
+
+        // a typo'd callee is caught with a suggestion
+        module.functions[1].name = "incr".to_string();
+        let err = module.resolve_calls().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "call to unknown function @inc in @main, did you mean @incr?"
+        );
+    }
+
+    #[test]
+    fn test_second_to_last_block_falls_through() {
+        let mut module = build_text(
+            "@main {
+  x: int = const 1;
+  jmp .body;
+.body:
+  y: int = add x x;
+.exit:
+  print y;
+}",
+        )
+        .unwrap();
+        let blocks = &module.functions[0].blocks;
+        assert_eq!(blocks[1].succs, vec![2]);
+        assert_eq!(blocks[2].preds, vec![1]);
+        SSAFormation::try_from(&mut module).unwrap();
+    }
+
+    #[test]
+    fn test_empty_block_falls_through() {
+        let module = build_text(
+            "@main {
+  x: int = const 1;
+.empty:
+.next:
+  print x;
+}",
+        )
+        .unwrap();
+        let blocks = &module.functions[0].blocks;
+        assert!(blocks[1].instrs.is_empty());
+        assert_eq!(blocks[0].succs, vec![1]);
+        assert_eq!(blocks[1].succs, vec![2]);
+        assert_eq!(blocks[2].preds, vec![1]);
+    }
+
+    #[test]
+    fn test_leading_label() {
+        // nothing jumps to .start, so it's the entry itself
+        let module = build_text(
+            "@main {
+.start:
+  x: int = const 1;
+  print x;
+}",
+        )
+        .unwrap();
+        let func = &module.functions[0];
+        assert_eq!(func.blocks.len(), 1);
+        assert_eq!(func.blocks[0].label, "start");
+        assert_eq!(func.block_index(&"start".to_string()), Some(0));
+
+        // a back-edge to it keeps a separate entry in front
+        let mut module = build_text(
+            "@main {
+.start:
+  i: int = const 0;
+  one: int = const 1;
+  i: int = add i one;
+  c: bool = lt i one;
+  br c .start .done;
+.done:
+  print i;
+}",
+        )
+        .unwrap();
+        let func = &module.functions[0];
+        let labels: Vec<&str> = func.blocks.iter().map(|b| b.label.as_str()).collect();
+        assert_eq!(labels, ["entry", "start", "done"]);
+        assert!(func.blocks[0].instrs.is_empty());
+        assert_eq!(func.blocks[0].succs, vec![1]);
+        assert_eq!(func.blocks[1].preds, vec![0, 1]);
+        assert_eq!(func.block_index(&"start".to_string()), Some(1));
+
+        SSAFormation::try_from(&mut module).unwrap();
+        assert_ssa(&module.functions[0]);
+    }
+
+    #[test]
+    fn test_every_variable_has_a_type() {
+        let mut module = build_module(include_str!("../../tests/palindrome.json")).unwrap();
+        for func in &module.functions {
+            let instrs = func.blocks.iter().flat_map(|b| &b.instrs);
+            for var in instrs.flat_map(|i| [i.defs().to_vec(), i.uses()].concat()) {
+                assert!(func.type_of(&var).is_some(), "@{}: `{}`", func.name, var);
+            }
+        }
+        assert_eq!(module.functions[2].type_of("in"), Some(&Type::Int));
+
+        // the renamed ones inherit theirs. Only defs, a phi can still name
+        // the original on a path where it was never defined
+        SSAFormation::try_from(&mut module).unwrap();
+        for func in &module.functions {
+            for var in func
+                .blocks
+                .iter()
+                .flat_map(|b| &b.instrs)
+                .flat_map(|i| i.defs())
+            {
+                assert!(func.type_of(var).is_some(), "@{}: `{}`", func.name, var);
+            }
+        }
+        let func = &module.functions[2];
+        assert!(func.types.keys().any(|var| var.contains('$')));
+    }
+
+    #[test]
+    fn test_each_function_keeps_its_dominators() {
+        let mut module = build_module(include_str!("../../tests/palindrome.json")).unwrap();
+        let ssa = SSAFormation::try_from(&mut module).unwrap();
+
+        assert_eq!(ssa.functions.len(), module.functions.len());
+        for func in &module.functions {
+            let info = ssa.for_function(&func.name).unwrap();
+            assert_eq!(info.idom.len(), func.blocks.len(), "@{}", func.name);
+        }
+    }
+
+    #[test]
+    fn test_idom_when_block_order_isnt_rpo() {
+        // .c comes before .b in the text but .b dominates it
+        let module = build_text(
+            "@main(cond: bool) {
+  jmp .b;
+.c:
+  jmp .exit;
+.b:
+  br cond .c .d;
+.d:
+  jmp .exit;
+.exit:
+  ret;
+}",
+        )
+        .unwrap();
+        let func = &module.functions[0];
+        let idx = |label: &str| func.block_index(&label.to_string()).unwrap();
+        assert!(idx("c") < idx("b"));
+
+        let info = DominatorInfo::compute(func).unwrap();
+        assert_eq!(info.idom[&idx("b")], 0);
+        assert_eq!(info.idom[&idx("c")], idx("b"));
+        assert_eq!(info.idom[&idx("d")], idx("b"));
+        assert_eq!(info.idom[&idx("exit")], idx("b"));
+    }
 }
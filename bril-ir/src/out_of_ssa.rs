@@ -0,0 +1,333 @@
+//! Out of SSA: every phi becomes copies at the end of its preds, so
+//! instruction selection only ever sees plain moves.
+//!
+//! The copies of one edge happen in parallel, `a, b = b, a` style, so they're
+//! put in an order that doesn't clobber a value another copy still reads, with
+//! a temporary when they go around in a cycle. Critical edges get a block of
+//! their own first, or the copies would also run on the pred's other way out,
+//! unless nothing that way reads what they write.
+
+use crate::analysis::liveness::{self, Liveness};
+use crate::cfg::{IrFunction, IrInstruction, PhiSource};
+use crate::BlockID;
+use bril_frontend::Symbol;
+
+/// Replace every phi in `func` with copies in its preds
+pub fn destruct_ssa(func: &mut IrFunction) {
+    split_critical_edges(func);
+
+    let mut temps = 0;
+    for b in 0..func.blocks.len() {
+        let phis: Vec<(Symbol, Vec<PhiSource>)> = func.blocks[b]
+            .instrs
+            .iter()
+            .filter_map(|instr| match instr {
+                IrInstruction::Phi { dest, sources } => Some((*dest, sources.clone())),
+                _ => None,
+            })
+            .collect();
+        if phis.is_empty() {
+            continue;
+        }
+
+        let preds = func.blocks[b].preds.clone();
+        for (j, &p) in preds.iter().enumerate() {
+            // both edges of a `br c .b .b` take the same copies, once is enough
+            if preds[..j].contains(&p) {
+                continue;
+            }
+            // a None source is undefined coming from that pred, nothing to copy
+            let label = &func.blocks[p].label;
+            let copies: Vec<(Symbol, Symbol)> = phis
+                .iter()
+                .filter_map(|(dest, sources)| {
+                    let src = sources.iter().find(|s| s.pred == *label)?;
+                    Some((*dest, src.value?))
+                })
+                .collect();
+
+            for (lhs, rhs) in sequentialize(copies, func, &mut temps) {
+                let pred = &mut func.blocks[p];
+                // the copy has to run before the pred leaves
+                let at = match pred.instrs.last() {
+                    Some(last) if last.is_terminator() => pred.instrs.len() - 1,
+                    _ => pred.instrs.len(),
+                };
+                pred.insert_instr(at, IrInstruction::Assign { lhs, rhs }, None, None);
+            }
+        }
+
+        let block = &mut func.blocks[b];
+        let kept: Vec<usize> = (0..block.instrs.len())
+            .filter(|&i| !matches!(block.instrs[i], IrInstruction::Phi { .. }))
+            .collect();
+        block.positions = kept.iter().map(|&i| block.pos(i)).collect();
+        block.origins = kept.iter().map(|&i| block.origin(i)).collect();
+        block.instrs = kept.iter().map(|&i| block.instrs[i].clone()).collect();
+    }
+}
+
+/// Order a set of parallel copies `(dest, src)` so each one runs before
+/// anything overwrites its `src`. A cycle is broken by saving one of the
+/// dests in a fresh temporary first.
+fn sequentialize(
+    mut pending: Vec<(Symbol, Symbol)>,
+    func: &mut IrFunction,
+    temps: &mut usize,
+) -> Vec<(Symbol, Symbol)> {
+    pending.retain(|(dest, src)| dest != src);
+    let mut out = Vec::with_capacity(pending.len());
+
+    while !pending.is_empty() {
+        // a copy whose dest nobody still needs can go now
+        let ready = pending
+            .iter()
+            .position(|(dest, _)| !pending.iter().any(|(_, src)| src == dest));
+        if let Some(i) = ready {
+            out.push(pending.remove(i));
+            continue;
+        }
+
+        // everything left is a cycle, move one value out of the way
+        let saved = pending[0].0;
+        *temps += 1;
+        let temp = Symbol::from(format!("__phi_tmp{}", temps));
+        if let Some(typ) = func.types.get(&saved).cloned() {
+            func.types.insert(temp, typ);
+        }
+        out.push((temp, saved));
+        for (_, src) in pending.iter_mut() {
+            if *src == saved {
+                *src = temp;
+            }
+        }
+    }
+
+    out
+}
+
+/// Give every edge from a block with several succs into a block with
+/// several preds a block of its own, holding just a `jmp`. Only if the
+/// phis have something to copy along it
+fn split_critical_edges(func: &mut IrFunction) {
+    let live = liveness::compute(func);
+    for b in 0..func.blocks.len() {
+        let has_phis = func.blocks[b]
+            .instrs
+            .iter()
+            .any(|i| matches!(i, IrInstruction::Phi { .. }));
+        if !has_phis || func.blocks[b].preds.len() < 2 {
+            continue;
+        }
+
+        for j in 0..func.blocks[b].preds.len() {
+            let p = func.blocks[b].preds[j];
+            if func.blocks[p].succs.len() < 2 {
+                continue;
+            }
+            // no copy to make on this edge, no block needed for it
+            let pred_label = func.blocks[p].label.clone();
+            let copies = func.blocks[b].instrs.iter().any(|i| match i {
+                IrInstruction::Phi { dest, sources } => sources
+                    .iter()
+                    .any(|s| s.pred == pred_label && s.value.is_some_and(|v| v != *dest)),
+                _ => false,
+            });
+            if !copies || copies_fit_in_pred(func, &live, p, b) {
+                continue;
+            }
+            let target = func.blocks[b].label.clone();
+            let label = func.fresh_label(&format!("{}.{}", func.blocks[p].label, target));
+            let mid: BlockID = func
+                .add_block(&label)
+                .expect("fresh_label picks a label that isn't taken");
+            func.append_instr(
+                mid,
+                &IrInstruction::Jmp {
+                    label: target.clone(),
+                },
+                None,
+                None,
+            );
+
+            // only this edge's source moves, the pred may have another
+            for instr in &mut func.blocks[b].instrs {
+                if let IrInstruction::Phi { sources, .. } = instr
+                    && let Some(src) = sources.iter_mut().find(|s| s.pred == pred_label)
+                {
+                    src.pred = label.clone();
+                }
+            }
+
+            // rewire this one edge, a `br c .b .b` has two of them
+            let i = func.blocks[p].succs.iter().position(|&s| s == b).unwrap();
+            func.blocks[p].succs[i] = mid;
+            if let Some(IrInstruction::Br {
+                then_lbl, else_lbl, ..
+            }) = func.blocks[p].instrs.last_mut()
+            {
+                if i == 0 {
+                    *then_lbl = label;
+                } else {
+                    *else_lbl = label;
+                }
+            }
+            func.blocks[b].preds[j] = mid;
+            func.blocks[mid].preds.push(p);
+            func.blocks[mid].succs.push(b);
+        }
+    }
+}
+
+/// Whether the copies into `b` can go at the end of `p` after all: when
+/// nothing on `p`'s other ways out reads what they write, and neither does
+/// the `br`. Like the back edge of a loop tested at the bottom, whose
+/// header's phis are only read inside the loop
+fn copies_fit_in_pred(func: &IrFunction, live: &Liveness, p: BlockID, b: BlockID) -> bool {
+    let dests: Vec<Symbol> = func.blocks[b]
+        .instrs
+        .iter()
+        .filter_map(|instr| match instr {
+            IrInstruction::Phi { dest, .. } => Some(*dest),
+            _ => None,
+        })
+        .collect();
+    let pred = &func.blocks[p];
+    if pred
+        .instrs
+        .last()
+        .is_some_and(|last| last.uses().iter().any(|v| dests.contains(v)))
+    {
+        return false;
+    }
+    pred.succs.iter().filter(|&&s| s != b).all(|&s| {
+        // an edge split already, what's read is read past the new block
+        let (from, s) = match live.live_in.get(s) {
+            Some(_) => (p, s),
+            None => (s, func.blocks[s].succs[0]),
+        };
+        let from_label = &func.blocks[from].label;
+        let phis_read = func.blocks[s].instrs.iter().any(|instr| match instr {
+            IrInstruction::Phi { sources, .. } => sources.iter().any(|src| {
+                src.pred == *from_label && src.value.is_some_and(|v| dests.contains(&v))
+            }),
+            _ => false,
+        });
+        !phis_read && !dests.iter().any(|d| live.live_in[s].contains(d))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::parse_function;
+
+    #[test]
+    fn test_swapping_phis_in_a_loop() {
+        let mut func = parse_function(
+            "
+@main(n: int) {
+.entry:
+  a0 = const 1
+  b0 = const 2
+  jmp .loop
+.loop:
+  a = phi [a0, .entry] [b, .loop]
+  b = phi [b0, .entry] [a, .loop]
+  c = lt a n
+  br c .loop .exit
+.exit:
+  print a b
+  ret
+}",
+        )
+        .unwrap();
+        destruct_ssa(&mut func);
+        let instrs = func.blocks.iter().flat_map(|b| &b.instrs);
+        assert!(!instrs
+            .into_iter()
+            .any(|i| matches!(i, IrInstruction::Phi { .. })));
+
+        // the back edge is critical, it gets its own block for the copies
+        let back = func.block_index(&"loop.loop".to_string()).unwrap();
+        let lines: Vec<String> = func.blocks[back]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "__phi_tmp1 = id a",
+                "a = id b",
+                "b = id __phi_tmp1",
+                "jmp .loop"
+            ]
+        );
+        let looped = &func.blocks[func.block_index(&"loop".to_string()).unwrap()];
+        assert_eq!(
+            looped.instrs.last().unwrap().to_string(),
+            "br c .loop.loop .exit"
+        );
+        assert_eq!(looped.succs[0], back);
+
+        let entry: Vec<String> = func.blocks[0]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(entry[2..], ["a = id a0", "b = id b0", "jmp .loop"]);
+    }
+
+    #[test]
+    fn test_back_edge_copies_stay_in_the_latch() {
+        let mut func = parse_function(
+            "
+@main(n: int) {
+.entry:
+  zero = const 0
+  one = const 1
+  jmp .loop
+.loop:
+  i = phi [zero, .entry] [i2, .loop]
+  i2 = add i one
+  c = lt i2 n
+  br c .loop .exit
+.exit:
+  print i2
+  ret
+}",
+        )
+        .unwrap();
+        destruct_ssa(&mut func);
+        // the exit doesn't read `i`, writing it on the way out is harmless
+        assert_eq!(func.blocks.len(), 3);
+        let lines: Vec<String> = func.blocks[1]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "i2 = add i one",
+                "c = lt i2 n",
+                "i = id i2",
+                "br c .loop .exit"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_copies_that_chain_need_no_temporary() {
+        // b reads a before a is overwritten, no cycle
+        let mut func = IrFunction::new("f");
+        let copies = vec![("a".into(), "x".into()), ("b".into(), "a".into())];
+        let ordered = sequentialize(copies, &mut func, &mut 0);
+        let names: Vec<(&str, &str)> = ordered
+            .iter()
+            .map(|(d, s)| (d.as_str(), s.as_str()))
+            .collect();
+        assert_eq!(names, [("b", "a"), ("a", "x")]);
+    }
+}
@@ -1,10 +1,18 @@
+use crate::analysis::bitset::BitSet;
+use crate::analysis::liveness;
 use crate::cfg::collect_defs;
+use crate::cfg::postorder_from;
+use crate::cfg::shadow_name;
 use crate::cfg::IrFunction;
 use crate::cfg::IrModule;
+use crate::cfg::PhiSource;
 use crate::BlockID;
 use crate::IrInstruction;
-use anyhow::Result;
+use anyhow::{bail, Result};
+use bril_frontend::{Symbol, Type};
+use std::cell::OnceCell;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
 
 /// Set up the Dominator Trees and Dominance Frontier
 /// Using the Cytron algo for creating a SSA
@@ -19,9 +27,55 @@ use std::collections::{BTreeMap, HashMap, HashSet};
 ///4.Place ϕ-nodes for each variable at all blocks in the union of DF(definition blocks).
 #[derive(Debug, Default)]
 pub struct SSAFormation {
+    /// What was worked out for each function, keyed by its name
+    pub functions: HashMap<String, DominatorInfo>,
+}
+
+/// Dominator analysis of a single function
+#[derive(Debug, Default, Clone)]
+pub struct DominatorInfo {
     pub idom: HashMap<BlockID, BlockID>,
     pub dom_tree: HashMap<BlockID, Vec<BlockID>>,
     pub dom_frontier: BTreeMap<BlockID, Vec<BlockID>>,
+
+    /// The same three on the reversed CFG. Every way out of the function
+    /// goes through one virtual exit block, numbered `func.blocks.len()`
+    pub post_idom: HashMap<BlockID, BlockID>,
+    pub post_dom_tree: HashMap<BlockID, Vec<BlockID>>,
+    pub post_dom_frontier: BTreeMap<BlockID, Vec<BlockID>>,
+
+    // (pre, post) DFS number of each block in `dom_tree`, worked out on the
+    // first `dominates` query after the tree was (re)built
+    dfs_numbers: OnceCell<Vec<(usize, usize)>>,
+}
+
+/// Which of the DF+ blocks of a variable actually get a phi for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SsaStyle {
+    /// All of them, whether or not anything reads the phi
+    #[default]
+    Minimal,
+    /// Only for the "global names", variables some block reads before
+    /// writing, so a temporary that never leaves its block gets none
+    SemiPruned,
+    /// Only where the variable is live going into the block
+    Pruned,
+}
+
+impl FromStr for SsaStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minimal" => Ok(SsaStyle::Minimal),
+            "semi-pruned" => Ok(SsaStyle::SemiPruned),
+            "pruned" => Ok(SsaStyle::Pruned),
+            other => Err(format!(
+                "unknown SSA style `{}`, expected minimal, semi-pruned or pruned",
+                other
+            )),
+        }
+    }
 }
 
 /// Convert our IrModule into a true SSA form
@@ -36,268 +90,592 @@ impl TryFrom<&mut IrModule> for SSAFormation {
 
 impl SSAFormation {
     pub fn new(funcs: &mut [IrFunction]) -> Result<Self> {
+        Self::with_style(funcs, SsaStyle::Minimal)
+    }
+
+    /// [`SSAFormation::new`], placing phis the way `style` says
+    pub fn with_style(funcs: &mut [IrFunction], style: SsaStyle) -> Result<Self> {
+        Self::with_dominators(funcs, style, DominatorInfo::compute)
+    }
+
+    /// [`SSAFormation::with_style`], getting each function's dominators
+    /// from `dominators` instead of working them out, e.g. from a cache of
+    /// them. SSA construction leaves the CFG as it is, so they're still
+    /// right afterwards
+    pub fn with_dominators(
+        funcs: &mut [IrFunction],
+        style: SsaStyle,
+        mut dominators: impl FnMut(&IrFunction) -> Result<DominatorInfo>,
+    ) -> Result<Self> {
         let mut out = SSAFormation::default();
 
         for func in funcs {
-            out.compute_idom(func)?;
-            out.compute_df(func)?;
-            out.build_dom_tree()?;
+            let info = dominators(func)?;
 
+            split_input_phis(func);
             let def_sites_map = collect_defs(func);
-            out.phi_insert(func, &def_sites_map);
-
-            let mut counter: HashMap<String, BlockID> = HashMap::new();
-            let mut stacks: HashMap<String, Vec<String>> = HashMap::new();
+            match style {
+                SsaStyle::Minimal => info.phi_insert(func, &def_sites_map),
+                SsaStyle::SemiPruned => {
+                    let globals = global_names(func);
+                    info.place_phis(func, &def_sites_map, |var, _| globals.contains(var));
+                }
+                SsaStyle::Pruned => {
+                    let live = liveness::compute(func);
+                    info.place_phis(func, &def_sites_map, |var, b| live.live_in[b].contains(var));
+                }
+            }
 
-            for (var, _def_sites) in def_sites_map {
-                counter.insert(var.clone(), 0);
-                stacks.insert(var.clone(), Vec::new());
+            let mut counter: HashMap<Symbol, BlockID> = HashMap::new();
+            let mut stacks: HashMap<Symbol, Vec<Symbol>> = HashMap::new();
+
+            // only what's defined more than once gets renamed. An arg
+            // starts out as itself, the value the caller passed
+            for (var, def_sites) in def_sites_map {
+                if def_sites.len() > 1 {
+                    counter.insert(var, 0);
+                    let stack = if func.args.contains(&var) {
+                        vec![var]
+                    } else {
+                        Vec::new()
+                    };
+                    stacks.insert(var, stack);
+                }
             }
-            rename_pass(0, &out.dom_tree, func, &mut counter, &mut stacks);
+            let phi_vars: Vec<Vec<Symbol>> = func
+                .blocks
+                .iter()
+                .map(|b| {
+                    b.instrs
+                        .iter()
+                        .filter_map(|i| match i {
+                            IrInstruction::Phi { dest, .. } => Some(*dest),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .collect();
+            rename_pass(
+                0,
+                &info.dom_tree,
+                func,
+                &phi_vars,
+                &mut counter,
+                &mut stacks,
+            );
+
+            // the names from before renaming are gone, so are their types
+            let defined: HashSet<Symbol> = func
+                .blocks
+                .iter()
+                .flat_map(|b| &b.instrs)
+                .flat_map(|i| i.defs().to_vec())
+                .chain(func.args.iter().copied())
+                .collect();
+            func.types.retain(|var, _| defined.contains(var));
+
+            out.functions.insert(func.name.clone(), info);
         }
 
         Ok(out)
     }
 
+    /// Only the dominator analysis of each function, leaving them as they are
+    pub fn analyze(funcs: &[IrFunction]) -> Result<Self> {
+        let mut out = SSAFormation::default();
+        for func in funcs {
+            out.functions
+                .insert(func.name.clone(), DominatorInfo::compute(func)?);
+        }
+        Ok(out)
+    }
+
+    /// [`DominatorInfo::dom_tree_preorder`] of `func`, empty if it wasn't analyzed
+    pub fn dom_tree_preorder(&self, func: &IrFunction) -> Vec<BlockID> {
+        self.for_function(&func.name)
+            .map(DominatorInfo::dom_tree_preorder)
+            .unwrap_or_default()
+    }
+
+    /// [`DominatorInfo::iterated_df`] in `func`, empty if it wasn't analyzed
+    pub fn iterated_df(&self, func: &IrFunction, blocks: &[BlockID]) -> BitSet {
+        match self.for_function(&func.name) {
+            Some(info) => info.iterated_df(blocks),
+            None => BitSet::new(func.blocks.len()),
+        }
+    }
+
+    /// The dominator analysis of the function called `name`
+    pub fn for_function(&self, name: &str) -> Option<&DominatorInfo> {
+        self.functions.get(name)
+    }
+}
+
+impl DominatorInfo {
+    /// Dominators, dominance frontiers and the dominator tree of `func`
+    pub fn compute(func: &IrFunction) -> Result<Self> {
+        let mut info = DominatorInfo::default();
+        info.compute_idom(func)?;
+        info.compute_df(func)?;
+        info.build_dom_tree()?;
+        info.compute_post_idom(func);
+        info.compute_pdf(func);
+        info.build_post_dom_tree();
+        Ok(info)
+    }
+
     // TODO: Later in the future implement lengauer_tarjan_idom
+    /// Cooper-Harvey-Kennedy: iterate in reverse postorder until nothing
+    /// changes, meeting two preds' dominator chains on their RPO numbers
     pub fn compute_idom(&mut self, func: &IrFunction) -> Result<()> {
-        let n = func.blocks.len();
-        // usize::MAX means the idom is an unknown for now
-        let mut idom_vec = vec![usize::MAX; n];
-
-        // entry point to entry
-        idom_vec[0] = 0;
-
-        // find the fix-point of the loop
-        loop {
-            let mut changed = false;
-            // b_idx = block index
-            // starting from block 1 because idom[0] is 0
-            for b in 1..n {
-                let preds = &func.blocks[b].preds;
-
-                // Skip for if preds empty, we care for the preds because of the idom
-                if preds.is_empty() {
-                    continue;
-                }
+        let preds: Vec<Vec<BlockID>> = func.blocks.iter().map(|b| b.preds.clone()).collect();
+        let idom_vec = immediate_dominators(&func.reverse_postorder(), &preds);
+
+        if let Some(block) = idom_vec.iter().position(|&d| d == usize::MAX) {
+            bail!(
+                "@{}: could not compute idom for Block {}, it's unreachable",
+                func.name,
+                block
+            );
+        }
+        self.idom = idom_vec.into_iter().enumerate().collect();
 
-                let mut new_idom = match preds.iter().find(|&&p| idom_vec[p] != usize::MAX) {
-                    Some(&p) => p,
-                    None => continue,
-                };
+        Ok(())
+    }
 
-                // collect into a Vec<usize>
-                let others: Vec<usize> = preds
-                    .iter()
-                    .copied()
-                    .filter(|&p| p != new_idom && idom_vec[p] != usize::MAX)
-                    .collect();
+    // TODO: Finish this and dom tree too. Then test it out
+    pub fn compute_df(&mut self, func: &IrFunction) -> Result<()> {
+        // index directly, a duplicated label would make `block_index` point elsewhere
+        let preds: Vec<Vec<BlockID>> = func.blocks.iter().map(|b| b.preds.clone()).collect();
+        self.dom_frontier = frontiers(&preds, &self.idom);
 
-                // climb the preds in order to see if the dominance chains match
-                for p in others {
-                    let mut finger1 = p;
-                    let mut finger2 = new_idom;
-                    while finger1 != finger2 {
-                        while finger1 > finger2 {
-                            finger1 = idom_vec[finger1];
-                        }
-                        while finger2 > finger1 {
-                            finger2 = idom_vec[finger2];
-                        }
-                    }
-                    new_idom = finger1;
-                }
+        Ok(())
+    }
 
-                if idom_vec[b] != new_idom {
-                    idom_vec[b] = new_idom;
-                    changed = true;
-                }
-            }
+    pub fn build_dom_tree(&mut self) -> Result<()> {
+        self.dom_tree = children_of(&self.idom);
+        self.dfs_numbers = OnceCell::new();
+        Ok(())
+    }
+
+    /// Whether every path from the entry to `b` goes through `a`, a block
+    /// dominates itself
+    pub fn dominates(&self, a: BlockID, b: BlockID) -> bool {
+        let numbers = self.dfs_numbers.get_or_init(|| self.number_dom_tree());
+        match (numbers.get(a), numbers.get(b)) {
+            // b's subtree sits inside a's
+            (Some(&(pre_a, post_a)), Some(&(pre_b, post_b))) => pre_a <= pre_b && post_b <= post_a,
+            _ => false,
+        }
+    }
 
-            if !changed {
-                break;
+    /// Blocks in the order a walk down the dominator tree meets them, each
+    /// block before the ones it dominates and siblings by index
+    pub fn dom_tree_preorder(&self) -> Vec<BlockID> {
+        let mut order = Vec::with_capacity(self.idom.len());
+        if self.idom.is_empty() {
+            return order;
+        }
+        let mut stack = vec![0];
+        while let Some(b) = stack.pop() {
+            order.push(b);
+            if let Some(children) = self.dom_tree.get(&b) {
+                stack.extend(children.iter().rev());
             }
         }
+        order
+    }
+
+    pub fn strictly_dominates(&self, a: BlockID, b: BlockID) -> bool {
+        a != b && self.dominates(a, b)
+    }
 
-        self.idom.clear();
-        for (block, &dom) in idom_vec.iter().enumerate() {
-            if dom == usize::MAX {
-                panic!("could not compute idom for Block {}", block);
+    fn number_dom_tree(&self) -> Vec<(usize, usize)> {
+        let mut numbers = vec![(usize::MAX, 0); self.idom.len()];
+        if numbers.is_empty() {
+            return numbers;
+        }
+        let mut clock = 0;
+        // (block, next child to visit)
+        let mut stack = vec![(0, 0)];
+        numbers[0].0 = clock;
+
+        while let Some((b, i)) = stack.last_mut() {
+            let children = self.dom_tree.get(b).map(Vec::as_slice).unwrap_or_default();
+            clock += 1;
+            match children.get(*i) {
+                Some(&child) => {
+                    *i += 1;
+                    numbers[child].0 = clock;
+                    stack.push((child, 0));
+                }
+                None => {
+                    numbers[*b].1 = clock;
+                    stack.pop();
+                }
             }
-            self.idom.insert(block, dom);
         }
 
-        Ok(())
+        numbers
     }
 
-    // TODO: Finish this and dom tree too. Then test it out
-    pub fn compute_df(&mut self, func: &IrFunction) -> Result<()> {
-        self.dom_frontier.clear();
+    /// Post-dominators, the idoms of the reversed CFG starting at the
+    /// virtual exit. Blocks stuck in an infinite loop never reach a `ret`, so
+    /// the loop gets an edge to the exit of its own rather than no post-idom.
+    pub fn compute_post_idom(&mut self, func: &IrFunction) {
+        let (succs, preds) = reverse_cfg(func);
+        let exit = func.blocks.len();
+        let mut rpo = postorder_from(exit, &succs);
+        rpo.reverse();
+        self.post_idom = immediate_dominators(&rpo, &preds)
+            .into_iter()
+            .enumerate()
+            .collect();
+    }
+
+    /// Post-dominance frontiers, needs [`DominatorInfo::compute_post_idom`]
+    pub fn compute_pdf(&mut self, func: &IrFunction) {
+        let (_, preds) = reverse_cfg(func);
+        self.post_dom_frontier = frontiers(&preds, &self.post_idom);
+    }
 
-        for block in &func.blocks {
-            let b = func.block_index(&block.label).unwrap();
+    pub fn build_post_dom_tree(&mut self) {
+        self.post_dom_tree = children_of(&self.post_idom);
+    }
+
+    /// The iterated dominance frontier DF+ of `blocks`: their frontiers,
+    /// the frontiers of those, and so on. Where a variable defined in
+    /// `blocks` needs its phis. Each block goes on the worklist once, so
+    /// it's linear in the size of the frontiers
+    pub fn iterated_df(&self, blocks: &[BlockID]) -> BitSet {
+        let n = self.idom.len();
+        let mut df_plus = BitSet::new(n);
+        let mut queued = BitSet::new(n);
+        let mut worklist: Vec<BlockID> = blocks
+            .iter()
+            .copied()
+            .filter(|&b| queued.insert(b))
+            .collect();
+
+        while let Some(b) = worklist.pop() {
+            for &m in self.dom_frontier.get(&b).into_iter().flatten() {
+                // a block that defines the variable itself can still need a
+                // phi for it, so `queued` doesn't stop it going in DF+
+                df_plus.insert(m);
+                if queued.insert(m) {
+                    worklist.push(m);
+                }
+            }
+        }
+        df_plus
+    }
+
+    pub fn phi_insert(&self, func: &mut IrFunction, def_sites_map: &HashMap<Symbol, Vec<BlockID>>) {
+        self.place_phis(func, def_sites_map, |_, _| true);
+    }
 
-            // making sure it's a joint point
-            if block.preds.len() < 2 {
+    /// [`DominatorInfo::phi_insert`], only putting the phi for a variable in
+    /// a block when `wanted(var, block)` says so
+    pub fn place_phis(
+        &self,
+        func: &mut IrFunction,
+        def_sites_map: &HashMap<Symbol, Vec<BlockID>>,
+        wanted: impl Fn(&Symbol, BlockID) -> bool,
+    ) {
+        // sorted so phis land in the same order on every run
+        let mut vars: Vec<(&Symbol, &Vec<BlockID>)> = def_sites_map.iter().collect();
+        vars.sort_by_key(|(var, _)| *var);
+
+        for (var, blocks_with_defs) in vars {
+            // a single def is SSA already, the renamer leaves it alone too
+            if blocks_with_defs.len() < 2 {
                 continue;
             }
 
-            let idom_b = *self.idom.get(&b).expect("idom wasn't computed");
+            for m in self.iterated_df(blocks_with_defs).iter() {
+                if !wanted(var, m) {
+                    continue;
+                }
+                // the renamer fills the values in
+                let sources = func.blocks[m]
+                    .preds
+                    .iter()
+                    .map(|&p| PhiSource::new(&func.blocks[p].label, None))
+                    .collect();
+                let phi = IrInstruction::Phi {
+                    dest: *var,
+                    sources,
+                };
+                func.blocks[m].insert_instr(0, phi, None, None);
+            }
+        }
+    }
+}
 
-            for &p in &block.preds {
-                let mut runner = p;
+/// The idom of every block in the graph given by `preds`, visited in `rpo`
+/// which starts at the entry, the entry being its own. usize::MAX for a
+/// block the entry can't reach.
+fn immediate_dominators(rpo: &[BlockID], preds: &[Vec<BlockID>]) -> Vec<BlockID> {
+    let n = preds.len();
+    let entry = rpo[0];
+    let mut rpo_num = vec![usize::MAX; n];
+    for (i, &b) in rpo.iter().enumerate() {
+        rpo_num[b] = i;
+    }
 
-                while runner != idom_b {
-                    let entry = self.dom_frontier.entry(runner).or_default();
-                    if !entry.contains(&b) {
-                        entry.push(b);
+    // usize::MAX means the idom is an unknown for now
+    let mut idom_vec = vec![usize::MAX; n];
+    idom_vec[entry] = entry;
+
+    // find the fix-point of the loop
+    loop {
+        let mut changed = false;
+        // skipping the entry, it's its own idom
+        for &b in &rpo[1..] {
+            let mut new_idom = match preds[b].iter().find(|&&p| idom_vec[p] != usize::MAX) {
+                Some(&p) => p,
+                None => continue,
+            };
+
+            // collect into a Vec<usize>
+            let others: Vec<usize> = preds[b]
+                .iter()
+                .copied()
+                .filter(|&p| p != new_idom && idom_vec[p] != usize::MAX)
+                .collect();
+
+            // climb both dominator chains until they meet, the one later
+            // in RPO is the one that moves up
+            for p in others {
+                let mut finger1 = p;
+                let mut finger2 = new_idom;
+                while finger1 != finger2 {
+                    while rpo_num[finger1] > rpo_num[finger2] {
+                        finger1 = idom_vec[finger1];
+                    }
+                    while rpo_num[finger2] > rpo_num[finger1] {
+                        finger2 = idom_vec[finger2];
                     }
+                }
+                new_idom = finger1;
+            }
+
+            if idom_vec[b] != new_idom {
+                idom_vec[b] = new_idom;
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    idom_vec
+}
 
-                    // climbing up the pred, the one runner is equal to
-                    runner = *self.idom.get(&runner).unwrap();
+/// Dominance frontiers from the idoms, walking up from the preds of every join point
+fn frontiers(
+    preds: &[Vec<BlockID>],
+    idom: &HashMap<BlockID, BlockID>,
+) -> BTreeMap<BlockID, Vec<BlockID>> {
+    let mut frontier: BTreeMap<BlockID, Vec<BlockID>> = BTreeMap::new();
+
+    for (b, block_preds) in preds.iter().enumerate() {
+        // making sure it's a joint point
+        if block_preds.len() < 2 {
+            continue;
+        }
+
+        let idom_b = *idom.get(&b).expect("idom wasn't computed");
+
+        for &p in block_preds {
+            let mut runner = p;
+
+            // an unreachable pred has no idom to climb
+            while runner != idom_b && idom[&runner] != usize::MAX {
+                let entry = frontier.entry(runner).or_default();
+                if !entry.contains(&b) {
+                    entry.push(b);
                 }
+
+                // climbing up the pred, the one runner is equal to
+                runner = idom[&runner];
             }
         }
+    }
 
-        Ok(())
+    frontier
+}
+
+/// The tree the idoms make, each block's children sorted
+fn children_of(idom: &HashMap<BlockID, BlockID>) -> HashMap<BlockID, Vec<BlockID>> {
+    let mut tree: HashMap<BlockID, Vec<BlockID>> = HashMap::new();
+
+    for (&b, &p) in idom {
+        // make sure we've skipped the entry
+        if b != p && p != usize::MAX {
+            tree.entry(p).or_default().push(b);
+        }
     }
 
-    pub fn build_dom_tree(&mut self) -> Result<()> {
-        self.dom_tree.clear();
+    // the renamer walks children in this order, keep it independent of HashMap order
+    for children in tree.values_mut() {
+        children.sort_unstable();
+    }
+    tree
+}
+
+/// `(succs, preds)` of the reversed CFG, with the virtual exit at
+/// `func.blocks.len()`. The exit's succs are the blocks that leave the
+/// function, plus any that can't get to one of those.
+fn reverse_cfg(func: &IrFunction) -> (Vec<Vec<BlockID>>, Vec<Vec<BlockID>>) {
+    let n = func.blocks.len();
+    let mut succs: Vec<Vec<BlockID>> = func.blocks.iter().map(|b| b.preds.clone()).collect();
+    let mut preds: Vec<Vec<BlockID>> = func.blocks.iter().map(|b| b.succs.clone()).collect();
+    succs.push(Vec::new());
+    preds.push(Vec::new());
+
+    let exits = (0..n).filter(|&b| func.blocks[b].succs.is_empty());
+    for b in exits {
+        succs[n].push(b);
+        preds[b].push(n);
+    }
 
-        for (&b, &p) in &self.idom {
-            // make sure we've skipped the entry
-            if b != p {
-                self.dom_tree.entry(p).or_default().push(b);
+    // whatever the exit still can't reach is in a loop that never leaves
+    let mut reached = vec![false; n + 1];
+    for b in postorder_from(n, &succs) {
+        reached[b] = true;
+    }
+    for b in 0..n {
+        if !reached[b] {
+            succs[n].push(b);
+            preds[b].push(n);
+            for r in postorder_from(n, &succs) {
+                reached[r] = true;
             }
         }
-        Ok(())
     }
 
-    pub fn phi_insert(&self, func: &mut IrFunction, def_sites_map: &HashMap<String, Vec<BlockID>>) {
-        for (var, blocks_with_defs) in def_sites_map {
-            // `var` - the Variable we're looking for
-            // `blocks_with_defs` - blocks where `var` is defined at
-            let mut worklist: Vec<BlockID> = blocks_with_defs.clone();
-            let mut has_phi: HashSet<BlockID> = blocks_with_defs.iter().cloned().collect();
-
-            while let Some(block_id_def) = worklist.pop() {
-                if let Some(frontier) = self.dom_frontier.get(&block_id_def) {
-                    for &m in frontier {
-                        if has_phi.insert(m) {
-                            let block = &mut func.blocks[m];
-                            block.instrs.insert(
-                                0,
-                                IrInstruction::Phi {
-                                    dest: var.clone(),
-                                    sources: vec![None; block.preds.len()],
-                                },
-                            );
-
-                            worklist.push(m);
-                        }
-                    }
-                }
+    (succs, preds)
+}
+
+/// Variables read in some block before that block writes them, the only
+/// ones a phi can matter for
+fn global_names(func: &IrFunction) -> HashSet<Symbol> {
+    let mut globals = HashSet::new();
+    for block in &func.blocks {
+        let mut defined: HashSet<Symbol> = HashSet::new();
+        for instr in &block.instrs {
+            globals.extend(instr.uses().into_iter().filter(|u| !defined.contains(u)));
+            defined.extend(instr.defs().iter().copied());
+        }
+    }
+    globals
+}
+
+/// Phis the program came in with get the same treatment as Bril's `set`/`get`:
+/// each pred copies its source into the phi's shadow and the phi becomes a read
+/// of it. Phi placement then rebuilds them with names the renamer knows about.
+fn split_input_phis(func: &mut IrFunction) {
+    for b in 0..func.blocks.len() {
+        for i in 0..func.blocks[b].instrs.len() {
+            let IrInstruction::Phi { dest, sources } = &func.blocks[b].instrs[i] else {
+                continue;
+            };
+            let (dest, sources) = (*dest, sources.clone());
+            if let Some(typ) = func.types.get(&dest).cloned() {
+                func.types.insert(shadow_name(&dest), typ);
+            }
+
+            for src in sources {
+                let (Some(p), Some(value)) = (func.block_index(&src.pred), src.value) else {
+                    continue;
+                };
+                let copy = IrInstruction::Assign {
+                    lhs: shadow_name(&dest),
+                    rhs: value,
+                };
+                let pred = &mut func.blocks[p];
+                // the copy has to run before the pred leaves
+                let at = match pred.instrs.last() {
+                    Some(
+                        IrInstruction::Br { .. }
+                        | IrInstruction::Jmp { .. }
+                        | IrInstruction::Ret { .. },
+                    ) => pred.instrs.len() - 1,
+                    _ => pred.instrs.len(),
+                };
+                pred.insert_instr(at, copy, None, None);
             }
+
+            func.blocks[b].instrs[i] = IrInstruction::Assign {
+                rhs: shadow_name(&dest),
+                lhs: dest,
+            };
         }
     }
 }
 
 /// Rename pass for all the blocks, it'll convert every indiviual variables in each block
-/// with it's own unique name
+/// with it's own unique name. `phi_vars` is what each block's phis were
+/// placed for, in order, from before any of them got renamed
 pub fn rename_pass(
     block_id: BlockID,
     dom_tree: &HashMap<BlockID, Vec<BlockID>>,
     func: &mut IrFunction,
-    counter: &mut HashMap<String, BlockID>,
-    stacks: &mut HashMap<String, Vec<String>>,
+    phi_vars: &[Vec<Symbol>],
+    counter: &mut HashMap<Symbol, BlockID>,
+    stacks: &mut HashMap<Symbol, Vec<Symbol>>,
 ) {
+    // the original names this block pushed a new name for
+    let mut pushed = Vec::new();
     {
         let blocks = &mut func.blocks;
-        // Manage all the Phi-nodes block
+        let types = &mut func.types;
+        // Manage all the Phi-nodes block, their sources are filled from the preds
         for instr in blocks[block_id].instrs.iter_mut() {
             if let IrInstruction::Phi { dest, .. } = instr {
-                *dest = create_new_name(dest, counter, stacks);
+                pushed.push(*dest);
+                *dest = create_new_name(dest, counter, stacks, types);
             }
         }
-        // Rename all non-phi instructions for current block
+        // Rename all non-phi instructions for current block, reads before
+        // the write so `x = add x one` reads the old `x`
         for instr in blocks[block_id].instrs.iter_mut() {
-            // TODO: Maybe find a better way of handling this? This relates
-            // to the ID opcode for Bril...
-            match instr {
-                IrInstruction::Assign { lhs, rhs } => {
-                    *rhs = current_name(rhs, stacks);
-                    *lhs = create_new_name(lhs, counter, stacks);
-                }
-
-                IrInstruction::Not { dest, args } => {
-                    *args = current_name(args, stacks);
-                    *dest = create_new_name(dest, counter, stacks);
-                }
-
-                // TODO: Added more instructions
-                IrInstruction::Add { lhs, rhs, dest }
-                | IrInstruction::Mul { lhs, rhs, dest }
-                | IrInstruction::Sub { lhs, rhs, dest }
-                | IrInstruction::Div { lhs, rhs, dest }
-                | IrInstruction::Eq { lhs, rhs, dest }
-                | IrInstruction::Lt { lhs, rhs, dest }
-                | IrInstruction::Gt { lhs, rhs, dest }
-                | IrInstruction::Ge { lhs, rhs, dest }
-                | IrInstruction::Le { lhs, rhs, dest }
-                | IrInstruction::Or { lhs, rhs, dest }
-                | IrInstruction::And { lhs, rhs, dest } => {
-                    *lhs = current_name(lhs, stacks);
-                    *rhs = current_name(rhs, stacks);
-                    *dest = create_new_name(dest, counter, stacks);
-                }
-
-                IrInstruction::Call { args, dest, .. } => {
-                    if !args.is_empty() {
-                        for a in args.iter_mut() {
-                            *a = current_name(a, stacks);
-                        }
-                    }
-
-                    if let Some(d) = dest {
-                        *dest = Some(create_new_name(d, counter, stacks));
-                    }
-                }
-
-                IrInstruction::Print { values } => {
-                    if !values.is_empty() {
-                        for a in values.iter_mut() {
-                            *a = current_name(a, stacks);
-                        }
-                    }
-                }
-
-                IrInstruction::Ret { args } => {
-                    if !args.is_empty() {
-                        for a in args.iter_mut() {
-                            *a = current_name(a, stacks);
-                        }
-                    }
-                }
-
-                _ => {}
+            if let IrInstruction::Phi { .. } = instr {
+                continue;
+            }
+            for var in instr.uses_mut() {
+                *var = current_name(var, stacks);
+            }
+            if let Some(dest) = instr.defs_mut() {
+                pushed.push(*dest);
+                *dest = create_new_name(dest, counter, stacks, types);
             }
         }
     }
 
     // Check each of the successors of the current Block and fill in the Phi-nodes
     // if needed
+    let label = func.blocks[block_id].label.clone();
     for succ in func.blocks[block_id].succs.clone() {
-        let succ_block = &mut func.blocks[succ];
-        for instr in succ_block.instrs.iter_mut() {
-            if let IrInstruction::Phi { dest, sources } = instr {
-                let idx = succ_block
-                    .preds
-                    .iter()
-                    .position(|&p| p == block_id)
-                    .unwrap();
-                // Source is the size of the preds
-                sources[idx] = Some(current_name(dest, stacks));
+        let phis = func.blocks[succ]
+            .instrs
+            .iter_mut()
+            .filter_map(|instr| match instr {
+                IrInstruction::Phi { sources, .. } => Some(sources),
+                _ => None,
+            });
+        for (sources, var) in phis.zip(&phi_vars[succ]) {
+            for src in sources.iter_mut().filter(|s| s.pred == label) {
+                // a renamed variable with nothing on its stack isn't defined
+                // on the way in from here, the old name would be a use of
+                // something no longer defined anywhere
+                src.value = match stacks.get(var) {
+                    Some(stack) => stack.last().copied(),
+                    None => Some(*var),
+                };
             }
         }
     }
@@ -305,45 +683,43 @@ pub fn rename_pass(
     // Recursively rename each immediate child of a block through the dominator tree
     if let Some(child_blocks) = dom_tree.get(&block_id) {
         for &child in child_blocks {
-            rename_pass(child, dom_tree, func, counter, stacks);
+            rename_pass(child, dom_tree, func, phi_vars, counter, stacks);
         }
     }
 
     // Now we have to pop all the values on the SSA rename stacks hashmap
     // in order to have a distinct values
-    for instr in &func.blocks[block_id].instrs {
-        //println!("Instr!! {:#?}", &instr);
-        //println!("Block!! {:#?}", &func.blocks[block_id]);
-        for var in instr.defs() {
-            //println!("Var!! {}", var);
-            if stacks.contains_key(var) {
-                stacks.get_mut(var).expect("Something is wrong twin").pop();
-            }
+    for var in pushed {
+        if let Some(stack) = stacks.get_mut(&var) {
+            stack.pop();
         }
     }
 }
 
 /// Helper function with getting the current variable with subscript (if there is any) on the stack
-fn current_name(var: &String, stacks: &HashMap<String, Vec<String>>) -> String {
+fn current_name(var: &Symbol, stacks: &HashMap<Symbol, Vec<Symbol>>) -> Symbol {
     stacks
         .get(var)
-        .and_then(|stk| stk.last().cloned())
-        .unwrap_or_else(|| var.to_string())
+        .and_then(|stk| stk.last().copied())
+        .unwrap_or(*var)
 }
 
 /// Helper function for creating a new name for variables in SSA Form
 fn create_new_name(
-    var: &str,
-    counter: &mut HashMap<String, BlockID>,
-    stacks: &mut HashMap<String, Vec<String>>,
-) -> String {
-    let count = counter.entry(var.to_string()).or_insert(0);
+    var: &Symbol,
+    counter: &mut HashMap<Symbol, BlockID>,
+    stacks: &mut HashMap<Symbol, Vec<Symbol>>,
+    types: &mut HashMap<Symbol, Type>,
+) -> Symbol {
+    let Some(count) = counter.get_mut(var) else {
+        return *var;
+    };
     *count += 1;
 
-    let new_var = format!("{}${}", &var, count);
-    stacks
-        .entry(var.to_string())
-        .or_default()
-        .push(new_var.clone());
+    let new_var = Symbol::from(format!("{}${}", var, count));
+    stacks.entry(*var).or_default().push(new_var);
+    if let Some(typ) = types.get(var).cloned() {
+        types.insert(new_var, typ);
+    }
     new_var
 }
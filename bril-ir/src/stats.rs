@@ -0,0 +1,185 @@
+//! Counts over a module for judging what passes do: instructions by
+//! opcode, blocks, phis and how deep the loops nest. Serializes to JSON,
+//! and prints as a table with `{}`.
+
+use crate::analysis::loops;
+use crate::cfg::{IrFunction, IrInstruction, IrModule};
+use crate::ssa::DominatorInfo;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FunctionStats {
+    pub name: String,
+    pub blocks: usize,
+    /// Every instruction, phis and terminators included
+    pub instrs: usize,
+    pub phis: usize,
+    /// 0 without loops, 1 for loops that aren't inside another
+    pub max_loop_depth: usize,
+    pub opcodes: BTreeMap<&'static str, usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ModuleStats {
+    pub functions: Vec<FunctionStats>,
+}
+
+impl IrFunction {
+    pub fn stats(&self) -> FunctionStats {
+        let mut stats = FunctionStats {
+            name: self.name.clone(),
+            blocks: self.blocks.len(),
+            max_loop_depth: self.max_loop_depth(),
+            ..FunctionStats::default()
+        };
+        for instr in self.blocks.iter().flat_map(|b| &b.instrs) {
+            stats.instrs += 1;
+            if let IrInstruction::Phi { .. } = instr {
+                stats.phis += 1;
+            }
+            *stats.opcodes.entry(instr.opcode()).or_default() += 1;
+        }
+        stats
+    }
+
+    // a CFG with unreachable blocks has no dominators to find loops with,
+    // it's counted as having none
+    fn max_loop_depth(&self) -> usize {
+        let Ok(doms) = DominatorInfo::compute(self) else {
+            return 0;
+        };
+        loops::compute(self, &doms)
+            .loops
+            .iter()
+            .map(|l| l.depth)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl IrModule {
+    pub fn stats(&self) -> ModuleStats {
+        ModuleStats {
+            functions: self.functions.iter().map(IrFunction::stats).collect(),
+        }
+    }
+}
+
+impl ModuleStats {
+    /// The counts of every function added up, under the name `total`.
+    /// The loop depth is the deepest of any of them
+    pub fn total(&self) -> FunctionStats {
+        let mut total = FunctionStats {
+            name: "total".to_string(),
+            ..FunctionStats::default()
+        };
+        for func in &self.functions {
+            total.blocks += func.blocks;
+            total.instrs += func.instrs;
+            total.phis += func.phis;
+            total.max_loop_depth = total.max_loop_depth.max(func.max_loop_depth);
+            for (op, n) in &func.opcodes {
+                *total.opcodes.entry(op).or_default() += n;
+            }
+        }
+        total
+    }
+}
+
+impl fmt::Display for FunctionStats {
+    /// One line, e.g. `@main: 3 blocks, 12 instrs, 1 phis, loop depth 1`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "@{}: {} blocks, {} instrs, {} phis, loop depth {}",
+            self.name, self.blocks, self.instrs, self.phis, self.max_loop_depth
+        )
+    }
+}
+
+impl fmt::Display for ModuleStats {
+    /// A row per function and a total, then the opcode counts of the module
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total();
+        writeln!(
+            f,
+            "{:<20} {:>7} {:>7} {:>5} {:>10}",
+            "function", "blocks", "instrs", "phis", "loop depth"
+        )?;
+        let names = self.functions.iter().map(|s| format!("@{}", s.name));
+        let rows = self.functions.iter().chain([&total]);
+        for (name, stats) in names.chain(["total".to_string()]).zip(rows) {
+            writeln!(
+                f,
+                "{:<20} {:>7} {:>7} {:>5} {:>10}",
+                name, stats.blocks, stats.instrs, stats.phis, stats.max_loop_depth
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<20} {:>7}", "opcode", "count")?;
+        for (op, n) in &total.opcodes {
+            writeln!(f, "{:<20} {:>7}", op, n)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SSAFormation;
+    use bril_frontend::Program;
+
+    #[test]
+    fn test_stats_of_add() {
+        let program: Program = serde_json::from_str(include_str!("../../tests/add.json")).unwrap();
+        let module = IrModule::try_from(&program).unwrap();
+        let stats = module.stats();
+        assert_eq!(stats.functions.len(), 1);
+
+        let add = &stats.functions[0];
+        assert_eq!(add.name, "add_test");
+        assert_eq!((add.blocks, add.instrs, add.phis), (1, 5, 0));
+        assert_eq!(add.max_loop_depth, 0);
+        let opcodes: Vec<(&str, usize)> = add.opcodes.iter().map(|(&op, &n)| (op, n)).collect();
+        assert_eq!(
+            opcodes,
+            [("add", 1), ("const", 2), ("print", 1), ("ret", 1)]
+        );
+        assert_eq!(stats.total().instrs, 5);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["functions"][0]["opcodes"]["const"], 2);
+        assert!(stats.to_string().contains("@add_test"));
+    }
+
+    #[test]
+    fn test_phis_and_loop_depth() {
+        let mut func = IrFunction::parse(
+            "@main(n: int) {
+.entry:
+  i = const 0
+  one = const 1
+  jmp .outer
+.outer:
+  c = lt i n
+  br c .inner .done
+.inner:
+  i = add i one
+  d = lt i n
+  br d .inner .outer
+.done:
+  ret
+}",
+        )
+        .unwrap();
+        SSAFormation::new(std::slice::from_mut(&mut func)).unwrap();
+        let stats = func.stats();
+        assert_eq!(stats.max_loop_depth, 2);
+        assert_eq!(stats.phis, 2);
+        assert_eq!(stats.opcodes["phi"], 2);
+        assert_eq!(stats.instrs, stats.opcodes.values().sum::<usize>());
+    }
+}
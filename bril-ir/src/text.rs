@@ -0,0 +1,961 @@
+//! Compact textual form of the IR.
+//!
+//! `Display` renders functions like
+//!
+//! ```text
+//! @main(n: int): int {
+//! .entry:  # preds: [] succs: [.loop]
+//!   one: int = const 1
+//!   jmp .loop
+//! .loop:  # preds: [.entry, .loop] succs: [.loop, .exit]
+//!   i$1: int = phi [one, .entry] [i$2, .loop]
+//!   i$2: int = add i$1 one
+//!   c: bool = lt i$2 n
+//!   br c .loop .exit
+//! .exit:  # preds: [.loop] succs: []
+//!   ret i$2
+//! }
+//! ```
+//!
+//! and `parse_function`/`parse_module` read exactly that back, so dumps can be
+//! edited by hand and fed into the later stages again. The types come from
+//! `IrFunction::types` and can be left out when writing IR by hand.
+
+use crate::cfg::{wire_block_edges, IrBasicBlock, IrFunction, IrInstruction, IrModule, PhiSource};
+use crate::BlockID;
+use anyhow::{anyhow, bail, Context, Result};
+use bril_frontend::{Literal, Symbol, Type};
+use std::fmt;
+
+impl fmt::Display for IrInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_instr(f, self, false)
+    }
+}
+
+impl fmt::Display for IrBasicBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, ".{}:", self.label)?;
+        for instr in &self.instrs {
+            writeln!(f, "  {}", instr)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for IrFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}(", self.name)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", arg)?;
+            if let Some(typ) = self.arg_types.get(i) {
+                write!(f, ": {}", typ)?;
+            }
+        }
+        write!(f, ")")?;
+        if let Some(typ) = &self.ret_typ {
+            write!(f, ": {}", typ)?;
+        }
+        writeln!(f, " {{")?;
+
+        let label_of = |idx: &BlockID| match self.blocks.get(*idx) {
+            Some(b) => format!(".{}", b.label),
+            None => format!(".<missing block {}>", idx),
+        };
+
+        for block in &self.blocks {
+            let preds: Vec<String> = block.preds.iter().map(label_of).collect();
+            let succs: Vec<String> = block.succs.iter().map(label_of).collect();
+            writeln!(
+                f,
+                ".{}:  # preds: [{}] succs: [{}]",
+                block.label,
+                preds.join(", "),
+                succs.join(", ")
+            )?;
+
+            for instr in &block.instrs {
+                let text = Labelled(instr).to_string();
+                // every instruction with a dest prints as `dest = ...`, the
+                // type goes in front of the `=` like in Bril
+                let typed = instr.defs().first().and_then(|d| {
+                    let typ = self.types.get(d)?;
+                    let rest = text.strip_prefix(&format!("{} = ", d))?;
+                    Some(format!("{}: {} = {}", d, typ, rest))
+                });
+                writeln!(f, "  {}", typed.unwrap_or(text))?;
+            }
+        }
+
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for IrModule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, func) in self.functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", func)?;
+        }
+        Ok(())
+    }
+}
+
+/// An instruction printed with its phi sources labelled
+struct Labelled<'a>(&'a IrInstruction);
+
+impl fmt::Display for Labelled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_instr(f, self.0, true)
+    }
+}
+
+fn fmt_literal(value: &Literal) -> String {
+    match value {
+        Literal::Int(i) => i.to_string(),
+        Literal::Bool(b) => b.to_string(),
+        // Debug keeps the `.0` on whole floats so they don't read back as ints
+        Literal::Float(f) => format!("{:?}", f),
+        Literal::Char(c) => format!("{:?}", c),
+    }
+}
+
+/// `labelled` has phis print which edge each value comes in on, without
+/// it only the values are printed
+fn fmt_instr(f: &mut fmt::Formatter<'_>, instr: &IrInstruction, labelled: bool) -> fmt::Result {
+    match instr {
+        IrInstruction::Add { dest, lhs, rhs } => write!(f, "{} = add {} {}", dest, lhs, rhs),
+        IrInstruction::Mul { dest, lhs, rhs } => write!(f, "{} = mul {} {}", dest, lhs, rhs),
+        IrInstruction::Sub { dest, lhs, rhs } => write!(f, "{} = sub {} {}", dest, lhs, rhs),
+        IrInstruction::Div { dest, lhs, rhs } => write!(f, "{} = div {} {}", dest, lhs, rhs),
+        IrInstruction::Shl { dest, src, amount } => write!(f, "{} = shl {} {}", dest, src, amount),
+        IrInstruction::Shr { dest, src, amount } => write!(f, "{} = shr {} {}", dest, src, amount),
+        IrInstruction::Sra { dest, src, amount } => write!(f, "{} = sra {} {}", dest, src, amount),
+        IrInstruction::Eq { dest, lhs, rhs } => write!(f, "{} = eq {} {}", dest, lhs, rhs),
+        IrInstruction::Lt { dest, lhs, rhs } => write!(f, "{} = lt {} {}", dest, lhs, rhs),
+        IrInstruction::Gt { dest, lhs, rhs } => write!(f, "{} = gt {} {}", dest, lhs, rhs),
+        IrInstruction::Ge { dest, lhs, rhs } => write!(f, "{} = ge {} {}", dest, lhs, rhs),
+        IrInstruction::Le { dest, lhs, rhs } => write!(f, "{} = le {} {}", dest, lhs, rhs),
+        IrInstruction::FAdd { dest, lhs, rhs } => write!(f, "{} = fadd {} {}", dest, lhs, rhs),
+        IrInstruction::FSub { dest, lhs, rhs } => write!(f, "{} = fsub {} {}", dest, lhs, rhs),
+        IrInstruction::FMul { dest, lhs, rhs } => write!(f, "{} = fmul {} {}", dest, lhs, rhs),
+        IrInstruction::FDiv { dest, lhs, rhs } => write!(f, "{} = fdiv {} {}", dest, lhs, rhs),
+        IrInstruction::FEq { dest, lhs, rhs } => write!(f, "{} = feq {} {}", dest, lhs, rhs),
+        IrInstruction::FLt { dest, lhs, rhs } => write!(f, "{} = flt {} {}", dest, lhs, rhs),
+        IrInstruction::FGt { dest, lhs, rhs } => write!(f, "{} = fgt {} {}", dest, lhs, rhs),
+        IrInstruction::FLe { dest, lhs, rhs } => write!(f, "{} = fle {} {}", dest, lhs, rhs),
+        IrInstruction::FGe { dest, lhs, rhs } => write!(f, "{} = fge {} {}", dest, lhs, rhs),
+        IrInstruction::CEq { dest, lhs, rhs } => write!(f, "{} = ceq {} {}", dest, lhs, rhs),
+        IrInstruction::CLt { dest, lhs, rhs } => write!(f, "{} = clt {} {}", dest, lhs, rhs),
+        IrInstruction::CGt { dest, lhs, rhs } => write!(f, "{} = cgt {} {}", dest, lhs, rhs),
+        IrInstruction::CLe { dest, lhs, rhs } => write!(f, "{} = cle {} {}", dest, lhs, rhs),
+        IrInstruction::CGe { dest, lhs, rhs } => write!(f, "{} = cge {} {}", dest, lhs, rhs),
+        IrInstruction::Or { dest, lhs, rhs } => write!(f, "{} = or {} {}", dest, lhs, rhs),
+        IrInstruction::And { dest, lhs, rhs } => write!(f, "{} = and {} {}", dest, lhs, rhs),
+        IrInstruction::Not { dest, args } => write!(f, "{} = not {}", dest, args),
+        IrInstruction::Float2Bits { dest, src } => write!(f, "{} = float2bits {}", dest, src),
+        IrInstruction::Bits2Float { dest, src } => write!(f, "{} = bits2float {}", dest, src),
+        IrInstruction::Char2Int { dest, src } => write!(f, "{} = char2int {}", dest, src),
+        IrInstruction::Int2Char { dest, src } => write!(f, "{} = int2char {}", dest, src),
+
+        IrInstruction::Call {
+            target_func,
+            args,
+            dest,
+            ..
+        } => {
+            if let Some(d) = dest {
+                write!(f, "{} = ", d)?;
+            }
+            write!(f, "call @{}", target_func)?;
+            for a in args {
+                write!(f, " {}", a)?;
+            }
+            Ok(())
+        }
+
+        IrInstruction::Opaque {
+            op,
+            dest,
+            args,
+            funcs,
+            labels,
+            ..
+        } => {
+            if let Some(d) = dest {
+                write!(f, "{} = ", d)?;
+            }
+            write!(f, "opaque {}", op)?;
+            for a in args {
+                write!(f, " {}", a)?;
+            }
+            for func in funcs {
+                write!(f, " @{}", func)?;
+            }
+            for label in labels {
+                write!(f, " .{}", label)?;
+            }
+            Ok(())
+        }
+
+        IrInstruction::Br {
+            cond,
+            then_lbl,
+            else_lbl,
+        } => write!(f, "br {} .{} .{}", cond, then_lbl, else_lbl),
+        IrInstruction::Jmp { label } => write!(f, "jmp .{}", label),
+        IrInstruction::Ret { args } => {
+            write!(f, "ret")?;
+            for a in args {
+                write!(f, " {}", a)?;
+            }
+            Ok(())
+        }
+
+        IrInstruction::Phi { dest, sources } => {
+            write!(f, "{} = phi", dest)?;
+            for src in sources {
+                let value = src.value.as_deref().unwrap_or("_");
+                if labelled {
+                    write!(f, " [{}, .{}]", value, src.pred)?;
+                } else {
+                    write!(f, " {}", value)?;
+                }
+            }
+            Ok(())
+        }
+
+        IrInstruction::Alloc { dest, size } => write!(f, "{} = alloc {}", dest, size),
+        IrInstruction::Load { dest, ptr } => write!(f, "{} = load {}", dest, ptr),
+        IrInstruction::Store { ptr, value } => write!(f, "store {} {}", ptr, value),
+        IrInstruction::Free { ptr } => write!(f, "free {}", ptr),
+        IrInstruction::PtrAdd { dest, ptr, offset } => {
+            write!(f, "{} = ptradd {} {}", dest, ptr, offset)
+        }
+
+        IrInstruction::Const { dest, value } => {
+            write!(f, "{} = const {}", dest, fmt_literal(value))
+        }
+        IrInstruction::Nop => write!(f, "nop"),
+        IrInstruction::Print { values } => {
+            write!(f, "print")?;
+            for v in values {
+                write!(f, " {}", v)?;
+            }
+            Ok(())
+        }
+        IrInstruction::Assign { lhs, rhs } => write!(f, "{} = id {}", lhs, rhs),
+    }
+}
+
+/// Parse every function of a printed `IrModule`
+pub fn parse_module(text: &str) -> Result<IrModule> {
+    let mut module = parse_functions(text)?;
+    module.resolve_calls()?;
+    Ok(module)
+}
+
+/// Parse a single printed `IrFunction`, its calls are left unresolved
+pub fn parse_function(text: &str) -> Result<IrFunction> {
+    let mut module = parse_functions(text)?;
+    match module.functions.len() {
+        1 => Ok(module.functions.remove(0)),
+        n => bail!("expected exactly one function, found {}", n),
+    }
+}
+
+impl IrFunction {
+    /// Same as [`parse_function`], handy for writing test CFGs as text
+    pub fn parse(text: &str) -> Result<IrFunction> {
+        parse_function(text)
+    }
+}
+
+fn parse_functions(text: &str) -> Result<IrModule> {
+    let mut functions = Vec::new();
+    let mut lines = text.lines().enumerate().peekable();
+
+    while let Some(&(_, line)) = lines.peek() {
+        if strip_comment(line).trim().is_empty() {
+            lines.next();
+            continue;
+        }
+        functions.push(parse_function_lines(&mut lines)?);
+    }
+
+    Ok(IrModule { functions })
+}
+
+/// Edge annotations taken from a block header, by label
+struct BlockEdges {
+    preds: Vec<String>,
+    succs: Vec<String>,
+}
+
+fn parse_function_lines<'a>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+) -> Result<IrFunction> {
+    let (line_no, header) = lines.next().ok_or_else(|| anyhow!("expected a function"))?;
+    let mut func =
+        parse_function_header(header).with_context(|| format!("line {}", line_no + 1))?;
+
+    let mut edges: Vec<Option<BlockEdges>> = Vec::new();
+    let mut closed = false;
+
+    for (line_no, raw) in lines.by_ref() {
+        let ctx = || format!("line {}", line_no + 1);
+        let line = strip_comment(raw).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line == "}" {
+            closed = true;
+            break;
+        }
+
+        if let Some(label) = line.strip_prefix('.').and_then(|l| l.strip_suffix(':')) {
+            func.add_block(label).with_context(ctx)?;
+            edges.push(parse_edge_annotation(raw).with_context(ctx)?);
+            continue;
+        }
+
+        let Some(block_idx) = func.blocks.len().checked_sub(1) else {
+            bail!("{}: instruction before the first block label", ctx());
+        };
+        let (line, typ) = split_dest_type(line).with_context(ctx)?;
+        let mut instr = parse_instr(&line).with_context(ctx)?;
+        if let (Some(typ), Some(&dest)) = (typ, instr.defs().first()) {
+            // extension ops keep theirs on the instruction too
+            if let IrInstruction::Opaque { typ: op_typ, .. } = &mut instr {
+                *op_typ = Some(typ.clone());
+            }
+            func.declare(dest, typ);
+        }
+        func.append_instr(block_idx, &instr, None, None);
+    }
+
+    if !closed {
+        bail!("@{}: missing closing `}}`", func.name);
+    }
+
+    if edges.iter().any(Option::is_some) {
+        for (idx, block_edges) in edges.into_iter().enumerate() {
+            let Some(block_edges) = block_edges else {
+                continue;
+            };
+            for p in &block_edges.preds {
+                let p_idx = resolve_label(&func, p)?;
+                func.blocks[idx].preds.push(p_idx);
+            }
+            for s in &block_edges.succs {
+                let s_idx = resolve_label(&func, s)?;
+                func.blocks[idx].succs.push(s_idx);
+            }
+        }
+    } else {
+        wire_block_edges(&mut func)?;
+    }
+
+    // a labelled source has to name a block, whether it's a pred is up
+    // to the verifier
+    for instr in func.blocks.iter().flat_map(|b| &b.instrs) {
+        if let IrInstruction::Phi { sources, .. } = instr {
+            for src in sources.iter().filter(|s| !s.pred.is_empty()) {
+                resolve_label(&func, &src.pred)?;
+            }
+        }
+    }
+    func.pair_phi_sources();
+    Ok(func)
+}
+
+fn resolve_label(func: &IrFunction, label: &str) -> Result<BlockID> {
+    func.label_to_idx
+        .get(label)
+        .copied()
+        .ok_or_else(|| anyhow!("@{}: unknown label .{}", func.name, label))
+}
+
+fn strip_comment(line: &str) -> &str {
+    match comment_start(line) {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+/// Byte offset of the `#` starting a comment, skipping ones inside char literals
+fn comment_start(line: &str) -> Option<usize> {
+    let mut in_char = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_char => escaped = true,
+            '\'' => in_char = !in_char,
+            '#' if !in_char => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_function_header(line: &str) -> Result<IrFunction> {
+    let line = strip_comment(line).trim();
+    let rest = line
+        .strip_prefix('@')
+        .and_then(|l| l.strip_suffix('{'))
+        .ok_or_else(|| anyhow!("expected `@name(args): type {{`, found `{}`", line))?
+        .trim();
+
+    let name_end = rest.find(['(', ':']).unwrap_or(rest.len());
+    let (name, mut rest) = rest.split_at(name_end);
+    let mut func = IrFunction::new(name.trim());
+
+    if let Some(after_paren) = rest.strip_prefix('(') {
+        let (args, after_args) = after_paren
+            .split_once(')')
+            .ok_or_else(|| anyhow!("unclosed argument list in `{}`", line))?;
+        for arg in args.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            match arg.split_once(':') {
+                Some((name, typ)) => {
+                    let (name, typ) = (Symbol::from(name.trim()), parse_type(typ)?);
+                    func.args.push(name);
+                    func.arg_types.push(typ.clone());
+                    func.declare(name, typ);
+                }
+                None => func.args.push(arg.into()),
+            }
+        }
+        if !func.arg_types.is_empty() && func.arg_types.len() != func.args.len() {
+            bail!(
+                "either every argument has a type or none does, in `{}`",
+                line
+            );
+        }
+        rest = after_args.trim();
+    }
+
+    if let Some(typ) = rest.strip_prefix(':') {
+        func.ret_typ = Some(parse_type(typ)?);
+    } else if !rest.is_empty() {
+        bail!("unexpected `{}` in function header", rest);
+    }
+
+    Ok(func)
+}
+
+fn parse_type(text: &str) -> Result<Type> {
+    text.parse::<Type>().map_err(|e| anyhow!(e))
+}
+
+/// `# preds: [.a, .b] succs: [.c]` after a block label, optional
+fn parse_edge_annotation(line: &str) -> Result<Option<BlockEdges>> {
+    let Some(comment) = comment_start(line).map(|i| &line[i + 1..]) else {
+        return Ok(None);
+    };
+    let comment = comment.trim();
+    if !comment.starts_with("preds:") {
+        return Ok(None);
+    }
+
+    let list = |key: &str| -> Result<Vec<String>> {
+        let start = comment
+            .find(key)
+            .ok_or_else(|| anyhow!("block annotation is missing `{}`", key))?;
+        let rest = &comment[start + key.len()..];
+        let open = rest
+            .find('[')
+            .ok_or_else(|| anyhow!("expected `[` after `{}`", key))?;
+        let close = rest
+            .find(']')
+            .ok_or_else(|| anyhow!("expected `]` after `{}`", key))?;
+        rest[open + 1..close]
+            .split(',')
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|l| {
+                l.strip_prefix('.')
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("expected a `.label`, found `{}`", l))
+            })
+            .collect()
+    };
+
+    Ok(Some(BlockEdges {
+        preds: list("preds:")?,
+        succs: list("succs:")?,
+    }))
+}
+
+fn label_operand(token: &str) -> Result<String> {
+    token
+        .strip_prefix('.')
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("expected a `.label`, found `{}`", token))
+}
+
+fn parse_literal(token: &str) -> Result<Literal> {
+    if let Some(quoted) = token.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        return parse_char(quoted)
+            .map(Literal::Char)
+            .ok_or_else(|| anyhow!("invalid char literal `{}`", token));
+    }
+
+    match token {
+        "true" => Ok(Literal::Bool(true)),
+        "false" => Ok(Literal::Bool(false)),
+        _ => token
+            .parse::<i64>()
+            .map(Literal::Int)
+            .or_else(|_| token.parse::<f64>().map(Literal::Float))
+            .map_err(|_| anyhow!("invalid literal `{}`", token)),
+    }
+}
+
+/// Undo `char::escape_debug`, which is what `Display` prints chars with
+fn parse_char(quoted: &str) -> Option<char> {
+    let mut chars = quoted.chars();
+    let c = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '\'' => '\'',
+            '"' => '"',
+            'u' => {
+                let hex = chars.as_str().strip_prefix('{')?.strip_suffix('}')?;
+                chars = "".chars();
+                char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+            }
+            _ => return None,
+        },
+        c => c,
+    };
+    chars.next().is_none().then_some(c)
+}
+
+/// Returns the instruction plus, for phis written with labels, the label of each source
+#[allow(clippy::type_complexity)]
+/// `x: int = add a b` → `x = add a b` and the `int`, the type is optional
+fn split_dest_type(line: &str) -> Result<(String, Option<Type>)> {
+    if let Some((lhs, rhs)) = line.split_once('=')
+        && let Some((dest, typ)) = lhs.split_once(':')
+    {
+        return Ok((format!("{} ={}", dest.trim(), rhs), Some(parse_type(typ)?)));
+    }
+    Ok((line.to_string(), None))
+}
+
+fn parse_instr(line: &str) -> Result<IrInstruction> {
+    let (dest, rhs) = match line.split_once('=') {
+        Some((d, r)) => (Some(Symbol::from(d.trim())), r.trim()),
+        None => (None, line),
+    };
+
+    let (opcode, rest) = rhs.split_once(char::is_whitespace).unwrap_or((rhs, ""));
+    let operands: Vec<&str> = rest.split_whitespace().collect();
+
+    let need_dest = || dest.ok_or_else(|| anyhow!("`{}` needs a destination", opcode));
+    let arity = |n: usize| -> Result<()> {
+        if operands.len() != n {
+            bail!(
+                "`{}` takes {} operand(s), found {}",
+                opcode,
+                n,
+                operands.len()
+            );
+        }
+        Ok(())
+    };
+    let binary = |make: fn(Symbol, Symbol, Symbol) -> IrInstruction| -> Result<IrInstruction> {
+        arity(2)?;
+        Ok(make(need_dest()?, operands[0].into(), operands[1].into()))
+    };
+    // the amount is a number, not a name
+    let shift = |make: fn(Symbol, Symbol, u32) -> IrInstruction| -> Result<IrInstruction> {
+        arity(2)?;
+        let amount = operands[1]
+            .parse()
+            .map_err(|_| anyhow!("bad shift amount `{}`", operands[1]))?;
+        Ok(make(need_dest()?, operands[0].into(), amount))
+    };
+
+    let instr = match opcode {
+        "add" => binary(|dest, lhs, rhs| IrInstruction::Add { dest, lhs, rhs })?,
+        "mul" => binary(|dest, lhs, rhs| IrInstruction::Mul { dest, lhs, rhs })?,
+        "sub" => binary(|dest, lhs, rhs| IrInstruction::Sub { dest, lhs, rhs })?,
+        "div" => binary(|dest, lhs, rhs| IrInstruction::Div { dest, lhs, rhs })?,
+        "shl" => shift(|dest, src, amount| IrInstruction::Shl { dest, src, amount })?,
+        "shr" => shift(|dest, src, amount| IrInstruction::Shr { dest, src, amount })?,
+        "sra" => shift(|dest, src, amount| IrInstruction::Sra { dest, src, amount })?,
+        "eq" => binary(|dest, lhs, rhs| IrInstruction::Eq { dest, lhs, rhs })?,
+        "lt" => binary(|dest, lhs, rhs| IrInstruction::Lt { dest, lhs, rhs })?,
+        "gt" => binary(|dest, lhs, rhs| IrInstruction::Gt { dest, lhs, rhs })?,
+        "ge" => binary(|dest, lhs, rhs| IrInstruction::Ge { dest, lhs, rhs })?,
+        "le" => binary(|dest, lhs, rhs| IrInstruction::Le { dest, lhs, rhs })?,
+        "fadd" => binary(|dest, lhs, rhs| IrInstruction::FAdd { dest, lhs, rhs })?,
+        "fsub" => binary(|dest, lhs, rhs| IrInstruction::FSub { dest, lhs, rhs })?,
+        "fmul" => binary(|dest, lhs, rhs| IrInstruction::FMul { dest, lhs, rhs })?,
+        "fdiv" => binary(|dest, lhs, rhs| IrInstruction::FDiv { dest, lhs, rhs })?,
+        "feq" => binary(|dest, lhs, rhs| IrInstruction::FEq { dest, lhs, rhs })?,
+        "flt" => binary(|dest, lhs, rhs| IrInstruction::FLt { dest, lhs, rhs })?,
+        "fgt" => binary(|dest, lhs, rhs| IrInstruction::FGt { dest, lhs, rhs })?,
+        "fle" => binary(|dest, lhs, rhs| IrInstruction::FLe { dest, lhs, rhs })?,
+        "fge" => binary(|dest, lhs, rhs| IrInstruction::FGe { dest, lhs, rhs })?,
+        "ceq" => binary(|dest, lhs, rhs| IrInstruction::CEq { dest, lhs, rhs })?,
+        "clt" => binary(|dest, lhs, rhs| IrInstruction::CLt { dest, lhs, rhs })?,
+        "cgt" => binary(|dest, lhs, rhs| IrInstruction::CGt { dest, lhs, rhs })?,
+        "cle" => binary(|dest, lhs, rhs| IrInstruction::CLe { dest, lhs, rhs })?,
+        "cge" => binary(|dest, lhs, rhs| IrInstruction::CGe { dest, lhs, rhs })?,
+        "or" => binary(|dest, lhs, rhs| IrInstruction::Or { dest, lhs, rhs })?,
+        "and" => binary(|dest, lhs, rhs| IrInstruction::And { dest, lhs, rhs })?,
+
+        "not" => {
+            arity(1)?;
+            IrInstruction::Not {
+                dest: need_dest()?,
+                args: operands[0].into(),
+            }
+        }
+        "float2bits" => {
+            arity(1)?;
+            IrInstruction::Float2Bits {
+                dest: need_dest()?,
+                src: operands[0].into(),
+            }
+        }
+        "bits2float" => {
+            arity(1)?;
+            IrInstruction::Bits2Float {
+                dest: need_dest()?,
+                src: operands[0].into(),
+            }
+        }
+        "char2int" => {
+            arity(1)?;
+            IrInstruction::Char2Int {
+                dest: need_dest()?,
+                src: operands[0].into(),
+            }
+        }
+        "int2char" => {
+            arity(1)?;
+            IrInstruction::Int2Char {
+                dest: need_dest()?,
+                src: operands[0].into(),
+            }
+        }
+        "id" => {
+            arity(1)?;
+            IrInstruction::Assign {
+                lhs: need_dest()?,
+                rhs: operands[0].into(),
+            }
+        }
+        // not split on whitespace, `' '` is a valid literal
+        "const" => IrInstruction::Const {
+            dest: need_dest()?,
+            value: parse_literal(rest.trim())?,
+        },
+
+        "alloc" => {
+            arity(1)?;
+            IrInstruction::Alloc {
+                dest: need_dest()?,
+                size: operands[0].into(),
+            }
+        }
+        "load" => {
+            arity(1)?;
+            IrInstruction::Load {
+                dest: need_dest()?,
+                ptr: operands[0].into(),
+            }
+        }
+        "store" => {
+            arity(2)?;
+            IrInstruction::Store {
+                ptr: operands[0].into(),
+                value: operands[1].into(),
+            }
+        }
+        "free" => {
+            arity(1)?;
+            IrInstruction::Free {
+                ptr: operands[0].into(),
+            }
+        }
+        "ptradd" => {
+            arity(2)?;
+            IrInstruction::PtrAdd {
+                dest: need_dest()?,
+                ptr: operands[0].into(),
+                offset: operands[1].into(),
+            }
+        }
+
+        "call" => {
+            let (target, args) = operands
+                .split_first()
+                .ok_or_else(|| anyhow!("`call` needs a target function"))?;
+            let target_func = target
+                .strip_prefix('@')
+                .ok_or_else(|| anyhow!("expected `@func`, found `{}`", target))?;
+            IrInstruction::Call {
+                target_func: target_func.to_string(),
+                callee: None,
+                args: args.iter().map(|&a| a.into()).collect(),
+                dest,
+            }
+        }
+        // `opaque name args... @funcs... .labels...`, the type isn't kept
+        "opaque" => {
+            let (op, rest) = operands
+                .split_first()
+                .ok_or_else(|| anyhow!("`opaque` needs the op's name"))?;
+            let (mut args, mut funcs, mut labels) = (Vec::new(), Vec::new(), Vec::new());
+            for operand in rest {
+                if let Some(func) = operand.strip_prefix('@') {
+                    funcs.push(func.to_string());
+                } else if let Some(label) = operand.strip_prefix('.') {
+                    labels.push(label.to_string());
+                } else {
+                    args.push(Symbol::from(*operand));
+                }
+            }
+            IrInstruction::Opaque {
+                op: op.to_string(),
+                dest,
+                args,
+                funcs,
+                labels,
+                typ: None,
+            }
+        }
+        "br" => {
+            arity(3)?;
+            IrInstruction::Br {
+                cond: operands[0].into(),
+                then_lbl: label_operand(operands[1])?,
+                else_lbl: label_operand(operands[2])?,
+            }
+        }
+        "jmp" => {
+            arity(1)?;
+            IrInstruction::Jmp {
+                label: label_operand(operands[0])?,
+            }
+        }
+        "ret" => IrInstruction::Ret {
+            args: operands.iter().map(|&a| a.into()).collect(),
+        },
+        "print" => IrInstruction::Print {
+            values: operands.iter().map(|&a| a.into()).collect(),
+        },
+        "nop" => {
+            arity(0)?;
+            IrInstruction::Nop
+        }
+
+        "phi" => return parse_phi(need_dest()?, rest),
+
+        other => bail!("unknown opcode `{}`", other),
+    };
+
+    if dest.is_some() && instr.defs().is_empty() {
+        bail!("`{}` doesn't produce a value", opcode);
+    }
+
+    Ok(instr)
+}
+
+/// Either `phi a b` (positional) or `phi [a, .B] [b, .C]`, `_` is a missing
+/// source. Positional sources, and `.?` ones, are left without a pred for
+/// `IrFunction::pair_phi_sources` to fill in once the edges are known
+fn parse_phi(dest: Symbol, rest: &str) -> Result<IrInstruction> {
+    let source = |v: &str| (v != "_").then(|| Symbol::from(v));
+
+    if !rest.contains('[') {
+        let sources = rest
+            .split_whitespace()
+            .map(|v| PhiSource::new("", source(v)))
+            .collect();
+        return Ok(IrInstruction::Phi { dest, sources });
+    }
+
+    let mut sources = Vec::new();
+    for pair in rest.split(']').map(str::trim).filter(|p| !p.is_empty()) {
+        let pair = pair
+            .strip_prefix('[')
+            .ok_or_else(|| anyhow!("expected `[value, .label]`, found `{}`", pair))?;
+        let (value, label) = pair
+            .split_once(',')
+            .ok_or_else(|| anyhow!("expected `[value, .label]`, found `[{}]`", pair))?;
+        let label = label.trim();
+        let pred = if label == ".?" {
+            String::new()
+        } else {
+            label_operand(label)?
+        };
+        sources.push(PhiSource {
+            pred,
+            value: source(value.trim()),
+        });
+    }
+
+    Ok(IrInstruction::Phi { dest, sources })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SSAFormation;
+    use bril_frontend::Program;
+
+    fn ssa_module(json: &str) -> IrModule {
+        let program: Program = serde_json::from_str(json).unwrap();
+        let mut module = IrModule::try_from(&program).unwrap();
+        SSAFormation::try_from(&mut module).unwrap();
+        module
+    }
+
+    #[test]
+    fn test_parse_print_roundtrip_on_benchmarks() {
+        let benchmarks = [
+            include_str!("../../tests/add.json"),
+            include_str!("../../tests/char.json"),
+            include_str!("../../tests/condition.json"),
+            include_str!("../../tests/factorial.json"),
+            include_str!("../../tests/float.json"),
+            include_str!("../../tests/loop.json"),
+            include_str!("../../tests/palindrome.json"),
+            include_str!("../../tests/spill.json"),
+        ];
+
+        for json in benchmarks {
+            let module = ssa_module(json);
+            for func in &module.functions {
+                let printed = func.to_string();
+                let parsed = parse_function(&printed).unwrap();
+                // on its own a function's calls can't be resolved
+                let mut unresolved = func.clone();
+                for instr in unresolved.blocks.iter_mut().flat_map(|b| &mut b.instrs) {
+                    if let IrInstruction::Call { callee, .. } = instr {
+                        *callee = None;
+                    }
+                }
+                assert_eq!(parsed, unresolved, "roundtrip failed for:\n{}", printed);
+            }
+
+            let reparsed = parse_module(&module.to_string()).unwrap();
+            assert_eq!(reparsed, module);
+        }
+    }
+
+    #[test]
+    fn test_printed_add() {
+        let module = ssa_module(include_str!("../../tests/add.json"));
+        assert_eq!(
+            module.to_string(),
+            "@add_test(): int {
+.entry:  # preds: [] succs: []
+  v0: int = const 1
+  v1: int = const 1
+  v2: int = add v0 v1
+  print v2
+  ret v2
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_parse_without_edge_annotations() {
+        let text = "
+@main(n: int) {
+.entry:
+  one = const 1
+  c = lt one n
+  br c .then .exit
+.then:
+  print one
+.exit:
+  ret
+}";
+        let func = parse_function(text).unwrap();
+        assert_eq!(func.args, vec!["n".to_string()]);
+        assert_eq!(func.arg_types, vec![Type::Int]);
+        assert_eq!(func.blocks[0].succs, vec![1, 2]);
+        // .then falls through into .exit
+        assert_eq!(func.blocks[2].preds, vec![0, 1]);
+        assert_eq!(func.block_index(&"then".to_string()), Some(1));
+    }
+
+    #[test]
+    fn test_phi_sources_follow_pred_order() {
+        let text = "
+@f() {
+.a:  # preds: [] succs: [.c]
+  x = const 1
+  jmp .c
+.b:  # preds: [] succs: [.c]
+  x = const 2
+  jmp .c
+.c:  # preds: [.a, .b] succs: []
+  y = phi [x2, .b] [x1, .a]
+  ret y
+}";
+        let func = parse_function(text).unwrap();
+        match &func.blocks[2].instrs[0] {
+            IrInstruction::Phi { sources, .. } => {
+                let expected = [
+                    PhiSource::new("a", Some("x1".into())),
+                    PhiSource::new("b", Some("x2".into())),
+                ];
+                assert_eq!(sources, &expected)
+            }
+            other => panic!("expected a phi, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_shifts_round_trip() {
+        let text = "@f(x: int): int {
+.entry:  # preds: [] succs: []
+  a = shl x 3
+  b = shr a 61
+  c = sra b 1
+  ret c
+}
+";
+        assert_eq!(IrFunction::parse(text).unwrap().to_string(), text);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_function("@f() {\n  x = const 1\n}").is_err());
+        assert!(parse_function("@f() {\n.a:\n  x = addd a b\n}").is_err());
+        let err = IrFunction::parse("@f() {\n.a:\n  jmp .nowhere\n}").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unknown label .nowhere"),
+            "{:#}",
+            err
+        );
+        assert!(parse_function("@f() {\n.a:\n  ret").is_err());
+        // a shift takes a number of bits, not a variable
+        let err = IrFunction::parse("@f(x: int) {\n.a:\n  y = shl x n\n  ret\n}").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("bad shift amount `n`"),
+            "{:#}",
+            err
+        );
+    }
+}
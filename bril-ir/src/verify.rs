@@ -0,0 +1,473 @@
+//! Checks that an `IrFunction` is well formed, so a pass that breaks the CFG
+//! or SSA gets caught right after it runs instead of showing up as nonsense
+//! assembly much later.
+//!
+//! [`verify`] looks at the CFG: terminators, labels, preds/succs and phi
+//! source counts. [`verify_ssa`] also wants every variable defined once, by
+//! a def that dominates all its uses. Run after every pass, the dominators
+//! are most of the cost, [`verify_ssa_with`] takes ones already worked out.
+
+use crate::analysis::bitset::BitSet;
+use crate::cfg::{IrFunction, IrInstruction};
+use crate::ssa::DominatorInfo;
+use crate::BlockID;
+use bril_frontend::Symbol;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+
+/// One broken invariant, in one block
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub func: String,
+    pub block: BlockID,
+    /// Index into the block's `instrs`, when it's down to one instruction
+    pub instr: Option<usize>,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyErrorKind {
+    /// The last block has to end the function itself, there's nothing to
+    /// fall through to. Void functions get a `ret` added when the CFG is
+    /// built, so it's one returning a value that falls off the end
+    MissingTerminator,
+    TerminatorMidBlock,
+    UnknownLabel(String),
+    /// `succs` isn't what the terminator (or falling through) says
+    WrongSuccs {
+        expected: Vec<BlockID>,
+        found: Vec<BlockID>,
+    },
+    /// A block index in `preds`/`succs` past the end of the function
+    NoSuchBlock(BlockID),
+    /// `to` is in our succs, but we're not in its preds
+    MissingPred {
+        to: BlockID,
+    },
+    /// `from` is in our preds, but we're not in its succs
+    MissingSucc {
+        from: BlockID,
+    },
+    PhiSourceCount {
+        sources: usize,
+        preds: usize,
+    },
+    /// A phi source for a block that isn't a pred
+    PhiSourceNotPred(String),
+    /// SSA only, from here down
+    Unreachable,
+    MultipleDefs(Symbol),
+    Undefined(Symbol),
+    NotDominated(Symbol),
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyErrorKind::MissingTerminator => {
+                write!(f, "last block doesn't end in br, jmp or ret")
+            }
+            VerifyErrorKind::TerminatorMidBlock => {
+                write!(f, "terminator before the end of the block")
+            }
+            VerifyErrorKind::UnknownLabel(label) => write!(f, "unknown label .{}", label),
+            VerifyErrorKind::WrongSuccs { expected, found } => {
+                write!(
+                    f,
+                    "succs are {:?}, the terminator says {:?}",
+                    found, expected
+                )
+            }
+            VerifyErrorKind::NoSuchBlock(b) => {
+                write!(f, "edge to block {}, which doesn't exist", b)
+            }
+            VerifyErrorKind::MissingPred { to } => {
+                write!(
+                    f,
+                    "block {} is a succ but doesn't have this one as a pred",
+                    to
+                )
+            }
+            VerifyErrorKind::MissingSucc { from } => {
+                write!(
+                    f,
+                    "block {} is a pred but doesn't have this one as a succ",
+                    from
+                )
+            }
+            VerifyErrorKind::PhiSourceCount { sources, preds } => {
+                write!(f, "phi has {} sources for {} preds", sources, preds)
+            }
+            VerifyErrorKind::PhiSourceNotPred(label) => {
+                write!(f, "phi has a source for .{}, which isn't a pred", label)
+            }
+            VerifyErrorKind::Unreachable => write!(f, "block can't be reached from the entry"),
+            VerifyErrorKind::MultipleDefs(var) => write!(f, "`{}` is defined more than once", var),
+            VerifyErrorKind::Undefined(var) => write!(f, "`{}` is never defined", var),
+            VerifyErrorKind::NotDominated(var) => {
+                write!(f, "`{}` is used where its def doesn't dominate", var)
+            }
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}: block {}", self.func, self.block)?;
+        if let Some(i) = self.instr {
+            write!(f, "[{}]", i)?;
+        }
+        write!(f, ": {}", self.kind)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Check the CFG of `func`, every problem found rather than just the first
+pub fn verify(func: &IrFunction) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::new();
+    check_cfg(func, &mut errors);
+    into_result(errors)
+}
+
+/// [`verify`], and on top of it that `func` really is in SSA form
+pub fn verify_ssa(func: &IrFunction) -> Result<(), Vec<VerifyError>> {
+    let mut doms = None;
+    let doms = &mut doms;
+    verify_ssa_with(func, move || {
+        doms.insert(DominatorInfo::compute(func)).as_ref().ok()
+    })
+}
+
+/// [`verify_ssa`] with the dominators of `func` from `dominators`, for a
+/// caller that keeps them anyway. Only asked for once the CFG checks out,
+/// None when they can't be worked out
+pub fn verify_ssa_with<'a>(
+    func: &IrFunction,
+    dominators: impl FnOnce() -> Option<&'a DominatorInfo>,
+) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::new();
+    check_cfg(func, &mut errors);
+    // the dominance check trusts the edges, no point with broken ones
+    if errors.is_empty() {
+        check_ssa(func, dominators, &mut errors);
+    }
+    into_result(errors)
+}
+
+fn into_result(errors: Vec<VerifyError>) -> Result<(), Vec<VerifyError>> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn check_cfg(func: &IrFunction, errors: &mut Vec<VerifyError>) {
+    let n = func.blocks.len();
+    let mut error = |block, instr, kind| {
+        errors.push(VerifyError {
+            func: func.name.clone(),
+            block,
+            instr,
+            kind,
+        })
+    };
+
+    for (b, block) in func.blocks.iter().enumerate() {
+        let last = block.instrs.len().checked_sub(1);
+        for (i, instr) in block.instrs.iter().enumerate() {
+            if instr.is_terminator() && Some(i) != last {
+                error(b, Some(i), VerifyErrorKind::TerminatorMidBlock);
+            }
+        }
+
+        // where the end of the block says control goes
+        let mut expected = match block.instrs.last() {
+            Some(IrInstruction::Br {
+                then_lbl, else_lbl, ..
+            }) => vec![then_lbl, else_lbl],
+            Some(IrInstruction::Jmp { label }) => vec![label],
+            Some(IrInstruction::Ret { .. }) => Vec::new(),
+            // no terminator falls through, which the last block can't
+            _ if b + 1 < n => {
+                let next = &func.blocks[b + 1].label;
+                vec![next]
+            }
+            _ => {
+                error(b, None, VerifyErrorKind::MissingTerminator);
+                Vec::new()
+            }
+        }
+        .into_iter()
+        .filter_map(|label| {
+            let idx = func.block_index(label);
+            if idx.is_none() {
+                error(b, last, VerifyErrorKind::UnknownLabel(label.clone()));
+            }
+            idx
+        })
+        .collect::<Vec<_>>();
+        expected.sort_unstable();
+        expected.dedup();
+        let mut found = block.succs.clone();
+        found.sort_unstable();
+        found.dedup();
+        if expected != found {
+            error(b, None, VerifyErrorKind::WrongSuccs { expected, found });
+        }
+
+        for &s in &block.succs {
+            match func.blocks.get(s) {
+                None => error(b, None, VerifyErrorKind::NoSuchBlock(s)),
+                Some(succ) if !succ.preds.contains(&b) => {
+                    error(b, None, VerifyErrorKind::MissingPred { to: s })
+                }
+                Some(_) => {}
+            }
+        }
+        for &p in &block.preds {
+            match func.blocks.get(p) {
+                None => error(b, None, VerifyErrorKind::NoSuchBlock(p)),
+                Some(pred) if !pred.succs.contains(&b) => {
+                    error(b, None, VerifyErrorKind::MissingSucc { from: p })
+                }
+                Some(_) => {}
+            }
+        }
+
+        let pred_labels: Vec<&str> = block
+            .preds
+            .iter()
+            .filter_map(|&p| func.blocks.get(p))
+            .map(|p| p.label.as_str())
+            .collect();
+        for (i, instr) in block.instrs.iter().enumerate() {
+            let IrInstruction::Phi { sources, .. } = instr else {
+                continue;
+            };
+            if sources.len() != block.preds.len() {
+                let kind = VerifyErrorKind::PhiSourceCount {
+                    sources: sources.len(),
+                    preds: block.preds.len(),
+                };
+                error(b, Some(i), kind);
+            }
+            for src in sources.iter().filter(|s| !pred_labels.contains(&&*s.pred)) {
+                error(
+                    b,
+                    Some(i),
+                    VerifyErrorKind::PhiSourceNotPred(src.pred.clone()),
+                );
+            }
+        }
+    }
+}
+
+fn check_ssa<'a>(
+    func: &IrFunction,
+    dominators: impl FnOnce() -> Option<&'a DominatorInfo>,
+    errors: &mut Vec<VerifyError>,
+) {
+    let mut error = |block, instr, kind| {
+        errors.push(VerifyError {
+            func: func.name.clone(),
+            block,
+            instr,
+            kind,
+        })
+    };
+
+    let mut reachable = BitSet::new(func.blocks.len());
+    for b in func.reverse_postorder() {
+        reachable.insert(b);
+    }
+    for b in (0..func.blocks.len()).filter(|&b| !reachable.contains(b)) {
+        error(b, None, VerifyErrorKind::Unreachable);
+    }
+    let Some(doms) = dominators() else {
+        return;
+    };
+
+    // the args are defined before the entry's first instruction, at -1 so
+    // to speak. `None` stands for that
+    let mut def_at: HashMap<Symbol, (BlockID, Option<usize>)> = HashMap::new();
+    for &arg in &func.args {
+        def_at.insert(arg, (0, None));
+    }
+    for (b, block) in func.blocks.iter().enumerate() {
+        for (i, instr) in block.instrs.iter().enumerate() {
+            for &var in instr.defs() {
+                // the first def stands, the uses are checked against it
+                match def_at.entry(var) {
+                    Entry::Occupied(_) => error(b, Some(i), VerifyErrorKind::MultipleDefs(var)),
+                    Entry::Vacant(e) => {
+                        e.insert((b, Some(i)));
+                    }
+                }
+            }
+        }
+    }
+
+    // whether the def of `var` comes before instruction `at` of block `b`,
+    // `at` being None for the very end of the block
+    let available = |var: Symbol, b: BlockID, at: Option<usize>| {
+        let Some(&(def_b, def_i)) = def_at.get(&var) else {
+            return false;
+        };
+        if def_b != b {
+            return doms.strictly_dominates(def_b, b);
+        }
+        match (def_i, at) {
+            (None, _) => true,
+            (Some(_), None) => true,
+            (Some(d), Some(u)) => d < u,
+        }
+    };
+
+    for (b, block) in func.blocks.iter().enumerate() {
+        for (i, instr) in block.instrs.iter().enumerate() {
+            // a phi's source is read at the end of the pred it comes from
+            let uses: Vec<(Symbol, BlockID, Option<usize>)> = match instr {
+                IrInstruction::Phi { sources, .. } => sources
+                    .iter()
+                    .filter_map(|src| Some((src.value?, func.block_index(&src.pred)?, None)))
+                    .collect(),
+                _ => instr.uses().into_iter().map(|v| (v, b, Some(i))).collect(),
+            };
+            for (var, at_block, at) in uses {
+                if !def_at.contains_key(&var) {
+                    error(b, Some(i), VerifyErrorKind::Undefined(var));
+                } else if !available(var, at_block, at) {
+                    error(b, Some(i), VerifyErrorKind::NotDominated(var));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bril_frontend::Literal;
+
+    fn kinds(func: &IrFunction, ssa: bool) -> Vec<VerifyErrorKind> {
+        let result = if ssa { verify_ssa(func) } else { verify(func) };
+        result.unwrap_err().into_iter().map(|e| e.kind).collect()
+    }
+
+    fn parse(src: &str) -> IrFunction {
+        IrFunction::parse(src).unwrap()
+    }
+
+    const LOOP: &str = "@main(n: int) {
+.entry:
+  i0 = const 0
+  jmp .head
+.head:
+  i1 = phi [i0, .entry] [i2, .body]
+  c = lt i1 n
+  br c .body .done
+.body:
+  one = const 1
+  i2 = add i1 one
+  jmp .head
+.done:
+  print i1
+  ret
+}";
+
+    #[test]
+    fn test_well_formed_functions_pass() {
+        let func = parse(LOOP);
+        verify(&func).unwrap();
+        verify_ssa(&func).unwrap();
+    }
+
+    #[test]
+    fn test_broken_cfgs_are_caught() {
+        // a ret halfway through, and nothing at the end
+        let mut func = parse(LOOP);
+        let done = func.blocks.len() - 1;
+        func.blocks[done].instrs.swap(0, 1);
+        assert_eq!(
+            kinds(&func, false),
+            [
+                VerifyErrorKind::TerminatorMidBlock,
+                VerifyErrorKind::MissingTerminator
+            ]
+        );
+
+        // a jmp to a label that went away
+        let mut func = parse(LOOP);
+        func.blocks[2].instrs[2] = IrInstruction::Jmp {
+            label: "gone".to_string(),
+        };
+        assert_eq!(
+            kinds(&func, false),
+            [
+                VerifyErrorKind::UnknownLabel("gone".to_string()),
+                VerifyErrorKind::WrongSuccs {
+                    expected: Vec::new(),
+                    found: vec![1]
+                }
+            ]
+        );
+
+        // an edge only one side knows about
+        let mut func = parse(LOOP);
+        func.blocks[1].preds.retain(|&p| p != 2);
+        let errors = kinds(&func, false);
+        assert!(errors.contains(&VerifyErrorKind::MissingPred { to: 1 }));
+        assert!(errors.contains(&VerifyErrorKind::PhiSourceCount {
+            sources: 2,
+            preds: 1
+        }));
+        assert!(errors.contains(&VerifyErrorKind::PhiSourceNotPred("body".to_string())));
+    }
+
+    #[test]
+    fn test_broken_ssa_is_caught() {
+        // i2 used at the top of the body, before it's defined
+        let mut func = parse(LOOP);
+        func.blocks[2].instrs.swap(0, 1);
+        func.blocks[2].instrs[0] = IrInstruction::Add {
+            dest: "i2".into(),
+            lhs: "i2".into(),
+            rhs: "one".into(),
+        };
+        verify(&func).unwrap();
+        assert_eq!(
+            kinds(&func, true),
+            [
+                VerifyErrorKind::NotDominated("i2".into()),
+                VerifyErrorKind::NotDominated("one".into())
+            ]
+        );
+
+        // the body's def only reaches .done around the loop's back edge
+        let mut func = parse(LOOP);
+        let done = func.blocks.len() - 1;
+        func.blocks[done].instrs[0] = IrInstruction::Print {
+            values: vec!["i2".into()],
+        };
+        assert_eq!(
+            kinds(&func, true),
+            [VerifyErrorKind::NotDominated("i2".into())]
+        );
+
+        let mut func = parse(LOOP);
+        func.blocks[2].instrs[0] = IrInstruction::Const {
+            dest: "i0".into(),
+            value: Literal::Int(1),
+        };
+        assert_eq!(
+            kinds(&func, true),
+            [
+                VerifyErrorKind::MultipleDefs("i0".into()),
+                VerifyErrorKind::Undefined("one".into())
+            ]
+        );
+    }
+}
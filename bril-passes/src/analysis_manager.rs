@@ -0,0 +1,223 @@
+//! Analyses kept between passes, so the next pass that asks for one gets it
+//! without working it out again.
+//!
+//! Each result is kept per function, by name, until a pass changes that
+//! function without saying it preserves it. The call graph is one for the
+//! whole module and goes whenever any function changes without preserving
+//! it. The effects of the functions are one for the module too, but only
+//! go when a module pass changes it: only a module pass can give a function
+//! effects it didn't have, what a function pass takes out leaves the ones
+//! kept saying a function does more than it does, which is still safe to
+//! go on.
+
+use anyhow::Result;
+use bril_ir::analysis::callgraph::CallGraph;
+use bril_ir::analysis::effects::Effects;
+use bril_ir::analysis::liveness::{self, Liveness};
+use bril_ir::analysis::loops::{self, LoopInfo};
+use bril_ir::{DominatorInfo, IrFunction, IrModule, SSAFormation, SsaStyle};
+use std::collections::HashMap;
+
+/// The analyses an [`AnalysisManager`] keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Analysis {
+    Dominators,
+    Liveness,
+    Loops,
+    CallGraph,
+    Effects,
+}
+
+impl Analysis {
+    /// Everything worked out from the CFG and the calls alone, still right
+    /// after a pass that only rewrites instructions in place
+    pub const CFG: &'static [Analysis] =
+        &[Analysis::Dominators, Analysis::Loops, Analysis::CallGraph];
+}
+
+// what's been worked out for one function
+#[derive(Default)]
+struct Slots {
+    dominators: Option<DominatorInfo>,
+    liveness: Option<Liveness>,
+    loops: Option<LoopInfo>,
+}
+
+#[derive(Default)]
+pub struct AnalysisManager {
+    functions: HashMap<String, Slots>,
+    call_graph: Option<CallGraph>,
+    effects: Option<Effects>,
+    // times each analysis was worked out, cached ones aren't counted again
+    computed: HashMap<Analysis, usize>,
+}
+
+impl AnalysisManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The dominators of `func`, worked out now unless they're still kept
+    pub fn dominators(&mut self, func: &IrFunction) -> Result<&DominatorInfo> {
+        if self.slots(func).dominators.is_none() {
+            let info = DominatorInfo::compute(func)?;
+            self.count(Analysis::Dominators);
+            self.slots(func).dominators = Some(info);
+        }
+        Ok(self.slots(func).dominators.as_ref().unwrap())
+    }
+
+    /// The liveness of `func`, worked out now unless it's still kept
+    pub fn liveness(&mut self, func: &IrFunction) -> &Liveness {
+        if self.slots(func).liveness.is_none() {
+            let live = liveness::compute(func);
+            self.count(Analysis::Liveness);
+            self.slots(func).liveness = Some(live);
+        }
+        self.slots(func).liveness.as_ref().unwrap()
+    }
+
+    /// The loops of `func`, worked out now unless they're still kept. Takes
+    /// the dominators the same way
+    pub fn loops(&mut self, func: &IrFunction) -> Result<&LoopInfo> {
+        if self.slots(func).loops.is_none() {
+            let info = loops::compute(func, self.dominators(func)?);
+            self.count(Analysis::Loops);
+            self.slots(func).loops = Some(info);
+        }
+        Ok(self.slots(func).loops.as_ref().unwrap())
+    }
+
+    /// Who calls whom in `module`, worked out now unless it's still kept
+    pub fn call_graph(&mut self, module: &IrModule) -> &CallGraph {
+        if self.call_graph.is_none() {
+            self.call_graph = Some(CallGraph::build(module));
+            self.count(Analysis::CallGraph);
+        }
+        self.call_graph.as_ref().unwrap()
+    }
+
+    /// What calling each function in `module` can do, worked out now
+    /// unless it's still kept. Takes the call graph the same way
+    pub fn effects(&mut self, module: &IrModule) -> &Effects {
+        if self.effects.is_none() {
+            let effects = Effects::compute(module, self.call_graph(module));
+            self.count(Analysis::Effects);
+            self.effects = Some(effects);
+        }
+        self.effects.as_ref().unwrap()
+    }
+
+    /// The effects, if they're kept. A function pass has no module to work
+    /// them out from, the [`crate::PassManager`] does that before it runs
+    pub fn kept_effects(&self) -> Option<&Effects> {
+        self.effects.as_ref()
+    }
+
+    /// Put `funcs` into SSA (see [`SSAFormation::with_dominators`]) with
+    /// the dominators kept here, working out and keeping the ones missing.
+    /// Their liveness is dropped, the names changed
+    pub fn build_ssa(&mut self, funcs: &mut [IrFunction], style: SsaStyle) -> Result<SSAFormation> {
+        let ssa =
+            SSAFormation::with_dominators(funcs, style, |func| self.dominators(func).cloned())?;
+        for func in funcs.iter() {
+            self.invalidate(func, Analysis::CFG);
+        }
+        Ok(ssa)
+    }
+
+    /// `func` changed: drop what's kept for it other than `preserved`
+    pub fn invalidate(&mut self, func: &IrFunction, preserved: &[Analysis]) {
+        if let Some(slots) = self.functions.get_mut(&func.name) {
+            slots.drop_unless(preserved);
+        }
+        if !preserved.contains(&Analysis::CallGraph) {
+            self.call_graph = None;
+        }
+    }
+
+    /// The module changed: drop what's kept for every function other than
+    /// `preserved`, and everything for the functions it no longer has
+    pub fn invalidate_module(&mut self, module: &IrModule, preserved: &[Analysis]) {
+        self.functions
+            .retain(|name, _| module.functions.iter().any(|f| f.name == *name));
+        for slots in self.functions.values_mut() {
+            slots.drop_unless(preserved);
+        }
+        if !preserved.contains(&Analysis::CallGraph) {
+            self.call_graph = None;
+        }
+        if !preserved.contains(&Analysis::Effects) {
+            self.effects = None;
+        }
+    }
+
+    /// Drop everything kept, the counts stay
+    pub fn clear(&mut self) {
+        self.functions.clear();
+        self.call_graph = None;
+        self.effects = None;
+    }
+
+    /// How many times `analysis` was worked out, over every function
+    pub fn computed(&self, analysis: Analysis) -> usize {
+        self.computed.get(&analysis).copied().unwrap_or(0)
+    }
+
+    fn slots(&mut self, func: &IrFunction) -> &mut Slots {
+        self.functions.entry(func.name.clone()).or_default()
+    }
+
+    fn count(&mut self, analysis: Analysis) {
+        *self.computed.entry(analysis).or_default() += 1;
+    }
+}
+
+impl Slots {
+    fn drop_unless(&mut self, preserved: &[Analysis]) {
+        if !preserved.contains(&Analysis::Dominators) {
+            self.dominators = None;
+        }
+        if !preserved.contains(&Analysis::Liveness) {
+            self.liveness = None;
+        }
+        // loops are found from the dominators, they go with them
+        if !preserved.contains(&Analysis::Loops) || self.dominators.is_none() {
+            self.loops = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ssa_leaves_its_dominators_behind() {
+        let mut funcs = [IrFunction::parse(
+            "@main(c: bool) {
+.entry:
+  x = const 1
+  br c .then .join
+.then:
+  x = const 2
+  jmp .join
+.join:
+  print x
+  ret
+}",
+        )
+        .unwrap()];
+        let mut am = AnalysisManager::new();
+        am.liveness(&funcs[0]);
+        let ssa = am.build_ssa(&mut funcs, SsaStyle::Minimal).unwrap();
+        assert!(ssa.for_function("main").is_some());
+        assert!(funcs[0].to_string().contains("phi"));
+
+        // the same dominators, the liveness has to be worked out again
+        am.loops(&funcs[0]).unwrap();
+        am.liveness(&funcs[0]);
+        assert_eq!(am.computed(Analysis::Dominators), 1);
+        assert_eq!(am.computed(Analysis::Liveness), 2);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::analysis_manager::{Analysis, AnalysisManager};
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_ir::{BlockID, IrFunction};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+/// What an [`AnalysisPrinterPass`] prints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Printed {
+    /// Each block's immediate dominator, the blocks it immediately
+    /// dominates, and its dominance frontier
+    Dominators,
+    /// The variables live into and out of each block
+    Liveness,
+}
+
+/// Prints an analysis of every function it runs on, changing nothing
+///
+/// The analysis comes from the [`AnalysisManager`], so it's the one the
+/// passes around it see. One line per block, by block index, with the
+/// blocks and variables on it sorted, so the output only changes when the
+/// analysis does. In a pipeline it's `print-doms` or `print-liveness`,
+/// printing to stderr every time round
+pub struct AnalysisPrinterPass {
+    printed: Printed,
+    sink: Box<dyn Write>,
+}
+
+impl AnalysisPrinterPass {
+    /// Printing to stderr
+    pub fn new(printed: Printed) -> Self {
+        Self::with_sink(printed, io::stderr())
+    }
+
+    pub fn with_sink<W: Write + 'static>(printed: Printed, sink: W) -> Self {
+        Self {
+            printed,
+            sink: Box::new(sink),
+        }
+    }
+}
+
+impl FunctionPass for AnalysisPrinterPass {
+    fn name(&self) -> &str {
+        match self.printed {
+            Printed::Dominators => "DominatorPrinterPass",
+            Printed::Liveness => "LivenessPrinterPass",
+        }
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        self.run_with_analyses(function, &mut AnalysisManager::new(), stats)
+    }
+
+    fn run_with_analyses(
+        &mut self,
+        function: &mut IrFunction,
+        analyses: &mut AnalysisManager,
+        _stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let text = match self.printed {
+            Printed::Dominators => print_dominators(function, analyses)?,
+            Printed::Liveness => print_liveness(function, analyses),
+        };
+        self.sink
+            .write_all(text.as_bytes())
+            .map_err(|e| PassError::Output {
+                detail: e.to_string(),
+            })?;
+        Ok(false)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        &[
+            Analysis::Dominators,
+            Analysis::Liveness,
+            Analysis::Loops,
+            Analysis::CallGraph,
+            Analysis::Effects,
+        ]
+    }
+}
+
+fn print_dominators(
+    func: &IrFunction,
+    analyses: &mut AnalysisManager,
+) -> Result<String, PassError> {
+    let doms = analyses
+        .dominators(func)
+        .map_err(|e| PassError::MalformedIR {
+            function: func.name.clone(),
+            detail: e.to_string(),
+        })?;
+    let mut text = format!("; *** dominators of @{} ***\n", func.name);
+    for b in 0..func.blocks.len() {
+        // the entry is its own idom
+        let idom = match doms.idom.get(&b) {
+            Some(&d) if d != b => label(func, d),
+            _ => "-".to_string(),
+        };
+        let children = doms.dom_tree.get(&b).map_or(&[][..], |c| &c[..]);
+        let frontier = doms.dom_frontier.get(&b).map_or(&[][..], |f| &f[..]);
+        let _ = writeln!(
+            text,
+            "{}: idom {}, dominates [{}], frontier [{}]",
+            label(func, b),
+            idom,
+            labels(func, children),
+            labels(func, frontier)
+        );
+    }
+    Ok(text)
+}
+
+fn print_liveness(func: &IrFunction, analyses: &mut AnalysisManager) -> String {
+    let live = analyses.liveness(func);
+    let mut text = format!("; *** liveness of @{} ***\n", func.name);
+    let sorted = |vars: &std::collections::HashSet<bril_frontend::Symbol>| {
+        let mut vars: Vec<&str> = vars.iter().map(|v| v.as_ref()).collect();
+        vars.sort_unstable();
+        vars.join(", ")
+    };
+    for b in 0..func.blocks.len() {
+        let _ = writeln!(
+            text,
+            "{}: in [{}], out [{}]",
+            label(func, b),
+            sorted(&live.live_in[b]),
+            sorted(&live.live_out[b])
+        );
+    }
+    text
+}
+
+fn label(func: &IrFunction, b: BlockID) -> String {
+    format!(".{}", func.blocks[b].label)
+}
+
+/// `blocks` by index, as labels
+fn labels(func: &IrFunction, blocks: &[BlockID]) -> String {
+    let mut blocks = blocks.to_vec();
+    blocks.sort_unstable();
+    blocks.dedup();
+    let labels: Vec<String> = blocks.iter().map(|&b| label(func, b)).collect();
+    labels.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PassManager;
+    use bril_ir::IrModule;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // a sink the test can still read once the pass has it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_prints_both_analyses_and_changes_nothing() {
+        let func = IrFunction::parse(
+            "@main(n: int) {
+.entry:
+  zero = const 0
+  one = const 1
+  jmp .head
+.head:
+  i = phi [zero, .entry] [i2, .body]
+  c = lt i n
+  br c .body .exit
+.body:
+  print i
+  i2 = add i one
+  jmp .head
+.exit:
+  ret
+}",
+        )
+        .unwrap();
+        let mut module = IrModule {
+            functions: vec![func.clone()],
+        };
+        let sink = Shared::default();
+        let mut pm = PassManager::new();
+        pm.add_pass(AnalysisPrinterPass::with_sink(
+            Printed::Dominators,
+            sink.clone(),
+        ));
+        pm.add_pass(AnalysisPrinterPass::with_sink(
+            Printed::Liveness,
+            sink.clone(),
+        ));
+        let summary = pm.run(&mut module).unwrap();
+        assert_eq!(summary.iterations, 1);
+        assert_eq!(module.functions[0], func);
+
+        let text = String::from_utf8(sink.0.borrow().clone()).unwrap();
+        assert_eq!(
+            text,
+            "; *** dominators of @main ***
+.entry: idom -, dominates [.head], frontier []
+.head: idom .entry, dominates [.body, .exit], frontier [.head]
+.body: idom .head, dominates [], frontier [.head]
+.exit: idom .head, dominates [], frontier []
+; *** liveness of @main ***
+.entry: in [n], out [n, one, zero]
+.head: in [i, n, one], out [i, n, one]
+.body: in [i, n, one], out [i2, n, one]
+.exit: in [], out []
+"
+        );
+    }
+}
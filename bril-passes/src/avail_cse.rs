@@ -0,0 +1,251 @@
+use crate::analysis_manager::Analysis;
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::Symbol;
+use bril_ir::analysis::available::{self, AvailableExprs, ExprId};
+use bril_ir::{BlockID, IrFunction, IrInstruction, PhiSource};
+use std::collections::HashMap;
+
+/// Common subexpressions by availability, on SSA
+///
+/// What [`GvnPass`](crate::GvnPass) can't see: an expression every path to
+/// an instruction has already computed, though no one block that does
+/// dominates it, e.g. `a + b` in both arms of a diamond and again at the
+/// join. The instruction becomes an `id` of the value the paths bring in,
+/// with a phi where they meet to pick the one each came with. Those phis
+/// are new, so the pass is left out of the `-O` pipelines and only runs
+/// when `--passes` names it
+pub struct AvailCsePass {}
+
+impl FunctionPass for AvailCsePass {
+    fn name(&self) -> &str {
+        "AvailCsePass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        // names only hold one value in SSA, nothing would ever kill an
+        // expression otherwise
+        if bril_ir::verify_ssa(function).is_err() || function.blocks.is_empty() {
+            return Ok(false);
+        }
+        let avail = available::compute(function);
+        let mut cse = Cse {
+            avail: &avail,
+            last_in: last_computed(function, &avail),
+            phis: HashMap::new(),
+            pending: Vec::new(),
+        };
+        let redundant = cse.find_redundant(function);
+        if redundant.is_empty() {
+            return Ok(false);
+        }
+
+        for &(b, i, value) in &redundant {
+            let block = &mut function.blocks[b];
+            let [dest] = block.instrs[i].defs() else {
+                continue;
+            };
+            block.instrs[i] = IrInstruction::Assign {
+                lhs: *dest,
+                rhs: value,
+            };
+        }
+        let phis = cse.pending.len();
+        for (b, dest, sources) in cse.pending {
+            function.blocks[b].insert_instr(0, IrInstruction::Phi { dest, sources }, None, None);
+        }
+        stats.add("redundant expressions", redundant.len());
+        stats.add("phis inserted", phis);
+        Ok(true)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        Analysis::CFG
+    }
+}
+
+/// Per block, the name the last instruction computing each expression
+/// there defines
+fn last_computed(func: &IrFunction, avail: &AvailableExprs) -> Vec<HashMap<ExprId, Symbol>> {
+    func.blocks
+        .iter()
+        .map(|block| {
+            let mut last = HashMap::new();
+            for instr in &block.instrs {
+                if let (Some(id), [dest]) = (avail.id_of(instr), instr.defs()) {
+                    last.insert(id, *dest);
+                }
+            }
+            last
+        })
+        .collect()
+}
+
+struct Cse<'a> {
+    avail: &'a AvailableExprs,
+    last_in: Vec<HashMap<ExprId, Symbol>>,
+    // the name holding an expression at the top of a block where a phi
+    // had to pick it, and whether anything read it yet
+    phis: HashMap<(BlockID, ExprId), (Symbol, bool)>,
+    // phis to put in once the walk is done, so no index moves under it
+    pending: Vec<(BlockID, Symbol, Vec<PhiSource>)>,
+}
+
+impl Cse<'_> {
+    /// Every (block, instr) computing an expression already available
+    /// there, with the name it can read instead
+    fn find_redundant(&mut self, func: &mut IrFunction) -> Vec<(BlockID, usize, Symbol)> {
+        let mut redundant = Vec::new();
+        for b in func.reverse_postorder() {
+            let mut here: HashMap<ExprId, Symbol> = HashMap::new();
+            for i in 0..func.blocks[b].instrs.len() {
+                let instr = &func.blocks[b].instrs[i];
+                let (Some(id), &[dest]) = (self.avail.id_of(instr), instr.defs()) else {
+                    continue;
+                };
+                let value = match here.get(&id) {
+                    Some(&value) => Some(value),
+                    None if self.avail.avail_in[b].contains(id.0) => {
+                        Some(self.at_top(func, b, id, dest))
+                    }
+                    None => None,
+                };
+                match value {
+                    Some(value) => redundant.push((b, i, value)),
+                    None => {
+                        here.insert(id, dest);
+                    }
+                }
+            }
+        }
+        redundant
+    }
+
+    /// The name holding `id` at the top of `b`, where it's available.
+    /// `like` is a name the expression is computed into, for the phi's
+    /// name and type
+    fn at_top(&mut self, func: &mut IrFunction, b: BlockID, id: ExprId, like: Symbol) -> Symbol {
+        if let Some((name, read)) = self.phis.get_mut(&(b, id)) {
+            *read = true;
+            return *name;
+        }
+        let preds = func.blocks[b].preds.clone();
+        if let [p] = preds[..] {
+            return self.at_bottom(func, p, id, like);
+        }
+
+        // declared before the preds are asked, a loop brings them back here
+        let dest = func.fresh_var(like.as_ref());
+        if let Some(typ) = func.types.get(&like).cloned() {
+            func.types.insert(dest, typ);
+        }
+        self.phis.insert((b, id), (dest, false));
+        let values: Vec<Symbol> = preds
+            .iter()
+            .map(|&p| self.at_bottom(func, p, id, like))
+            .collect();
+
+        // one value from every side needs no phi, if nothing's read it
+        if !self.phis[&(b, id)].1 && values.iter().all(|&v| v == values[0]) {
+            func.types.remove(&dest);
+            self.phis.insert((b, id), (values[0], false));
+            return values[0];
+        }
+        let sources = preds
+            .iter()
+            .zip(values)
+            .map(|(&p, value)| PhiSource::new(&func.blocks[p].label, Some(value)))
+            .collect();
+        self.pending.push((b, dest, sources));
+        dest
+    }
+
+    /// The name holding `id` at the bottom of `b`, where it's available
+    fn at_bottom(&mut self, func: &mut IrFunction, b: BlockID, id: ExprId, like: Symbol) -> Symbol {
+        match self.last_in[b].get(&id) {
+            Some(&name) => name,
+            None => self.at_top(func, b, id, like),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_lines(func: &IrFunction, label: &str) -> Vec<String> {
+        let b = func.block_index(&label.to_string()).unwrap();
+        func.blocks[b]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    fn cse(src: &str) -> IrFunction {
+        let mut func = IrFunction::parse(src).unwrap();
+        AvailCsePass {}
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap();
+        bril_ir::verify_ssa(&func).unwrap();
+        func
+    }
+
+    #[test]
+    fn test_join_reuses_what_both_arms_computed() {
+        let func = cse("@main(a: int, b: int, c: bool) {
+.entry:
+  br c .left .right
+.left:
+  x = add a b
+  print x
+  jmp .join
+.right:
+  y = add b a
+  jmp .join
+.join:
+  z = add a b
+  print z
+  ret
+}");
+        assert_eq!(
+            block_lines(&func, "join"),
+            ["z.1 = phi x y", "z = id z.1", "print z", "ret"]
+        );
+        // the arms still compute their own, neither is redundant
+        assert_eq!(block_lines(&func, "left")[0], "x = add a b");
+        assert_eq!(block_lines(&func, "right")[0], "y = add b a");
+    }
+
+    #[test]
+    fn test_one_arm_alone_is_not_enough() {
+        let func = cse("@main(a: int, b: int, c: bool) {
+.entry:
+  s = mul a b
+  br c .left .right
+.left:
+  x = add a b
+  jmp .join
+.right:
+  jmp .join
+.join:
+  z = add a b
+  t = mul a b
+  print z t
+  ret
+}");
+        assert_eq!(
+            block_lines(&func, "join"),
+            [
+                "z = add a b",
+                // the same name comes in from both sides, no phi for it
+                "t = id s",
+                "print z t",
+                "ret"
+            ]
+        );
+    }
+}
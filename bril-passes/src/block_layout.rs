@@ -0,0 +1,214 @@
+use crate::analysis_manager::AnalysisManager;
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_ir::analysis::loops::LoopInfo;
+use bril_ir::{BlockID, IrFunction, IrInstruction};
+
+/// Block Layout
+///
+/// Puts the blocks in an order where as many as can go on into the block
+/// after them, so the emitter has no jump to write there. From the entry,
+/// each block is followed by the block it goes to, the `then` side of a
+/// `br` over the `else`, since a `br` is a branch to the `else` and a jump
+/// to the `then`. When that block is already placed a new run starts at
+/// what the block placed last leads to. A block doesn't go on out of a
+/// loop until every block of the loop has a place, so a loop's blocks stay
+/// together. Only the order changes, and it only depends on the CFG, so a
+/// second run leaves it as it is
+pub struct BlockLayoutPass {}
+
+impl FunctionPass for BlockLayoutPass {
+    fn name(&self) -> &str {
+        "BlockLayoutPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        self.run_with_analyses(function, &mut AnalysisManager::new(), stats)
+    }
+
+    fn run_with_analyses(
+        &mut self,
+        function: &mut IrFunction,
+        analyses: &mut AnalysisManager,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        if function.blocks.is_empty() {
+            return Ok(false);
+        }
+        let loops = analyses
+            .loops(function)
+            .map_err(|e| PassError::MalformedIR {
+                function: function.name.clone(),
+                detail: e.to_string(),
+            })?;
+        let order = layout(function, loops);
+        let moved = order.iter().enumerate().filter(|&(i, &b)| i != b).count();
+        if moved == 0 {
+            return Ok(false);
+        }
+        function.reorder_blocks(&order);
+        stats.add("blocks moved", moved);
+        Ok(true)
+    }
+}
+
+/// The order to put the blocks of `func` in, the ones the entry can't get
+/// to last as they were
+fn layout(func: &IrFunction, loops: &LoopInfo) -> Vec<BlockID> {
+    let n = func.blocks.len();
+    let mut placed = vec![false; n];
+    let mut order = Vec::with_capacity(n);
+    let mut next = Some(0);
+    while let Some(start) = next {
+        let mut b = start;
+        loop {
+            placed[b] = true;
+            order.push(b);
+            let Some(s) = succ_to_place(func, loops, &placed, b) else {
+                break;
+            };
+            b = s;
+        }
+        next = order
+            .iter()
+            .rev()
+            .find_map(|&b| succ_to_place(func, loops, &placed, b))
+            .or_else(|| {
+                // out of a loop after all, one of its blocks only the rest
+                // of the loop leads to is yet to go
+                order.iter().rev().find_map(|&b| {
+                    successors(func, b)
+                        .into_iter()
+                        .find(|&s| s != 0 && !placed[s])
+                })
+            });
+    }
+    order.extend((0..n).filter(|&b| !placed[b]));
+    order
+}
+
+/// The first block `b` goes to that has no place yet and is still in every
+/// loop `b` is in that does
+fn succ_to_place(
+    func: &IrFunction,
+    loops: &LoopInfo,
+    placed: &[bool],
+    b: BlockID,
+) -> Option<BlockID> {
+    successors(func, b).into_iter().find(|&s| {
+        s != 0 && !placed[s] && {
+            let mut l = loops.loop_of(b);
+            // every loop `b` is in and `s` isn't is done with
+            loop {
+                match l {
+                    Some(lp) if !lp.blocks.contains(&s) => {
+                        if lp.blocks.iter().any(|&m| !placed[m]) {
+                            break false;
+                        }
+                        l = lp.parent.map(|p| &loops.loops[p]);
+                    }
+                    _ => break true,
+                }
+            }
+        }
+    })
+}
+
+/// Where `b` goes, the `then` of a `br` first
+fn successors(func: &IrFunction, b: BlockID) -> Vec<BlockID> {
+    let block = &func.blocks[b];
+    let labels = match block.instrs.last() {
+        Some(IrInstruction::Br {
+            then_lbl, else_lbl, ..
+        }) => vec![then_lbl, else_lbl],
+        Some(IrInstruction::Jmp { label }) => vec![label],
+        // falls through, or returns
+        _ => return block.succs.clone(),
+    };
+    labels
+        .into_iter()
+        .filter_map(|label| func.block_index(label))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn laid_out(src: &str) -> IrFunction {
+        let mut func = IrFunction::parse(src).unwrap();
+        BlockLayoutPass {}
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap();
+        bril_ir::verify_ssa(&func).unwrap();
+        func
+    }
+
+    fn labels(func: &IrFunction) -> Vec<&str> {
+        func.blocks.iter().map(|b| b.label.as_str()).collect()
+    }
+
+    #[test]
+    fn test_loop_body_follows_its_header() {
+        let mut func = laid_out(
+            "@main(n: int) {
+.entry:
+  zero = const 0
+  one = const 1
+.head:
+  i = phi [zero, .entry] [i2, .inner] [i2, .body]
+  c = lt i n
+  br c .body .done
+.done:
+  print i
+  ret
+.inner:
+  print i2
+  jmp .head
+.body:
+  i2 = add i one
+  big = lt one i2
+  br big .inner .head
+}",
+        );
+        assert_eq!(labels(&func), ["entry", "head", "body", "inner", "done"]);
+        // .entry fell through to .head, which is still after it, and .done
+        // had a `ret` already
+        assert_eq!(func.blocks[0].instrs.len(), 2);
+
+        // the order is what it would pick again
+        assert!(!BlockLayoutPass {}
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_moved_fall_throughs_get_jumps() {
+        let func = laid_out(
+            "@main(c: bool) {
+.entry:
+  br c .then .else
+.else:
+  x = const 2
+.join:
+  print c
+  ret
+.then:
+  y = const 1
+  jmp .join
+}",
+        );
+        assert_eq!(labels(&func), ["entry", "then", "join", "else"]);
+        let lines: Vec<String> = func.blocks[3]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(lines, ["x = const 2", "jmp .join"]);
+        // .then goes on into .join, its jump is left for the emitter
+        assert_eq!(func.blocks[2].preds, [3, 1]);
+    }
+}
@@ -1,52 +1,398 @@
-use crate::pass_manager::FunctionPass;
-use bril_frontend::Literal;
+use crate::analysis_manager::Analysis;
+use crate::pass_manager::{FunctionPass, PassError, PassOptions, Statistics};
+use bril_frontend::{Literal, Symbol};
 use bril_ir::IrFunction;
 use bril_ir::IrInstruction;
+use std::collections::{HashMap, HashSet};
 
 /// Intraprocedural Constant Fold
-pub struct ConstantFoldPass {}
+///
+/// Works on SSA, a `const` is the one def of its name so what it holds is
+/// known wherever the name is read. An op whose operands are all known
+/// becomes a `const` of its result, which the ops after it can fold in
+/// turn. Division by zero and overflow are left for run time, unless
+/// `assume_no_overflow` (the `fold.assume-no-overflow` option) says an
+/// `add`, `sub` or `mul` may fold to the value it wraps round to. A name
+/// with two defs is an error, the function can't be in SSA, and so is an
+/// op on constants of the wrong type, like `eq` of a bool.
+#[derive(Default)]
+pub struct ConstantFoldPass {
+    pub assume_no_overflow: bool,
+}
 
 impl FunctionPass for ConstantFoldPass {
     fn name(&self) -> &str {
         "ConstantFoldPass"
     }
 
-    fn run_on_function(&mut self, function: &mut IrFunction) -> bool {
-        for blocks in function.blocks.iter_mut() {
-            for instr in blocks.instrs.iter_mut() {
-                // TODO: Added more folds
-                match instr {
-                    IrInstruction::Add { dest, lhs, rhs } => {
-                        if rhs.parse::<i64>().is_err() || lhs.parse::<i64>().is_err() {
-                            continue;
-                        }
-
-                        let right = rhs.parse::<i64>().unwrap();
-                        let left = lhs.parse::<i64>().unwrap();
-                        let sum = left + right;
-                        *instr = IrInstruction::Const {
-                            dest: dest.to_string(),
-                            value: Literal::Int(sum),
-                        };
-                    }
-
-                    IrInstruction::Mul { dest, lhs, rhs } => {
-                        if rhs.parse::<i64>().is_err() || lhs.parse::<i64>().is_err() {
-                            continue;
-                        }
-
-                        let right = rhs.parse::<i64>().unwrap();
-                        let left = lhs.parse::<i64>().unwrap();
-                        let product = left * right;
-                        *instr = IrInstruction::Const {
-                            dest: dest.to_string(),
-                            value: Literal::Int(product),
-                        };
-                    }
-                    _ => {}
-                }
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let before = function.clone();
+        let folded = fold_constants(function, self.assume_no_overflow)?;
+        stats.add("constants folded", folded);
+        Ok(*function != before)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        Analysis::CFG
+    }
+
+    fn set_options(&mut self, options: &PassOptions) -> Result<(), PassError> {
+        if let Some(assume) = options.get_bool("fold.assume-no-overflow")? {
+            self.assume_no_overflow = assume;
+        }
+        Ok(())
+    }
+
+    fn option_keys(&self) -> &[&'static str] {
+        &["fold.assume-no-overflow"]
+    }
+}
+
+/// Returns how many instructions became a `const`
+fn fold_constants(func: &mut IrFunction, assume_no_overflow: bool) -> Result<usize, PassError> {
+    // a name with more than one def can't be known from just one of them
+    let mut defined: HashSet<Symbol> = func.args.iter().copied().collect();
+    for instr in func.blocks.iter().flat_map(|b| &b.instrs) {
+        if let Some(&dest) = instr.defs().first()
+            && !defined.insert(dest)
+        {
+            return Err(PassError::MalformedIR {
+                function: func.name.clone(),
+                detail: format!("`{}` is defined more than once, expected SSA", dest),
+            });
+        }
+    }
+
+    let mut const_env: HashMap<Symbol, Literal> = HashMap::new();
+    let mut folded = 0;
+    let name = &func.name;
+    // in SSA a def dominates its uses, so RPO sees it before them
+    for b in func.reverse_postorder() {
+        for instr in func.blocks[b].instrs.iter_mut() {
+            let value = try_fold(instr, &const_env).map_err(|detail| PassError::MalformedIR {
+                function: name.clone(),
+                detail,
+            })?;
+            let value = value.or_else(|| {
+                assume_no_overflow
+                    .then(|| fold_wrapping(instr, &const_env))
+                    .flatten()
+            });
+            if let Some(value) = value {
+                let dest = instr.defs()[0];
+                *instr = IrInstruction::Const { dest, value };
+                folded += 1;
+            }
+            if let IrInstruction::Const { dest, value } = instr {
+                const_env.insert(*dest, value.clone());
             }
         }
-        true
+    }
+    Ok(folded)
+}
+
+/// How an op folds, by the types of its operands. `None` from the function
+/// leaves it for run time, for ops that can trap or overflow
+#[derive(Clone, Copy)]
+enum Fold {
+    Ints(fn(i64, i64) -> Option<Literal>),
+    Int(fn(i64) -> Option<Literal>),
+    Bools(fn(bool, bool) -> Literal),
+    Bool(fn(bool) -> Literal),
+    Chars(fn(char, char) -> Literal),
+    Char(fn(char) -> Literal),
+    /// The value shifted and the amount, which is part of the instruction
+    Shift(fn(i64, u32) -> Option<Literal>),
+}
+
+/// Every op that folds, by opcode
+const FOLDS: &[(&str, Fold)] = &[
+    ("add", Fold::Ints(|a, b| a.checked_add(b).map(Literal::Int))),
+    ("sub", Fold::Ints(|a, b| a.checked_sub(b).map(Literal::Int))),
+    ("mul", Fold::Ints(|a, b| a.checked_mul(b).map(Literal::Int))),
+    // None for a zero divisor, and for i64::MIN / -1
+    ("div", Fold::Ints(|a, b| a.checked_div(b).map(Literal::Int))),
+    // a left shift overflows when the multiply it stands for does
+    (
+        "shl",
+        Fold::Shift(|a, k| a.checked_mul(1i64.checked_shl(k)?).map(Literal::Int)),
+    ),
+    (
+        "shr",
+        Fold::Shift(|a, k| (a as u64).checked_shr(k).map(|v| Literal::Int(v as i64))),
+    ),
+    (
+        "sra",
+        Fold::Shift(|a, k| a.checked_shr(k).map(Literal::Int)),
+    ),
+    ("eq", Fold::Ints(|a, b| Some(Literal::Bool(a == b)))),
+    ("lt", Fold::Ints(|a, b| Some(Literal::Bool(a < b)))),
+    ("gt", Fold::Ints(|a, b| Some(Literal::Bool(a > b)))),
+    ("le", Fold::Ints(|a, b| Some(Literal::Bool(a <= b)))),
+    ("ge", Fold::Ints(|a, b| Some(Literal::Bool(a >= b)))),
+    ("and", Fold::Bools(|a, b| Literal::Bool(a && b))),
+    ("or", Fold::Bools(|a, b| Literal::Bool(a || b))),
+    ("not", Fold::Bool(|a| Literal::Bool(!a))),
+    ("ceq", Fold::Chars(|a, b| Literal::Bool(a == b))),
+    ("clt", Fold::Chars(|a, b| Literal::Bool(a < b))),
+    ("cgt", Fold::Chars(|a, b| Literal::Bool(a > b))),
+    ("cle", Fold::Chars(|a, b| Literal::Bool(a <= b))),
+    ("cge", Fold::Chars(|a, b| Literal::Bool(a >= b))),
+    // a char is just its codepoint, not every int is one though
+    ("char2int", Fold::Char(|c| Literal::Int(c as i64))),
+    (
+        "int2char",
+        Fold::Int(|i| {
+            u32::try_from(i)
+                .ok()
+                .and_then(char::from_u32)
+                .map(Literal::Char)
+        }),
+    ),
+];
+
+/// What `instr` computes, when all of its operands are in `const_env` and
+/// computing it can't trap or overflow. An error says how the operands
+/// are the wrong type for it
+pub(crate) fn try_fold(
+    instr: &IrInstruction,
+    const_env: &HashMap<Symbol, Literal>,
+) -> Result<Option<Literal>, String> {
+    let Some(&(_, how)) = FOLDS.iter().find(|(op, _)| *op == instr.opcode()) else {
+        return Ok(None);
+    };
+    let mut values = Vec::new();
+    for var in instr.uses() {
+        match const_env.get(&var) {
+            Some(value) => values.push(value),
+            None => return Ok(None),
+        }
+    }
+
+    use Literal::{Bool, Char, Int};
+    let value = match (how, &values[..]) {
+        (Fold::Ints(f), [Int(a), Int(b)]) => f(*a, *b),
+        (Fold::Int(f), [Int(a)]) => f(*a),
+        (Fold::Bools(f), [Bool(a), Bool(b)]) => Some(f(*a, *b)),
+        (Fold::Bool(f), [Bool(a)]) => Some(f(*a)),
+        (Fold::Chars(f), [Char(a), Char(b)]) => Some(f(*a, *b)),
+        (Fold::Char(f), [Char(a)]) => Some(f(*a)),
+        (Fold::Shift(f), [Int(a)]) => match instr {
+            IrInstruction::Shl { amount, .. }
+            | IrInstruction::Shr { amount, .. }
+            | IrInstruction::Sra { amount, .. } => f(*a, *amount),
+            _ => None,
+        },
+        (how, _) => {
+            let expected = match how {
+                Fold::Ints(_) | Fold::Int(_) | Fold::Shift(_) => "ints",
+                Fold::Bools(_) | Fold::Bool(_) => "bools",
+                Fold::Chars(_) | Fold::Char(_) => "chars",
+            };
+            let read: Vec<&str> = values
+                .iter()
+                .map(|value| match value {
+                    Int(_) => "an int",
+                    Bool(_) => "a bool",
+                    Char(_) => "a char",
+                    Literal::Float(_) => "a float",
+                })
+                .collect();
+            return Err(format!(
+                "`{}` reads {}, expected {}",
+                instr,
+                read.join(" and "),
+                expected
+            ));
+        }
+    };
+    Ok(value)
+}
+
+/// An `add`, `sub` or `mul` of two known ints as the machine does it,
+/// wrapping round where [`try_fold`] would leave it for overflowing
+fn fold_wrapping(instr: &IrInstruction, const_env: &HashMap<Symbol, Literal>) -> Option<Literal> {
+    let op: fn(i64, i64) -> i64 = match instr {
+        IrInstruction::Add { .. } => i64::wrapping_add,
+        IrInstruction::Sub { .. } => i64::wrapping_sub,
+        IrInstruction::Mul { .. } => i64::wrapping_mul,
+        _ => return None,
+    };
+    match instr.uses()[..] {
+        [a, b] => match (const_env.get(&a)?, const_env.get(&b)?) {
+            (Literal::Int(a), Literal::Int(b)) => Some(Literal::Int(op(*a, *b))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// [`try_fold`], with operands of the wrong type not folding
+pub(crate) fn fold(instr: &IrInstruction, const_env: &HashMap<Symbol, Literal>) -> Option<Literal> {
+    try_fold(instr, const_env).ok().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folded(src: &str) -> Vec<String> {
+        folded_assuming(src, false)
+    }
+
+    fn folded_assuming(src: &str, assume_no_overflow: bool) -> Vec<String> {
+        let mut func = IrFunction::parse(src).unwrap();
+        fold_constants(&mut func, assume_no_overflow).unwrap();
+        func.blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_chains_fold_fully() {
+        let lines = folded(
+            "@main(n: int) {
+.entry:
+  a = const 2
+  b = const 3
+  c = add a b
+  jmp .next
+.next:
+  d = mul c c
+  e = lt d c
+  f = not e
+  g = add d n
+  ret
+}",
+        );
+        assert_eq!(
+            lines,
+            [
+                "a = const 2",
+                "b = const 3",
+                "c = const 5",
+                "jmp .next",
+                "d = const 25",
+                "e = const false",
+                "f = const true",
+                "g = add d n",
+                "ret"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_traps_and_overflow_are_left_alone() {
+        let src = "@main() {
+.entry:
+  x = const 7
+  zero = const 0
+  q = div x zero
+  big = const 9223372036854775807
+  s = add big x
+  min = const -9223372036854775808
+  neg = const -1
+  r = div min neg
+  ret
+}";
+        let lines = folded(src);
+        assert_eq!(lines[2], "q = div x zero");
+        assert_eq!(lines[4], "s = add big x");
+        assert_eq!(lines[7], "r = div min neg");
+
+        // told it can't overflow, the add wraps round, the traps still don't
+        let lines = folded_assuming(src, true);
+        assert_eq!(lines[2], "q = div x zero");
+        assert_eq!(lines[4], "s = const -9223372036854775802");
+        assert_eq!(lines[7], "r = div min neg");
+    }
+
+    #[test]
+    fn test_two_defs_name_the_pass_and_function() {
+        let broken = IrFunction::parse(
+            "@twice() {
+.entry:
+  a = const 1
+  a = const 2
+  b = add a a
+  print b
+  ret
+}",
+        )
+        .unwrap();
+        let mut module = bril_ir::IrModule {
+            functions: vec![broken.clone()],
+        };
+        let mut pm = crate::PassManager::new();
+        pm.add_pass(ConstantFoldPass::default());
+        let err = pm.run(&mut module).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ConstantFoldPass failed, malformed IR in @twice: `a` is defined more than once, \
+             expected SSA"
+        );
+        let Some(PassError::InPass { pass, error }) = err.downcast_ref::<PassError>() else {
+            panic!("expected a PassError, got {:?}", err);
+        };
+        assert_eq!(pass, "ConstantFoldPass");
+        assert!(
+            matches!(**error, PassError::MalformedIR { ref function, .. } if function == "twice")
+        );
+        // nothing was folded on the way
+        assert_eq!(module.functions[0], broken);
+    }
+
+    #[test]
+    fn test_comparisons_and_logic_fold() {
+        let lines = folded(
+            "@main(x: bool) {
+.entry:
+  three = const 3
+  five = const 5
+  a = lt three five
+  b = ge three five
+  t = const true
+  c = and t x
+  d = or a b
+  e = not d
+  p = const 'p'
+  q = const 'q'
+  f = clt p q
+  ret
+}",
+        );
+        assert_eq!(lines[2], "a = const true");
+        assert_eq!(lines[3], "b = const false");
+        // `x` isn't known, that's for InstCombine
+        assert_eq!(lines[5], "c = and t x");
+        assert_eq!(lines[6], "d = const true");
+        assert_eq!(lines[7], "e = const false");
+        assert_eq!(lines[10], "f = const true");
+    }
+
+    #[test]
+    fn test_operands_of_the_wrong_type_are_an_error() {
+        let mut func = IrFunction::parse(
+            "@main() {
+.entry:
+  t = const true
+  one = const 1
+  x = eq t one
+  print x
+  ret
+}",
+        )
+        .unwrap();
+        let err = fold_constants(&mut func, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "malformed IR in @main: `x = eq t one` reads a bool and an int, expected ints"
+        );
     }
 }
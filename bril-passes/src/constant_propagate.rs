@@ -1,10 +1,40 @@
-use crate::pass_manager::FunctionPass;
-use bril_frontend::Literal;
+use crate::analysis_manager::Analysis;
+use crate::constant_folding::fold;
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::{Literal, Symbol};
+use bril_ir::IrBasicBlock;
 use bril_ir::IrFunction;
 use bril_ir::IrInstruction;
 use std::collections::HashMap;
 
+/// What's known about a variable at some point. `Top` until a def of it
+/// has been seen on some path, `Bottom` once two paths disagree or the
+/// value is only known at run time
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstLattice {
+    Top,
+    Const(Literal),
+    Bottom,
+}
+
+impl ConstLattice {
+    pub fn meet(&self, other: &ConstLattice) -> ConstLattice {
+        match (self, other) {
+            (ConstLattice::Top, x) | (x, ConstLattice::Top) => x.clone(),
+            (ConstLattice::Const(a), ConstLattice::Const(b)) if a == b => self.clone(),
+            _ => ConstLattice::Bottom,
+        }
+    }
+}
+
+/// The lattice value of every variable, a missing one is `Top`
+pub type ConstState = HashMap<Symbol, ConstLattice>;
+
 /// Intraprocedural Constant Propagation
+///
+/// A forward dataflow over the CFG: the state coming out of each block is
+/// met over the preds going into the next. Afterwards every copy, phi or
+/// op found to always give the same constant becomes a `const` of it.
 pub struct ConstantPropagationPass {}
 
 impl FunctionPass for ConstantPropagationPass {
@@ -12,65 +42,313 @@ impl FunctionPass for ConstantPropagationPass {
         "ConstantPropagationPass"
     }
 
-    fn run_on_function(&mut self, function: &mut IrFunction) -> bool {
-        let mut const_env: HashMap<String, Option<Literal>> = HashMap::new();
-        for blocks in function.blocks.iter_mut() {
-            for instr in blocks.instrs.iter_mut() {
-                match instr {
-                    // TODO: Need to add more patterns to match for
-                    IrInstruction::Const { dest, value } => {
-                        const_env.insert(dest.clone(), Some(value.clone()));
-                    }
-
-                    IrInstruction::Assign { rhs, .. } => {
-                        if let Some(Literal::Int(j)) = const_env.get(rhs).cloned().flatten() {
-                            *rhs = j.to_string();
-                        }
-                    }
-
-                    IrInstruction::Add { lhs, rhs, .. }
-                    | IrInstruction::Mul { lhs, rhs, .. }
-                    | IrInstruction::Sub { lhs, rhs, .. }
-                    | IrInstruction::Div { lhs, rhs, .. } => {
-                        if let Some(Literal::Int(i)) = const_env.get(lhs).cloned().flatten() {
-                            *lhs = i.to_string();
-                        }
-
-                        if let Some(Literal::Int(j)) = const_env.get(rhs).cloned().flatten() {
-                            *rhs = j.to_string();
-                        }
-                    }
-
-                    IrInstruction::Eq { lhs, rhs, .. }
-                    | IrInstruction::Lt { lhs, rhs, .. }
-                    | IrInstruction::Gt { lhs, rhs, .. }
-                    | IrInstruction::Ge { lhs, rhs, .. } => {
-                        if let Some(Literal::Bool(i)) = const_env.get(lhs).cloned().flatten() {
-                            *lhs = i.to_string();
-                        }
-
-                        if let Some(Literal::Bool(j)) = const_env.get(rhs).cloned().flatten() {
-                            *rhs = j.to_string();
-                        }
-                    }
-
-                    IrInstruction::Br { cond, .. } => {
-                        if let Some(Literal::Bool(j)) = const_env.get(cond).cloned().flatten() {
-                            *cond = j.to_string();
-                        }
-                    }
-
-                    IrInstruction::Ret { args } => {
-                        for arg in args.iter_mut() {
-                            if let Some(Literal::Int(i)) = const_env.get(arg).cloned().flatten() {
-                                *arg = i.to_string();
-                            }
-                        }
-                    }
-                    _ => {}
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        _stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let before = function.clone();
+        propagate_constants(function);
+        Ok(*function != before)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        Analysis::CFG
+    }
+}
+
+/// The state going into each block, by block index. A block nothing
+/// reaches has everything `Top`
+pub fn analyze(func: &IrFunction) -> Vec<ConstState> {
+    solve(func).0
+}
+
+/// The states going into and coming out of each block, `None` coming out
+/// of a block nothing reaches
+fn solve(func: &IrFunction) -> (Vec<ConstState>, Vec<Option<ConstState>>) {
+    let n = func.blocks.len();
+    let mut ins: Vec<ConstState> = vec![ConstState::new(); n];
+    // None until the block has been visited once
+    let mut outs: Vec<Option<ConstState>> = vec![None; n];
+    let rpo = func.reverse_postorder();
+
+    // Fix-pointed iteration (forwards)
+    loop {
+        let mut changed = false;
+        for &b in &rpo {
+            // the caller could pass anything
+            let mut state: ConstState = if b == 0 {
+                func.args
+                    .iter()
+                    .map(|&arg| (arg, ConstLattice::Bottom))
+                    .collect()
+            } else {
+                ConstState::new()
+            };
+            for &p in &func.blocks[b].preds {
+                if let Some(out) = &outs[p] {
+                    meet_into(&mut state, out);
                 }
             }
+            ins[b] = state.clone();
+
+            for instr in &func.blocks[b].instrs {
+                transfer(func, instr, &mut state, &outs);
+            }
+            if outs[b].as_ref() != Some(&state) {
+                outs[b] = Some(state);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    (ins, outs)
+}
+
+fn propagate_constants(func: &mut IrFunction) {
+    let (ins, outs) = solve(func);
+    for (b, mut state) in ins.into_iter().enumerate() {
+        let mut rewrites: Vec<(usize, Symbol, Literal)> = Vec::new();
+        for (i, instr) in func.blocks[b].instrs.iter().enumerate() {
+            let value = transfer(func, instr, &mut state, &outs);
+            let rewritable = !instr.has_side_effects()
+                && !matches!(instr, IrInstruction::Const { .. })
+                && instr.defs().len() == 1;
+            if let (true, Some(ConstLattice::Const(lit))) = (rewritable, value) {
+                rewrites.push((i, instr.defs()[0], lit));
+            }
         }
-        true
+
+        replace_with_consts(&mut func.blocks[b], rewrites);
+    }
+}
+
+/// Make each `(index, dest, value)` of `rewrites` a `const`. The ones that
+/// were phis go after the phis that are left, so those stay together at
+/// the top of the block
+pub(crate) fn replace_with_consts(
+    block: &mut IrBasicBlock,
+    rewrites: Vec<(usize, Symbol, Literal)>,
+) {
+    let mut phis_gone = Vec::new();
+    for (i, dest, value) in rewrites {
+        if let IrInstruction::Phi { .. } = block.instrs[i] {
+            phis_gone.push((dest, value, block.pos(i), block.origin(i)));
+        } else {
+            block.instrs[i] = IrInstruction::Const { dest, value };
+        }
+    }
+    if phis_gone.is_empty() {
+        return;
+    }
+    block.remove_instrs(|instr| match instr {
+        IrInstruction::Phi { dest, .. } => phis_gone.iter().any(|(d, ..)| d == dest),
+        _ => false,
+    });
+    let at = block
+        .instrs
+        .iter()
+        .take_while(|i| matches!(i, IrInstruction::Phi { .. }))
+        .count();
+    for (k, (dest, value, pos, origin)) in phis_gone.into_iter().enumerate() {
+        block.insert_instr(at + k, IrInstruction::Const { dest, value }, pos, origin);
+    }
+}
+
+fn meet_into(state: &mut ConstState, other: &ConstState) {
+    for (var, value) in other {
+        let met = match state.get(var) {
+            Some(old) => old.meet(value),
+            None => value.clone(),
+        };
+        state.insert(*var, met);
+    }
+}
+
+/// Step `state` over `instr`, returning what it defines its dest to. A
+/// phi reads the state coming out of each pred from `outs`, a pred
+/// without one hasn't been reached yet
+fn transfer(
+    func: &IrFunction,
+    instr: &IrInstruction,
+    state: &mut ConstState,
+    outs: &[Option<ConstState>],
+) -> Option<ConstLattice> {
+    let &[dest] = instr.defs() else {
+        return None;
+    };
+    let get =
+        |state: &ConstState, var: &Symbol| state.get(var).cloned().unwrap_or(ConstLattice::Top);
+
+    let value = match instr {
+        IrInstruction::Phi { sources, .. } => {
+            let mut met = ConstLattice::Top;
+            for src in sources {
+                let p = func.block_index(&src.pred);
+                let out = p.and_then(|p| outs.get(p)).and_then(Option::as_ref);
+                if let (Some(out), Some(value)) = (out, &src.value) {
+                    met = met.meet(&get(out, value));
+                }
+            }
+            met
+        }
+        _ => evaluate(instr, |var| get(state, &var)),
+    };
+
+    state.insert(dest, value.clone());
+    Some(value)
+}
+
+/// What the dest of `instr`, anything but a phi, is given `value_of` its
+/// operands
+pub(crate) fn evaluate(
+    instr: &IrInstruction,
+    value_of: impl Fn(Symbol) -> ConstLattice,
+) -> ConstLattice {
+    match instr {
+        IrInstruction::Const { value, .. } => return ConstLattice::Const(value.clone()),
+        IrInstruction::Assign { rhs, .. } => return value_of(*rhs),
+        _ if instr.has_side_effects() => return ConstLattice::Bottom,
+        // comparing a value with itself, whatever it turns out to be
+        IrInstruction::Eq { lhs, rhs, .. }
+        | IrInstruction::Le { lhs, rhs, .. }
+        | IrInstruction::Ge { lhs, rhs, .. }
+            if lhs == rhs =>
+        {
+            return ConstLattice::Const(Literal::Bool(true));
+        }
+        IrInstruction::Lt { lhs, rhs, .. } | IrInstruction::Gt { lhs, rhs, .. } if lhs == rhs => {
+            return ConstLattice::Const(Literal::Bool(false));
+        }
+        _ => {}
+    }
+
+    let mut known = HashMap::new();
+    let mut value = None;
+    for var in instr.uses() {
+        match value_of(var) {
+            ConstLattice::Const(lit) => {
+                known.insert(var, lit);
+            }
+            ConstLattice::Bottom => value = Some(ConstLattice::Bottom),
+            ConstLattice::Top => {
+                value.get_or_insert(ConstLattice::Top);
+            }
+        }
+    }
+    // loads, allocs and float ops never fold
+    value.unwrap_or_else(|| match fold(instr, &known) {
+        Some(lit) => ConstLattice::Const(lit),
+        None => ConstLattice::Bottom,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bril_ir::SSAFormation;
+
+    /// The diamond with `x` set in both arms and read after the join
+    fn diamond(left: i64, right: i64) -> IrFunction {
+        IrFunction::parse(&format!(
+            "@main(c: bool) {{
+.entry:
+  br c .left .right
+.left:
+  x = const {}
+  jmp .join
+.right:
+  x = const {}
+  jmp .join
+.join:
+  one = const 1
+  y = add x one
+  print y
+  ret
+}}",
+            left, right
+        ))
+        .unwrap()
+    }
+
+    fn join_lines(func: &IrFunction) -> Vec<String> {
+        let join = func.block_index(&"join".to_string()).unwrap();
+        func.blocks[join]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_same_constant_on_both_arms_propagates() {
+        let func = diamond(5, 5);
+        let join = func.block_index(&"join".to_string()).unwrap();
+        let ins = analyze(&func);
+        assert_eq!(
+            ins[join][&Symbol::from("x")],
+            ConstLattice::Const(Literal::Int(5))
+        );
+        assert_eq!(ins[join][&Symbol::from("c")], ConstLattice::Bottom);
+
+        let mut func = func;
+        SSAFormation::new(std::slice::from_mut(&mut func)).unwrap();
+        propagate_constants(&mut func);
+        bril_ir::verify_ssa(&func).unwrap();
+        let lines = join_lines(&func);
+        assert!(lines[0].ends_with(" = const 5"), "{:?}", lines);
+        assert_eq!(lines[2], "y = const 6");
+    }
+
+    #[test]
+    fn test_different_constants_meet_to_bottom() {
+        let func = diamond(5, 6);
+        let join = func.block_index(&"join".to_string()).unwrap();
+        assert_eq!(
+            analyze(&func)[join][&Symbol::from("x")],
+            ConstLattice::Bottom
+        );
+
+        let mut func = func;
+        SSAFormation::new(std::slice::from_mut(&mut func)).unwrap();
+        propagate_constants(&mut func);
+        let lines = join_lines(&func);
+        assert!(lines[0].contains(" = phi "), "{:?}", lines);
+        assert!(lines[2].starts_with("y = add "), "{:?}", lines);
+    }
+
+    #[test]
+    fn test_loop_counter_is_not_constant() {
+        let mut func = IrFunction::parse(
+            "@main() {
+.entry:
+  i = const 0
+  n = const 10
+  one = const 1
+  jmp .head
+.head:
+  c = lt i n
+  br c .body .done
+.body:
+  i = add i one
+  m = id n
+  jmp .head
+.done:
+  print i m
+  ret
+}",
+        )
+        .unwrap();
+        SSAFormation::new(std::slice::from_mut(&mut func)).unwrap();
+        propagate_constants(&mut func);
+        let text = func.to_string();
+        // `m` only ever copies `n`, `i` goes up every time round
+        assert!(text.contains("m = const 10"), "{}", text);
+        assert!(text.contains(" = phi "), "{}", text);
+        assert!(!text.contains("c = const"), "{}", text);
     }
 }
@@ -0,0 +1,208 @@
+use crate::analysis_manager::Analysis;
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::Symbol;
+use bril_ir::{IrBasicBlock, IrFunction, IrInstruction};
+use std::collections::{HashMap, HashSet};
+
+/// Copy Propagation
+///
+/// Every read of a name that's only a copy (`x = id y`) reads what it's a
+/// copy of instead, through chains of copies too, and the copies are left
+/// for dead code removal. In SSA `y` never changes, so that's done over the
+/// whole function. Anything else only gets it within a block, up to where
+/// either name is defined again.
+pub struct CopyPropagationPass {}
+
+impl FunctionPass for CopyPropagationPass {
+    fn name(&self) -> &str {
+        "CopyPropagationPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        _stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let before = function.clone();
+        propagate_copies(function);
+        Ok(*function != before)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        Analysis::CFG
+    }
+}
+
+fn propagate_copies(func: &mut IrFunction) {
+    if bril_ir::verify_ssa(func).is_ok() {
+        propagate_global(func);
+    } else {
+        for block in &mut func.blocks {
+            propagate_local(block);
+        }
+    }
+}
+
+fn propagate_global(func: &mut IrFunction) {
+    let copies: HashMap<Symbol, Symbol> = func
+        .blocks
+        .iter()
+        .flat_map(|b| &b.instrs)
+        .filter_map(|instr| match instr {
+            IrInstruction::Assign { lhs, rhs } if lhs != rhs => Some((*lhs, *rhs)),
+            _ => None,
+        })
+        .collect();
+    if copies.is_empty() {
+        return;
+    }
+
+    // where a chain of copies starts. One going round in a circle has no
+    // start, its names are left as they are
+    let source = |var: Symbol| {
+        let mut seen = HashSet::new();
+        let mut cur = var;
+        while let Some(&next) = copies.get(&cur) {
+            if !seen.insert(cur) {
+                return var;
+            }
+            cur = next;
+        }
+        cur
+    };
+
+    for instr in func.blocks.iter_mut().flat_map(|b| &mut b.instrs) {
+        for var in instr.uses_mut() {
+            *var = source(*var);
+        }
+    }
+}
+
+fn propagate_local(block: &mut IrBasicBlock) {
+    // dest -> what it's a copy of, both unchanged since the copy. Sources
+    // are looked through as they go in, so chains come out in one step
+    let mut copies: HashMap<Symbol, Symbol> = HashMap::new();
+    for instr in &mut block.instrs {
+        // a phi reads its values at the end of the preds, not here
+        if !matches!(instr, IrInstruction::Phi { .. }) {
+            for var in instr.uses_mut() {
+                if let Some(&src) = copies.get(var) {
+                    *var = src;
+                }
+            }
+        }
+        for def in instr.defs() {
+            copies.remove(def);
+            copies.retain(|_, src| src != def);
+        }
+        if let IrInstruction::Assign { lhs, rhs } = instr
+            && lhs != rhs
+        {
+            copies.insert(*lhs, *rhs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(func: &IrFunction) -> Vec<String> {
+        func.blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_chains_forward_to_the_source_in_ssa() {
+        let mut func = IrFunction::parse(
+            "@main(n: int) {
+.entry:
+  a = id n
+  b = id a
+  c = add b a
+  br c .left .right
+.left:
+  d = id b
+  jmp .join
+.right:
+  jmp .join
+.join:
+  e = phi [d, .left] [a, .right]
+  print e
+  ret
+}",
+        )
+        .unwrap();
+        propagate_copies(&mut func);
+        let text = func.to_string();
+        assert!(text.contains("c = add n n"), "{}", text);
+        assert!(text.contains("br c .left .right"), "{}", text);
+        assert!(text.contains("e = phi [n, .left] [n, .right]"), "{}", text);
+        // the copies stay for dead code removal, reading the source too
+        assert!(text.contains("b = id n"), "{}", text);
+    }
+
+    #[test]
+    fn test_copy_cycles_are_left_alone() {
+        // not reachable from the entry, but nothing stops it being there
+        let mut func = IrFunction::parse(
+            "@main() {
+.entry:
+  ret
+.dead:
+  a = id b
+  b = id a
+  print a
+  jmp .dead
+}",
+        )
+        .unwrap();
+        propagate_global(&mut func);
+        assert_eq!(lines(&func)[3], "print a");
+    }
+
+    #[test]
+    fn test_redefinitions_stop_local_propagation() {
+        // `x` is defined twice, so this isn't SSA
+        let mut func = IrFunction::parse(
+            "@main(n: int) {
+.entry:
+  x = id n
+  y = id x
+  print y
+  x = const 1
+  print x y
+  n = const 2
+  print y
+  jmp .next
+.next:
+  print y
+  ret
+}",
+        )
+        .unwrap();
+        assert!(bril_ir::verify_ssa(&func).is_err());
+        propagate_copies(&mut func);
+        assert_eq!(
+            lines(&func),
+            [
+                "x = id n",
+                "y = id n",
+                "print n",
+                "x = const 1",
+                // `y` still holds what `n` did
+                "print x n",
+                "n = const 2",
+                // now it doesn't
+                "print y",
+                "jmp .next",
+                // and nothing is known in another block
+                "print y",
+                "ret"
+            ]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use crate::analysis_manager::{Analysis, AnalysisManager};
+use crate::pass_manager::{ModulePass, PassError, Statistics};
+use bril_ir::analysis::callgraph::CallGraph;
+use bril_ir::{FuncId, IrInstruction, IrModule};
+use std::collections::HashSet;
+
+/// Dead Function Elimination
+///
+/// Drops every function `main` never gets to through calls, like helpers
+/// another file brought in that nothing uses, or ones inlined everywhere
+/// they were called. Functions on the export list stay whether they're
+/// called or not, and so does everything they call. Without a `main`
+/// there's no telling what's used, so nothing goes.
+#[derive(Default)]
+pub struct DeadFunctionPass {
+    exports: HashSet<String>,
+}
+
+impl DeadFunctionPass {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep `names` (and what they call) too, as if `main` called them
+    pub fn export<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.exports.extend(names.into_iter().map(Into::into));
+        self
+    }
+}
+
+impl ModulePass for DeadFunctionPass {
+    fn name(&self) -> &str {
+        "DeadFunctionPass"
+    }
+
+    fn run_on_module(
+        &mut self,
+        module: &mut IrModule,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        self.run_with_analyses(module, &mut AnalysisManager::new(), stats)
+    }
+
+    fn run_with_analyses(
+        &mut self,
+        module: &mut IrModule,
+        analyses: &mut AnalysisManager,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let graph = analyses.call_graph(module);
+        let removed = remove_dead_functions(module, graph, &self.exports);
+        stats.add("functions removed", removed.len());
+        Ok(!removed.is_empty())
+    }
+
+    /// The functions left are as they were, bar the callee indices
+    fn preserves(&self) -> &[Analysis] {
+        &[
+            Analysis::Dominators,
+            Analysis::Liveness,
+            Analysis::Loops,
+            Analysis::Effects,
+        ]
+    }
+}
+
+/// Returns the names of the functions removed
+fn remove_dead_functions(
+    module: &mut IrModule,
+    graph: &CallGraph,
+    exports: &HashSet<String>,
+) -> Vec<String> {
+    if !module.functions.iter().any(|f| f.name == "main") {
+        eprintln!("warning: no @main, keeping every function");
+        return Vec::new();
+    }
+
+    let mut live = vec![false; module.functions.len()];
+    let mut worklist: Vec<FuncId> = module
+        .functions
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.name == "main" || exports.contains(&f.name))
+        .map(|(i, _)| FuncId(i))
+        .collect();
+    while let Some(f) = worklist.pop() {
+        if !std::mem::replace(&mut live[f.0], true) {
+            worklist.extend(&graph.callees[f.0]);
+        }
+    }
+
+    // the calls hold indices into `functions`, which move down
+    let mut new_ids = Vec::with_capacity(live.len());
+    let mut next = 0;
+    for &l in &live {
+        new_ids.push(l.then_some(FuncId(next)));
+        next += usize::from(l);
+    }
+
+    let mut removed = Vec::new();
+    let mut i = 0;
+    module.functions.retain(|f| {
+        i += 1;
+        if !live[i - 1] {
+            removed.push(f.name.clone());
+        }
+        live[i - 1]
+    });
+    for instr in module
+        .functions
+        .iter_mut()
+        .flat_map(|f| &mut f.blocks)
+        .flat_map(|b| &mut b.instrs)
+    {
+        if let IrInstruction::Call {
+            callee: Some(id), ..
+        } = instr
+        {
+            *id = new_ids[id.0].expect("a live function calls a dead one");
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(src: &str) -> IrModule {
+        let mut module = bril_ir::text::parse_module(src).unwrap();
+        module.resolve_calls().unwrap();
+        module
+    }
+
+    fn names(module: &IrModule) -> Vec<&str> {
+        module.functions.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    const SRC: &str = "@unused() {
+.entry:
+  ret
+}
+
+@lib(n: int): int {
+.entry:
+  r = call @leaf n
+  ret r
+}
+
+@main(a: int) {
+.entry:
+  x = call @leaf a
+  print x
+  ret
+}
+
+@leaf(n: int): int {
+.entry:
+  ret n
+}";
+
+    #[test]
+    fn test_only_what_main_calls_stays() {
+        let mut module = module(SRC);
+        let graph = CallGraph::build(&module);
+        let removed = remove_dead_functions(&mut module, &graph, &HashSet::new());
+        assert_eq!(removed, ["unused", "lib"]);
+        assert_eq!(names(&module), ["main", "leaf"]);
+
+        // the call in main still goes to @leaf, now at index 1
+        let call = module.functions[0].blocks[0].instrs[0].clone();
+        let IrInstruction::Call { callee, .. } = call else {
+            panic!("expected a call, got {}", call);
+        };
+        assert_eq!(callee, Some(FuncId(1)));
+    }
+
+    #[test]
+    fn test_exports_stay() {
+        let mut module = module(SRC);
+        let mut pass = DeadFunctionPass::new().export(["lib"]);
+        pass.run_on_module(&mut module, &mut Statistics::default())
+            .unwrap();
+        assert_eq!(names(&module), ["lib", "main", "leaf"]);
+        let graph = CallGraph::build(&module);
+        assert_eq!(graph.callees[0], [FuncId(2)]);
+    }
+
+    #[test]
+    fn test_nothing_goes_without_main() {
+        let mut module = module(
+            "@f() {
+.entry:
+  ret
+}
+
+@g() {
+.entry:
+  ret
+}",
+        );
+        assert!(!DeadFunctionPass::new()
+            .run_on_module(&mut module, &mut Statistics::default())
+            .unwrap());
+        assert_eq!(names(&module), ["f", "g"]);
+    }
+}
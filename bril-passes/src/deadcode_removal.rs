@@ -1,10 +1,19 @@
-use crate::liveness::compute_liveness;
-use crate::pass_manager::FunctionPass;
-use bril_ir::IrFunction;
-use bril_ir::IrInstruction;
+use crate::analysis_manager::{Analysis, AnalysisManager};
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::Symbol;
+use bril_ir::analysis::def_use::DefUse;
+use bril_ir::analysis::effects::{Effect, Effects};
+use bril_ir::{IrFunction, IrInstruction};
 use std::collections::HashSet;
 
-/// Intraprocedural Constant Propagation
+/// Global dead code elimination over SSA, mark and sweep
+///
+/// Whatever has an effect is live: output, memory writes, calls and the
+/// terminators, so the conditions branches test are too. So is every def a
+/// live instruction reads, followed back along the def-use chains.
+/// Everything else goes, phis that only feed each other round a loop
+/// included. With the effects of the module's functions kept, a call to
+/// one that never changes anything is only live for its result
 pub struct DeadCodeRemovalPass {}
 
 impl FunctionPass for DeadCodeRemovalPass {
@@ -12,40 +21,229 @@ impl FunctionPass for DeadCodeRemovalPass {
         "DeadCodeRemovalPass"
     }
 
-    fn run_on_function(&mut self, function: &mut IrFunction) -> bool {
-        eliminate_deadcode(function);
-        true
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        self.run_with_analyses(function, &mut AnalysisManager::new(), stats)
+    }
+
+    fn run_with_analyses(
+        &mut self,
+        function: &mut IrFunction,
+        analyses: &mut AnalysisManager,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let before = function.clone();
+        eliminate_deadcode(function, analyses.kept_effects());
+        let instrs = |f: &IrFunction| f.blocks.iter().map(|b| b.instrs.len()).sum::<usize>();
+        stats.add("instructions removed", instrs(&before) - instrs(function));
+        Ok(*function != before)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        Analysis::CFG
     }
 }
 
-fn eliminate_deadcode(func: &mut IrFunction) {
-    // iterate over each block then for each block,
-    // iterate over them in reverse
+fn eliminate_deadcode(func: &mut IrFunction, effects: Option<&Effects>) {
+    let du = DefUse::compute(func);
+    // a call that doesn't change anything has to stay for as long as its
+    // result is read, and no longer
+    let has_effects = |instr: &IrInstruction| match (instr, effects) {
+        (IrInstruction::Call { .. }, Some(effects)) => effects.of_instr(instr) == Effect::Impure,
+        _ => instr.has_side_effects(),
+    };
+
+    let mut live: HashSet<Symbol> = HashSet::new();
+    let mut worklist: Vec<Symbol> = func
+        .blocks
+        .iter()
+        .flat_map(|b| &b.instrs)
+        .filter(|i| has_effects(i))
+        .flat_map(|i| i.uses())
+        .collect();
+    while let Some(var) = worklist.pop() {
+        if !live.insert(var) {
+            continue;
+        }
+        // an arg has no def to follow
+        let Some(site) = du.def(var) else { continue };
+        worklist.extend(func.blocks[site.block].instrs[site.instr].uses());
+    }
 
-    let (live_out, _live_in) = compute_liveness(func);
+    // nops go along with the dead defs
+    func.remove_instrs(|instr| match instr {
+        IrInstruction::Nop => true,
+        _ if has_effects(instr) => false,
+        _ => instr.defs().iter().all(|d| !live.contains(d)),
+    });
+}
 
-    for (b, block) in func.blocks.iter_mut().enumerate() {
-        let mut live: HashSet<String> = live_out[b].clone();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let mut new_instrs: Vec<IrInstruction> = Vec::with_capacity(block.instrs.len());
-        for instr in block.instrs.iter().rev() {
-            // check to see if a definition is live
-            if let Some(d) = instr.defs().first() {
-                // if not live, then skip
-                if !live.contains(d) {
-                    continue;
-                }
-                // in case we had kept a old definition, we want to remove
-                live.remove(d);
-            }
+    #[test]
+    fn test_side_effects_stay() {
+        let mut func = IrFunction::parse(
+            "@main() {
+.entry:
+  n = const 4
+  p = alloc n
+  nop
+  dead = const 7
+  v = load p
+  store p n
+  r = call @f n
+  free p
+  ret
+}",
+        )
+        .unwrap();
+        eliminate_deadcode(&mut func, None);
+        let lines: Vec<String> = func.blocks[0]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        // the unread const and load go and so does the nop, the call stays
+        // for what it does
+        assert_eq!(
+            lines,
+            [
+                "n = const 4",
+                "p = alloc n",
+                "store p n",
+                "r = call @f n",
+                "free p",
+                "ret"
+            ]
+        );
+    }
 
-            for u in instr.uses() {
-                live.insert(u.clone());
-            }
+    #[test]
+    fn test_dead_chains_go_across_blocks() {
+        // y only feeds the unused phi, and x only feeds y
+        let mut func = IrFunction::parse(
+            "@main(c: bool) {
+.entry:
+  x = const 1
+  one = const 1
+  br c .left .right
+.left:
+  y = add x one
+  jmp .join
+.right:
+  jmp .join
+.join:
+  z = phi y one
+  print one
+  ret
+}",
+        )
+        .unwrap();
+        eliminate_deadcode(&mut func, None);
+        let text = func.to_string();
+        for gone in ["x =", "y =", "z ="] {
+            assert!(!text.contains(gone), "{}", text);
+        }
+        assert_eq!(func.blocks[1].instrs.len(), 1);
+        assert_eq!(func.blocks[0].instrs[0].to_string(), "one = const 1");
+        assert_eq!(func.blocks[0].positions.len(), 2);
+    }
 
-            new_instrs.push(instr.clone());
+    #[test]
+    fn test_unused_chains_and_loop_phis_go() {
+        // `t` only feeds the next one and `acc` only goes round the loop
+        let mut func = IrFunction::parse(
+            "@main(n: int) {
+.entry:
+  zero = const 0
+  one = const 1
+  t = add n one
+  u = mul t t
+  jmp .head
+.head:
+  i = phi [zero, .entry] [i2, .body]
+  acc = phi [zero, .entry] [acc2, .body]
+  c = lt i n
+  br c .body .done
+.body:
+  i2 = add i one
+  acc2 = add acc u
+  jmp .head
+.done:
+  print i
+  ret
+}",
+        )
+        .unwrap();
+        eliminate_deadcode(&mut func, None);
+        let text = func.to_string();
+        for gone in ["t =", "u =", "acc =", "acc2 ="] {
+            assert!(!text.contains(gone), "{}", text);
+        }
+        // what the branch tests stays, and so does what it's computed from
+        for kept in ["i =", "i2 =", "c =", "zero =", "one ="] {
+            assert!(text.contains(kept), "{}", text);
         }
-        new_instrs.reverse();
-        block.instrs = new_instrs;
+    }
+
+    #[test]
+    fn test_calls_stay_with_their_result_unused() {
+        let mut func = IrFunction::parse(
+            "@main() {
+.entry:
+  x = const 1
+  r = call @f x
+  s = add r x
+  ret
+}",
+        )
+        .unwrap();
+        eliminate_deadcode(&mut func, None);
+        let lines: Vec<String> = func.blocks[0]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(lines, ["x = const 1", "r = call @f x", "ret"]);
+    }
+
+    #[test]
+    fn test_unused_calls_to_pure_functions_go() {
+        let mut module = bril_ir::text::parse_module(
+            "@main(n: int) {
+.entry:
+  s = call @square n
+  t = call @shout n
+  ret
+}
+
+@square(n: int): int {
+.entry:
+  s = mul n n
+  ret s
+}
+
+@shout(n: int): int {
+.entry:
+  print n
+  ret n
+}",
+        )
+        .unwrap();
+        let mut pm = crate::PassManager::new();
+        pm.add_pass(DeadCodeRemovalPass {});
+        pm.run(&mut module).unwrap();
+        let lines: Vec<String> = module.functions[0].blocks[0]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        // what @shout prints has to come out, unread or not
+        assert_eq!(lines, ["t = call @shout n", "ret"]);
     }
 }
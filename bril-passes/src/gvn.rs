@@ -0,0 +1,278 @@
+use crate::analysis_manager::{Analysis, AnalysisManager};
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::Symbol;
+use bril_ir::analysis::value_table::ValueTable;
+use bril_ir::{BlockID, DominatorInfo, IrFunction, IrInstruction, PhiSource};
+use std::collections::HashMap;
+
+/// Global Value Numbering over the dominator tree, on SSA
+///
+/// A walk down the dominator tree with a [`ValueTable`] scoped to it: an
+/// instruction computing what a block above it already did becomes an `id`
+/// of that, and whatever reads it reads the earlier name instead. A phi
+/// whose values all come to the same one is an `id` of it too. Nothing
+/// moves, so what two arms of a branch both compute stays in each.
+pub struct GvnPass {}
+
+impl FunctionPass for GvnPass {
+    fn name(&self) -> &str {
+        "GvnPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        self.run_with_analyses(function, &mut AnalysisManager::new(), stats)
+    }
+
+    fn run_with_analyses(
+        &mut self,
+        function: &mut IrFunction,
+        analyses: &mut AnalysisManager,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        // names only hold one value in SSA, a table of them says nothing otherwise
+        if bril_ir::verify_ssa(function).is_err() || function.blocks.is_empty() {
+            return Ok(false);
+        }
+        let Ok(doms) = analyses.dominators(function) else {
+            return Ok(false);
+        };
+        let before = function.clone();
+        stats.add("redundant values", gvn(function, doms));
+        Ok(*function != before)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        Analysis::CFG
+    }
+}
+
+/// Returns how many names turned out to hold an earlier value
+fn gvn(func: &mut IrFunction, doms: &DominatorInfo) -> usize {
+    // name -> the earlier one holding the same value
+    let mut leaders: HashMap<Symbol, Symbol> = HashMap::new();
+    number_block(func, doms, 0, &mut ValueTable::new(), &mut leaders);
+
+    // phis read values round back edges, from blocks the walk got to later
+    for instr in func.blocks.iter_mut().flat_map(|b| &mut b.instrs) {
+        for var in instr.uses_mut() {
+            if let Some(&leader) = leaders.get(var) {
+                *var = leader;
+            }
+        }
+    }
+    leaders.len()
+}
+
+fn number_block(
+    func: &mut IrFunction,
+    doms: &DominatorInfo,
+    b: BlockID,
+    table: &mut ValueTable,
+    leaders: &mut HashMap<Symbol, Symbol>,
+) {
+    table.push_scope();
+
+    let block = &mut func.blocks[b];
+    let mut phis_gone = Vec::new();
+    for (i, instr) in block.instrs.iter_mut().enumerate() {
+        if let IrInstruction::Phi { dest, sources } = instr {
+            if let Some(value) = same_value(*dest, sources, leaders) {
+                leaders.insert(*dest, value);
+                phis_gone.push((i, *dest, value));
+            }
+            continue;
+        }
+
+        for var in instr.uses_mut() {
+            if let Some(&leader) = leaders.get(var) {
+                *var = leader;
+            }
+        }
+        match (table.lookup(instr), instr.defs()) {
+            (Some(leader), &[dest]) => {
+                leaders.insert(dest, leader);
+                *instr = IrInstruction::Assign {
+                    lhs: dest,
+                    rhs: leader,
+                };
+            }
+            _ => {
+                table.insert(instr);
+            }
+        }
+    }
+
+    // the phis that went become copies after the ones left, so those stay
+    // together at the top of the block
+    if !phis_gone.is_empty() {
+        let kept: Vec<_> = phis_gone
+            .iter()
+            .map(|&(i, dest, value)| (dest, value, block.pos(i), block.origin(i)))
+            .collect();
+        block.remove_instrs(|instr| match instr {
+            IrInstruction::Phi { dest, .. } => kept.iter().any(|(d, ..)| d == dest),
+            _ => false,
+        });
+        let at = block
+            .instrs
+            .iter()
+            .take_while(|i| matches!(i, IrInstruction::Phi { .. }))
+            .count();
+        for (k, (lhs, rhs, pos, origin)) in kept.into_iter().enumerate() {
+            block.insert_instr(at + k, IrInstruction::Assign { lhs, rhs }, pos, origin);
+        }
+    }
+
+    let children = doms.dom_tree.get(&b).cloned().unwrap_or_default();
+    for child in children {
+        number_block(func, doms, child, table, leaders);
+    }
+
+    table.pop_scope();
+}
+
+/// The one value every source of the phi defining `dest` has, leaving out
+/// the phi reading itself round a loop. None if a source is undefined
+fn same_value(
+    dest: Symbol,
+    sources: &[PhiSource],
+    leaders: &HashMap<Symbol, Symbol>,
+) -> Option<Symbol> {
+    let mut same = None;
+    for src in sources {
+        let value = src.value?;
+        let value = leaders.get(&value).copied().unwrap_or(value);
+        if value == dest {
+            continue;
+        }
+        match same {
+            None => same = Some(value),
+            Some(v) if v == value => {}
+            Some(_) => return None,
+        }
+    }
+    same
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_lines(func: &IrFunction, label: &str) -> Vec<String> {
+        let b = func.block_index(&label.to_string()).unwrap();
+        func.blocks[b]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_dominating_computation_is_reused() {
+        let mut func = IrFunction::parse(
+            "@main(a: int, b: int, c: bool) {
+.entry:
+  s = add a b
+  r = call @f a
+  br c .left .right
+.left:
+  jmp .join
+.right:
+  jmp .join
+.join:
+  t = add b a
+  u = mul t t
+  v = mul s s
+  w = call @f a
+  print u v w
+  ret
+}",
+        )
+        .unwrap();
+        GvnPass {}
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap();
+        bril_ir::verify_ssa(&func).unwrap();
+        assert_eq!(
+            block_lines(&func, "join"),
+            [
+                "t = id s",
+                "u = mul s s",
+                "v = id u",
+                // calls are never the same value
+                "w = call @f a",
+                "print u u w",
+                "ret"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_both_arms_keep_their_own() {
+        // neither arm dominates the other or the join, there's nothing
+        // for the second one to reuse
+        let mut func = IrFunction::parse(
+            "@main(a: int, b: int, c: bool) {
+.entry:
+  br c .left .right
+.left:
+  x = add a b
+  jmp .join
+.right:
+  y = add a b
+  jmp .join
+.join:
+  p = phi [x, .left] [y, .right]
+  z = add a b
+  print p z
+  ret
+}",
+        )
+        .unwrap();
+        GvnPass {}
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap();
+        assert_eq!(block_lines(&func, "left")[0], "x = add a b");
+        assert_eq!(block_lines(&func, "right")[0], "y = add a b");
+        let join = block_lines(&func, "join");
+        assert!(join.contains(&"z = add a b".to_string()), "{:?}", join);
+        assert!(join[0].starts_with("p = phi "), "{:?}", join);
+    }
+
+    #[test]
+    fn test_phi_of_one_value_is_a_copy() {
+        let mut func = IrFunction::parse(
+            "@main(a: int, b: int, c: bool) {
+.entry:
+  s = add a b
+  br c .left .right
+.left:
+  x = add a b
+  jmp .join
+.right:
+  y = add b a
+  jmp .join
+.join:
+  q = phi [a, .left] [b, .right]
+  p = phi [x, .left] [y, .right]
+  print p q
+  ret
+}",
+        )
+        .unwrap();
+        GvnPass {}
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap();
+        bril_ir::verify_ssa(&func).unwrap();
+        assert_eq!(block_lines(&func, "left")[0], "x = id s");
+        assert_eq!(block_lines(&func, "right")[0], "y = id s");
+        assert_eq!(
+            block_lines(&func, "join"),
+            ["q = phi a b", "p = id s", "print s q", "ret"]
+        );
+    }
+}
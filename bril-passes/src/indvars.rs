@@ -0,0 +1,364 @@
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::{Literal, Symbol, Type};
+use bril_ir::analysis::loops::{self, Loop};
+use bril_ir::{BlockID, DominatorInfo, IrFunction, IrInstruction, PhiSource};
+use std::collections::HashMap;
+
+/// Biggest factor a derived induction variable is rewritten for
+const MAX_FACTOR: i64 = 64;
+
+/// Induction Variable Simplification, on SSA
+///
+/// A basic induction variable is a phi in a loop's header that gets a
+/// constant added to it each time round: `i = phi [init, .pre] [next,
+/// .latch]` with `next = add i c`. Anything that's `mul i k` of one, `k` a
+/// small positive constant, goes up by `c * k` each time too, so it gets a
+/// phi of its own starting from `init * k` in the preheader, and the latch
+/// adds the step to it where the multiply was done every iteration before.
+/// A `shl i s` counts as a multiply by `2^s`. Only loops with one latch are
+/// looked at.
+pub struct IndVarPass {}
+
+impl FunctionPass for IndVarPass {
+    fn name(&self) -> &str {
+        "IndVarPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        // no multiply went, so no preheader it made is needed either
+        let before = function.clone();
+        let replaced = simplify_induction_variables(function);
+        if replaced == 0 {
+            *function = before;
+            return Ok(false);
+        }
+        stats.add("multiplies replaced", replaced);
+        Ok(true)
+    }
+}
+
+/// A basic induction variable: where it starts and what's added each time
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BasicIv {
+    pub init: Symbol,
+    pub step: i64,
+}
+
+/// The blocks of a loop new code goes in
+struct Shape {
+    header: BlockID,
+    pre: BlockID,
+    pre_label: String,
+    latch: BlockID,
+    latch_label: String,
+}
+
+/// Returns how many multiplies were replaced
+fn simplify_induction_variables(func: &mut IrFunction) -> usize {
+    if bril_ir::verify_ssa(func).is_err() || func.blocks.is_empty() {
+        return 0;
+    }
+    let Ok(doms) = DominatorInfo::compute(func) else {
+        return 0;
+    };
+    let mut info = loops::compute(func, &doms);
+    if !func.ensure_preheaders(&info).is_empty() {
+        let Ok(doms) = DominatorInfo::compute(func) else {
+            return 0;
+        };
+        info = loops::compute(func, &doms);
+    }
+
+    let ints = int_consts(func);
+    let mut count = 0;
+    for l in info.loops_in_postorder() {
+        count += simplify_loop(func, l, &ints);
+    }
+    count
+}
+
+/// Every name defined by an int `const`, with its value
+pub(crate) fn int_consts(func: &IrFunction) -> HashMap<Symbol, i64> {
+    func.blocks
+        .iter()
+        .flat_map(|b| &b.instrs)
+        .filter_map(|instr| match instr {
+            IrInstruction::Const {
+                dest,
+                value: Literal::Int(i),
+            } => Some((*dest, *i)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The basic induction variables of `l`, by their phi in the header
+pub(crate) fn basic_ivs(
+    func: &IrFunction,
+    l: &Loop,
+    pre: BlockID,
+    latch: BlockID,
+    ints: &HashMap<Symbol, i64>,
+) -> HashMap<Symbol, BasicIv> {
+    let (pre_label, latch_label) = (&func.blocks[pre].label, &func.blocks[latch].label);
+    // what each name in the loop is defined as
+    let defs: HashMap<Symbol, &IrInstruction> = l
+        .blocks
+        .iter()
+        .flat_map(|&b| &func.blocks[b].instrs)
+        .filter_map(|instr| Some((*instr.defs().first()?, instr)))
+        .collect();
+
+    let mut basic = HashMap::new();
+    for instr in &func.blocks[l.header].instrs {
+        let IrInstruction::Phi { dest, sources } = instr else {
+            continue;
+        };
+        let value_from = |label: &str| {
+            sources
+                .iter()
+                .find(|s| s.pred == label)
+                .and_then(|s| s.value)
+        };
+        let (Some(init), Some(next)) = (value_from(pre_label), value_from(latch_label)) else {
+            continue;
+        };
+        let step = match defs.get(&next) {
+            Some(IrInstruction::Add { lhs, rhs, .. }) if lhs == dest => ints.get(rhs),
+            Some(IrInstruction::Add { lhs, rhs, .. }) if rhs == dest => ints.get(lhs),
+            _ => None,
+        };
+        if let Some(&step) = step {
+            basic.insert(*dest, BasicIv { init, step });
+        }
+    }
+    basic
+}
+
+fn simplify_loop(func: &mut IrFunction, l: &Loop, ints: &HashMap<Symbol, i64>) -> usize {
+    let (Some(pre), &[latch]) = (l.preheader, &l.latches[..]) else {
+        return 0;
+    };
+    let shape = Shape {
+        header: l.header,
+        pre,
+        pre_label: func.blocks[pre].label.clone(),
+        latch,
+        latch_label: func.blocks[latch].label.clone(),
+    };
+
+    let basic = basic_ivs(func, l, pre, latch, ints);
+    if basic.is_empty() {
+        return 0;
+    }
+
+    // (block, dest, the basic one, factor) of every multiply to go. By
+    // dest, the phis going in move the header's instructions down
+    let mut derived = Vec::new();
+    for &b in &l.blocks {
+        for instr in &func.blocks[b].instrs {
+            let found = match instr {
+                IrInstruction::Mul { dest, lhs, rhs } => match (ints.get(lhs), ints.get(rhs)) {
+                    (_, Some(&k)) if basic.contains_key(lhs) => Some((*dest, *lhs, k)),
+                    (Some(&k), _) if basic.contains_key(rhs) => Some((*dest, *rhs, k)),
+                    _ => None,
+                },
+                IrInstruction::Shl { dest, src, amount }
+                    if basic.contains_key(src) && *amount < 63 =>
+                {
+                    Some((*dest, *src, 1 << amount))
+                }
+                _ => None,
+            };
+            if let Some((dest, iv, k)) = found
+                && (2..=MAX_FACTOR).contains(&k)
+            {
+                derived.push((b, dest, iv, k));
+            }
+        }
+    }
+
+    // one new variable per basic one and factor
+    let mut made: HashMap<(Symbol, i64), Symbol> = HashMap::new();
+    let mut count = 0;
+    for (b, dest, iv, k) in derived {
+        let BasicIv { init, step } = basic[&iv];
+        let Some(step) = step.checked_mul(k) else {
+            continue;
+        };
+        let var = match made.get(&(iv, k)) {
+            Some(&var) => var,
+            None => {
+                let var = new_iv(func, &shape, init, k, step, ints, dest);
+                made.insert((iv, k), var);
+                var
+            }
+        };
+        let mul = func.blocks[b]
+            .instrs
+            .iter_mut()
+            .find(|instr| instr.defs() == [dest])
+            .unwrap();
+        *mul = IrInstruction::Assign {
+            lhs: dest,
+            rhs: var,
+        };
+        count += 1;
+    }
+    count
+}
+
+/// Add `var = phi [init * k, .pre] [var + step, .latch]` to the header,
+/// the start worked out in the preheader and the add done in the latch
+fn new_iv(
+    func: &mut IrFunction,
+    shape: &Shape,
+    init: Symbol,
+    k: i64,
+    step: i64,
+    ints: &HashMap<Symbol, i64>,
+    dest: Symbol,
+) -> Symbol {
+    let base = dest.to_string();
+    let fresh = |func: &mut IrFunction, suffix: &str| {
+        let var = func.fresh_var(&format!("{}.{}", base, suffix));
+        func.types.insert(var, Type::Int);
+        var
+    };
+    let var = fresh(func, "iv");
+    let start = fresh(func, "start");
+    let by = fresh(func, "step");
+    let next = fresh(func, "next");
+
+    // consts of their own, the ones the loop has needn't be in scope here
+    let mut in_pre = Vec::new();
+    match ints.get(&init).and_then(|i| i.checked_mul(k)) {
+        Some(value) => in_pre.push(IrInstruction::Const {
+            dest: start,
+            value: Literal::Int(value),
+        }),
+        None => {
+            let factor = fresh(func, "factor");
+            in_pre.push(IrInstruction::Const {
+                dest: factor,
+                value: Literal::Int(k),
+            });
+            in_pre.push(IrInstruction::Mul {
+                dest: start,
+                lhs: init,
+                rhs: factor,
+            });
+        }
+    }
+    in_pre.push(IrInstruction::Const {
+        dest: by,
+        value: Literal::Int(step),
+    });
+    for instr in in_pre {
+        insert_before_end(func, shape.pre, instr);
+    }
+    insert_before_end(
+        func,
+        shape.latch,
+        IrInstruction::Add {
+            dest: next,
+            lhs: var,
+            rhs: by,
+        },
+    );
+
+    let phi = IrInstruction::Phi {
+        dest: var,
+        sources: vec![
+            PhiSource::new(&shape.pre_label, Some(start)),
+            PhiSource::new(&shape.latch_label, Some(next)),
+        ],
+    };
+    func.blocks[shape.header].insert_instr(0, phi, None, None);
+    var
+}
+
+/// Put `instr` last in block `b`, before its terminator if it has one
+fn insert_before_end(func: &mut IrFunction, b: BlockID, instr: IrInstruction) {
+    let block = &mut func.blocks[b];
+    let at = match block.instrs.last() {
+        Some(last) if last.is_terminator() => block.instrs.len() - 1,
+        _ => block.instrs.len(),
+    };
+    block.insert_instr(at, instr, None, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counted(body: &str) -> IrFunction {
+        IrFunction::parse(&format!(
+            "@main(n: int) {{
+.entry:
+  zero = const 0
+  one = const 1
+  jmp .head
+.head:
+  i = phi [zero, .entry] [i2, .body]
+  sum = phi [zero, .entry] [sum2, .body]
+  c = lt i n
+  br c .body .exit
+.body:
+{}
+  sum2 = add sum off
+  i2 = add i one
+  jmp .head
+.exit:
+  print sum
+  ret
+}}",
+            body
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_multiply_becomes_an_add() {
+        let mut func = counted("  eight = const 8\n  off = mul i eight");
+        assert_eq!(simplify_induction_variables(&mut func), 1);
+        bril_ir::verify_ssa(&func).unwrap();
+        let text = func.to_string();
+        assert!(!text.contains("mul"), "{}", text);
+        assert!(text.contains("off = id off.iv"), "{}", text);
+        assert!(
+            text.contains("off.iv: int = phi [off.start, .entry] [off.next, .body]"),
+            "{}",
+            text
+        );
+        assert!(text.contains("off.start: int = const 0"), "{}", text);
+        assert!(text.contains("off.step: int = const 8"), "{}", text);
+        assert!(
+            text.contains("off.next: int = add off.iv off.step\n  jmp .head"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_shift_becomes_an_add() {
+        let mut func = counted("  off = shl i 2");
+        assert_eq!(simplify_induction_variables(&mut func), 1);
+        bril_ir::verify_ssa(&func).unwrap();
+        let text = func.to_string();
+        assert!(!text.contains("shl"), "{}", text);
+        assert!(text.contains("off.step: int = const 4"), "{}", text);
+    }
+
+    #[test]
+    fn test_big_factors_stay() {
+        let mut func = counted("  big = const 1000\n  off = mul i big");
+        let before = func.clone();
+        assert_eq!(simplify_induction_variables(&mut func), 0);
+        assert_eq!(func, before);
+    }
+}
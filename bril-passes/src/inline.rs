@@ -0,0 +1,427 @@
+use crate::pass_manager::{ModulePass, PassError, PassOptions, Statistics};
+use bril_frontend::Symbol;
+use bril_ir::analysis::callgraph::CallGraph;
+use bril_ir::{BlockID, FuncId, IrFunction, IrInstruction, IrModule, PhiSource};
+use std::collections::{HashMap, HashSet};
+
+/// Inlining of small functions, on SSA
+///
+/// A call to a function of at most `threshold` instructions that can't end
+/// up calling itself is replaced by a copy of its body. The block with the
+/// call is split in two: the first half jumps into the copy, and every
+/// `ret` of the copy jumps to the second half, where the call's dest is an
+/// `id` of the value returned, or a phi of them when there's more than one
+/// `ret`. Callees go first, so what they call has been inlined into them
+/// already. The callee itself stays, see `DeadFunctionPass`. The
+/// `inline.threshold` option overrides the threshold it was made with.
+pub struct InlinePass {
+    threshold: usize,
+}
+
+impl InlinePass {
+    pub fn new(threshold: usize) -> Self {
+        Self { threshold }
+    }
+}
+
+impl Default for InlinePass {
+    fn default() -> Self {
+        Self::new(32)
+    }
+}
+
+impl ModulePass for InlinePass {
+    fn name(&self) -> &str {
+        "InlinePass"
+    }
+
+    fn run_on_module(
+        &mut self,
+        module: &mut IrModule,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let inlined = inline_calls(module, self.threshold);
+        stats.add("calls inlined", inlined);
+        Ok(inlined > 0)
+    }
+
+    fn set_options(&mut self, options: &PassOptions) -> Result<(), PassError> {
+        if let Some(threshold) = options.get_usize("inline.threshold")? {
+            self.threshold = threshold;
+        }
+        Ok(())
+    }
+
+    fn option_keys(&self) -> &[&'static str] {
+        &["inline.threshold"]
+    }
+}
+
+/// Inline every call that qualifies, returning how many
+fn inline_calls(module: &mut IrModule, threshold: usize) -> usize {
+    let graph = CallGraph::build(module);
+    let ids: HashMap<String, FuncId> = module
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (f.name.clone(), FuncId(i)))
+        .collect();
+    let inlinable = |module: &IrModule, caller: FuncId, callee: FuncId| {
+        let func = &module.functions[callee.0];
+        let size: usize = func.blocks.iter().map(|b| b.instrs.len()).sum();
+        // a jump back to the entry would need the call's edge in its phis
+        callee != caller
+            && !graph.is_recursive(callee)
+            && size <= threshold
+            && func.blocks.first().is_some_and(|b| b.preds.is_empty())
+    };
+
+    let mut count = 0;
+    for caller in graph.bottom_up() {
+        loop {
+            let func = &module.functions[caller.0];
+            let site = func.blocks.iter().enumerate().find_map(|(b, block)| {
+                block.instrs.iter().enumerate().find_map(|(i, instr)| {
+                    let IrInstruction::Call {
+                        target_func,
+                        callee,
+                        ..
+                    } = instr
+                    else {
+                        return None;
+                    };
+                    let callee = callee.or_else(|| ids.get(target_func).copied())?;
+                    inlinable(module, caller, callee).then_some((b, i, callee))
+                })
+            });
+            let Some((b, i, callee)) = site else {
+                break;
+            };
+            let body = module.functions[callee.0].clone();
+            inline_call(&mut module.functions[caller.0], b, i, &body);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Replace the call at `func.blocks[b].instrs[i]` with a copy of `callee`
+fn inline_call(func: &mut IrFunction, b: BlockID, i: usize, callee: &IrFunction) {
+    let IrInstruction::Call { args, dest, .. } = func.blocks[b].instrs[i].clone() else {
+        panic!("instruction {} of block {} isn't a call", i, b);
+    };
+
+    // the params are the args, everything else gets a name of its own
+    let mut taken: HashSet<Symbol> = func
+        .blocks
+        .iter()
+        .flat_map(|b| &b.instrs)
+        .flat_map(|i| i.defs().iter().copied().chain(i.uses()))
+        .chain(func.args.iter().copied())
+        .chain(func.types.keys().copied())
+        .collect();
+    let mut names: HashMap<Symbol, Symbol> = callee.args.iter().copied().zip(args).collect();
+    for instr in callee.blocks.iter().flat_map(|b| &b.instrs) {
+        for var in instr.defs().iter().copied().chain(instr.uses()) {
+            if names.contains_key(&var) {
+                continue;
+            }
+            let base = format!("{}.{}", callee.name, var);
+            let fresh = (0..)
+                .map(|n| match n {
+                    0 => Symbol::from(base.as_str()),
+                    n => Symbol::from(format!("{}.{}", base, n)),
+                })
+                .find(|v| !taken.contains(v))
+                .unwrap();
+            taken.insert(fresh);
+            names.insert(var, fresh);
+            if let Some(typ) = callee.types.get(&var) {
+                func.types.insert(fresh, typ.clone());
+            }
+        }
+    }
+
+    let mut labels: HashMap<String, String> = HashMap::new();
+    let mut copies: Vec<BlockID> = Vec::new();
+    for block in &callee.blocks {
+        let label = func.fresh_label(&format!("{}.{}", callee.name, block.label));
+        copies.push(func.add_block(&label).unwrap());
+        labels.insert(block.label.clone(), label);
+    }
+    let cont_label = func.fresh_label(&format!("{}.cont", func.blocks[b].label));
+    let cont = func.add_block(&cont_label).unwrap();
+
+    // everything after the call goes on in `cont`, and so do the edges out
+    let block = &mut func.blocks[b];
+    block.positions.resize(block.instrs.len(), None);
+    block.origins.resize(block.instrs.len(), None);
+    let mut rest = block.instrs.split_off(i);
+    let mut positions = block.positions.split_off(i);
+    let mut origins = block.origins.split_off(i);
+    rest.remove(0);
+    positions.remove(0);
+    origins.remove(0);
+    let succs = std::mem::take(&mut block.succs);
+    let label = block.label.clone();
+    if !rest.last().is_some_and(|i| i.is_terminator()) {
+        // fell through, or off the end of the function
+        rest.push(match succs.first() {
+            Some(&next) => IrInstruction::Jmp {
+                label: func.blocks[next].label.clone(),
+            },
+            None => IrInstruction::Ret { args: Vec::new() },
+        });
+        positions.push(None);
+        origins.push(None);
+    }
+    for &s in &succs {
+        for p in func.blocks[s].preds.iter_mut().filter(|p| **p == b) {
+            *p = cont;
+        }
+        func.retarget_phi_sources(s, &label, &cont_label);
+    }
+    let cont_block = &mut func.blocks[cont];
+    cont_block.instrs = rest;
+    cont_block.positions = positions;
+    cont_block.origins = origins;
+    cont_block.succs = succs;
+
+    let entry = labels[&callee.blocks[0].label].clone();
+    func.append_instr(b, &IrInstruction::Jmp { label: entry }, None, None);
+    func.add_edge(b, copies[0]);
+
+    // the copy, its `ret`s going to `cont`
+    let mut returns: Vec<(String, Option<Symbol>)> = Vec::new();
+    for (k, block) in callee.blocks.iter().enumerate() {
+        let to = copies[k];
+        for (j, instr) in block.instrs.iter().enumerate() {
+            let mut instr = instr.clone();
+            for var in instr.uses_mut() {
+                *var = names[var];
+            }
+            if let Some(var) = instr.defs_mut() {
+                *var = names[var];
+            }
+            match &mut instr {
+                IrInstruction::Ret { args } => {
+                    returns.push((func.blocks[to].label.clone(), args.first().copied()));
+                    instr = IrInstruction::Jmp {
+                        label: cont_label.clone(),
+                    };
+                }
+                IrInstruction::Jmp { label } => *label = labels[label].clone(),
+                IrInstruction::Br {
+                    then_lbl, else_lbl, ..
+                } => {
+                    *then_lbl = labels[then_lbl].clone();
+                    *else_lbl = labels[else_lbl].clone();
+                }
+                IrInstruction::Phi { sources, .. } => {
+                    for src in sources {
+                        src.pred = labels[&src.pred].clone();
+                    }
+                }
+                IrInstruction::Opaque {
+                    labels: targets, ..
+                } => {
+                    for target in targets {
+                        *target = labels[target].clone();
+                    }
+                }
+                _ => {}
+            }
+            // the origins count instructions of the caller
+            func.append_instr(to, &instr, block.pos(j), None);
+        }
+
+        if !block.instrs.last().is_some_and(|i| i.is_terminator()) {
+            let label = match block.succs.first() {
+                Some(&next) => func.blocks[copies[next]].label.clone(),
+                None => {
+                    returns.push((func.blocks[to].label.clone(), None));
+                    cont_label.clone()
+                }
+            };
+            func.append_instr(to, &IrInstruction::Jmp { label }, None, None);
+        }
+        for &s in &block.succs {
+            func.add_edge(to, copies[s]);
+        }
+        if block.succs.is_empty() {
+            func.add_edge(to, cont);
+        }
+    }
+
+    let Some(dest) = dest else {
+        return;
+    };
+    let result = match &returns[..] {
+        [(_, Some(value))] => IrInstruction::Assign {
+            lhs: dest,
+            rhs: *value,
+        },
+        _ => IrInstruction::Phi {
+            dest,
+            sources: returns
+                .iter()
+                .map(|(pred, value)| PhiSource::new(pred, *value))
+                .collect(),
+        },
+    };
+    func.blocks[cont].insert_instr(0, result, None, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pass_manager::PassManager;
+    use crate::DeadFunctionPass;
+
+    fn module(src: &str) -> IrModule {
+        let mut module = bril_ir::text::parse_module(src).unwrap();
+        module.resolve_calls().unwrap();
+        module
+    }
+
+    #[test]
+    fn test_small_callee_inlined_at_each_call() {
+        let mut module = module(
+            "@main(a: int) {
+.entry:
+  x = call @square a
+  y = call @square x
+  print y
+  ret
+}
+
+@square(x: int): int {
+.entry:
+  r = mul x x
+  ret r
+}",
+        );
+        let mut pm = PassManager::new();
+        pm.add_module_pass(InlinePass::default());
+        pm.add_module_pass(DeadFunctionPass::new());
+        pm.set_verify_each(true);
+        pm.run(&mut module).unwrap();
+
+        let main = &module.functions[0];
+        let text = main.to_string();
+        assert!(!text.contains("call"), "{}", text);
+        assert_eq!(text.matches(" = mul ").count(), 2, "{}", text);
+        assert!(text.contains("square.r = mul a a"), "{}", text);
+        assert!(text.contains("x = id square.r\n"), "{}", text);
+        assert!(text.contains("  print y\n  ret\n"), "{}", text);
+        // nothing calls `square` any more
+        assert_eq!(module.functions.len(), 1);
+    }
+
+    #[test]
+    fn test_threshold_option_decides_the_call() {
+        let calls = |threshold: usize| {
+            let mut module = module(
+                "@main(a: int) {
+.entry:
+  x = call @square a
+  print x
+  ret
+}
+
+@square(x: int): int {
+.entry:
+  r = mul x x
+  ret r
+}",
+            );
+            let mut pm = PassManager::new();
+            pm.add_module_pass(InlinePass::default());
+            pm.set_options(PassOptions::new().with("inline.threshold", threshold));
+            pm.set_verify_each(true);
+            pm.run(&mut module).unwrap();
+            module.functions[0].to_string().matches("call").count()
+        };
+        // `square` is two instructions
+        assert_eq!(calls(1), 1);
+        assert_eq!(calls(2), 0);
+    }
+
+    #[test]
+    fn test_recursive_and_big_callees_stay() {
+        let src = "@main(n: int) {
+.entry:
+  f = call @fact n
+  b = call @big n
+  print f b
+  ret
+}
+
+@fact(n: int): int {
+.entry:
+  one = const 1
+  c = le n one
+  br c .base .rec
+.base:
+  ret one
+.rec:
+  m = sub n one
+  r = call @fact m
+  p = mul n r
+  ret p
+}
+
+@big(n: int): int {
+.entry:
+  a = add n n
+  b = add a a
+  c = add b b
+  ret c
+}";
+        let mut module = module(src);
+        assert_eq!(inline_calls(&mut module, 3), 0);
+        assert_eq!(inline_calls(&mut module, 4), 1);
+        let main = module.functions[0].to_string();
+        assert!(main.contains("call @fact"), "{}", main);
+        assert!(!main.contains("call @big"), "{}", main);
+    }
+
+    #[test]
+    fn test_several_returns_meet_in_a_phi() {
+        let mut module = module(
+            "@main(n: int) {
+.entry:
+  one = const 1
+  m = call @abs n
+  s = add m one
+  print s
+  ret
+}
+
+@abs(n: int): int {
+.entry:
+  zero = const 0
+  neg = lt n zero
+  br neg .flip .keep
+.flip:
+  r = sub zero n
+  ret r
+.keep:
+  ret n
+}",
+        );
+        assert_eq!(inline_calls(&mut module, 32), 1);
+        let main = &module.functions[0];
+        bril_ir::verify_ssa(main).unwrap();
+        let cont = main.block_index(&"entry.cont".to_string()).unwrap();
+        let lines: Vec<String> = main.blocks[cont]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            ["m = phi abs.r n", "s = add m one", "print s", "ret"]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use crate::analysis_manager::Analysis;
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::{Literal, Symbol};
+use bril_ir::IrFunction;
+use bril_ir::IrInstruction;
+use std::collections::HashMap;
+
+/// Algebraic Simplification
+///
+/// Works on SSA, like constant folding. Ops that give back one of their
+/// operands (`add x 0`, `mul x 1`, `and x true`, `not` of a `not`, ...)
+/// become an `id` of it, and ones whose result doesn't depend on the
+/// operands (`mul x 0`, `sub x x`, `eq x x`, ...) a `const`. A constant
+/// operand of a commutative op goes on the right first, so only that side
+/// needs looking at here and value numbering sees one order. Floats are
+/// left alone, `fadd x 0.0` isn't `x` when `x` is `-0.0`.
+pub struct InstCombinePass {}
+
+impl FunctionPass for InstCombinePass {
+    fn name(&self) -> &str {
+        "InstCombinePass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        _stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let before = function.clone();
+        combine(function);
+        Ok(*function != before)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        Analysis::CFG
+    }
+}
+
+fn combine(func: &mut IrFunction) {
+    let mut const_env: HashMap<Symbol, Literal> = HashMap::new();
+    // dest -> operand of every `not`
+    let mut nots: HashMap<Symbol, Symbol> = HashMap::new();
+    // in SSA a def dominates its uses, so RPO sees it before them
+    for b in func.reverse_postorder() {
+        for instr in func.blocks[b].instrs.iter_mut() {
+            constants_to_the_right(instr, &const_env);
+            if let Some(simpler) = simplify(instr, &const_env, &nots) {
+                *instr = simpler;
+            }
+            match instr {
+                IrInstruction::Const { dest, value } => {
+                    const_env.insert(*dest, value.clone());
+                }
+                IrInstruction::Not { dest, args } => {
+                    nots.insert(*dest, *args);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn constants_to_the_right(instr: &mut IrInstruction, const_env: &HashMap<Symbol, Literal>) {
+    if let IrInstruction::Add { lhs, rhs, .. }
+    | IrInstruction::Mul { lhs, rhs, .. }
+    | IrInstruction::And { lhs, rhs, .. }
+    | IrInstruction::Or { lhs, rhs, .. }
+    | IrInstruction::Eq { lhs, rhs, .. }
+    | IrInstruction::CEq { lhs, rhs, .. } = instr
+        && const_env.contains_key(lhs)
+        && !const_env.contains_key(rhs)
+    {
+        std::mem::swap(lhs, rhs);
+    }
+}
+
+/// The simpler instruction `instr` is the same as, its constant operand
+/// already on the right
+fn simplify(
+    instr: &IrInstruction,
+    const_env: &HashMap<Symbol, Literal>,
+    nots: &HashMap<Symbol, Symbol>,
+) -> Option<IrInstruction> {
+    let int = |var: &Symbol| match const_env.get(var) {
+        Some(Literal::Int(i)) => Some(*i),
+        _ => None,
+    };
+    let boolean = |var: &Symbol| match const_env.get(var) {
+        Some(Literal::Bool(b)) => Some(*b),
+        _ => None,
+    };
+    let copy = |lhs: &Symbol, rhs: &Symbol| IrInstruction::Assign {
+        lhs: *lhs,
+        rhs: *rhs,
+    };
+    let constant = |dest: &Symbol, value| IrInstruction::Const { dest: *dest, value };
+
+    let simpler = match instr {
+        IrInstruction::Add { dest, lhs, rhs } | IrInstruction::Sub { dest, lhs, rhs }
+            if int(rhs) == Some(0) =>
+        {
+            copy(dest, lhs)
+        }
+        IrInstruction::Mul { dest, lhs, rhs } | IrInstruction::Div { dest, lhs, rhs }
+            if int(rhs) == Some(1) =>
+        {
+            copy(dest, lhs)
+        }
+        // anything times 0 is 0 and anything minus itself is too, `div x x`
+        // isn't 1 though, not when `x` is 0
+        IrInstruction::Mul { dest, rhs, .. } if int(rhs) == Some(0) => {
+            constant(dest, Literal::Int(0))
+        }
+        IrInstruction::Sub { dest, lhs, rhs } if lhs == rhs => constant(dest, Literal::Int(0)),
+
+        IrInstruction::And { dest, lhs, rhs } => match boolean(rhs) {
+            Some(true) => copy(dest, lhs),
+            Some(false) => constant(dest, Literal::Bool(false)),
+            None if lhs == rhs => copy(dest, lhs),
+            None => return None,
+        },
+        IrInstruction::Or { dest, lhs, rhs } => match boolean(rhs) {
+            Some(false) => copy(dest, lhs),
+            Some(true) => constant(dest, Literal::Bool(true)),
+            None if lhs == rhs => copy(dest, lhs),
+            None => return None,
+        },
+        IrInstruction::Not { dest, args } => copy(dest, nots.get(args)?),
+
+        IrInstruction::Eq { dest, lhs, rhs }
+        | IrInstruction::Le { dest, lhs, rhs }
+        | IrInstruction::Ge { dest, lhs, rhs }
+        | IrInstruction::CEq { dest, lhs, rhs }
+        | IrInstruction::CLe { dest, lhs, rhs }
+        | IrInstruction::CGe { dest, lhs, rhs }
+            if lhs == rhs =>
+        {
+            constant(dest, Literal::Bool(true))
+        }
+        IrInstruction::Lt { dest, lhs, rhs }
+        | IrInstruction::Gt { dest, lhs, rhs }
+        | IrInstruction::CLt { dest, lhs, rhs }
+        | IrInstruction::CGt { dest, lhs, rhs }
+            if lhs == rhs =>
+        {
+            constant(dest, Literal::Bool(false))
+        }
+        _ => return None,
+    };
+    Some(simpler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn combined(src: &str) -> Vec<String> {
+        let mut func = IrFunction::parse(src).unwrap();
+        combine(&mut func);
+        func.blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_identities() {
+        let lines = combined(
+            "@main(x: int, p: bool) {
+.entry:
+  zero = const 0
+  one = const 1
+  t = const true
+  f = const false
+  a = add zero x
+  b = sub x zero
+  c = mul one x
+  d = div x one
+  e = and p t
+  g = or f p
+  h = not p
+  i = not h
+  j = and p p
+  ret
+}",
+        );
+        assert_eq!(
+            lines[4..],
+            [
+                "a = id x",
+                "b = id x",
+                "c = id x",
+                "d = id x",
+                "e = id p",
+                "g = id p",
+                "h = not p",
+                "i = id p",
+                "j = id p",
+                "ret"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_results_that_dont_depend_on_the_operands() {
+        // neither can overflow, with x = i64::MIN too: `mul x 0` and
+        // `sub x x` are 0 whether the arithmetic wraps or not
+        let lines = combined(
+            "@main(x: int, p: bool, c: char) {
+.entry:
+  zero = const 0
+  t = const true
+  f = const false
+  a = mul x zero
+  b = sub x x
+  d = and f p
+  e = or p t
+  g = eq x x
+  h = lt x x
+  i = cge c c
+  j = div x x
+  ret
+}",
+        );
+        assert_eq!(
+            lines[3..],
+            [
+                "a = const 0",
+                "b = const 0",
+                "d = const false",
+                "e = const true",
+                "g = const true",
+                "h = const false",
+                "i = const true",
+                // traps when x is 0, that has to stay
+                "j = div x x",
+                "ret"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constants_go_to_the_right() {
+        let lines = combined(
+            "@main(x: int, y: int) {
+.entry:
+  two = const 2
+  a = mul two x
+  b = add two y
+  c = sub two x
+  d = eq two x
+  ret
+}",
+        );
+        assert_eq!(
+            lines[1..5],
+            [
+                "a = mul x two",
+                "b = add y two",
+                "c = sub two x",
+                "d = eq x two"
+            ]
+        );
+    }
+}
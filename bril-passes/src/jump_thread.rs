@@ -0,0 +1,409 @@
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use crate::simplify_cfg::retarget;
+use bril_frontend::{Literal, Symbol};
+use bril_ir::{BlockID, IrFunction, IrInstruction, PhiSource};
+use std::collections::{HashMap, HashSet};
+
+/// Most instructions, phis and the `br` aside, a block can have and still
+/// be copied for a pred
+const MAX_COPIED: usize = 8;
+
+/// Jump Threading, on SSA
+///
+/// A block ending in a `br` whose condition is known coming in from one of
+/// its preds, because it's a `const`, a phi taking a `const` from that
+/// pred, or the pred was only got to by branching on it, needn't be gone
+/// through from there: the pred goes where the `br` would have sent it.
+/// Whatever else the block does comes along, in a copy of it for that pred
+/// ending in a `jmp`, as long as that's at most `threshold` instructions,
+/// none with side effects, and what they define isn't read past the block
+/// other than by the phis of its succs. A block every pred was threaded
+/// past goes
+pub struct JumpThreadPass {
+    threshold: usize,
+}
+
+impl JumpThreadPass {
+    pub fn new(threshold: usize) -> Self {
+        Self { threshold }
+    }
+}
+
+impl Default for JumpThreadPass {
+    fn default() -> Self {
+        Self::new(MAX_COPIED)
+    }
+}
+
+impl FunctionPass for JumpThreadPass {
+    fn name(&self) -> &str {
+        "JumpThreadPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let threaded = thread_jumps(function, self.threshold);
+        stats.add("jumps threaded", threaded);
+        Ok(threaded > 0)
+    }
+}
+
+/// Thread every edge that can be, returns how many were
+fn thread_jumps(func: &mut IrFunction, threshold: usize) -> usize {
+    // a pred only goes past a block once, or a loop of `br`s on constants
+    // would send it round forever
+    let mut done = HashSet::new();
+    // one at a time, each changes the preds of the blocks it touches. The
+    // copies go on the end, so the indices hold until the blocks left
+    // behind are taken out
+    while let Some((p, b, taken)) = threadable(func, threshold, &done) {
+        done.insert((p, b));
+        thread(func, p, b, taken);
+    }
+    if !done.is_empty() {
+        func.remove_unreachable_blocks();
+    }
+    done.len()
+}
+
+/// A pred, the block it can go past, and the succ it goes to instead
+fn threadable(
+    func: &IrFunction,
+    threshold: usize,
+    done: &HashSet<(BlockID, BlockID)>,
+) -> Option<(BlockID, BlockID, BlockID)> {
+    let mut consts = HashMap::new();
+    for instr in func.blocks.iter().flat_map(|b| &b.instrs) {
+        if let IrInstruction::Const {
+            dest,
+            value: Literal::Bool(value),
+        } = instr
+        {
+            consts.insert(*dest, *value);
+        }
+    }
+
+    for (b, block) in func.blocks.iter().enumerate() {
+        let Some(IrInstruction::Br {
+            cond,
+            then_lbl,
+            else_lbl,
+        }) = block.instrs.last()
+        else {
+            continue;
+        };
+        if then_lbl == else_lbl || block.preds.is_empty() {
+            continue;
+        }
+        let copied = block
+            .instrs
+            .iter()
+            .filter(|i| !matches!(i, IrInstruction::Phi { .. }) && !i.is_terminator());
+        if copied.clone().count() > threshold
+            || copied.clone().any(|i| i.has_side_effects())
+            || !kept_in(func, b)
+        {
+            continue;
+        }
+        for &p in &block.preds {
+            if p == b || done.contains(&(p, b)) || !phis_defined_from(func, p, b) {
+                continue;
+            }
+            let Some(value) = known_on_edge(func, &consts, *cond, p, b) else {
+                continue;
+            };
+            let label = if value { then_lbl } else { else_lbl };
+            match func.block_index(label) {
+                Some(taken) if taken != b => return Some((p, b, taken)),
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+/// Whether what `b` defines is only read in `b`, or by phis on the edges
+/// out of it, so a copy of it needs nothing merged back together after
+fn kept_in(func: &IrFunction, b: BlockID) -> bool {
+    let block = &func.blocks[b];
+    let defs: HashSet<Symbol> = block
+        .instrs
+        .iter()
+        .flat_map(|i| i.defs().iter().copied())
+        .collect();
+    func.blocks
+        .iter()
+        .enumerate()
+        .filter(|&(c, _)| c != b)
+        .flat_map(|(_, other)| &other.instrs)
+        .all(|instr| match instr {
+            IrInstruction::Phi { sources, .. } => sources
+                .iter()
+                .all(|s| s.pred == block.label || s.value.is_none_or(|v| !defs.contains(&v))),
+            _ => instr.uses().iter().all(|var| !defs.contains(var)),
+        })
+}
+
+/// Whether every phi of `b` has a value coming in from `p`
+fn phis_defined_from(func: &IrFunction, p: BlockID, b: BlockID) -> bool {
+    let label = &func.blocks[p].label;
+    func.blocks[b].instrs.iter().all(|instr| match instr {
+        IrInstruction::Phi { sources, .. } => sources
+            .iter()
+            .any(|s| s.pred == *label && s.value.is_some()),
+        _ => true,
+    })
+}
+
+/// What `cond` is on the edge from `p` into `b`, if that's known
+fn known_on_edge(
+    func: &IrFunction,
+    consts: &HashMap<Symbol, bool>,
+    mut cond: Symbol,
+    p: BlockID,
+    b: BlockID,
+) -> Option<bool> {
+    // a phi of `b` is what it takes from `p`
+    let pred_label = &func.blocks[p].label;
+    for instr in &func.blocks[b].instrs {
+        if let IrInstruction::Phi { dest, sources } = instr
+            && *dest == cond
+        {
+            cond = sources.iter().find(|s| s.pred == *pred_label)?.value?;
+        }
+    }
+    if let Some(&value) = consts.get(&cond) {
+        return Some(value);
+    }
+
+    // otherwise a `br` on it further up, with only one way from there to
+    // the edge
+    let (mut from, mut to) = (p, b);
+    let mut seen = HashSet::new();
+    while seen.insert(from) {
+        if let Some(IrInstruction::Br {
+            cond: c,
+            then_lbl,
+            else_lbl,
+        }) = func.blocks[from].instrs.last()
+        {
+            if *c == cond && then_lbl != else_lbl {
+                return Some(*then_lbl == func.blocks[to].label);
+            }
+            return None;
+        }
+        let [up] = func.blocks[from].preds[..] else {
+            return None;
+        };
+        (from, to) = (up, from);
+    }
+    None
+}
+
+/// Send `p` on to `taken` instead of through `b`, by way of a copy of `b`
+/// for it unless `b` only has phis to copy
+fn thread(func: &mut IrFunction, p: BlockID, b: BlockID, taken: BlockID) {
+    let block = func.blocks[b].clone();
+    let pred_label = func.blocks[p].label.clone();
+    let taken_label = func.blocks[taken].label.clone();
+
+    // the phis of `b` are what they take from `p`, everything else it
+    // defines gets a name of its own
+    let mut names: HashMap<Symbol, Symbol> = HashMap::new();
+    let mut copied = Vec::new();
+    for (i, instr) in block.instrs.iter().enumerate() {
+        match instr {
+            IrInstruction::Phi { dest, sources } => {
+                let value = sources.iter().find(|s| s.pred == pred_label);
+                names.insert(*dest, value.unwrap().value.unwrap());
+            }
+            _ if instr.is_terminator() => {}
+            _ => {
+                let mut instr = instr.clone();
+                for var in instr.uses_mut() {
+                    *var = names.get(var).copied().unwrap_or(*var);
+                }
+                if let Some(def) = instr.defs_mut() {
+                    let var = func.fresh_var(def.as_ref());
+                    if let Some(typ) = func.types.get(def).cloned() {
+                        func.types.insert(var, typ);
+                    }
+                    names.insert(*def, var);
+                    *def = var;
+                }
+                copied.push((instr, block.pos(i), block.origin(i)));
+            }
+        }
+    }
+    // what the phis of `taken` get along the new edge
+    let incoming: Vec<Option<Symbol>> = func.blocks[taken]
+        .instrs
+        .iter()
+        .filter_map(|instr| match instr {
+            IrInstruction::Phi { sources, .. } => {
+                let value = sources.iter().find(|s| s.pred == block.label)?.value;
+                Some(value.map(|v| names.get(&v).copied().unwrap_or(v)))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let edges = func.blocks[p].succs.iter().filter(|&&s| s == b).count();
+    // a pred already going to `taken`, or twice to `b`, would need two
+    // values in the same phi: it gets a copy to go through
+    let direct = copied.is_empty() && edges == 1 && !func.blocks[p].succs.contains(&taken);
+    let (from, from_label) = if direct {
+        (p, pred_label.clone())
+    } else {
+        let label = func.fresh_label(&block.label);
+        let copy = func.add_block(&label).unwrap();
+        for (instr, pos, origin) in &copied {
+            func.append_instr(copy, instr, *pos, *origin);
+        }
+        let jmp = IrInstruction::Jmp {
+            label: taken_label.clone(),
+        };
+        func.append_instr(copy, &jmp, None, None);
+        (copy, label)
+    };
+
+    // straight to `taken`, or to the copy
+    let goes_to = if direct { &taken_label } else { &from_label };
+    func.remove_edge(p, b);
+    retarget(func, p, &block.label, goes_to);
+    if !direct {
+        for _ in 0..edges {
+            func.add_edge(p, from);
+        }
+    }
+    func.add_edge(from, taken);
+    let phis = func.blocks[taken]
+        .instrs
+        .iter_mut()
+        .filter(|i| matches!(i, IrInstruction::Phi { .. }));
+    for (instr, value) in phis.zip(incoming) {
+        if let IrInstruction::Phi { sources, .. } = instr {
+            sources.push(PhiSource::new(&from_label, value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(func: &IrFunction) -> Vec<&str> {
+        func.blocks.iter().map(|b| b.label.as_str()).collect()
+    }
+
+    fn lines(func: &IrFunction, label: &str) -> Vec<String> {
+        let b = func.block_index(&label.to_string()).unwrap();
+        func.blocks[b]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_join_branching_again_is_threaded() {
+        let mut func = IrFunction::parse(
+            "@main(c: bool) {
+.entry:
+  br c .left .right
+.left:
+  one = const 1
+  print one
+  jmp .join
+.right:
+  two = const 2
+  print two
+  jmp .join
+.join:
+  br c .yes .no
+.yes:
+  ret
+.no:
+  ret
+}",
+        )
+        .unwrap();
+        assert_eq!(thread_jumps(&mut func, MAX_COPIED), 2);
+        bril_ir::verify_ssa(&func).unwrap();
+
+        // each side knows which way `c` went, .join is gone
+        assert_eq!(labels(&func), ["entry", "left", "right", "yes", "no"]);
+        assert_eq!(lines(&func, "left")[2], "jmp .yes");
+        assert_eq!(lines(&func, "right")[2], "jmp .no");
+        assert_eq!(func.blocks[3].preds, [1]);
+        assert_eq!(func.blocks[4].preds, [2]);
+    }
+
+    #[test]
+    fn test_phi_of_constants_is_threaded_with_copies() {
+        let mut func = IrFunction::parse(
+            "@main(c: bool, n: int) {
+.entry:
+  br c .left .right
+.left:
+  t = const true
+  jmp .join
+.right:
+  f = const false
+  jmp .join
+.join:
+  d = phi [t, .left] [f, .right]
+  m = add n n
+  br d .yes .exit
+.yes:
+  print n
+  jmp .exit
+.exit:
+  r = phi [m, .join] [n, .yes]
+  print r
+  ret
+}",
+        )
+        .unwrap();
+        assert_eq!(thread_jumps(&mut func, MAX_COPIED), 2);
+        bril_ir::verify_ssa(&func).unwrap();
+
+        assert_eq!(
+            labels(&func),
+            ["entry", "left", "right", "yes", "exit", "join.1", "join.2"]
+        );
+        assert_eq!(lines(&func, "join.1"), ["m.1 = add n n", "jmp .yes"]);
+        assert_eq!(lines(&func, "join.2"), ["m.2 = add n n", "jmp .exit"]);
+        // the copy going to .exit gives its phi its own `m`
+        assert_eq!(lines(&func, "exit")[0], "r = phi n m.2");
+    }
+
+    #[test]
+    fn test_big_or_effectful_blocks_stay() {
+        let src = "@main(c: bool, n: int) {
+.entry:
+  br c .left .right
+.left:
+  jmp .join
+.right:
+  jmp .join
+.join:
+  m = add n n
+  br c .yes .no
+.yes:
+  ret
+.no:
+  ret
+}";
+        let mut func = IrFunction::parse(src).unwrap();
+        assert_eq!(thread_jumps(&mut func, 0), 0);
+
+        let printing = src.replace("m = add n n", "print n");
+        let mut func = IrFunction::parse(&printing).unwrap();
+        assert_eq!(thread_jumps(&mut func, MAX_COPIED), 0);
+        assert!(labels(&func).contains(&"join"));
+    }
+}
@@ -1,14 +1,64 @@
+pub mod analysis_manager;
+pub mod analysis_printer;
+pub mod avail_cse;
+pub mod block_layout;
 pub mod constant_folding;
 pub mod constant_propagate;
+pub mod copy_propagation;
+pub mod dead_function;
 pub mod deadcode_removal;
+pub mod gvn;
+pub mod indvars;
+pub mod inline;
+pub mod inst_combine;
+pub mod jump_thread;
 pub mod liveness;
+pub mod loop_rotate;
+pub mod loop_unroll;
+pub mod lvn;
 pub mod pass_manager;
+pub mod phi_simplify;
+pub mod pipeline;
+pub mod sccp;
+pub mod simplify_cfg;
+pub mod strength_reduction;
+pub mod tail_call;
+pub use analysis_manager::Analysis;
+pub use analysis_manager::AnalysisManager;
+pub use analysis_printer::AnalysisPrinterPass;
+pub use avail_cse::AvailCsePass;
+pub use block_layout::BlockLayoutPass;
 pub use constant_folding::ConstantFoldPass;
 pub use constant_propagate::ConstantPropagationPass;
+pub use copy_propagation::CopyPropagationPass;
+pub use dead_function::DeadFunctionPass;
 pub use deadcode_removal::DeadCodeRemovalPass;
+pub use gvn::GvnPass;
+pub use indvars::IndVarPass;
+pub use inline::InlinePass;
+pub use inst_combine::InstCombinePass;
+pub use jump_thread::JumpThreadPass;
 pub use liveness::*;
+pub use loop_rotate::LoopRotatePass;
+pub use loop_unroll::LoopUnrollPass;
+pub use lvn::LvnPass;
 pub use pass_manager::FunctionPass;
+pub use pass_manager::ModulePass;
+pub use pass_manager::PassError;
 pub use pass_manager::PassManager;
+pub use pass_manager::PassOptions;
+pub use pass_manager::PassStatistics;
+pub use pass_manager::PassStats;
+pub use pass_manager::PassTiming;
+pub use pass_manager::RunSummary;
+pub use pass_manager::Statistics;
+pub use pass_manager::DEFAULT_MAX_ITERATIONS;
+pub use phi_simplify::PhiSimplifyPass;
+pub use pipeline::PassGroup;
+pub use sccp::SccpPass;
+pub use simplify_cfg::SimplifyCfgPass;
+pub use strength_reduction::StrengthReductionPass;
+pub use tail_call::TailCallPass;
 
 // TODO: Need to create a proper test for this crate
 #[cfg(test)]
@@ -31,7 +81,7 @@ mod tests {
     fn diamond_cfg() -> IrFunction {
         let block_labels = ["entry", "A", "B", "C", "D", "Exit"];
 
-        let preds = vec![
+        let preds = [
             Vec::new(), // 0: entry
             vec![0],    // 1: A
             vec![1],    // 2: B
@@ -45,10 +95,18 @@ mod tests {
             blocks.push(IrBasicBlock {
                 label: label.to_string(),
                 instrs: Vec::new(),
+                positions: Vec::new(),
+                origins: Vec::new(),
                 preds: preds[i].clone(),
                 succs: Vec::new(),
             });
         }
+        // the other direction of the same edges
+        for (i, ps) in preds.iter().enumerate() {
+            for &p in ps {
+                blocks[p].succs.push(i);
+            }
+        }
 
         let mut label_to_idx = std::collections::HashMap::new();
         for (i, &label) in block_labels.iter().enumerate() {
@@ -58,6 +116,9 @@ mod tests {
         IrFunction {
             name: "diamond".to_string(),
             args: Vec::new(),
+            arg_types: Vec::new(),
+            ret_typ: None,
+            types: std::collections::HashMap::new(),
             blocks,
             label_to_idx,
         }
@@ -69,13 +130,13 @@ mod tests {
         // both block B & C are going to be a definition of var X that will then be managed
         // by block D (maybe)
         let def_x_b = IrInstruction::Assign {
-            lhs: "x".to_string(),
-            rhs: "5".to_string(),
+            lhs: "x".into(),
+            rhs: "5".into(),
         };
 
         let def_x_c = IrInstruction::Assign {
-            lhs: "x".to_string(),
-            rhs: "10".to_string(),
+            lhs: "x".into(),
+            rhs: "10".into(),
         };
 
         // index 2 is block B
@@ -0,0 +1,434 @@
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::Symbol;
+use bril_ir::analysis::loops::{self, Loop};
+use bril_ir::{BlockID, DominatorInfo, IrFunction, IrInstruction, PhiSource};
+use std::collections::{HashMap, HashSet};
+
+/// Most instructions, phis and the `br` aside, a header can have and still
+/// be copied into its latch
+const MAX_HEADER_SIZE: usize = 8;
+
+/// Loop Rotation, on SSA
+///
+/// A loop testing at its header whether to go round again takes a branch
+/// there and a jump back to it at the bottom, every time round. The latch
+/// gets a copy of the header instead, ending in the same `br`, so the test
+/// is at the bottom and the loop goes back to its body from there. The
+/// header is left only run the first time, as the guard in front of a
+/// loop that now starts at the body, and SimplifyCfg folds it into the
+/// preheader.
+///
+/// Only loops with a preheader and one latch ending in a `jmp` to the
+/// header, whose header is the one way out, by a `br` to a body and an
+/// exit that nothing else goes to, and has at most `threshold`
+/// instructions to copy. What the header defines gets a phi at the top of
+/// the body and of the exit, picking between the header's and the copy's
+pub struct LoopRotatePass {
+    threshold: usize,
+}
+
+impl LoopRotatePass {
+    pub fn new(threshold: usize) -> Self {
+        Self { threshold }
+    }
+}
+
+impl Default for LoopRotatePass {
+    fn default() -> Self {
+        Self::new(MAX_HEADER_SIZE)
+    }
+}
+
+impl FunctionPass for LoopRotatePass {
+    fn name(&self) -> &str {
+        "LoopRotatePass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        // the preheaders it adds on the way are left out when nothing was
+        // rotated, same as unrolling
+        let before = function.clone();
+        let rotated = rotate_loops(function, self.threshold);
+        if rotated == 0 {
+            *function = before;
+            return Ok(false);
+        }
+        stats.add("loops rotated", rotated);
+        Ok(true)
+    }
+}
+
+/// A loop that can be rotated
+#[derive(Debug)]
+struct Rotation {
+    header: BlockID,
+    latch: BlockID,
+    /// Where the header's `br` goes to stay in the loop, and to leave it
+    body: BlockID,
+    exit: BlockID,
+}
+
+/// Returns how many loops were rotated
+fn rotate_loops(func: &mut IrFunction, threshold: usize) -> usize {
+    if bril_ir::verify_ssa(func).is_err() || func.blocks.is_empty() {
+        return 0;
+    }
+    // headers of the loops already looked at, by label since a rotation
+    // changes which block is a loop's header
+    let mut seen: HashSet<String> = HashSet::new();
+    // up front only: a rotated loop's header is the body it went round
+    // to, one more preheader would go between it and the guard
+    let Ok(doms) = DominatorInfo::compute(func) else {
+        return 0;
+    };
+    func.ensure_preheaders(&loops::compute(func, &doms));
+    let mut count = 0;
+    loop {
+        let Ok(doms) = DominatorInfo::compute(func) else {
+            return count;
+        };
+        let info = loops::compute(func, &doms);
+        let Some(l) = info
+            .loops
+            .iter()
+            .find(|l| !seen.contains(&func.blocks[l.header].label))
+        else {
+            return count;
+        };
+        seen.insert(func.blocks[l.header].label.clone());
+
+        let Some(r) = rotation(func, l, threshold) else {
+            continue;
+        };
+        // the body is the header now, it isn't rotated again
+        seen.insert(func.blocks[r.body].label.clone());
+        rotate(func, l, &r);
+        count += 1;
+    }
+}
+
+fn rotation(func: &IrFunction, l: &Loop, threshold: usize) -> Option<Rotation> {
+    l.preheader?;
+    let header = l.header;
+    let [latch] = l.latches[..] else {
+        return None;
+    };
+    if latch == header {
+        return None;
+    }
+    let block = &func.blocks[header];
+    let Some(IrInstruction::Br {
+        then_lbl, else_lbl, ..
+    }) = block.instrs.last()
+    else {
+        return None;
+    };
+    let then_idx = func.block_index(then_lbl)?;
+    let else_idx = func.block_index(else_lbl)?;
+    let (body, exit) = match (l.blocks.contains(&then_idx), l.blocks.contains(&else_idx)) {
+        (true, false) => (then_idx, else_idx),
+        (false, true) => (else_idx, then_idx),
+        _ => return None,
+    };
+
+    let jumps_back = matches!(
+        func.blocks[latch].instrs.last(),
+        Some(IrInstruction::Jmp { label }) if *label == block.label
+    );
+    let one_way_out = l
+        .blocks
+        .iter()
+        .filter(|&&b| b != header)
+        .all(|&b| func.blocks[b].succs.iter().all(|s| l.blocks.contains(s)));
+    let size = block
+        .instrs
+        .iter()
+        .filter(|i| !matches!(i, IrInstruction::Phi { .. }) && !i.is_terminator())
+        .count();
+    // the copy needs a value for every phi coming round from the latch
+    let latch_label = &func.blocks[latch].label;
+    let defined_round = block.instrs.iter().all(|instr| match instr {
+        IrInstruction::Phi { sources, .. } => sources
+            .iter()
+            .any(|s| s.pred == *latch_label && s.value.is_some()),
+        _ => true,
+    });
+    let ok = jumps_back
+        && one_way_out
+        && size <= threshold
+        && defined_round
+        && func.blocks[body].preds == [header]
+        && func.blocks[exit].preds == [header];
+    ok.then_some(Rotation {
+        header,
+        latch,
+        body,
+        exit,
+    })
+}
+
+fn rotate(func: &mut IrFunction, l: &Loop, r: &Rotation) {
+    let header = func.blocks[r.header].clone();
+    let latch_label = func.blocks[r.latch].label.clone();
+
+    // everything the header defines gets a name for reading it in the
+    // loop, and one for reading it after the exit
+    let defs: Vec<Symbol> = header
+        .instrs
+        .iter()
+        .flat_map(|i| i.defs().iter().copied())
+        .collect();
+    // names the text format leaves untyped aren't in `types`, so the new
+    // ones are kept track of here
+    let mut taken: HashSet<Symbol> = func
+        .blocks
+        .iter()
+        .flat_map(|b| &b.instrs)
+        .flat_map(|i| i.defs().iter().copied())
+        .chain(func.args.iter().copied())
+        .chain(func.types.keys().copied())
+        .collect();
+    let mut inside = HashMap::new();
+    let mut outside = HashMap::new();
+    for &def in &defs {
+        inside.insert(def, declare_like(func, &mut taken, def));
+        outside.insert(def, declare_like(func, &mut taken, def));
+    }
+
+    // what the latch's copy of the header gives each of them: for a phi
+    // what it took from the latch, the rest get names of their own
+    let mut copy: HashMap<Symbol, Symbol> = HashMap::new();
+    let mut copied = Vec::new();
+    for (i, instr) in header.instrs.iter().enumerate() {
+        match instr {
+            IrInstruction::Phi { dest, sources } => {
+                let value = sources.iter().find(|s| s.pred == latch_label);
+                let value = value.and_then(|s| s.value).unwrap();
+                copy.insert(*dest, inside.get(&value).copied().unwrap_or(value));
+            }
+            _ => {
+                let mut instr = instr.clone();
+                for var in instr.uses_mut() {
+                    *var = copy.get(var).copied().unwrap_or(*var);
+                }
+                if let Some(def) = instr.defs_mut() {
+                    let var = declare_like(func, &mut taken, *def);
+                    copy.insert(*def, var);
+                    *def = var;
+                }
+                copied.push((instr, header.pos(i), header.origin(i)));
+            }
+        }
+    }
+
+    // every other read of them goes by where it is, a phi's by the pred
+    // it comes from
+    for b in (0..func.blocks.len()).filter(|&b| b != r.header) {
+        let mut instrs = std::mem::take(&mut func.blocks[b].instrs);
+        for instr in &mut instrs {
+            if let IrInstruction::Phi { sources, .. } = instr {
+                for src in sources {
+                    let p = func.block_index(&src.pred);
+                    if let (Some(p), Some(value)) = (p, &mut src.value) {
+                        *value = renamed(*value, p, r.header, l, &inside, &outside);
+                    }
+                }
+            } else {
+                for var in instr.uses_mut() {
+                    *var = renamed(*var, b, r.header, l, &inside, &outside);
+                }
+            }
+        }
+        func.blocks[b].instrs = instrs;
+    }
+
+    // the latch ends in the copy instead of going back to the header
+    func.blocks[r.latch].remove_instrs(|i| matches!(i, IrInstruction::Jmp { .. }));
+    for (instr, pos, origin) in copied {
+        func.append_instr(r.latch, &instr, pos, origin);
+    }
+    func.remove_edge(r.latch, r.header);
+    for &s in &header.succs {
+        func.add_edge(r.latch, s);
+    }
+
+    // the phis already in the body and the exit get what the copy has
+    for s in [r.body, r.exit] {
+        for instr in &mut func.blocks[s].instrs {
+            if let IrInstruction::Phi { sources, .. } = instr {
+                let from_header = sources.iter().find(|s| s.pred == header.label);
+                let value = from_header
+                    .and_then(|s| s.value)
+                    .map(|v| copy.get(&v).copied().unwrap_or(v));
+                sources.push(PhiSource::new(&latch_label, value));
+            }
+        }
+    }
+
+    // and new ones for what the header defines, where something reads them
+    let mut used: HashSet<Symbol> = func
+        .blocks
+        .iter()
+        .flat_map(|b| &b.instrs)
+        .flat_map(|i| i.uses())
+        .collect();
+    loop {
+        let more: Vec<Symbol> = defs
+            .iter()
+            .filter(|d| used.contains(&inside[*d]) || used.contains(&outside[*d]))
+            .map(|d| copy[d])
+            .filter(|v| !used.contains(v))
+            .collect();
+        if more.is_empty() {
+            break;
+        }
+        used.extend(more);
+    }
+    for (s, names) in [(r.body, &inside), (r.exit, &outside)] {
+        let mut at = 0;
+        for def in &defs {
+            let dest = names[def];
+            if !used.contains(&dest) {
+                func.types.remove(&dest);
+                continue;
+            }
+            let sources = vec![
+                PhiSource::new(&header.label, Some(*def)),
+                PhiSource::new(&latch_label, Some(copy[def])),
+            ];
+            func.blocks[s].insert_instr(at, IrInstruction::Phi { dest, sources }, None, None);
+            at += 1;
+        }
+    }
+}
+
+/// A fresh name for another value of `var`, with its type
+fn declare_like(func: &mut IrFunction, taken: &mut HashSet<Symbol>, var: Symbol) -> Symbol {
+    let fresh = (1..)
+        .map(|n| Symbol::from(format!("{}.{}", var, n)))
+        .find(|name| !taken.contains(name))
+        .unwrap();
+    taken.insert(fresh);
+    if let Some(typ) = func.types.get(&var).cloned() {
+        func.types.insert(fresh, typ);
+    }
+    fresh
+}
+
+/// What a read of `var` at the bottom of block `at` reads after the
+/// rotation, `var` itself unless the header defines it
+fn renamed(
+    var: Symbol,
+    at: BlockID,
+    header: BlockID,
+    l: &Loop,
+    inside: &HashMap<Symbol, Symbol>,
+    outside: &HashMap<Symbol, Symbol>,
+) -> Symbol {
+    if at == header {
+        return var;
+    }
+    let names = if l.blocks.contains(&at) {
+        inside
+    } else {
+        outside
+    };
+    names.get(&var).copied().unwrap_or(var)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_lines(func: &IrFunction, label: &str) -> Vec<String> {
+        let b = func.block_index(&label.to_string()).unwrap();
+        func.blocks[b]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    fn rotated(src: &str) -> IrFunction {
+        let mut func = IrFunction::parse(src).unwrap();
+        assert!(LoopRotatePass::default()
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap());
+        bril_ir::verify_ssa(&func).unwrap();
+        func
+    }
+
+    #[test]
+    fn test_counting_loop_tests_at_the_bottom() {
+        let func = rotated(
+            "@main(n: int) {
+.entry:
+  zero = const 0
+  one = const 1
+  jmp .head
+.head:
+  i = phi [zero, .entry] [i2, .body]
+  c = lt i n
+  br c .body .done
+.body:
+  print i
+  i2 = add i one
+  jmp .head
+.done:
+  print i
+  ret
+}",
+        );
+        // only the way in goes through the header now
+        assert_eq!(
+            block_lines(&func, "head"),
+            ["i = phi zero", "c = lt i n", "br c .body .done"]
+        );
+        assert_eq!(
+            block_lines(&func, "body"),
+            [
+                "i.1 = phi i i2",
+                "print i.1",
+                "i2 = add i.1 one",
+                "c.3 = lt i2 n",
+                "br c.3 .body .done"
+            ]
+        );
+        assert_eq!(
+            block_lines(&func, "done"),
+            ["i.2 = phi i i2", "print i.2", "ret"]
+        );
+    }
+
+    #[test]
+    fn test_loop_with_a_second_way_out_stays() {
+        let mut func = IrFunction::parse(
+            "@main(n: int) {
+.entry:
+  zero = const 0
+  one = const 1
+  jmp .head
+.head:
+  i = phi [zero, .entry] [i2, .body]
+  c = lt i n
+  br c .body .done
+.body:
+  i2 = add i one
+  big = lt n i2
+  br big .done .head
+.done:
+  ret
+}",
+        )
+        .unwrap();
+        let before = func.clone();
+        assert!(!LoopRotatePass::default()
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap());
+        assert_eq!(func, before);
+    }
+}
@@ -0,0 +1,882 @@
+use crate::indvars::{basic_ivs, int_consts};
+use crate::pass_manager::{FunctionPass, PassError, PassOptions, Statistics};
+use bril_frontend::{Literal, Symbol, Type};
+use bril_ir::analysis::loops::{self, Loop};
+use bril_ir::{BlockID, DominatorInfo, IrFunction, IrInstruction, PhiSource};
+use std::collections::{HashMap, HashSet};
+
+/// Most times round a loop that's unrolled completely
+const MAX_TRIP_COUNT: u64 = 16;
+
+/// Most instructions all the copies of a loop can add up to
+const MAX_UNROLLED_SIZE: usize = 256;
+
+/// Loop Unrolling, on SSA
+///
+/// Only innermost loops with a preheader and one latch, whose header is
+/// the one way out, by a `br` on a comparison of a basic induction
+/// variable against something the loop doesn't change.
+///
+/// When the variable starts at a `const`, and the bound is one too, the
+/// number of times round is known, and a loop that goes round at most
+/// [`MAX_TRIP_COUNT`] times is replaced by that many copies of its blocks
+/// one after the other, the header's phis reading what the copy before
+/// left. The header itself stays for the last test, which always goes
+/// out, so it becomes a `jmp` there.
+///
+/// Any other loop stepping by 1 (or -1), with `factor` above 1, gets a
+/// loop in front of it going round `factor` copies at a time, for as long
+/// as at least that many times round are left. The original loop comes
+/// after it and does whatever's left over. `unroll.factor` in the
+/// options overrides the factor it was made with.
+pub struct LoopUnrollPass {
+    factor: usize,
+}
+
+impl LoopUnrollPass {
+    pub fn new(factor: usize) -> Self {
+        Self { factor }
+    }
+}
+
+impl Default for LoopUnrollPass {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl FunctionPass for LoopUnrollPass {
+    fn name(&self) -> &str {
+        "LoopUnrollPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        // the preheaders it adds on the way are left out when nothing was
+        // unrolled, or they'd be added every time SimplifyCfg took them out
+        let before = function.clone();
+        let unrolled = unroll_loops(function, self.factor);
+        if unrolled == 0 {
+            *function = before;
+            return Ok(false);
+        }
+        stats.add("loops unrolled", unrolled);
+        Ok(true)
+    }
+
+    fn set_options(&mut self, options: &PassOptions) -> Result<(), PassError> {
+        if let Some(factor) = options.get_usize("unroll.factor")? {
+            self.factor = factor;
+        }
+        Ok(())
+    }
+
+    fn option_keys(&self) -> &[&'static str] {
+        &["unroll.factor"]
+    }
+}
+
+/// The comparisons a loop test can be, `Ne` only from negating an `Eq`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Cmp {
+    fn holds(self, a: i64, b: i64) -> bool {
+        match self {
+            Cmp::Eq => a == b,
+            Cmp::Ne => a != b,
+            Cmp::Lt => a < b,
+            Cmp::Le => a <= b,
+            Cmp::Gt => a > b,
+            Cmp::Ge => a >= b,
+        }
+    }
+
+    /// `b op' a` for `a op b`
+    fn flip(self) -> Cmp {
+        match self {
+            Cmp::Lt => Cmp::Gt,
+            Cmp::Le => Cmp::Ge,
+            Cmp::Gt => Cmp::Lt,
+            Cmp::Ge => Cmp::Le,
+            op => op,
+        }
+    }
+
+    fn negate(self) -> Cmp {
+        match self {
+            Cmp::Eq => Cmp::Ne,
+            Cmp::Ne => Cmp::Eq,
+            Cmp::Lt => Cmp::Ge,
+            Cmp::Le => Cmp::Gt,
+            Cmp::Gt => Cmp::Le,
+            Cmp::Ge => Cmp::Lt,
+        }
+    }
+}
+
+/// A loop that can be unrolled, and how it's tested
+#[derive(Debug)]
+struct Candidate {
+    header: BlockID,
+    pre: BlockID,
+    latch: BlockID,
+    /// Where the header's `br` goes to stay in the loop, and to leave it
+    body: BlockID,
+    exit: BlockID,
+    /// Going round again while `iv op bound`
+    iv: Symbol,
+    op: Cmp,
+    bound: Symbol,
+    init: Symbol,
+    step: i64,
+    /// Instructions in the loop's blocks
+    size: usize,
+}
+
+/// Returns how many loops were unrolled, completely or not
+fn unroll_loops(func: &mut IrFunction, factor: usize) -> usize {
+    if bril_ir::verify_ssa(func).is_err() || func.blocks.is_empty() {
+        return 0;
+    }
+    // headers of the loops already looked at, by label since the block
+    // indices move when the unrolled ones' leftovers go
+    let mut seen: HashSet<String> = HashSet::new();
+    // and the ones a run before this one unrolled partially: the copies'
+    // `{header}.unrolled` and the original one after it
+    for block in &func.blocks {
+        if let Some(header) = block.label.strip_suffix(".unrolled") {
+            seen.insert(header.to_string());
+            seen.insert(block.label.clone());
+        }
+    }
+    let mut count = 0;
+    loop {
+        let Ok(doms) = DominatorInfo::compute(func) else {
+            return count;
+        };
+        let mut info = loops::compute(func, &doms);
+        if !func.ensure_preheaders(&info).is_empty() {
+            let Ok(doms) = DominatorInfo::compute(func) else {
+                return count;
+            };
+            info = loops::compute(func, &doms);
+        }
+        let Some(l) = info
+            .loops
+            .iter()
+            .find(|l| l.children.is_empty() && !seen.contains(&func.blocks[l.header].label))
+        else {
+            return count;
+        };
+        seen.insert(func.blocks[l.header].label.clone());
+
+        let ints = int_consts(func);
+        let Some(c) = candidate(func, l, &ints) else {
+            continue;
+        };
+        let trips = match (ints.get(&c.init), ints.get(&c.bound)) {
+            (Some(&init), Some(&bound)) => trip_count(&c, init, bound),
+            _ => None,
+        };
+        match trips {
+            Some(trips) if trips as usize * c.size <= MAX_UNROLLED_SIZE => {
+                unroll_completely(func, l, &c, trips);
+            }
+            _ if can_unroll_partially(&c, factor) => {
+                let label = unroll_partially(func, l, &c, factor);
+                seen.insert(label);
+            }
+            _ => continue,
+        }
+        count += 1;
+    }
+}
+
+fn candidate(func: &IrFunction, l: &Loop, ints: &HashMap<Symbol, i64>) -> Option<Candidate> {
+    let (Some(pre), &[latch]) = (l.preheader, &l.latches[..]) else {
+        return None;
+    };
+    if !l.children.is_empty() {
+        return None;
+    }
+    let header = l.header;
+    let block = &func.blocks[header];
+    let Some(IrInstruction::Br {
+        cond,
+        then_lbl,
+        else_lbl,
+    }) = block.instrs.last()
+    else {
+        return None;
+    };
+    let (then_b, else_b) = (func.block_index(then_lbl)?, func.block_index(else_lbl)?);
+    let (body, exit, taken) = match (l.blocks.contains(&then_b), l.blocks.contains(&else_b)) {
+        (true, false) => (then_b, else_b, true),
+        (false, true) => (else_b, then_b, false),
+        _ => return None,
+    };
+
+    // nothing else leaves, and nothing jumps somewhere the copies can't
+    // be pointed at
+    for &b in &l.blocks {
+        if b != header && func.blocks[b].succs.iter().any(|s| !l.blocks.contains(s)) {
+            return None;
+        }
+        let opaque = func.blocks[b].instrs.iter().any(
+            |instr| matches!(instr, IrInstruction::Opaque { labels, .. } if !labels.is_empty()),
+        );
+        if opaque {
+            return None;
+        }
+    }
+
+    let (op, lhs, rhs) = block.instrs.iter().find_map(|instr| match instr {
+        IrInstruction::Eq { dest, lhs, rhs } if dest == cond => Some((Cmp::Eq, lhs, rhs)),
+        IrInstruction::Lt { dest, lhs, rhs } if dest == cond => Some((Cmp::Lt, lhs, rhs)),
+        IrInstruction::Le { dest, lhs, rhs } if dest == cond => Some((Cmp::Le, lhs, rhs)),
+        IrInstruction::Gt { dest, lhs, rhs } if dest == cond => Some((Cmp::Gt, lhs, rhs)),
+        IrInstruction::Ge { dest, lhs, rhs } if dest == cond => Some((Cmp::Ge, lhs, rhs)),
+        _ => None,
+    })?;
+    let basic = basic_ivs(func, l, pre, latch, ints);
+    let (iv, op, bound) = if basic.contains_key(lhs) {
+        (*lhs, op, *rhs)
+    } else if basic.contains_key(rhs) {
+        (*rhs, op.flip(), *lhs)
+    } else {
+        return None;
+    };
+    let op = if taken { op } else { op.negate() };
+    let defined_in_loop = |var: &Symbol| {
+        l.blocks
+            .iter()
+            .any(|&b| func.blocks[b].instrs.iter().any(|i| i.defs().contains(var)))
+    };
+    if defined_in_loop(&bound) || !copyable_phis(func, l) {
+        return None;
+    }
+
+    Some(Candidate {
+        header,
+        pre,
+        latch,
+        body,
+        exit,
+        iv,
+        op,
+        bound,
+        init: basic[&iv].init,
+        step: basic[&iv].step,
+        size: l.blocks.iter().map(|&b| func.blocks[b].instrs.len()).sum(),
+    })
+}
+
+/// Whether every header phi that can be undefined is only read by other
+/// phis, which can say so with a source of `None`. An instruction reading
+/// one would have nothing to read in the copy of the first time round
+fn copyable_phis(func: &IrFunction, l: &Loop) -> bool {
+    let phis: Vec<(Symbol, Vec<Option<Symbol>>)> = func.blocks[l.header]
+        .instrs
+        .iter()
+        .filter_map(|instr| match instr {
+            IrInstruction::Phi { dest, sources } => {
+                Some((*dest, sources.iter().map(|s| s.value).collect()))
+            }
+            _ => None,
+        })
+        .collect();
+    let mut undefined: HashSet<Symbol> = HashSet::new();
+    loop {
+        let before = undefined.len();
+        for (dest, values) in &phis {
+            if values
+                .iter()
+                .any(|v| v.is_none_or(|v| undefined.contains(&v)))
+            {
+                undefined.insert(*dest);
+            }
+        }
+        if undefined.len() == before {
+            break;
+        }
+    }
+    l.blocks
+        .iter()
+        .flat_map(|&b| &func.blocks[b].instrs)
+        .filter(|instr| !matches!(instr, IrInstruction::Phi { .. }))
+        .all(|instr| instr.uses().iter().all(|v| !undefined.contains(v)))
+}
+
+/// How many times round the loop goes, if it's at most [`MAX_TRIP_COUNT`]
+fn trip_count(c: &Candidate, init: i64, bound: i64) -> Option<u64> {
+    let mut i = init;
+    for trips in 0..=MAX_TRIP_COUNT {
+        if !c.op.holds(i, bound) {
+            return Some(trips);
+        }
+        i = i.wrapping_add(c.step);
+    }
+    None
+}
+
+fn can_unroll_partially(c: &Candidate, factor: usize) -> bool {
+    let counts = matches!(
+        (c.step, c.op),
+        (1, Cmp::Lt | Cmp::Le) | (-1, Cmp::Gt | Cmp::Ge)
+    );
+    counts && factor > 1 && factor * c.size <= MAX_UNROLLED_SIZE
+}
+
+/// The header's phis, with what each is coming from the preheader and
+/// from the latch
+fn header_phis(func: &IrFunction, c: &Candidate) -> Vec<(Symbol, Option<Symbol>, Option<Symbol>)> {
+    let (pre, latch) = (&func.blocks[c.pre].label, &func.blocks[c.latch].label);
+    func.blocks[c.header]
+        .instrs
+        .iter()
+        .filter_map(|instr| {
+            let IrInstruction::Phi { dest, sources } = instr else {
+                return None;
+            };
+            let value_from = |label: &String| {
+                sources
+                    .iter()
+                    .find(|s| s.pred == *label)
+                    .and_then(|s| s.value)
+            };
+            Some((*dest, value_from(pre), value_from(latch)))
+        })
+        .collect()
+}
+
+/// Replace the loop with `trips` copies of it, the header left just
+/// going to the exit
+fn unroll_completely(func: &mut IrFunction, l: &Loop, c: &Candidate, trips: u64) {
+    let phis = header_phis(func, c);
+    let header_label = func.blocks[c.header].label.clone();
+    let mut values: HashMap<Symbol, Option<Symbol>> =
+        phis.iter().map(|&(dest, init, _)| (dest, init)).collect();
+    let (first, last, made) = copy_iterations(func, l, c, &phis, &mut values, trips, &header_label);
+    if let Some(first) = first {
+        let label = func.blocks[first].label.clone();
+        retarget(func, c.pre, &header_label, &label);
+    }
+
+    // the header's phis only have the last copy to come from now, and its
+    // test is known to fail
+    let last_label = func.blocks[last.unwrap_or(c.pre)].label.clone();
+    let exit_label = func.blocks[c.exit].label.clone();
+    let block = &mut func.blocks[c.header];
+    block.remove_instrs(|i| matches!(i, IrInstruction::Phi { .. }));
+    let (undefined, ids): (Vec<_>, Vec<_>) = phis
+        .iter()
+        .map(|(dest, _, _)| match values[dest] {
+            Some(rhs) => IrInstruction::Assign { lhs: *dest, rhs },
+            None => IrInstruction::Phi {
+                dest: *dest,
+                sources: vec![PhiSource::new(&last_label, None)],
+            },
+        })
+        .partition(|i| matches!(i, IrInstruction::Phi { .. }));
+    for (k, instr) in undefined.into_iter().chain(ids).enumerate() {
+        block.insert_instr(k, instr, None, None);
+    }
+    *block.instrs.last_mut().unwrap() = IrInstruction::Jmp { label: exit_label };
+
+    relink(func, c.pre);
+    relink(func, c.header);
+    for b in made {
+        relink(func, b);
+    }
+    func.remove_unreachable_blocks();
+}
+
+/// Put a loop going round `factor` copies at a time in front of the
+/// original one, returning its header's label
+fn unroll_partially(func: &mut IrFunction, l: &Loop, c: &Candidate, factor: usize) -> String {
+    let phis = header_phis(func, c);
+    let header_label = func.blocks[c.header].label.clone();
+    let pre_label = func.blocks[c.pre].label.clone();
+    let top_label = func.fresh_label(&format!("{}.unrolled", header_label));
+    let top = func.add_block(&top_label).unwrap();
+    let fresh = |func: &mut IrFunction, base: &str, typ: Option<Type>| {
+        let var = func.fresh_var(base);
+        if let Some(typ) = typ {
+            func.types.insert(var, typ);
+        }
+        var
+    };
+
+    // the new header's phis, standing in for the old ones
+    let mut starts: HashMap<Symbol, Symbol> = HashMap::new();
+    for &(dest, _, _) in &phis {
+        let typ = func.types.get(&dest).cloned();
+        starts.insert(dest, fresh(func, dest.as_ref(), typ));
+    }
+    let mut values: HashMap<Symbol, Option<Symbol>> = starts
+        .iter()
+        .map(|(&dest, &start)| (dest, Some(start)))
+        .collect();
+    let (first, last, made) =
+        copy_iterations(func, l, c, &phis, &mut values, factor as u64, &top_label);
+    let (first, last) = (first.unwrap(), last.unwrap());
+    let last_label = func.blocks[last].label.clone();
+    for (dest, init, _) in &phis {
+        let phi = IrInstruction::Phi {
+            dest: starts[dest],
+            sources: vec![
+                PhiSource::new(&pre_label, *init),
+                PhiSource::new(&last_label, values[dest]),
+            ],
+        };
+        func.append_instr(top, &phi, None, None);
+    }
+
+    // go round the copies while `factor` more times round are left. The
+    // difference is only looked at when the test passes, so it's never
+    // below 0, and one too big to fit comes out negative and is left to
+    // the original loop
+    let iv = starts[&c.iv];
+    let (lhs, rhs) = match c.op {
+        Cmp::Lt | Cmp::Le => (c.bound, iv),
+        _ => (iv, c.bound),
+    };
+    let left_by = match c.op {
+        Cmp::Lt | Cmp::Gt => factor as i64,
+        _ => factor as i64 - 1,
+    };
+    let more = fresh(func, &format!("{}.more", c.iv), Some(Type::Bool));
+    let count = fresh(func, &format!("{}.count", c.iv), Some(Type::Int));
+    let left = fresh(func, &format!("{}.left", c.iv), Some(Type::Int));
+    let enough = fresh(func, &format!("{}.enough", c.iv), Some(Type::Bool));
+    let go = fresh(func, &format!("{}.unrolled", c.iv), Some(Type::Bool));
+    let test = match c.op {
+        Cmp::Lt => IrInstruction::Lt {
+            dest: more,
+            lhs: iv,
+            rhs: c.bound,
+        },
+        Cmp::Le => IrInstruction::Le {
+            dest: more,
+            lhs: iv,
+            rhs: c.bound,
+        },
+        Cmp::Gt => IrInstruction::Gt {
+            dest: more,
+            lhs: iv,
+            rhs: c.bound,
+        },
+        _ => IrInstruction::Ge {
+            dest: more,
+            lhs: iv,
+            rhs: c.bound,
+        },
+    };
+    let guard = [
+        test,
+        IrInstruction::Const {
+            dest: count,
+            value: Literal::Int(left_by),
+        },
+        IrInstruction::Sub {
+            dest: left,
+            lhs,
+            rhs,
+        },
+        IrInstruction::Ge {
+            dest: enough,
+            lhs: left,
+            rhs: count,
+        },
+        IrInstruction::And {
+            dest: go,
+            lhs: more,
+            rhs: enough,
+        },
+        IrInstruction::Br {
+            cond: go,
+            then_lbl: func.blocks[first].label.clone(),
+            else_lbl: header_label.clone(),
+        },
+    ];
+    for instr in &guard {
+        func.append_instr(top, instr, None, None);
+    }
+
+    // the original loop is entered from the new one now, and does what's
+    // left over
+    retarget(func, c.pre, &header_label, &top_label);
+    for instr in &mut func.blocks[c.header].instrs {
+        if let IrInstruction::Phi { dest, sources } = instr {
+            for src in sources.iter_mut().filter(|s| s.pred == pre_label) {
+                src.pred = top_label.clone();
+                src.value = Some(starts[dest]);
+            }
+        }
+    }
+    relink(func, c.pre);
+    relink(func, top);
+    for b in made {
+        relink(func, b);
+    }
+    top_label
+}
+
+/// `n` copies of the loop one after the other, the last one's latch going
+/// to `then`. Returns the first copy's header, the last one's latch, and
+/// every block made
+fn copy_iterations(
+    func: &mut IrFunction,
+    l: &Loop,
+    c: &Candidate,
+    phis: &[(Symbol, Option<Symbol>, Option<Symbol>)],
+    values: &mut HashMap<Symbol, Option<Symbol>>,
+    n: u64,
+    then: &str,
+) -> (Option<BlockID>, Option<BlockID>, Vec<BlockID>) {
+    let header_label = func.blocks[c.header].label.clone();
+    let (mut first, mut last) = (None, None);
+    let mut made = Vec::new();
+    for _ in 0..n {
+        let (header, latch, blocks) = copy_iteration(func, l, c, phis, values);
+        if let Some(prev) = last {
+            let label = func.blocks[header].label.clone();
+            retarget(func, prev, &header_label, &label);
+        }
+        first.get_or_insert(header);
+        last = Some(latch);
+        made.extend(blocks);
+    }
+    if let Some(last) = last {
+        retarget(func, last, &header_label, then);
+    }
+    (first, last, made)
+}
+
+/// Copy every block of the loop for one time round it. The header's phis
+/// are left out, `values` says what they are this time, and is updated to
+/// what they'll be the next. The header's copy goes straight on into the
+/// body, and the latch's jumps back to the original header for the caller
+/// to point somewhere else. Returns the header's copy, the latch's, and
+/// every copy, none of them with their edges yet
+fn copy_iteration(
+    func: &mut IrFunction,
+    l: &Loop,
+    c: &Candidate,
+    phis: &[(Symbol, Option<Symbol>, Option<Symbol>)],
+    values: &mut HashMap<Symbol, Option<Symbol>>,
+) -> (BlockID, BlockID, Vec<BlockID>) {
+    let mut copies: HashMap<BlockID, BlockID> = HashMap::new();
+    let mut labels: HashMap<String, String> = HashMap::new();
+    for &b in &l.blocks {
+        let label = func.blocks[b].label.clone();
+        let copy = func.fresh_label(&label);
+        copies.insert(b, func.add_block(&copy).unwrap());
+        labels.insert(label, copy);
+    }
+    let header_label = func.blocks[c.header].label.clone();
+    // a jump back goes to the header, the phi sources from it to its copy
+    let target = |label: &String| match label == &header_label {
+        true => label.clone(),
+        false => labels[label].clone(),
+    };
+    let body_label = target(&func.blocks[c.body].label);
+
+    let mut names: HashMap<Symbol, Symbol> = HashMap::new();
+    for &b in &l.blocks {
+        for instr in func.blocks[b].instrs.clone() {
+            if b == c.header && matches!(instr, IrInstruction::Phi { .. }) {
+                continue;
+            }
+            for def in instr.defs() {
+                let var = func.fresh_var(def.as_ref());
+                if let Some(typ) = func.types.get(def).cloned() {
+                    func.types.insert(var, typ);
+                }
+                names.insert(*def, var);
+            }
+        }
+    }
+    let rename = |var: &Symbol| names.get(var).copied().unwrap_or(*var);
+
+    for &b in &l.blocks {
+        let to = copies[&b];
+        let block = func.blocks[b].clone();
+        for (i, instr) in block.instrs.iter().enumerate() {
+            if b == c.header && matches!(instr, IrInstruction::Phi { .. }) {
+                continue;
+            }
+            let mut instr = instr.clone();
+            match &mut instr {
+                IrInstruction::Phi { sources, .. } => {
+                    for src in sources {
+                        src.pred = labels[&src.pred].clone();
+                        src.value = match src.value {
+                            Some(v) if values.contains_key(&v) => values[&v],
+                            value => value.map(|v| rename(&v)),
+                        };
+                    }
+                }
+                _ => {
+                    // `copyable_phis` made sure these are all defined
+                    for var in instr.uses_mut() {
+                        *var = match values.get(var) {
+                            Some(value) => value.unwrap(),
+                            None => rename(var),
+                        };
+                    }
+                }
+            }
+            if let Some(var) = instr.defs_mut() {
+                *var = rename(var);
+            }
+            match &mut instr {
+                IrInstruction::Br { .. } if b == c.header => {
+                    instr = IrInstruction::Jmp {
+                        label: body_label.clone(),
+                    };
+                }
+                IrInstruction::Jmp { label } => *label = target(label),
+                IrInstruction::Br {
+                    then_lbl, else_lbl, ..
+                } => {
+                    *then_lbl = target(then_lbl);
+                    *else_lbl = target(else_lbl);
+                }
+                _ => {}
+            }
+            func.append_instr(to, &instr, block.pos(i), block.origin(i));
+        }
+        // falling through, which the copy can't
+        if !block.instrs.last().is_some_and(|i| i.is_terminator()) {
+            let label = target(&func.blocks[block.succs[0]].label);
+            func.append_instr(to, &IrInstruction::Jmp { label }, None, None);
+        }
+    }
+
+    // all from the values this time, a phi can read another
+    *values = phis
+        .iter()
+        .map(|(dest, _, next)| {
+            let value = match next {
+                Some(v) if values.contains_key(v) => values[v],
+                next => next.map(|v| rename(&v)),
+            };
+            (*dest, value)
+        })
+        .collect();
+
+    let made = l.blocks.iter().map(|b| copies[b]).collect();
+    (copies[&c.header], copies[&c.latch], made)
+}
+
+/// Point `b`'s terminator at `to` wherever it names `from`, giving it one
+/// if it fell through to `from`. The edges are left for `relink`
+fn retarget(func: &mut IrFunction, b: BlockID, from: &str, to: &str) {
+    let falls_to = func.blocks[b]
+        .succs
+        .first()
+        .map(|&s| func.blocks[s].label.clone());
+    match func.blocks[b].instrs.last_mut() {
+        Some(IrInstruction::Jmp { label }) if label == from => *label = to.to_string(),
+        Some(IrInstruction::Br {
+            then_lbl, else_lbl, ..
+        }) => {
+            for label in [then_lbl, else_lbl] {
+                if label == from {
+                    *label = to.to_string();
+                }
+            }
+        }
+        Some(IrInstruction::Jmp { .. } | IrInstruction::Ret { .. }) => {}
+        _ if falls_to.as_deref() == Some(from) => {
+            let jmp = IrInstruction::Jmp {
+                label: to.to_string(),
+            };
+            func.append_instr(b, &jmp, None, None);
+        }
+        _ => {}
+    }
+}
+
+/// Make `b`'s edges out the ones its terminator says
+fn relink(func: &mut IrFunction, b: BlockID) {
+    let targets = match func.blocks[b].instrs.last() {
+        Some(IrInstruction::Jmp { label }) => vec![label.clone()],
+        Some(IrInstruction::Br {
+            then_lbl, else_lbl, ..
+        }) => vec![then_lbl.clone(), else_lbl.clone()],
+        Some(IrInstruction::Ret { .. }) => Vec::new(),
+        // falls through, the edge already says where
+        _ => return,
+    };
+    for s in std::mem::take(&mut func.blocks[b].succs) {
+        let preds = &mut func.blocks[s].preds;
+        if let Some(k) = preds.iter().position(|&p| p == b) {
+            preds.remove(k);
+        }
+    }
+    for label in targets {
+        let to = func.block_index(&label).unwrap();
+        func.add_edge(b, to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConstantFoldPass, ConstantPropagationPass, DeadCodeRemovalPass, SimplifyCfgPass};
+
+    fn sum_to(bound: &str) -> IrFunction {
+        IrFunction::parse(&format!(
+            "@main(n: int) {{
+.entry:
+  zero = const 0
+  one = const 1
+  four = const 4
+  jmp .head
+.head:
+  i = phi [zero, .entry] [i2, .body]
+  sum = phi [zero, .entry] [sum2, .body]
+  c = lt i {}
+  br c .body .exit
+.body:
+  sum2 = add sum i
+  i2 = add i one
+  jmp .head
+.exit:
+  print sum
+  ret
+}}",
+            bound
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_constant_loop_unrolls_completely() {
+        let mut func = sum_to("four");
+        assert_eq!(unroll_loops(&mut func, 1), 1);
+        bril_ir::verify_ssa(&func).unwrap();
+        assert!(!func.to_string().contains("phi"), "{}", func);
+
+        // straight-line code now, with nothing left to work out at run time
+        ConstantPropagationPass {}
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap();
+        ConstantFoldPass::default()
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap();
+        DeadCodeRemovalPass {}
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap();
+        SimplifyCfgPass {}
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap();
+        assert_eq!(func.blocks.len(), 1, "{}", func);
+        let lines: Vec<String> = func.blocks[0]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(lines, ["sum = const 6", "print sum", "ret"]);
+    }
+
+    #[test]
+    fn test_exit_on_true_counts_too() {
+        // `br` out when the test holds, and down rather than up
+        let mut func = IrFunction::parse(
+            "@main {
+.entry:
+  three = const 3
+  zero = const 0
+  minus = const -1
+  jmp .head
+.head:
+  i = phi [three, .entry] [i2, .body]
+  done = le i zero
+  br done .exit .body
+.body:
+  print i
+  i2 = add i minus
+  jmp .head
+.exit:
+  ret
+}",
+        )
+        .unwrap();
+        assert_eq!(unroll_loops(&mut func, 1), 1);
+        bril_ir::verify_ssa(&func).unwrap();
+        let prints = func
+            .blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .filter(|i| matches!(i, IrInstruction::Print { .. }))
+            .count();
+        assert_eq!(prints, 3, "{}", func);
+    }
+
+    #[test]
+    fn test_unknown_bound_unrolls_partially() {
+        let mut func = sum_to("n");
+        assert_eq!(unroll_loops(&mut func, 2), 1);
+        bril_ir::verify_ssa(&func).unwrap();
+        let text = func.to_string();
+        assert!(
+            text.contains(
+                "  i.1 = phi [zero, .entry] [i2.2, .body.2]
+  sum.1 = phi [zero, .entry] [sum2.2, .body.2]
+  i.more: bool = lt i.1 n
+  i.count: int = const 2
+  i.left: int = sub n i.1
+  i.enough: bool = ge i.left i.count
+  i.unrolled: bool = and i.more i.enough
+  br i.unrolled .head.1 .head.preheader"
+            ),
+            "{}",
+            text
+        );
+        // two times round without a test in between
+        assert!(
+            text.contains("  i2.1 = add i.1 one\n  jmp .head.2"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("  c.2 = lt i2.1 n\n  jmp .body.2"),
+            "{}",
+            text
+        );
+        // and the original loop for the rest
+        assert!(
+            text.contains("  i = phi [i2, .body] [i.1, .head.preheader]"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_long_loops_stay_without_a_factor() {
+        let mut func = sum_to("n");
+        let before = func.clone();
+        assert_eq!(unroll_loops(&mut func, 1), 0);
+        assert_eq!(func, before);
+
+        let mut func =
+            IrFunction::parse(&sum_to("four").to_string().replace("const 4", "const 100")).unwrap();
+        let before = func.clone();
+        assert_eq!(unroll_loops(&mut func, 1), 0);
+        assert_eq!(func, before);
+    }
+}
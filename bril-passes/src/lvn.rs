@@ -0,0 +1,320 @@
+use crate::analysis_manager::Analysis;
+use crate::constant_folding::fold;
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::{Literal, Symbol};
+use bril_ir::analysis::value_table::{is_commutative, is_pure, ConstKey};
+use bril_ir::{IrBasicBlock, IrFunction, IrInstruction};
+use std::collections::HashMap;
+
+/// Local Value Numbering
+///
+/// Each block on its own: every value computed in it gets a number, and an
+/// op on numbers already seen is the value of the first instruction that
+/// computed it, so it becomes an `id` of the name still holding that (or a
+/// `const`, when the value is known). Operands go through the same numbers,
+/// which sees through `id`s and constants, and ops on constants fold.
+/// Doesn't need SSA, a name defined again just stops holding its old value
+pub struct LvnPass {}
+
+impl FunctionPass for LvnPass {
+    fn name(&self) -> &str {
+        "LvnPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        _stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let before = function.clone();
+        for block in &mut function.blocks {
+            number_values(block);
+        }
+        Ok(*function != before)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        Analysis::CFG
+    }
+}
+
+/// What a value number stands for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Value {
+    Const(ConstKey),
+    /// An op on the values of its operands, sorted when it commutes
+    Op(&'static str, Vec<usize>),
+}
+
+#[derive(Debug, Default)]
+struct Numbering {
+    values: HashMap<Value, usize>,
+    // by number: the name it was first given to, and its constant
+    homes: Vec<Symbol>,
+    consts: Vec<Option<Literal>>,
+    // the number each name holds right now
+    names: HashMap<Symbol, usize>,
+}
+
+impl Numbering {
+    fn fresh(&mut self, home: Symbol, value: Option<Literal>) -> usize {
+        self.homes.push(home);
+        self.consts.push(value);
+        self.homes.len() - 1
+    }
+
+    /// The number of what `var` holds. A name read before the block
+    /// defines it comes in with a value of its own
+    fn of(&mut self, var: Symbol) -> usize {
+        if let Some(&n) = self.names.get(&var) {
+            return n;
+        }
+        let n = self.fresh(var, None);
+        self.names.insert(var, n);
+        n
+    }
+
+    /// The name still holding `n`, if its home hasn't been defined again
+    fn holder(&self, n: usize) -> Option<Symbol> {
+        let home = self.homes[n];
+        (self.names.get(&home) == Some(&n)).then_some(home)
+    }
+
+    /// Memory may have changed, no load is known to read the same any more
+    fn forget_loads(&mut self) {
+        self.values
+            .retain(|value, _| !matches!(value, Value::Op("load", _)));
+    }
+}
+
+fn number_values(block: &mut IrBasicBlock) {
+    let mut lvn = Numbering::default();
+    for instr in &mut block.instrs {
+        // a phi reads its values at the end of the preds, what it gives
+        // is new here
+        if let IrInstruction::Phi { dest, .. } = instr {
+            let n = lvn.fresh(*dest, None);
+            lvn.names.insert(*dest, n);
+            continue;
+        }
+
+        // read whatever name holds the value now, which goes through `id`s
+        for var in instr.uses_mut() {
+            let n = lvn.of(*var);
+            if let Some(holder) = lvn.holder(n) {
+                *var = holder;
+            }
+        }
+
+        if matches!(
+            instr,
+            IrInstruction::Store { .. }
+                | IrInstruction::Free { .. }
+                | IrInstruction::Call { .. }
+                | IrInstruction::Opaque { .. }
+        ) {
+            lvn.forget_loads();
+        }
+
+        let &[dest] = instr.defs() else {
+            continue;
+        };
+        let value = match instr {
+            IrInstruction::Assign { rhs, .. } => {
+                // the dest is another name for the same number
+                let n = lvn.of(*rhs);
+                if let Some(value) = lvn.consts[n].clone() {
+                    *instr = IrInstruction::Const { dest, value };
+                }
+                lvn.names.insert(dest, n);
+                continue;
+            }
+            IrInstruction::Const { value, .. } => Value::Const(ConstKey::from(&*value)),
+            _ if is_pure(instr) || matches!(instr, IrInstruction::Load { .. }) => {
+                let uses = instr.uses();
+                let mut operands: Vec<usize> = uses.iter().map(|&var| lvn.of(var)).collect();
+                let known: HashMap<Symbol, Literal> = uses
+                    .into_iter()
+                    .zip(&operands)
+                    .filter_map(|(var, &n)| Some((var, lvn.consts[n].clone()?)))
+                    .collect();
+                if let Some(value) = fold(instr, &known) {
+                    let key = ConstKey::from(&value);
+                    *instr = IrInstruction::Const { dest, value };
+                    Value::Const(key)
+                } else {
+                    if is_commutative(instr) {
+                        operands.sort();
+                    }
+                    Value::Op(instr.opcode(), operands)
+                }
+            }
+            // calls and allocs give something new every time
+            _ => {
+                let n = lvn.fresh(dest, None);
+                lvn.names.insert(dest, n);
+                continue;
+            }
+        };
+
+        let seen = lvn.values.get(&value).copied();
+        let n = match seen {
+            Some(n) if lvn.consts[n].is_some() => {
+                let value = lvn.consts[n].clone().unwrap();
+                *instr = IrInstruction::Const { dest, value };
+                n
+            }
+            Some(n) if lvn.holder(n).is_some() => {
+                let rhs = lvn.holder(n).unwrap();
+                *instr = IrInstruction::Assign { lhs: dest, rhs };
+                n
+            }
+            // new, or whatever held it has been defined again since
+            _ => {
+                let lit = match &value {
+                    Value::Const(key) => Some(Literal::from(*key)),
+                    Value::Op(..) => None,
+                };
+                let n = lvn.fresh(dest, lit);
+                lvn.values.insert(value, n);
+                n
+            }
+        };
+        lvn.names.insert(dest, n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(src: &str) -> Vec<String> {
+        let mut func = IrFunction::parse(src).unwrap();
+        LvnPass {}
+            .run_on_function(&mut func, &mut Statistics::default())
+            .unwrap();
+        func.blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_repeated_add_is_computed_once() {
+        let lines = numbered(
+            "@main(a: int, b: int) {
+.entry:
+  s = add a b
+  t = add a b
+  u = add b a
+  print s t u
+  ret
+}",
+        );
+        assert_eq!(
+            lines,
+            ["s = add a b", "t = id s", "u = id s", "print s s s", "ret"]
+        );
+    }
+
+    #[test]
+    fn test_operand_order_of_commutative_ops() {
+        let lines = numbered(
+            "@main(x: int) {
+.entry:
+  two = const 2
+  p = mul x two
+  q = mul two x
+  d = sub x two
+  e = sub two x
+  print p q d e
+  ret
+}",
+        );
+        assert_eq!(lines[2], "q = id p");
+        // sub doesn't commute
+        assert_eq!(lines[4], "e = sub two x");
+    }
+
+    #[test]
+    fn test_id_chains_and_constants() {
+        let lines = numbered(
+            "@main(n: int) {
+.entry:
+  a = const 4
+  b = id a
+  c = id b
+  d = add c a
+  four = const 4
+  m = id n
+  k = id m
+  r = add k four
+  s = add n a
+  print d r s
+  ret
+}",
+        );
+        assert_eq!(
+            lines,
+            [
+                "a = const 4",
+                "b = const 4",
+                "c = const 4",
+                "d = const 8",
+                "four = const 4",
+                "m = id n",
+                "k = id n",
+                // `four` is `a`, `k` is `n`
+                "r = add n a",
+                "s = id r",
+                "print d r r",
+                "ret"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_redefined_names_lose_their_value() {
+        let lines = numbered(
+            "@main(a: int, b: int) {
+.entry:
+  s = add a b
+  s = const 0
+  t = add a b
+  a = const 1
+  u = add a b
+  print s t u
+  ret
+}",
+        );
+        // `s` doesn't hold the sum any more, `t` has to work it out again
+        assert_eq!(lines[2], "t = add a b");
+        assert_eq!(lines[4], "u = add a b");
+        assert_eq!(lines[5], "print s t u");
+    }
+
+    #[test]
+    fn test_memory_writes_forget_loads() {
+        let lines = numbered(
+            "@main(p: ptr<int>, x: int) {
+.entry:
+  v = load p
+  print v
+  w = load p
+  store p x
+  y = load p
+  z = call @f p
+  q = load p
+  r = load p
+  print w y q r
+  ret
+}",
+        );
+        // printing doesn't change memory, storing and calling might
+        assert_eq!(lines[2], "w = id v");
+        assert_eq!(lines[4], "y = load p");
+        assert_eq!(lines[6], "q = load p");
+        assert_eq!(lines[7], "r = id q");
+    }
+}
@@ -1,40 +1,1277 @@
+use crate::analysis_manager::{Analysis, AnalysisManager};
+use anyhow::{bail, Result};
+use bril_ir::FunctionStats;
 use bril_ir::IrFunction;
 use bril_ir::IrModule;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
 /// This trait will be inherited by optimizations or transformations of
 /// on functions within the Module scope
 pub trait FunctionPass {
     fn name(&self) -> &str;
 
-    fn run_on_function(&mut self, function: &mut IrFunction) -> bool;
+    /// `Ok(true)` when it changed the function, an error when the function
+    /// is in no shape for the pass. What it did along the way can go in
+    /// `stats`
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError>;
+
+    /// What the [`PassManager`] calls, with the analyses it's keeping for
+    /// the pass to take what it needs from. Plain `run_on_function` unless
+    /// the pass uses them
+    fn run_with_analyses(
+        &mut self,
+        function: &mut IrFunction,
+        _analyses: &mut AnalysisManager,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        self.run_on_function(function, stats)
+    }
+
+    /// The analyses still right after this pass changed a function, the
+    /// manager drops the others. None unless the pass says so
+    fn preserves(&self) -> &[Analysis] {
+        &[]
+    }
+
+    /// Pick up the settings of the [`PassManager`] it was added to, before
+    /// it runs. Passes without any ignore them, an error is a value the
+    /// pass can't take
+    fn set_options(&mut self, _options: &PassOptions) -> Result<(), PassError> {
+        Ok(())
+    }
+
+    /// The keys `set_options` reads, like `inline.threshold`, for a
+    /// pipeline's `pass<key=val>` to be checked against
+    fn option_keys(&self) -> &[&'static str] {
+        &[]
+    }
+}
+
+/// Settings passes take from the [`PassManager`] running them, so one
+/// pipeline can be tuned per opt level. Keys start with the pipeline name
+/// of the pass reading them, as in `inline.threshold=40`, and a key that
+/// isn't set leaves the pass as it was made
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PassOptions {
+    values: BTreeMap<String, String>,
+}
+
+impl PassOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// These with `key` set to `value`, for building them up in one go
+    pub fn with(mut self, key: &str, value: impl ToString) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    pub fn set(&mut self, key: &str, value: impl ToString) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    /// Everything in `other` on top of these
+    pub fn extend(&mut self, other: &PassOptions) {
+        for (key, value) in &other.values {
+            self.values.insert(key.clone(), value.clone());
+        }
+    }
+
+    /// The value of `key` as it was written
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// Every key set, in order
+    pub fn keys(&self) -> impl Iterator<Item = &str> + '_ {
+        self.values.keys().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// `key` as a count, an error when it's set to anything else
+    pub fn get_usize(&self, key: &str) -> Result<Option<usize>, PassError> {
+        self.parse(key, "a whole number")
+    }
+
+    /// `key` as `true` or `false`
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>, PassError> {
+        self.parse(key, "`true` or `false`")
+    }
+
+    fn parse<T: std::str::FromStr>(
+        &self,
+        key: &str,
+        expected: &'static str,
+    ) -> Result<Option<T>, PassError> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        match value.parse() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(PassError::BadOption {
+                key: key.to_string(),
+                value: value.to_string(),
+                expected,
+            }),
+        }
+    }
+}
+
+/// For passes that need the whole module at once, to look across functions
+/// or add and remove them
+pub trait ModulePass {
+    fn name(&self) -> &str;
+
+    fn run_on_module(
+        &mut self,
+        module: &mut IrModule,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError>;
+
+    /// Same as [`FunctionPass::run_with_analyses`]
+    fn run_with_analyses(
+        &mut self,
+        module: &mut IrModule,
+        _analyses: &mut AnalysisManager,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        self.run_on_module(module, stats)
+    }
+
+    /// Same as [`FunctionPass::preserves`], for every function left
+    fn preserves(&self) -> &[Analysis] {
+        &[]
+    }
+
+    /// Same as [`FunctionPass::set_options`]
+    fn set_options(&mut self, _options: &PassOptions) -> Result<(), PassError> {
+        Ok(())
+    }
+
+    /// Same as [`FunctionPass::option_keys`]
+    fn option_keys(&self) -> &[&'static str] {
+        &[]
+    }
+}
+
+/// Named counters a pass bumps as it goes, like "constants folded". The
+/// [`PassManager`] gives each pass its own, adding up over every function
+/// and every time round the pipeline
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Statistics {
+    counters: BTreeMap<&'static str, usize>,
+}
+
+impl Statistics {
+    pub fn bump(&mut self, counter: &'static str) {
+        self.add(counter, 1);
+    }
+
+    pub fn add(&mut self, counter: &'static str, n: usize) {
+        if n > 0 {
+            *self.counters.entry(counter).or_default() += n;
+        }
+    }
+
+    /// 0 for a counter never bumped
+    pub fn get(&self, counter: &str) -> usize {
+        self.counters.get(counter).copied().unwrap_or(0)
+    }
+
+    /// Every counter bumped, by name
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        self.counters.iter().map(|(&name, &n)| (name, n))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    /// Add everything in `other` to these
+    pub fn merge(&mut self, other: &Statistics) {
+        for (name, n) in other.iter() {
+            self.add(name, n);
+        }
+    }
+}
+
+/// Why a pass couldn't run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PassError {
+    /// The function breaks something the pass relies on
+    MalformedIR { function: String, detail: String },
+    /// An instruction the pass has no way of handling
+    UnsupportedInstruction { function: String, instr: String },
+    /// A [`PassOptions`] value the pass can't make sense of
+    BadOption {
+        key: String,
+        value: String,
+        expected: &'static str,
+    },
+    /// What the pass prints couldn't be written
+    Output { detail: String },
+    /// Any of the others, with the pass that ran into it
+    InPass { pass: String, error: Box<PassError> },
+}
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PassError::MalformedIR { function, detail } => {
+                write!(f, "malformed IR in @{}: {}", function, detail)
+            }
+            PassError::UnsupportedInstruction { function, instr } => {
+                write!(f, "unsupported instruction in @{}: {}", function, instr)
+            }
+            PassError::BadOption {
+                key,
+                value,
+                expected,
+            } => write!(f, "option `{}` is `{}`, expected {}", key, value, expected),
+            PassError::Output { detail } => write!(f, "couldn't write the output: {}", detail),
+            PassError::InPass { pass, error } => write!(f, "{} failed, {}", pass, error),
+        }
+    }
+}
+
+impl std::error::Error for PassError {}
+
+impl PassError {
+    fn in_pass(self, pass: &str) -> PassError {
+        PassError::InPass {
+            pass: pass.to_string(),
+            error: Box::new(self),
+        }
+    }
+}
+
+// one step of the pipeline
+enum Pass {
+    Function(Box<dyn FunctionPass>),
+    Module(Box<dyn ModulePass>),
+}
+
+impl Pass {
+    fn name(&self) -> &str {
+        match self {
+            Pass::Function(pass) => pass.name(),
+            Pass::Module(pass) => pass.name(),
+        }
+    }
+}
+
+/// What one pass did to one function
+#[derive(Debug, Clone)]
+pub struct PassStats {
+    pub pass: String,
+    pub before: FunctionStats,
+    pub after: FunctionStats,
+}
+
+impl fmt::Display for PassStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (before, after) = (&self.before, &self.after);
+        write!(
+            f,
+            "{} on @{}: {} -> {} instrs, {} -> {} blocks, {} -> {} phis",
+            self.pass,
+            after.name,
+            before.instrs,
+            after.instrs,
+            before.blocks,
+            after.blocks,
+            before.phis,
+            after.phis
+        )
+    }
+}
+
+/// One pass on one function, or on the whole module for a module pass
+#[derive(Debug, Clone, Serialize)]
+pub struct PassTiming {
+    pub pass: String,
+    /// None for a module pass
+    pub function: Option<String>,
+    #[serde(rename = "time_us", serialize_with = "micros")]
+    pub time: Duration,
+    pub changed: bool,
+    /// Instructions before and after
+    pub instrs: (usize, usize),
+    /// Blocks before and after
+    pub blocks: (usize, usize),
+}
+
+fn micros<S: serde::Serializer>(time: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(time.as_micros() as u64)
+}
+
+/// Where the time of the last [`PassManager::run`] went and what each pass
+/// did, see [`PassManager::statistics`]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PassStatistics {
+    /// Every pass on every function, in the order they ran, changed or
+    /// not. Empty unless `set_time_passes` turned it on
+    pub runs: Vec<PassTiming>,
+    /// What each pass counted, in pipeline order
+    pub counters: Vec<(String, Statistics)>,
+}
+
+impl PassStatistics {
+    pub fn total_time(&self) -> Duration {
+        self.runs.iter().map(|run| run.time).sum()
+    }
+}
+
+/// A table of the runs added up by pass, slowest first, with its counters
+/// under each pass
+impl fmt::Display for PassStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // name, time, runs, changed, instrs and blocks gained
+        let mut rows: Vec<(&str, Duration, usize, usize, i64, i64)> = Vec::new();
+        for run in &self.runs {
+            let i = match rows.iter().position(|row| row.0 == run.pass) {
+                Some(i) => i,
+                None => {
+                    rows.push((&run.pass, Duration::ZERO, 0, 0, 0, 0));
+                    rows.len() - 1
+                }
+            };
+            let row = &mut rows[i];
+            row.1 += run.time;
+            row.2 += 1;
+            row.3 += run.changed as usize;
+            row.4 += run.instrs.1 as i64 - run.instrs.0 as i64;
+            row.5 += run.blocks.1 as i64 - run.blocks.0 as i64;
+        }
+        rows.sort_by_key(|row| std::cmp::Reverse(row.1));
+
+        writeln!(
+            f,
+            "{:<32} {:>10} {:>6} {:>8} {:>7} {:>7}",
+            "pass", "time", "runs", "changed", "instrs", "blocks"
+        )?;
+        for (name, time, runs, changed, instrs, blocks) in rows {
+            writeln!(
+                f,
+                "{:<32} {:>8.3}ms {:>6} {:>8} {:>+7} {:>+7}",
+                name,
+                time.as_secs_f64() * 1000.0,
+                runs,
+                changed,
+                instrs,
+                blocks
+            )?;
+            for (pass, counters) in &self.counters {
+                if pass == name {
+                    for (counter, n) in counters.iter() {
+                        writeln!(f, "    {}: {}", counter, n)?;
+                    }
+                }
+            }
+        }
+        writeln!(
+            f,
+            "{:<32} {:>8.3}ms",
+            "total",
+            self.total_time().as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// How many times [`PassManager::run`] goes round the pipeline at most
+pub const DEFAULT_MAX_ITERATIONS: usize = 10;
+
+/// What one [`PassManager::run`] did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    /// Times round the pipeline, the last one changing nothing if it
+    /// converged
+    pub iterations: usize,
+    /// False when the iteration limit stopped it with passes still
+    /// changing things
+    pub converged: bool,
+    /// Every pass, in order, with how many times it changed a function
+    /// (or the module, for a module pass)
+    pub changes: Vec<(String, usize)>,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let how = if self.converged {
+            "converged"
+        } else {
+            "gave up"
+        };
+        write!(f, "{} after {} iterations", how, self.iterations)
+    }
+}
+
+// which passes the IR gets printed around, and where to
+struct IrPrinter {
+    sink: Box<dyn Write>,
+    // `Some(None)` for every pass
+    before: Option<Option<String>>,
+    after: Option<Option<String>>,
+    // only the functions a pass changed
+    changed_only: bool,
+}
+
+impl IrPrinter {
+    fn wants(filter: &Option<Option<String>>, pass: &str) -> bool {
+        match filter {
+            Some(Some(name)) => name == pass,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    fn dump(when: &str, pass: &str, func: &IrFunction) -> String {
+        format!(
+            "; *** IR {} {} on @{} ***\n{}\n",
+            when, pass, func.name, func
+        )
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        self.sink.write_all(text.as_bytes())
+    }
 }
 
-#[derive(Default)]
 pub struct PassManager {
-    passes: Vec<Box<dyn FunctionPass>>,
+    passes: Vec<Pass>,
+    // check the IR with `verify_ssa` before the first pass and after each one
+    verify_each: bool,
+    // `Some` when each pass's before/after stats are being kept
+    stats: Option<Vec<PassStats>>,
+    // `Some` when each pass's time on each function is being kept
+    timings: Option<Vec<PassTiming>>,
+    // what each pass counted in the last `run`, by pass
+    counters: Vec<Statistics>,
+    options: PassOptions,
+    // what each pass was added with, over `options`, by pass
+    pass_options: Vec<PassOptions>,
+    max_iterations: usize,
+    // what the passes worked out, for the ones after them
+    analyses: AnalysisManager,
+    printer: IrPrinter,
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PassManager {
     pub fn new() -> PassManager {
-        PassManager { passes: Vec::new() }
+        PassManager {
+            passes: Vec::new(),
+            verify_each: false,
+            stats: None,
+            timings: None,
+            counters: Vec::new(),
+            options: PassOptions::default(),
+            pass_options: Vec::new(),
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            analyses: AnalysisManager::new(),
+            printer: IrPrinter {
+                sink: Box::new(io::stderr()),
+                before: None,
+                after: None,
+                changed_only: false,
+            },
+        }
+    }
+
+    /// Settings handed to every pass when `run` starts, under whatever a
+    /// pipeline's `pass<key=val>` gave the pass itself
+    pub fn set_options(&mut self, options: PassOptions) {
+        self.options = options;
     }
 
-    pub fn run(&mut self, module: &mut IrModule) {
+    pub fn options(&self) -> &PassOptions {
+        &self.options
+    }
+
+    /// Verify every function before the passes and again after each pass,
+    /// failing `run` on the first one that leaves it broken
+    pub fn set_verify_each(&mut self, verify_each: bool) {
+        self.verify_each = verify_each;
+    }
+
+    /// Keep the stats of every function before and after each pass, see
+    /// [`PassManager::stats`]
+    pub fn set_collect_stats(&mut self, collect: bool) {
+        self.stats = collect.then(Vec::new);
+    }
+
+    /// What each pass did to each function in the last `run`, in the order
+    /// they ran, leaving out the runs that changed nothing. Empty unless
+    /// `set_collect_stats` turned it on
+    pub fn stats(&self) -> &[PassStats] {
+        self.stats.as_deref().unwrap_or_default()
+    }
+
+    /// Time each pass on each function, see [`PassManager::statistics`]
+    pub fn set_time_passes(&mut self, time_passes: bool) {
+        self.timings = time_passes.then(Vec::new);
+    }
+
+    /// How long each pass took on each function in the last `run`, and
+    /// what the passes counted
+    pub fn statistics(&self) -> PassStatistics {
+        PassStatistics {
+            runs: self.timings.clone().unwrap_or_default(),
+            counters: self
+                .pass_names()
+                .into_iter()
+                .zip(self.counters.clone())
+                .collect(),
+        }
+    }
+
+    /// Print every function after each pass called `filter`, or after
+    /// every pass for None, to the print sink. A pass can go by its name in
+    /// a pipeline too, like `fold`
+    pub fn print_after_each(&mut self, filter: Option<String>) {
+        self.printer.after =
+            Some(filter.map(|name| crate::pipeline::pass_name(&name).unwrap_or(name)));
+    }
+
+    /// Same as `print_after_each`, before the pass
+    pub fn print_before_each(&mut self, filter: Option<String>) {
+        self.printer.before =
+            Some(filter.map(|name| crate::pipeline::pass_name(&name).unwrap_or(name)));
+    }
+
+    /// Only print the functions a pass changed, before and after it
+    pub fn set_print_changed_only(&mut self, changed_only: bool) {
+        self.printer.changed_only = changed_only;
+    }
+
+    /// Where `print_after_each` and `print_before_each` write, stderr
+    /// unless set
+    pub fn set_print_sink<W: Write + 'static>(&mut self, sink: W) {
+        self.printer.sink = Box::new(sink);
+    }
+
+    /// Most times round the pipeline `run` goes before giving up on it
+    /// settling, [`DEFAULT_MAX_ITERATIONS`] unless set
+    pub fn set_max_iterations(&mut self, max_iterations: usize) {
+        self.max_iterations = max_iterations;
+    }
+
+    /// The analyses kept for the passes. Any put in before `run` (e.g. by
+    /// [`AnalysisManager::build_ssa`]) are used by it, `run` drops them all
+    /// once it's done
+    pub fn analyses(&self) -> &AnalysisManager {
+        &self.analyses
+    }
+
+    pub fn analyses_mut(&mut self) -> &mut AnalysisManager {
+        &mut self.analyses
+    }
+
+    /// Run the passes in the order they were added, over and over until
+    /// none of them changes anything or the iteration limit is hit. Within
+    /// one time round, function passes in a row take one function through
+    /// all of them before the next, a module pass waits for the ones before
+    /// it to be done with every function
+    pub fn run(&mut self, module: &mut IrModule) -> Result<RunSummary> {
+        let summary = self.run_to_fixpoint(module);
+        // the module's the caller's again, it can change in any way now
+        self.analyses.clear();
+        summary
+    }
+
+    fn run_to_fixpoint(&mut self, module: &mut IrModule) -> Result<RunSummary> {
+        if let Some(stats) = &mut self.stats {
+            stats.clear();
+        }
+        if let Some(timings) = &mut self.timings {
+            timings.clear();
+        }
+        self.counters = vec![Statistics::default(); self.passes.len()];
+        for (pass, own) in self.passes.iter_mut().zip(&self.pass_options) {
+            let mut options = self.options.clone();
+            options.extend(own);
+            match pass {
+                Pass::Function(pass) => pass.set_options(&options),
+                Pass::Module(pass) => pass.set_options(&options),
+            }
+            .map_err(|e| e.in_pass(pass.name()))?;
+        }
+        if self.verify_each {
+            for func in &module.functions {
+                check(func, &mut self.analyses, "before any pass")?;
+            }
+        }
+
+        let mut changes = vec![0; self.passes.len()];
+        let mut summary = RunSummary::default();
+        while summary.iterations < self.max_iterations {
+            summary.iterations += 1;
+            let before: usize = changes.iter().sum();
+            self.run_once(module, &mut changes)?;
+            if changes.iter().sum::<usize>() == before {
+                summary.converged = true;
+                break;
+            }
+        }
+        summary.changes = self.pass_names().into_iter().zip(changes).collect();
+        Ok(summary)
+    }
+
+    // every pass once, adding one to `changes[p]` each time pass `p`
+    // changes a function, or the module
+    fn run_once(&mut self, module: &mut IrModule, changes: &mut [usize]) -> Result<()> {
+        let mut start = 0;
+        while start < self.passes.len() {
+            if let Pass::Module(pass) = &mut self.passes[start] {
+                let before = self.stats.is_some().then(|| module.stats());
+                let print_before = IrPrinter::wants(&self.printer.before, pass.name());
+                let print_after = IrPrinter::wants(&self.printer.after, pass.name());
+                let old = (self.printer.changed_only && (print_before || print_after))
+                    .then(|| module.functions.clone());
+                if print_before && !self.printer.changed_only {
+                    for func in &module.functions {
+                        self.printer
+                            .write(&IrPrinter::dump("before", pass.name(), func))?;
+                    }
+                }
+                let size = module_size(module);
+                let started = Instant::now();
+                let changed = pass
+                    .run_with_analyses(module, &mut self.analyses, &mut self.counters[start])
+                    .map_err(|e| e.in_pass(pass.name()))?;
+                if let Some(timings) = &mut self.timings {
+                    let time = started.elapsed();
+                    let after = module_size(module);
+                    timings.push(PassTiming {
+                        pass: pass.name().to_string(),
+                        function: None,
+                        time,
+                        changed,
+                        instrs: (size.0, after.0),
+                        blocks: (size.1, after.1),
+                    });
+                }
+                if changed {
+                    self.analyses.invalidate_module(module, pass.preserves());
+                }
+                if let Some(old) = &old {
+                    // the ones the pass removed count as changed too
+                    let kept = |func: &IrFunction| module.functions.contains(func);
+                    for func in old.iter().filter(|func| print_before && !kept(func)) {
+                        self.printer
+                            .write(&IrPrinter::dump("before", pass.name(), func))?;
+                    }
+                }
+                if print_after {
+                    for func in &module.functions {
+                        if old.as_ref().is_none_or(|old| !old.contains(func)) {
+                            self.printer
+                                .write(&IrPrinter::dump("after", pass.name(), func))?;
+                        }
+                    }
+                }
+                if let (true, Some(stats), Some(before)) = (changed, &mut self.stats, before) {
+                    for after in module.stats().functions {
+                        // a function the pass added has nothing before it
+                        let before = before
+                            .functions
+                            .iter()
+                            .find(|f| f.name == after.name)
+                            .cloned()
+                            .unwrap_or_else(|| FunctionStats {
+                                name: after.name.clone(),
+                                ..FunctionStats::default()
+                            });
+                        stats.push(PassStats {
+                            pass: pass.name().to_string(),
+                            before,
+                            after,
+                        });
+                    }
+                }
+                if self.verify_each {
+                    for func in &module.functions {
+                        check(func, &mut self.analyses, &format!("after {}", pass.name()))?;
+                    }
+                }
+                changes[start] += changed as usize;
+                start += 1;
+                continue;
+            }
+
+            let end = self.passes[start..]
+                .iter()
+                .position(|p| matches!(p, Pass::Module(_)))
+                .map_or(self.passes.len(), |k| start + k);
+            self.run_function_passes(module, start..end, changes)?;
+            start = end;
+        }
+        Ok(())
+    }
+
+    fn run_function_passes(
+        &mut self,
+        module: &mut IrModule,
+        range: Range<usize>,
+        changes: &mut [usize],
+    ) -> Result<()> {
+        // there's no module to work these out from once the passes have a
+        // function each
+        self.analyses.effects(module);
         // loop throught each function in the module and run the pass
         for func in module.functions.iter_mut() {
             // loop there each of the element in the passes vector
-            for pass in self.passes.iter_mut() {
-                let changed = pass.run_on_function(func);
-                if !changed {
-                    // TODO: find a better way of dealing with this
-                    // maybe add an erroring system?
-                    break;
+            for (p, pass) in self
+                .passes
+                .iter_mut()
+                .enumerate()
+                .take(range.end)
+                .skip(range.start)
+            {
+                let Pass::Function(pass) = pass else {
+                    continue;
+                };
+                let before = self.stats.is_some().then(|| func.stats());
+                // held until it's known the pass changed something
+                let mut held = None;
+                if IrPrinter::wants(&self.printer.before, pass.name()) {
+                    let dump = IrPrinter::dump("before", pass.name(), func);
+                    if self.printer.changed_only {
+                        held = Some(dump);
+                    } else {
+                        self.printer.write(&dump)?;
+                    }
+                }
+                let size = instrs_and_blocks(func);
+                let started = Instant::now();
+                let changed = pass
+                    .run_with_analyses(func, &mut self.analyses, &mut self.counters[p])
+                    .map_err(|e| e.in_pass(pass.name()))?;
+                if let Some(timings) = &mut self.timings {
+                    let time = started.elapsed();
+                    let after = instrs_and_blocks(func);
+                    timings.push(PassTiming {
+                        pass: pass.name().to_string(),
+                        function: Some(func.name.clone()),
+                        time,
+                        changed,
+                        instrs: (size.0, after.0),
+                        blocks: (size.1, after.1),
+                    });
+                }
+                if changed {
+                    self.analyses.invalidate(func, pass.preserves());
+                }
+                if let Some(dump) = held.filter(|_| changed) {
+                    self.printer.write(&dump)?;
+                }
+                if IrPrinter::wants(&self.printer.after, pass.name())
+                    && (changed || !self.printer.changed_only)
+                {
+                    self.printer
+                        .write(&IrPrinter::dump("after", pass.name(), func))?;
                 }
+                if let (true, Some(stats), Some(before)) = (changed, &mut self.stats, before) {
+                    stats.push(PassStats {
+                        pass: pass.name().to_string(),
+                        before,
+                        after: func.stats(),
+                    });
+                }
+                if self.verify_each {
+                    check(func, &mut self.analyses, &format!("after {}", pass.name()))?;
+                }
+                changes[p] += changed as usize;
             }
         }
+        Ok(())
     }
 
     pub fn add_pass<P: FunctionPass + 'static>(&mut self, pass: P) {
-        self.passes.push(Box::new(pass));
+        self.add_boxed_pass(Box::new(pass), PassOptions::new());
+    }
+
+    pub fn add_module_pass<P: ModulePass + 'static>(&mut self, pass: P) {
+        self.add_boxed_module_pass(Box::new(pass), PassOptions::new());
+    }
+
+    /// With `options` of its own, taking over from the manager's
+    pub(crate) fn add_boxed_pass(&mut self, pass: Box<dyn FunctionPass>, options: PassOptions) {
+        self.passes.push(Pass::Function(pass));
+        self.pass_options.push(options);
+    }
+
+    pub(crate) fn add_boxed_module_pass(
+        &mut self,
+        pass: Box<dyn ModulePass>,
+        options: PassOptions,
+    ) {
+        self.passes.push(Pass::Module(pass));
+        self.pass_options.push(options);
+    }
+
+    /// The passes a pipeline like `constprop,fold,dce` names, see
+    /// [`crate::pipeline`]
+    pub fn from_pipeline(pipeline: &str) -> Result<PassManager> {
+        crate::pipeline::parse(pipeline)
+    }
+
+    /// Names of the registered passes, in the order they run
+    pub fn pass_names(&self) -> Vec<String> {
+        self.passes.iter().map(|p| p.name().to_string()).collect()
+    }
+}
+
+// instructions and blocks, cheap enough to count around every pass
+fn instrs_and_blocks(func: &IrFunction) -> (usize, usize) {
+    let instrs = func.blocks.iter().map(|b| b.instrs.len()).sum();
+    (instrs, func.blocks.len())
+}
+
+fn module_size(module: &IrModule) -> (usize, usize) {
+    module
+        .functions
+        .iter()
+        .map(instrs_and_blocks)
+        .fold((0, 0), |(i, b), (fi, fb)| (i + fi, b + fb))
+}
+
+/// `verify_ssa` with every problem in one error. The dominators come from
+/// `analyses`, kept there for the next pass, so checking works them out
+/// again only where a pass changed the CFG, which the next pass to want
+/// them would do anyway
+fn check(func: &IrFunction, analyses: &mut AnalysisManager, when: &str) -> Result<()> {
+    if let Err(errors) = bril_ir::verify_ssa_with(func, move || analyses.dominators(func).ok()) {
+        let lines: Vec<String> = errors.iter().map(|e| format!("  {}", e)).collect();
+        bail!("@{} is broken {}:\n{}", func.name, when, lines.join("\n"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeadCodeRemovalPass;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_stats_before_and_after_dce() {
+        // `b` and `c` are never read
+        let module = || IrModule {
+            functions: vec![IrFunction::parse(
+                "@main() {
+.entry:
+  a = const 1
+  b = const 2
+  c = add a b
+  print a
+  ret
+}",
+            )
+            .unwrap()],
+        };
+        let mut pm = PassManager::new();
+        pm.add_pass(DeadCodeRemovalPass {});
+        pm.run(&mut module()).unwrap();
+        assert!(pm.stats().is_empty());
+
+        pm.set_collect_stats(true);
+        let mut module = module();
+        pm.run(&mut module).unwrap();
+        let [dce] = pm.stats() else {
+            panic!("one pass on one function, got {:?}", pm.stats());
+        };
+        assert_eq!((dce.before.instrs, dce.after.instrs), (5, 3));
+        assert_eq!(dce.before.opcodes["const"], 2);
+        assert_eq!(dce.after.opcodes["const"], 1);
+        assert!(!dce.after.opcodes.contains_key("add"));
+        assert_eq!(dce.after, module.functions[0].stats());
+        assert_eq!(
+            dce.to_string(),
+            "DeadCodeRemovalPass on @main: 5 -> 3 instrs, 1 -> 1 blocks, 0 -> 0 phis"
+        );
+    }
+
+    #[test]
+    fn test_options_reach_the_passes() {
+        let module = || IrModule {
+            functions: vec![IrFunction::parse(
+                "@main(n: int) {
+.entry:
+  zero = const 0
+  one = const 1
+  jmp .head
+.head:
+  i = phi [zero, .entry] [i2, .body]
+  c = lt i n
+  br c .body .exit
+.body:
+  print i
+  i2 = add i one
+  jmp .head
+.exit:
+  ret
+}",
+            )
+            .unwrap()],
+        };
+        let blocks = |options: PassOptions| {
+            let mut pm = PassManager::new();
+            pm.add_pass(crate::LoopUnrollPass::default());
+            pm.set_options(options);
+            pm.set_verify_each(true);
+            let mut module = module();
+            pm.run(&mut module).unwrap();
+            module.functions[0].blocks.len()
+        };
+        // the pass's own factor of 1 unless the options say otherwise
+        assert_eq!(blocks(PassOptions::default()), 4);
+        assert!(blocks(PassOptions::new().with("unroll.factor", 2)) > 4);
+
+        let mut pm = PassManager::new();
+        pm.add_pass(crate::LoopUnrollPass::default());
+        pm.set_options(PassOptions::new().with("unroll.factor", "two"));
+        let err = pm.run(&mut module()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "LoopUnrollPass failed, option `unroll.factor` is `two`, expected a whole number"
+        );
+    }
+
+    /// Writes down the instruction count of every function it sees
+    struct CountInstrs(Rc<RefCell<Vec<usize>>>);
+
+    impl ModulePass for CountInstrs {
+        fn name(&self) -> &str {
+            "CountInstrs"
+        }
+
+        fn run_on_module(
+            &mut self,
+            module: &mut IrModule,
+            _stats: &mut Statistics,
+        ) -> Result<bool, PassError> {
+            let counts = module.functions.iter().map(|f| f.stats().instrs);
+            self.0.borrow_mut().extend(counts);
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn test_module_pass_waits_for_every_function() {
+        let func = |name: &str| {
+            IrFunction::parse(&format!(
+                "@{}() {{
+.entry:
+  a = const 1
+  b = const 2
+  print a
+  ret
+}}",
+                name
+            ))
+            .unwrap()
+        };
+        let mut module = IrModule {
+            functions: vec![func("main"), func("f")],
+        };
+        let counts = Rc::new(RefCell::new(Vec::new()));
+        let mut pm = PassManager::new();
+        pm.add_pass(DeadCodeRemovalPass {});
+        pm.add_module_pass(CountInstrs(counts.clone()));
+        pm.set_collect_stats(true);
+        pm.set_verify_each(true);
+        let summary = pm.run(&mut module).unwrap();
+
+        // DCE was done with both before the count, then the second time
+        // round changed nothing
+        assert_eq!(summary.iterations, 2);
+        assert_eq!(*counts.borrow(), [3, 3, 3, 3]);
+        assert_eq!(pm.pass_names(), ["DeadCodeRemovalPass", "CountInstrs"]);
+        let runs: Vec<(&str, &str)> = pm
+            .stats()
+            .iter()
+            .map(|s| (s.pass.as_str(), s.before.name.as_str()))
+            .collect();
+        assert_eq!(
+            runs,
+            [
+                ("DeadCodeRemovalPass", "main"),
+                ("DeadCodeRemovalPass", "f"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant_chain_converges() {
+        let mut module = IrModule {
+            functions: vec![IrFunction::parse(
+                "@main() {
+.entry:
+  a = const 1
+  b = add a a
+  c = mul b b
+  d = sub c a
+  print d
+  ret
+}",
+            )
+            .unwrap()],
+        };
+        let mut pm = PassManager::new();
+        pm.add_pass(crate::ConstantPropagationPass {});
+        pm.add_pass(crate::ConstantFoldPass::default());
+        pm.add_pass(DeadCodeRemovalPass {});
+        pm.set_verify_each(true);
+        let summary = pm.run(&mut module).unwrap();
+
+        // the first time round does it all, the second finds nothing left
+        let text = module.functions[0].to_string();
+        assert!(text.contains("d = const 3\n  print d\n  ret"), "{}", text);
+        assert_eq!(module.functions[0].stats().instrs, 3);
+        assert!(summary.converged);
+        assert_eq!(summary.iterations, 2);
+        let changes: Vec<(&str, usize)> = summary
+            .changes
+            .iter()
+            .map(|(name, n)| (name.as_str(), *n))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("ConstantPropagationPass", 1),
+                ("ConstantFoldPass", 0),
+                ("DeadCodeRemovalPass", 1)
+            ]
+        );
+        assert_eq!(summary.to_string(), "converged after 2 iterations");
+    }
+
+    fn straight_line() -> IrModule {
+        IrModule {
+            functions: vec![IrFunction::parse(
+                "@main() {
+.entry:
+  a = const 1
+  jmp .mid
+.mid:
+  b = add a a
+  jmp .exit
+.exit:
+  print b
+  ret
+}",
+            )
+            .unwrap()],
+        }
+    }
+
+    #[test]
+    fn test_dominators_are_kept_between_passes() {
+        let mut pm = PassManager::new();
+        pm.add_pass(crate::GvnPass {});
+        pm.add_pass(crate::GvnPass {});
+        let summary = pm.run(&mut straight_line()).unwrap();
+        assert_eq!(summary.iterations, 1);
+        assert_eq!(pm.analyses().computed(Analysis::Dominators), 1);
+    }
+
+    #[test]
+    fn test_changing_the_cfg_drops_the_dominators() {
+        let mut pm = PassManager::new();
+        pm.add_pass(crate::GvnPass {});
+        pm.add_pass(crate::SimplifyCfgPass {});
+        pm.add_pass(crate::GvnPass {});
+        let mut module = straight_line();
+        pm.run(&mut module).unwrap();
+        assert_eq!(module.functions[0].blocks.len(), 1);
+        // once for the three blocks, once for the one they were merged into
+        assert_eq!(pm.analyses().computed(Analysis::Dominators), 2);
+    }
+
+    /// Says it changed something every time
+    struct Restless;
+
+    impl FunctionPass for Restless {
+        fn name(&self) -> &str {
+            "Restless"
+        }
+
+        fn run_on_function(
+            &mut self,
+            _function: &mut IrFunction,
+            _stats: &mut Statistics,
+        ) -> Result<bool, PassError> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_iteration_limit() {
+        let mut module = IrModule {
+            functions: vec![IrFunction::parse("@main() {\n.entry:\n  ret\n}").unwrap()],
+        };
+        let mut pm = PassManager::new();
+        pm.add_pass(Restless);
+        assert_eq!(
+            pm.run(&mut module).unwrap().iterations,
+            DEFAULT_MAX_ITERATIONS
+        );
+
+        pm.set_max_iterations(3);
+        let summary = pm.run(&mut module).unwrap();
+        assert!(!summary.converged);
+        assert_eq!(summary.changes, [("Restless".to_string(), 3)]);
+        assert_eq!(summary.to_string(), "gave up after 3 iterations");
+    }
+
+    // a sink the test can still read once the manager has it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_printing_the_ir_between_passes() {
+        let module = || IrModule {
+            functions: vec![
+                IrFunction::parse("@main() {\n.entry:\n  a = const 1\n  ret\n}").unwrap(),
+                IrFunction::parse("@clean() {\n.entry:\n  ret\n}").unwrap(),
+            ],
+        };
+        let headers = |sink: &Shared| -> Vec<String> {
+            let text = String::from_utf8(sink.0.borrow().clone()).unwrap();
+            text.lines()
+                .filter(|line| line.starts_with("; ***"))
+                .map(str::to_string)
+                .collect()
+        };
+
+        let sink = Shared::default();
+        let mut pm = PassManager::new();
+        pm.add_pass(crate::ConstantFoldPass::default());
+        pm.add_pass(DeadCodeRemovalPass {});
+        pm.set_print_sink(sink.clone());
+        // by its pipeline name
+        pm.print_after_each(Some("dce".to_string()));
+        pm.run(&mut module()).unwrap();
+        assert_eq!(
+            headers(&sink),
+            [
+                "; *** IR after DeadCodeRemovalPass on @main ***",
+                "; *** IR after DeadCodeRemovalPass on @clean ***",
+                "; *** IR after DeadCodeRemovalPass on @main ***",
+                "; *** IR after DeadCodeRemovalPass on @clean ***",
+            ]
+        );
+        let text = String::from_utf8(sink.0.borrow().clone()).unwrap();
+        assert!(text.starts_with("; *** IR after DeadCodeRemovalPass on @main ***\n@main() {\n"));
+
+        // only DCE's first go at @main changes anything
+        let sink = Shared::default();
+        pm.set_print_sink(sink.clone());
+        pm.print_before_each(None);
+        pm.print_after_each(None);
+        pm.set_print_changed_only(true);
+        pm.run(&mut module()).unwrap();
+        assert_eq!(
+            headers(&sink),
+            [
+                "; *** IR before DeadCodeRemovalPass on @main ***",
+                "; *** IR after DeadCodeRemovalPass on @main ***",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_passes_count_what_they_did() {
+        let mut module = IrModule {
+            functions: vec![IrFunction::parse(
+                "@main() {
+.entry:
+  a = const 1
+  b = add a a
+  c = mul b b
+  d = sub c a
+  print d
+  ret
+}",
+            )
+            .unwrap()],
+        };
+        let mut pm = PassManager::new();
+        pm.add_pass(crate::ConstantFoldPass::default());
+        pm.add_pass(DeadCodeRemovalPass {});
+        pm.set_time_passes(true);
+        pm.run(&mut module).unwrap();
+
+        let statistics = pm.statistics();
+        let [(fold, folded), (dce, removed)] = &statistics.counters[..] else {
+            panic!("two passes, got {:?}", statistics.counters);
+        };
+        assert_eq!(
+            (fold.as_str(), folded.get("constants folded")),
+            ("ConstantFoldPass", 3)
+        );
+        assert_eq!(
+            (dce.as_str(), removed.get("instructions removed")),
+            ("DeadCodeRemovalPass", 3)
+        );
+
+        // both passes twice, the second time round changing nothing
+        let runs: Vec<(&str, bool, (usize, usize))> = statistics
+            .runs
+            .iter()
+            .map(|run| (run.pass.as_str(), run.changed, run.instrs))
+            .collect();
+        assert_eq!(
+            runs,
+            [
+                ("ConstantFoldPass", true, (6, 6)),
+                ("DeadCodeRemovalPass", true, (6, 3)),
+                ("ConstantFoldPass", false, (3, 3)),
+                ("DeadCodeRemovalPass", false, (3, 3)),
+            ]
+        );
+        assert!(statistics
+            .runs
+            .iter()
+            .all(|run| run.function.as_deref() == Some("main")));
+        let table = statistics.to_string();
+        assert!(table.contains("\n    constants folded: 3\n"), "{}", table);
+
+        // not timed, still counted
+        pm.set_time_passes(false);
+        pm.run(&mut module).unwrap();
+        assert!(pm.statistics().runs.is_empty());
+        assert_eq!(pm.statistics().counters.len(), 2);
     }
 }
@@ -0,0 +1,276 @@
+use crate::analysis_manager::Analysis;
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::Symbol;
+use bril_ir::{IrFunction, IrInstruction};
+use std::collections::{HashMap, HashSet};
+
+/// Trivial Phi Removal, after Braun et al.
+///
+/// A phi whose sources all hold the same value, leaving out the phi itself
+/// coming round a loop, is that value: whatever reads the phi reads the
+/// value instead, and the phi becomes an `id` of it for DCE to take. That
+/// can leave a phi reading it trivial in turn, so those are looked at
+/// again. A phi reading only itself is dropped when nothing else reads it.
+/// Runs once unreachable blocks are gone, their edges keep phis apart, and
+/// saves a move per pred out of SSA.
+pub struct PhiSimplifyPass {}
+
+impl FunctionPass for PhiSimplifyPass {
+    fn name(&self) -> &str {
+        "PhiSimplifyPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let removed = simplify_phis(function);
+        stats.add("phis removed", removed);
+        Ok(removed > 0)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        Analysis::CFG
+    }
+}
+
+// one phi of the function
+struct Phi {
+    block: usize,
+    dest: Symbol,
+    values: Vec<Option<Symbol>>,
+}
+
+/// Returns how many phis went
+fn simplify_phis(func: &mut IrFunction) -> usize {
+    let mut phis = Vec::new();
+    for (b, block) in func.blocks.iter().enumerate() {
+        for instr in &block.instrs {
+            if let IrInstruction::Phi { dest, sources } = instr {
+                phis.push(Phi {
+                    block: b,
+                    dest: *dest,
+                    values: sources.iter().map(|s| s.value).collect(),
+                });
+            }
+        }
+    }
+    // name -> the phis reading it
+    let mut users: HashMap<Symbol, Vec<usize>> = HashMap::new();
+    for (i, phi) in phis.iter().enumerate() {
+        for value in phi.values.iter().flatten() {
+            users.entry(*value).or_default().push(i);
+        }
+    }
+
+    // phi -> the value it turned out to be
+    let mut replaced: HashMap<Symbol, Symbol> = HashMap::new();
+    let mut self_only = Vec::new();
+    let mut worklist: Vec<usize> = (0..phis.len()).rev().collect();
+    while let Some(i) = worklist.pop() {
+        let phi = &phis[i];
+        if replaced.contains_key(&phi.dest) {
+            continue;
+        }
+        match same_value(phi, &replaced) {
+            Same::One(value) => {
+                replaced.insert(phi.dest, value);
+                let readers = users.get(&phi.dest).into_iter().flatten();
+                worklist.extend(readers.filter(|&&j| j != i));
+            }
+            Same::Itself => self_only.push(phi.dest),
+            Same::Many => {}
+        }
+    }
+    if replaced.is_empty() && self_only.is_empty() {
+        return 0;
+    }
+
+    for instr in func.blocks.iter_mut().flat_map(|b| &mut b.instrs) {
+        for var in instr.uses_mut() {
+            *var = resolve(*var, &replaced);
+        }
+    }
+
+    // one reading itself goes once nothing else reads it
+    let mut read = HashSet::new();
+    for instr in func.blocks.iter().flat_map(|b| &b.instrs) {
+        let defs = instr.defs();
+        read.extend(instr.uses().into_iter().filter(|var| !defs.contains(var)));
+    }
+    let dropped: HashSet<Symbol> = self_only
+        .into_iter()
+        .filter(|dest| !read.contains(dest))
+        .collect();
+
+    let blocks: HashSet<usize> = phis
+        .iter()
+        .filter(|phi| replaced.contains_key(&phi.dest) || dropped.contains(&phi.dest))
+        .map(|phi| phi.block)
+        .collect();
+    for b in blocks {
+        let block = &mut func.blocks[b];
+        // the copies go after the phis left, so those stay together at the
+        // top of the block
+        let mut kept = Vec::new();
+        for (i, instr) in block.instrs.iter().enumerate() {
+            if let IrInstruction::Phi { dest, .. } = instr
+                && let Some(&value) = replaced.get(dest)
+            {
+                kept.push((*dest, value, block.pos(i), block.origin(i)));
+            }
+        }
+        block.remove_instrs(|instr| match instr {
+            IrInstruction::Phi { dest, .. } => {
+                replaced.contains_key(dest) || dropped.contains(dest)
+            }
+            _ => false,
+        });
+        let at = block
+            .instrs
+            .iter()
+            .take_while(|i| matches!(i, IrInstruction::Phi { .. }))
+            .count();
+        for (k, (lhs, rhs, pos, origin)) in kept.into_iter().enumerate() {
+            let rhs = resolve(rhs, &replaced);
+            block.insert_instr(at + k, IrInstruction::Assign { lhs, rhs }, pos, origin);
+        }
+    }
+    replaced.len() + dropped.len()
+}
+
+enum Same {
+    /// Every source is this or the phi itself
+    One(Symbol),
+    /// Every source is the phi itself
+    Itself,
+    /// Two different values, or one undefined
+    Many,
+}
+
+fn same_value(phi: &Phi, replaced: &HashMap<Symbol, Symbol>) -> Same {
+    let mut same = None;
+    for value in &phi.values {
+        // along an edge with nothing defined, the value the others have
+        // might not reach the phi
+        let Some(value) = value else {
+            return Same::Many;
+        };
+        let value = resolve(*value, replaced);
+        if value == phi.dest {
+            continue;
+        }
+        match same {
+            None => same = Some(value),
+            Some(v) if v == value => {}
+            Some(_) => return Same::Many,
+        }
+    }
+    match same {
+        Some(value) => Same::One(value),
+        None => Same::Itself,
+    }
+}
+
+// what `var` stands for once the trivial phis are gone
+fn resolve(mut var: Symbol, replaced: &HashMap<Symbol, Symbol>) -> Symbol {
+    while let Some(&value) = replaced.get(&var) {
+        var = value;
+    }
+    var
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simplified(src: &str) -> (usize, Vec<String>) {
+        let mut func = IrFunction::parse(src).unwrap();
+        let removed = simplify_phis(&mut func);
+        bril_ir::verify_ssa(&func).unwrap();
+        let lines = func
+            .blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .map(|i| i.to_string())
+            .collect();
+        (removed, lines)
+    }
+
+    #[test]
+    fn test_phi_of_one_value_becomes_a_copy() {
+        let (removed, lines) = simplified(
+            "@main(c: bool, n: int) {
+.entry:
+  br c .left .right
+.left:
+  jmp .join
+.right:
+  jmp .join
+.join:
+  x = phi [n, .left] [n, .right]
+  y = phi [n, .left] [c, .right]
+  print x y
+  ret
+}",
+        );
+        assert_eq!(removed, 1);
+        assert_eq!(lines[3], "y = phi n c");
+        assert_eq!(lines[4], "x = id n");
+        assert_eq!(lines[5], "print n y");
+    }
+
+    #[test]
+    fn test_loop_phis_collapse_in_turn() {
+        // `i` is `n` coming in and itself round the loop, which makes `j`
+        // `n` too once `i` is gone
+        let (removed, lines) = simplified(
+            "@main(n: int, c: bool) {
+.entry:
+  jmp .head
+.head:
+  i = phi [n, .entry] [i, .body]
+  k = phi [k, .entry] [k, .body]
+  br c .body .exit
+.body:
+  j = phi [i, .head]
+  jmp .head
+.exit:
+  print i
+  ret
+}",
+        );
+        assert_eq!(removed, 3);
+        assert_eq!(
+            lines,
+            [
+                "jmp .head",
+                "i = id n",
+                "br c .body .exit",
+                "j = id n",
+                "jmp .head",
+                "print n",
+                "ret"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_undefined_sources_keep_the_phi() {
+        let src = "@main(c: bool, n: int) {
+.entry:
+  br c .left .right
+.left:
+  jmp .join
+.right:
+  jmp .join
+.join:
+  x = phi [n, .left] [_, .right]
+  print x
+  ret
+}";
+        let (removed, _) = simplified(src);
+        assert_eq!(removed, 0);
+    }
+}
@@ -0,0 +1,573 @@
+//! Pass pipelines written out as text, for trying orders of passes without
+//! a rebuild
+//!
+//! ```text
+//! pipeline := item (',' item)*
+//! item     := name ['<' option (',' option)* '>'] ['*' count]
+//!           | label '(' pipeline ')' ['*' count]
+//! option   := key '=' value
+//! ```
+//!
+//! A `name` is one of [`PASSES`]. `*3` puts three of the item in a row. A
+//! group like `loop(indvars,unroll)` runs its passes over and over until
+//! they stop changing anything, as one step of the pipeline around it,
+//! and the label only names it. So `constprop,fold*2,dce` runs constant
+//! propagation, folding twice, then DCE.
+//!
+//! `inline<threshold=40>` sets the `inline.threshold` of that one pass,
+//! over the [`PassOptions`] of the manager. The keys are checked against
+//! the ones the pass reads, and the values against what it takes.
+
+use crate::analysis_manager::AnalysisManager;
+use crate::analysis_printer::Printed;
+use crate::pass_manager::{
+    FunctionPass, ModulePass, PassError, PassManager, PassOptions, Statistics,
+};
+use crate::{
+    AnalysisPrinterPass, AvailCsePass, BlockLayoutPass, ConstantFoldPass, ConstantPropagationPass,
+    CopyPropagationPass, DeadCodeRemovalPass, DeadFunctionPass, GvnPass, IndVarPass, InlinePass,
+    InstCombinePass, JumpThreadPass, LoopRotatePass, LoopUnrollPass, LvnPass, PhiSimplifyPass,
+    SccpPass, SimplifyCfgPass, StrengthReductionPass, TailCallPass,
+};
+use anyhow::{bail, Result};
+use bril_frontend::validate::edit_distance;
+use bril_ir::IrModule;
+
+/// How to make a new one of a pass
+#[derive(Clone, Copy)]
+pub enum Make {
+    Function(fn() -> Box<dyn FunctionPass>),
+    Module(fn() -> Box<dyn ModulePass>),
+}
+
+/// Every pass a pipeline can name, by the name it goes by there
+pub const PASSES: &[(&str, Make)] = &[
+    ("availcse", Make::Function(|| Box::new(AvailCsePass {}))),
+    (
+        "blocklayout",
+        Make::Function(|| Box::new(BlockLayoutPass {})),
+    ),
+    (
+        "constprop",
+        Make::Function(|| Box::new(ConstantPropagationPass {})),
+    ),
+    (
+        "copyprop",
+        Make::Function(|| Box::new(CopyPropagationPass {})),
+    ),
+    ("dce", Make::Function(|| Box::new(DeadCodeRemovalPass {}))),
+    (
+        "deadfunc",
+        Make::Module(|| Box::new(DeadFunctionPass::new())),
+    ),
+    (
+        "fold",
+        Make::Function(|| Box::new(ConstantFoldPass::default())),
+    ),
+    ("gvn", Make::Function(|| Box::new(GvnPass {}))),
+    ("indvars", Make::Function(|| Box::new(IndVarPass {}))),
+    ("inline", Make::Module(|| Box::new(InlinePass::default()))),
+    (
+        "instcombine",
+        Make::Function(|| Box::new(InstCombinePass {})),
+    ),
+    (
+        "jumpthread",
+        Make::Function(|| Box::new(JumpThreadPass::default())),
+    ),
+    ("lvn", Make::Function(|| Box::new(LvnPass {}))),
+    (
+        "print-doms",
+        Make::Function(|| Box::new(AnalysisPrinterPass::new(Printed::Dominators))),
+    ),
+    (
+        "print-liveness",
+        Make::Function(|| Box::new(AnalysisPrinterPass::new(Printed::Liveness))),
+    ),
+    (
+        "rotate",
+        Make::Function(|| Box::new(LoopRotatePass::default())),
+    ),
+    ("sccp", Make::Function(|| Box::new(SccpPass {}))),
+    (
+        "simplifyphis",
+        Make::Function(|| Box::new(PhiSimplifyPass {})),
+    ),
+    (
+        "simplifycfg",
+        Make::Function(|| Box::new(SimplifyCfgPass {})),
+    ),
+    (
+        "strength",
+        Make::Function(|| Box::new(StrengthReductionPass {})),
+    ),
+    ("tailcall", Make::Function(|| Box::new(TailCallPass {}))),
+    (
+        "unroll",
+        Make::Function(|| Box::new(LoopUnrollPass::default())),
+    ),
+];
+
+/// The passes `pipeline` names, in a new [`PassManager`]
+pub fn parse(pipeline: &str) -> Result<PassManager> {
+    let mut parser = Parser {
+        src: pipeline,
+        at: 0,
+    };
+    let steps = parser.pipeline()?;
+    parser.skip_spaces();
+    if parser.at < pipeline.len() {
+        bail!(
+            "unexpected `{}` at {} in the pipeline",
+            parser.rest(),
+            parser.at
+        );
+    }
+    Ok(build(&steps))
+}
+
+/// One item of a pipeline, repeats already written out
+#[derive(Clone)]
+enum Step {
+    Pass(Make, PassOptions),
+    Group(String, Vec<Step>),
+}
+
+// new passes every time, a repeated step gets passes of its own
+fn build(steps: &[Step]) -> PassManager {
+    let mut pm = PassManager::new();
+    for step in steps {
+        match step {
+            Step::Pass(Make::Function(make), options) => pm.add_boxed_pass(make(), options.clone()),
+            Step::Pass(Make::Module(make), options) => {
+                pm.add_boxed_module_pass(make(), options.clone())
+            }
+            Step::Group(label, inner) => pm.add_module_pass(PassGroup::new(label, build(inner))),
+        }
+    }
+    pm
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    // byte offset of the next char
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn pipeline(&mut self) -> Result<Vec<Step>> {
+        let mut steps = Vec::new();
+        loop {
+            let start = self.at;
+            let step = self.item()?;
+            let count = if self.eat('*') { self.count()? } else { 1 };
+            if count > 1000 {
+                bail!("`*{}` at {} in the pipeline is too many", count, start);
+            }
+            steps.extend(std::iter::repeat_n(step, count));
+            if !self.eat(',') {
+                return Ok(steps);
+            }
+        }
+    }
+
+    fn item(&mut self) -> Result<Step> {
+        self.skip_spaces();
+        let start = self.at;
+        let name = self.name().to_string();
+        if name.is_empty() {
+            match self.rest().chars().next() {
+                Some(c) => bail!(
+                    "expected a pass name at {} in the pipeline, got `{}`",
+                    start,
+                    c
+                ),
+                None => bail!("expected a pass name at the end of the pipeline"),
+            }
+        }
+        if self.eat('<') {
+            let make = lookup(&name)?;
+            let options = self.options(&name, start)?;
+            check_options(&name, make, &options)?;
+            return Ok(Step::Pass(make, options));
+        }
+        if !self.eat('(') {
+            return Ok(Step::Pass(lookup(&name)?, PassOptions::new()));
+        }
+        let inner = self.pipeline()?;
+        if !self.eat(')') {
+            bail!("`{}(` at {} in the pipeline is never closed", name, start);
+        }
+        Ok(Step::Group(name, inner))
+    }
+
+    /// The `key=val`s after `pass<`, with the pass's name in front of
+    /// each key
+    fn options(&mut self, pass: &str, start: usize) -> Result<PassOptions> {
+        let mut options = PassOptions::new();
+        loop {
+            self.skip_spaces();
+            let at = self.at;
+            let key = self.name().to_string();
+            if key.is_empty() {
+                bail!("expected an option of `{}` at {} in the pipeline", pass, at);
+            }
+            if !self.eat('=') {
+                bail!(
+                    "expected `=` after `{}` at {} in the pipeline",
+                    key,
+                    self.at
+                );
+            }
+            self.skip_spaces();
+            let len = self
+                .rest()
+                .find(|c: char| c == ',' || c == '>' || c.is_whitespace())
+                .unwrap_or(self.rest().len());
+            if len == 0 {
+                bail!(
+                    "expected a value for `{}` at {} in the pipeline",
+                    key,
+                    self.at
+                );
+            }
+            options.set(&format!("{}.{}", pass, key), &self.rest()[..len]);
+            self.at += len;
+            if !self.eat(',') {
+                break;
+            }
+        }
+        if !self.eat('>') {
+            bail!("`{}<` at {} in the pipeline is never closed", pass, start);
+        }
+        Ok(options)
+    }
+
+    fn count(&mut self) -> Result<usize> {
+        self.skip_spaces();
+        let len = self
+            .rest()
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest().len());
+        let digits = &self.rest()[..len];
+        match digits.parse() {
+            Ok(n) if n > 0 => {
+                self.at += len;
+                Ok(n)
+            }
+            _ => bail!(
+                "expected how many times after `*` at {} in the pipeline",
+                self.at
+            ),
+        }
+    }
+
+    fn name(&mut self) -> &str {
+        let rest = &self.src[self.at..];
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(rest.len());
+        self.at += len;
+        &rest[..len]
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        if self.rest().starts_with(c) {
+            self.at += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        let rest = self.rest();
+        self.at += rest.len() - rest.trim_start().len();
+    }
+
+    fn rest(&self) -> &str {
+        &self.src[self.at..]
+    }
+}
+
+/// The name the pass a pipeline calls `name` goes by, e.g.
+/// `ConstantFoldPass` for `fold`
+pub(crate) fn pass_name(name: &str) -> Option<String> {
+    let &(_, make) = PASSES.iter().find(|(n, _)| *n == name)?;
+    Some(match make {
+        Make::Function(make) => make().name().to_string(),
+        Make::Module(make) => make().name().to_string(),
+    })
+}
+
+/// An error for a key in `options` the pass called `name` doesn't read, or
+/// a value it can't take
+fn check_options(name: &str, make: Make, options: &PassOptions) -> Result<()> {
+    // a pass of its own to ask, the one run gets them again from the manager
+    let (keys, set) = match make {
+        Make::Function(make) => {
+            let mut pass = make();
+            (pass.option_keys().to_vec(), pass.set_options(options))
+        }
+        Make::Module(make) => {
+            let mut pass = make();
+            (pass.option_keys().to_vec(), pass.set_options(options))
+        }
+    };
+    let short = |key: &'static str| key.split_once('.').map_or(key, |(_, k)| k);
+    for key in options.keys() {
+        if keys.contains(&key) {
+            continue;
+        }
+        let key = &key[name.len() + 1..];
+        if keys.is_empty() {
+            bail!("`{}` has no option `{}`, it takes none", name, key);
+        }
+        let known: Vec<&str> = keys.iter().map(|&k| short(k)).collect();
+        bail!(
+            "`{}` has no option `{}`, it takes {}",
+            name,
+            key,
+            known.join(", ")
+        );
+    }
+    Ok(set?)
+}
+
+/// What to make for the pass called `name`, or an error suggesting the
+/// ones it's close to
+fn lookup(name: &str) -> Result<Make> {
+    if let Some(&(_, make)) = PASSES.iter().find(|(n, _)| *n == name) {
+        return Ok(make);
+    }
+    let limit = (name.chars().count() / 3).max(1);
+    let mut close: Vec<(usize, &str)> = PASSES
+        .iter()
+        .map(|&(n, _)| (edit_distance(name, n), n))
+        .filter(|&(d, n)| d <= limit || n.starts_with(name))
+        .collect();
+    close.sort();
+    let close: Vec<String> = close
+        .iter()
+        .take(3)
+        .map(|(_, n)| format!("`{}`", n))
+        .collect();
+    let known: Vec<&str> = PASSES.iter().map(|&(n, _)| n).collect();
+    if close.is_empty() {
+        bail!(
+            "unknown pass `{}`, the passes are {}",
+            name,
+            known.join(", ")
+        );
+    }
+    bail!(
+        "unknown pass `{}`, did you mean {}? The passes are {}",
+        name,
+        close.join(" or "),
+        known.join(", ")
+    )
+}
+
+/// Passes run to their own fixpoint as one step of a bigger pipeline
+pub struct PassGroup {
+    name: String,
+    passes: PassManager,
+}
+
+impl PassGroup {
+    pub fn new(label: &str, passes: PassManager) -> Self {
+        let name = format!("{}({})", label, passes.pass_names().join(","));
+        Self { name, passes }
+    }
+}
+
+impl ModulePass for PassGroup {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run_on_module(
+        &mut self,
+        module: &mut IrModule,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        self.run_with_analyses(module, &mut AnalysisManager::new(), stats)
+    }
+
+    fn run_with_analyses(
+        &mut self,
+        module: &mut IrModule,
+        _analyses: &mut AnalysisManager,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        // without verify_each, only a pass can make `run` fail
+        let summary = self.passes.run(module).map_err(|e| {
+            e.downcast::<PassError>()
+                .expect("a pipeline fails with a PassError")
+        })?;
+        for (_, counted) in self.passes.statistics().counters {
+            stats.merge(&counted);
+        }
+        Ok(summary.changes.iter().any(|&(_, n)| n > 0))
+    }
+
+    fn set_options(&mut self, options: &PassOptions) -> Result<(), PassError> {
+        self.passes.set_options(options.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline_names_its_passes_in_order() {
+        let pm =
+            PassManager::from_pipeline("constprop, fold*2, dce, loop(indvars,unroll)").unwrap();
+        assert_eq!(
+            pm.pass_names(),
+            [
+                "ConstantPropagationPass",
+                "ConstantFoldPass",
+                "ConstantFoldPass",
+                "DeadCodeRemovalPass",
+                "loop(IndVarPass,LoopUnrollPass)",
+            ]
+        );
+
+        let pm = PassManager::from_pipeline("deadfunc,cleanup(copyprop,dce)*2").unwrap();
+        assert_eq!(
+            pm.pass_names(),
+            [
+                "DeadFunctionPass",
+                "cleanup(CopyPropagationPass,DeadCodeRemovalPass)",
+                "cleanup(CopyPropagationPass,DeadCodeRemovalPass)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_pass_suggests_the_close_ones() {
+        let err = PassManager::from_pipeline("constprp,dce")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.starts_with("unknown pass `constprp`, did you mean `constprop`?"),
+            "{}",
+            err
+        );
+
+        let err = PassManager::from_pipeline("dce,licm")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.starts_with("unknown pass `licm`, the passes are availcse,"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_malformed_pipelines() {
+        for (pipeline, expected) in [
+            ("dce,", "expected a pass name at the end of the pipeline"),
+            ("loop(dce", "`loop(` at 0 in the pipeline is never closed"),
+            (
+                "dce*0",
+                "expected how many times after `*` at 4 in the pipeline",
+            ),
+            ("dce)", "unexpected `)` at 3 in the pipeline"),
+        ] {
+            let err = PassManager::from_pipeline(pipeline).err().unwrap();
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_pass_options_in_the_pipeline() {
+        let src = "@main(a: int) {
+.entry:
+  x = call @square a
+  print x
+  ret
+}
+
+@square(x: int): int {
+.entry:
+  r = mul x x
+  ret r
+}";
+        let calls = |pipeline: &str, options: PassOptions| {
+            let mut module = bril_ir::text::parse_module(src).unwrap();
+            module.resolve_calls().unwrap();
+            let mut pm = PassManager::from_pipeline(pipeline).unwrap();
+            pm.set_options(options);
+            pm.run(&mut module).unwrap();
+            module.functions[0].to_string().matches("call").count()
+        };
+        assert_eq!(calls("inline<threshold=1>", PassOptions::new()), 1);
+        assert_eq!(calls("inline<threshold=2>", PassOptions::new()), 0);
+        // the pass's own over the manager's
+        let two = PassOptions::new().with("inline.threshold", 2);
+        assert_eq!(calls("inline<threshold=1>", two.clone()), 1);
+        assert_eq!(calls("inline", two), 0);
+    }
+
+    #[test]
+    fn test_bad_pass_options() {
+        for (pipeline, expected) in [
+            (
+                "inline<depth=2>",
+                "`inline` has no option `depth`, it takes threshold",
+            ),
+            (
+                "dce<threshold=2>",
+                "`dce` has no option `threshold`, it takes none",
+            ),
+            (
+                "fold<assume-no-overflow=yes>",
+                "option `fold.assume-no-overflow` is `yes`, expected `true` or `false`",
+            ),
+            (
+                "unroll<factor=>",
+                "expected a value for `factor` at 14 in the pipeline",
+            ),
+            (
+                "unroll<factor=2",
+                "`unroll<` at 0 in the pipeline is never closed",
+            ),
+        ] {
+            let err = PassManager::from_pipeline(pipeline).err().unwrap();
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_group_runs_to_its_own_fixpoint() {
+        let mut module = bril_ir::text::parse_module(
+            "@main {
+.entry:
+  a = const 1
+  b = id a
+  c = add b b
+  d = id c
+  print d
+  ret
+}",
+        )
+        .unwrap();
+        let mut pm = PassManager::from_pipeline("g(constprop,fold,dce)").unwrap();
+        let summary = pm.run(&mut module).unwrap();
+        assert!(summary.converged);
+        let lines: Vec<String> = module.functions[0].blocks[0]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(lines, ["d = const 2", "print d", "ret"]);
+    }
+}
@@ -0,0 +1,400 @@
+use crate::constant_propagate::{evaluate, replace_with_consts, ConstLattice};
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::{Literal, Symbol};
+use bril_ir::analysis::def_use::{DefUse, Site};
+use bril_ir::{BlockID, IrFunction, IrInstruction};
+use std::collections::{HashMap, HashSet};
+
+/// Sparse conditional constant propagation (Wegman-Zadeck), on SSA
+///
+/// Only edges a run can actually take count: a block is looked at once an
+/// edge into it is found executable, and a phi only meets the values of
+/// its executable edges. Afterwards the defs found constant become
+/// `const`s, a `br` that only ever goes one way becomes a `jmp`, and the
+/// blocks nothing gets to any more are dropped.
+pub struct SccpPass {}
+
+impl FunctionPass for SccpPass {
+    fn name(&self) -> &str {
+        "SccpPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        _stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        if let Some(label) = missing_target(function) {
+            return Err(PassError::MalformedIR {
+                function: function.name.clone(),
+                detail: format!("a branch goes to .{}, which isn't a block", label),
+            });
+        }
+        let before = function.clone();
+        sccp(function);
+        Ok(*function != before)
+    }
+}
+
+/// A label some `jmp` or `br` goes to that no block has, `analyze` can't
+/// follow it
+fn missing_target(func: &IrFunction) -> Option<&String> {
+    func.blocks
+        .iter()
+        .flat_map(|b| &b.instrs)
+        .flat_map(|instr| match instr {
+            IrInstruction::Jmp { label } => vec![label],
+            IrInstruction::Br {
+                then_lbl, else_lbl, ..
+            } => vec![then_lbl, else_lbl],
+            _ => vec![],
+        })
+        .find(|label| func.block_index(label).is_none())
+}
+
+/// What the propagation found out about a function
+#[derive(Debug, Default)]
+pub struct SccpResult {
+    /// Lattice value of each SSA name, a missing one is `Top`
+    pub values: HashMap<Symbol, ConstLattice>,
+    /// `(from, to)` edges some run can take
+    pub executable: HashSet<(BlockID, BlockID)>,
+    /// Blocks some run gets to
+    pub reached: Vec<bool>,
+}
+
+impl SccpResult {
+    pub fn value(&self, var: Symbol) -> ConstLattice {
+        self.values.get(&var).cloned().unwrap_or(ConstLattice::Top)
+    }
+}
+
+/// Run the two worklists to a fixpoint, changing nothing
+pub fn analyze(func: &IrFunction) -> SccpResult {
+    let du = DefUse::compute(func);
+    let mut out = SccpResult {
+        reached: vec![false; func.blocks.len()],
+        ..SccpResult::default()
+    };
+    // the caller could pass anything
+    for &arg in &func.args {
+        out.values.insert(arg, ConstLattice::Bottom);
+    }
+    if func.blocks.is_empty() {
+        return out;
+    }
+
+    // (from, to), the entry is reached from outside
+    let mut flow: Vec<(Option<BlockID>, BlockID)> = vec![(None, 0)];
+    // names whose value just went down
+    let mut ssa: Vec<Symbol> = Vec::new();
+
+    while !flow.is_empty() || !ssa.is_empty() {
+        while let Some((from, to)) = flow.pop() {
+            if let Some(from) = from
+                && !out.executable.insert((from, to))
+            {
+                continue;
+            }
+            // a block seen before only has its phis to redo, they're the
+            // only ones that look at which edges are executable
+            let first_visit = !out.reached[to];
+            out.reached[to] = true;
+            for i in 0..func.blocks[to].instrs.len() {
+                let is_phi = matches!(func.blocks[to].instrs[i], IrInstruction::Phi { .. });
+                if first_visit || is_phi {
+                    visit(
+                        func,
+                        Site {
+                            block: to,
+                            instr: i,
+                        },
+                        &mut out,
+                        &mut flow,
+                        &mut ssa,
+                    );
+                }
+            }
+            // a block with no terminator falls through to its succ, which
+            // no instruction above said is executable
+            if first_visit
+                && !func.blocks[to]
+                    .instrs
+                    .last()
+                    .is_some_and(|i| i.is_terminator())
+            {
+                flow.extend(func.blocks[to].succs.iter().map(|&s| (Some(to), s)));
+            }
+        }
+
+        while let Some(var) = ssa.pop() {
+            for &site in du.uses(var) {
+                if out.reached[site.block] {
+                    visit(func, site, &mut out, &mut flow, &mut ssa);
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// Work out the instruction at `site` again. A terminator adds the edges
+/// it can take to `flow`, a def that went down puts its name on `ssa`
+fn visit(
+    func: &IrFunction,
+    site: Site,
+    out: &mut SccpResult,
+    flow: &mut Vec<(Option<BlockID>, BlockID)>,
+    ssa: &mut Vec<Symbol>,
+) {
+    let b = site.block;
+    let instr = &func.blocks[b].instrs[site.instr];
+    let target = |label: &String| func.block_index(label).expect("branch to a missing label");
+
+    let value = match instr {
+        IrInstruction::Jmp { label } => {
+            flow.push((Some(b), target(label)));
+            return;
+        }
+        IrInstruction::Br {
+            cond,
+            then_lbl,
+            else_lbl,
+        } => {
+            match out.value(*cond) {
+                ConstLattice::Top => {}
+                ConstLattice::Const(Literal::Bool(true)) => flow.push((Some(b), target(then_lbl))),
+                ConstLattice::Const(Literal::Bool(false)) => flow.push((Some(b), target(else_lbl))),
+                _ => {
+                    flow.push((Some(b), target(then_lbl)));
+                    flow.push((Some(b), target(else_lbl)));
+                }
+            }
+            return;
+        }
+        IrInstruction::Phi { sources, .. } => {
+            let mut met = ConstLattice::Top;
+            for src in sources {
+                let Some(p) = func.block_index(&src.pred) else {
+                    continue;
+                };
+                if let (true, Some(value)) = (out.executable.contains(&(p, b)), src.value) {
+                    met = met.meet(&out.value(value));
+                }
+            }
+            met
+        }
+        _ => evaluate(instr, |var| out.value(var)),
+    };
+
+    if let &[dest] = instr.defs()
+        && out.value(dest) != value
+    {
+        out.values.insert(dest, value);
+        ssa.push(dest);
+    }
+}
+
+fn sccp(func: &mut IrFunction) {
+    let result = analyze(func);
+
+    for b in 0..func.blocks.len() {
+        if !result.reached[b] {
+            continue;
+        }
+        let mut rewrites: Vec<(usize, Symbol, Literal)> = Vec::new();
+        for (i, instr) in func.blocks[b].instrs.iter().enumerate() {
+            let &[dest] = instr.defs() else { continue };
+            if instr.has_side_effects() || matches!(instr, IrInstruction::Const { .. }) {
+                continue;
+            }
+            if let ConstLattice::Const(lit) = result.value(dest) {
+                rewrites.push((i, dest, lit));
+            }
+        }
+        replace_with_consts(&mut func.blocks[b], rewrites);
+        fold_branch(func, b, &result);
+    }
+
+    func.remove_unreachable_blocks();
+}
+
+/// A `br` in `b` with only one of its edges executable becomes a `jmp`
+/// down that one, the other edge goes along with the phi sources it fed
+fn fold_branch(func: &mut IrFunction, b: BlockID, result: &SccpResult) {
+    let Some(IrInstruction::Br {
+        then_lbl, else_lbl, ..
+    }) = func.blocks[b].instrs.last()
+    else {
+        return;
+    };
+    if then_lbl == else_lbl {
+        return;
+    }
+    let (Some(then_b), Some(else_b)) = (func.block_index(then_lbl), func.block_index(else_lbl))
+    else {
+        return;
+    };
+    let (taken, dropped) = match (
+        result.executable.contains(&(b, then_b)),
+        result.executable.contains(&(b, else_b)),
+    ) {
+        (true, false) => (then_lbl.clone(), else_b),
+        (false, true) => (else_lbl.clone(), then_b),
+        _ => return,
+    };
+
+    *func.blocks[b].instrs.last_mut().unwrap() = IrInstruction::Jmp { label: taken };
+    func.remove_edge(b, dropped);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bril_ir::SSAFormation;
+
+    fn ssa(src: &str) -> IrFunction {
+        let mut func = IrFunction::parse(src).unwrap();
+        SSAFormation::new(std::slice::from_mut(&mut func)).unwrap();
+        func
+    }
+
+    #[test]
+    fn test_constant_branch_removes_a_diamond_arm() {
+        let mut func = ssa("@main() {
+.entry:
+  a = const 4
+  b = const 4
+  c = eq a b
+  br c .left .right
+.left:
+  x = const 1
+  jmp .join
+.right:
+  x = const 2
+  jmp .join
+.join:
+  print x
+  ret
+}");
+        sccp(&mut func);
+        bril_ir::verify_ssa(&func).unwrap();
+        assert!(func.block_index(&"right".to_string()).is_none());
+        assert_eq!(func.blocks.len(), 3);
+        let text = func.to_string();
+        assert!(text.contains("  jmp .left\n"), "{}", text);
+        assert!(!text.contains(" = phi "), "{}", text);
+        let join = func.block_index(&"join".to_string()).unwrap();
+        assert!(
+            func.blocks[join].instrs[0]
+                .to_string()
+                .ends_with(" = const 1"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_comparing_a_value_with_itself() {
+        // nothing is known about `n`, `eq n n` is still always true
+        let mut func = ssa("@main(n: int) {
+.entry:
+  c = eq n n
+  br c .yes .no
+.yes:
+  print n
+  ret
+.no:
+  ret
+}");
+        let result = analyze(&func);
+        assert_eq!(
+            result.value("c".into()),
+            ConstLattice::Const(Literal::Bool(true))
+        );
+        sccp(&mut func);
+        assert!(func.block_index(&"no".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_loop_whose_exit_condition_is_constant() {
+        // `i` starts at 0 and the body only runs while it's negative, so
+        // the body never runs and `i` never changes
+        let mut func = ssa("@main() {
+.entry:
+  i = const 0
+  one = const 1
+  zero = const 0
+  jmp .head
+.head:
+  c = lt i zero
+  br c .body .done
+.body:
+  i = add i one
+  jmp .head
+.done:
+  print i
+  ret
+}");
+        let body = func.block_index(&"body".to_string()).unwrap();
+        let result = analyze(&func);
+        assert!(!result.reached[body]);
+
+        sccp(&mut func);
+        bril_ir::verify_ssa(&func).unwrap();
+        assert!(func.block_index(&"body".to_string()).is_none());
+        let text = func.to_string();
+        assert!(!text.contains(" = phi "), "{}", text);
+        assert!(text.contains("c = const false"), "{}", text);
+    }
+
+    #[test]
+    fn test_fall_through_pred_feeds_the_phi() {
+        let mut func = IrFunction::parse(
+            "@main(c: bool) {
+.entry:
+  zero = const 0
+  one = const 1
+  br c .then .else
+.then:
+  jmp .join
+.else:
+  x = id zero
+.join:
+  r = phi [one, .then] [zero, .else]
+  print r
+  ret
+}",
+        )
+        .unwrap();
+        sccp(&mut func);
+        let text = func.to_string();
+        assert!(text.contains("r = phi "), "{}", text);
+        assert!(!text.contains("r = const"), "{}", text);
+    }
+
+    #[test]
+    fn test_loop_that_runs_stays() {
+        let mut func = ssa("@main() {
+.entry:
+  i = const 0
+  one = const 1
+  ten = const 10
+  jmp .head
+.head:
+  c = lt i ten
+  br c .body .done
+.body:
+  i = add i one
+  jmp .head
+.done:
+  print i
+  ret
+}");
+        let before = func.blocks.len();
+        sccp(&mut func);
+        assert_eq!(func.blocks.len(), before);
+        assert!(func.to_string().contains(" = phi "));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::{Literal, Symbol};
+use bril_ir::{DominatorInfo, IrFunction, IrInstruction, PhiSource};
+use std::collections::HashMap;
+
+/// Folds away blocks that only pass control on: jump-only (or empty) blocks
+/// get their preds sent straight to the target, and a block whose only
+/// succ has it as its only pred swallows that succ. Before that a `br` that
+/// can only go one way becomes a `jmp`, and whatever only it led to goes
+pub struct SimplifyCfgPass {}
+
+impl FunctionPass for SimplifyCfgPass {
+    fn name(&self) -> &str {
+        "SimplifyCfgPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        _stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        Ok(simplify_cfg(function))
+    }
+}
+
+/// Fold the branches, then run both rewrites until neither applies, true
+/// if anything changed
+pub fn simplify_cfg(func: &mut IrFunction) -> bool {
+    let mut changed = fold_branches(func);
+    changed |= func.remove_unreachable_blocks() > 0;
+    loop {
+        // one rewrite at a time, the indices are stale after each
+        let Ok(doms) = DominatorInfo::compute(func) else {
+            return changed;
+        };
+        let done = (1..func.blocks.len())
+            .any(|b| skip_forwarding_block(func, &doms, b) || merge_into_pred(func, b));
+        if !done {
+            return changed;
+        }
+        func.remove_unreachable_blocks();
+        changed = true;
+    }
+}
+
+/// Make a `br` a `jmp` when its condition is a name whose only def is a
+/// `const`, or when both its labels are the same. True if any changed
+fn fold_branches(func: &mut IrFunction) -> bool {
+    let mut defs: HashMap<Symbol, Option<bool>> = HashMap::new();
+    for instr in func.blocks.iter().flat_map(|b| &b.instrs) {
+        for &dest in instr.defs() {
+            let value = match instr {
+                IrInstruction::Const {
+                    value: Literal::Bool(value),
+                    ..
+                } => Some(*value),
+                _ => None,
+            };
+            // a second def, the value isn't known
+            defs.entry(dest).and_modify(|v| *v = None).or_insert(value);
+        }
+    }
+
+    let mut changed = false;
+    for b in 0..func.blocks.len() {
+        let Some(IrInstruction::Br {
+            cond,
+            then_lbl,
+            else_lbl,
+        }) = func.blocks[b].instrs.last()
+        else {
+            continue;
+        };
+        // an arg has no def here, whatever the caller passes goes
+        let taken = match (then_lbl == else_lbl, defs.get(cond).copied().flatten()) {
+            (true, _) | (false, Some(true)) => then_lbl.clone(),
+            (false, Some(false)) => else_lbl.clone(),
+            (false, None) => continue,
+        };
+        let dropped = if taken == *then_lbl {
+            else_lbl.clone()
+        } else {
+            then_lbl.clone()
+        };
+        let (Some(target), Some(dropped)) = (func.block_index(&taken), func.block_index(&dropped))
+        else {
+            continue;
+        };
+
+        *func.blocks[b].instrs.last_mut().unwrap() = IrInstruction::Jmp { label: taken };
+        if target == dropped {
+            // the two edges are one now, and so are the phi sources they gave
+            once(&mut func.blocks[b].succs, |&s| s == target);
+            once(&mut func.blocks[target].preds, |&p| p == b);
+            let label = func.blocks[b].label.clone();
+            for instr in &mut func.blocks[target].instrs {
+                if let IrInstruction::Phi { sources, .. } = instr {
+                    once(sources, |s| s.pred == label);
+                }
+            }
+        } else {
+            func.remove_edge(b, dropped);
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// Keep only the first element `is` picks out
+fn once<T>(items: &mut Vec<T>, is: impl Fn(&T) -> bool) {
+    let mut seen = false;
+    items.retain(|item| !is(item) || !std::mem::replace(&mut seen, true));
+}
+
+/// `b` does nothing but go on to one other block: its preds go there instead
+fn skip_forwarding_block(func: &mut IrFunction, doms: &DominatorInfo, b: usize) -> bool {
+    let block = &func.blocks[b];
+    let forwards = matches!(block.instrs[..], [] | [IrInstruction::Jmp { .. }]);
+    let [target] = block.succs[..] else {
+        return false;
+    };
+    if !forwards || target == b || block.preds.is_empty() {
+        return false;
+    }
+    // a loop header stays, whatever it holds
+    if block.preds.iter().any(|&p| doms.dominates(b, p)) {
+        return false;
+    }
+    // a pred that already goes to the target could need two different
+    // values in the same phi
+    let has_phis = func.blocks[target]
+        .instrs
+        .iter()
+        .any(|i| matches!(i, IrInstruction::Phi { .. }));
+    let preds = block.preds.clone();
+    if has_phis && preds.iter().any(|p| func.blocks[target].preds.contains(p)) {
+        return false;
+    }
+
+    let label = block.label.clone();
+    let target_label = func.blocks[target].label.clone();
+    for &p in &preds {
+        retarget(func, p, &label, &target_label);
+        for s in func.blocks[p].succs.iter_mut().filter(|s| **s == b) {
+            *s = target;
+        }
+    }
+
+    // every pred of `b` takes over its edge, and its phi source
+    let pred_labels: Vec<String> = preds
+        .iter()
+        .map(|&p| func.blocks[p].label.clone())
+        .collect();
+    let j = func.blocks[target]
+        .preds
+        .iter()
+        .position(|&p| p == b)
+        .unwrap();
+    let target_block = &mut func.blocks[target];
+    target_block.preds.splice(j..=j, preds.iter().copied());
+    for instr in &mut target_block.instrs {
+        if let IrInstruction::Phi { sources, .. } = instr
+            && let Some(k) = sources.iter().position(|s| s.pred == label)
+        {
+            let value = sources[k].value;
+            let taken_over = pred_labels.iter().map(|p| PhiSource::new(p, value));
+            sources.splice(k..=k, taken_over);
+        }
+    }
+
+    let block = &mut func.blocks[b];
+    block.preds.clear();
+    block.succs.clear();
+    true
+}
+
+/// The only pred of `b` only goes to `b`, so `b`'s instructions can go on
+/// the end of it
+fn merge_into_pred(func: &mut IrFunction, b: usize) -> bool {
+    let [a] = func.blocks[b].preds[..] else {
+        return false;
+    };
+    if a == b || func.blocks[a].succs != [b] {
+        return false;
+    }
+    let block = &func.blocks[b];
+    // a phi with nothing coming in has no value to become
+    let undefined_phi = block.instrs.iter().any(|i| {
+        matches!(i, IrInstruction::Phi { sources, .. }
+            if sources.first().is_none_or(|s| s.value.is_none()))
+    });
+    let ends = block.instrs.last().is_some_and(|i| i.is_terminator());
+    if undefined_phi || (!ends && block.succs.is_empty()) {
+        return false;
+    }
+
+    let mut moved = std::mem::take(&mut func.blocks[b].instrs);
+    let mut positions: Vec<_> = (0..moved.len()).map(|i| func.blocks[b].pos(i)).collect();
+    let mut origins: Vec<_> = (0..moved.len()).map(|i| func.blocks[b].origin(i)).collect();
+    // it fell through to the block after it, it won't be after `a`
+    if !ends {
+        let next = func.blocks[b].succs[0];
+        moved.push(IrInstruction::Jmp {
+            label: func.blocks[next].label.clone(),
+        });
+        positions.push(None);
+        origins.push(None);
+    }
+    // with one pred a phi is just a copy
+    for instr in &mut moved {
+        if let IrInstruction::Phi { dest, sources } = instr {
+            *instr = IrInstruction::Assign {
+                lhs: *dest,
+                rhs: sources[0].value.unwrap(),
+            };
+        }
+    }
+
+    let pred = &mut func.blocks[a];
+    if matches!(pred.instrs.last(), Some(IrInstruction::Jmp { .. })) {
+        pred.instrs.pop();
+    }
+    pred.positions.resize(pred.instrs.len(), None);
+    pred.origins.resize(pred.instrs.len(), None);
+    pred.instrs.extend(moved);
+    pred.positions.extend(positions);
+    pred.origins.extend(origins);
+
+    let succs = std::mem::take(&mut func.blocks[b].succs);
+    let (from, to) = (func.blocks[b].label.clone(), func.blocks[a].label.clone());
+    for &s in &succs {
+        for p in func.blocks[s].preds.iter_mut().filter(|p| **p == b) {
+            *p = a;
+        }
+        // the edge into `s` leaves from `a` now
+        func.retarget_phi_sources(s, &from, &to);
+    }
+    func.blocks[a].succs = succs;
+    func.blocks[b].preds.clear();
+    true
+}
+
+/// Point `p`'s way out at `to` instead of `from`. A fall-through gets an
+/// explicit `jmp`, the blocks are about to move
+pub(crate) fn retarget(func: &mut IrFunction, p: usize, from: &str, to: &str) {
+    let block = &mut func.blocks[p];
+    match block.instrs.last_mut() {
+        Some(IrInstruction::Jmp { label }) => *label = to.to_string(),
+        Some(IrInstruction::Br {
+            then_lbl, else_lbl, ..
+        }) => {
+            for lbl in [then_lbl, else_lbl] {
+                if lbl == from {
+                    *lbl = to.to_string();
+                }
+            }
+        }
+        _ => {
+            let at = block.instrs.len();
+            let jmp = IrInstruction::Jmp {
+                label: to.to_string(),
+            };
+            block.insert_instr(at, jmp, None, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(func: &IrFunction) -> Vec<&str> {
+        func.blocks.iter().map(|b| b.label.as_str()).collect()
+    }
+
+    #[test]
+    fn test_chains_merge_and_jumps_are_skipped() {
+        let mut func = IrFunction::parse(
+            "@main(c: bool) {
+.entry:
+  br c .hop .other
+.hop:
+  jmp .join
+.other:
+  y = const 2
+  jmp .join
+.join:
+  x = phi [c, .hop] [y, .other]
+  jmp .tail
+.tail:
+  print x
+  ret
+}",
+        )
+        .unwrap();
+        assert!(simplify_cfg(&mut func));
+
+        // .hop is gone and .tail went into .join
+        assert_eq!(labels(&func), ["entry", "other", "join"]);
+        assert_eq!(func.blocks[0].instrs[0].to_string(), "br c .join .other");
+        let join = &func.blocks[2];
+        assert_eq!(join.preds, [0, 1]);
+        let lines: Vec<String> = join.instrs.iter().map(|i| i.to_string()).collect();
+        assert_eq!(lines, ["x = phi c y", "print x", "ret"]);
+
+        assert!(!simplify_cfg(&mut func));
+    }
+
+    #[test]
+    fn test_loop_header_is_kept() {
+        let mut func = IrFunction::parse(
+            "@main(c: bool) {
+.entry:
+  x = const 1
+.head:
+  jmp .body
+.body:
+  print x
+  br c .head .done
+.done:
+  ret
+}",
+        )
+        .unwrap();
+        simplify_cfg(&mut func);
+        assert!(labels(&func).contains(&"head"));
+        // but .body has only .head coming in, so it's merged into it
+        assert!(!labels(&func).contains(&"body"));
+    }
+
+    #[test]
+    fn test_constant_branches_fold() {
+        let mut func = IrFunction::parse(
+            "@main(c: bool) {
+.entry:
+  t = const true
+  br t .yes .no
+.yes:
+  x = const 1
+  br c .both .both
+.no:
+  x = const 2
+  jmp .both
+.both:
+  y = phi [x, .yes] [x, .no]
+  print y
+  ret
+}",
+        )
+        .unwrap();
+        assert!(simplify_cfg(&mut func));
+        bril_ir::verify_ssa(&func).unwrap();
+        // nothing's left to branch on, it's one block
+        assert_eq!(labels(&func), ["entry"]);
+        let lines: Vec<String> = func.blocks[0]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "t = const true",
+                "x = const 1",
+                "y = id x",
+                "print y",
+                "ret"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_branches_on_args_and_redefined_names_stay() {
+        let src = "@main(c: bool) {
+.entry:
+  d = const true
+  d = not c
+  br d .a .b
+.a:
+  br c .a .b
+.b:
+  ret
+}";
+        let mut func = IrFunction::parse(src).unwrap();
+        assert!(!fold_branches(&mut func));
+        assert_eq!(
+            func.to_string(),
+            IrFunction::parse(src).unwrap().to_string()
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::analysis_manager::Analysis;
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::{Literal, Symbol, Type};
+use bril_ir::{IrFunction, IrInstruction};
+use std::collections::HashMap;
+
+/// Strength Reduction of multiplication and division by powers of two
+///
+/// `mul x 8` is `shl x 3`, wrapping the same way. `div x 8` isn't `sra x 3`
+/// though: the shift rounds down and `div` towards zero, so a negative
+/// dividend gets 7 added first. That comes from the sign, spread over the
+/// word by `sra x 63` and cut down to its low 3 bits by a logical shift:
+///
+/// ```text
+/// s = sra x 63
+/// b = shr s 61
+/// t = add x b
+/// q = sra t 3
+/// ```
+///
+/// Only positive powers of two are done, and only a divisor or factor that
+/// is a `const` wherever it's read: the one def of its name.
+pub struct StrengthReductionPass {}
+
+impl FunctionPass for StrengthReductionPass {
+    fn name(&self) -> &str {
+        "StrengthReductionPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        _stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let before = function.clone();
+        reduce_strength(function);
+        Ok(*function != before)
+    }
+
+    fn preserves(&self) -> &[Analysis] {
+        Analysis::CFG
+    }
+}
+
+/// `k` when `c` is `2^k`, 1 or more
+fn log2(c: i64) -> Option<u32> {
+    (c > 1 && c.count_ones() == 1).then(|| c.trailing_zeros())
+}
+
+fn reduce_strength(func: &mut IrFunction) {
+    // names with a single def, that def an int `const`
+    let mut defs: HashMap<Symbol, usize> = HashMap::new();
+    let mut ints: HashMap<Symbol, i64> = HashMap::new();
+    for instr in func.blocks.iter().flat_map(|b| &b.instrs) {
+        for def in instr.defs() {
+            *defs.entry(*def).or_default() += 1;
+        }
+        if let IrInstruction::Const {
+            dest,
+            value: Literal::Int(i),
+        } = instr
+        {
+            ints.insert(*dest, *i);
+        }
+    }
+    ints.retain(|var, _| defs[var] == 1 && !func.args.contains(var));
+    let power = |var: &Symbol| ints.get(var).copied().and_then(log2);
+
+    for b in 0..func.blocks.len() {
+        let mut i = 0;
+        while i < func.blocks[b].instrs.len() {
+            let new = match func.blocks[b].instrs[i].clone() {
+                IrInstruction::Mul { dest, lhs, rhs } => match (power(&lhs), power(&rhs)) {
+                    (_, Some(k)) => vec![shl(dest, lhs, k)],
+                    (Some(k), None) => vec![shl(dest, rhs, k)],
+                    (None, None) => Vec::new(),
+                },
+                IrInstruction::Div { dest, lhs, rhs } => match power(&rhs) {
+                    Some(k) => divide(func, dest, lhs, k),
+                    None => Vec::new(),
+                },
+                _ => Vec::new(),
+            };
+            if new.is_empty() {
+                i += 1;
+                continue;
+            }
+
+            // the first takes the old one's place, the rest go after it
+            let (pos, origin) = (func.blocks[b].pos(i), func.blocks[b].origin(i));
+            let count = new.len();
+            for (k, instr) in new.into_iter().enumerate() {
+                for def in instr.defs() {
+                    func.types.insert(*def, Type::Int);
+                }
+                if k == 0 {
+                    func.blocks[b].instrs[i] = instr;
+                } else {
+                    func.blocks[b].insert_instr(i + k, instr, pos, origin);
+                }
+            }
+            i += count;
+        }
+    }
+}
+
+fn shl(dest: Symbol, src: Symbol, amount: u32) -> IrInstruction {
+    IrInstruction::Shl { dest, src, amount }
+}
+
+/// `dest = div x 2^k` as shifts, rounding towards zero
+fn divide(func: &IrFunction, dest: Symbol, x: Symbol, k: u32) -> Vec<IrInstruction> {
+    let mut instrs = Vec::new();
+    // 2^k - 1 for a negative `x`, 0 otherwise. For k = 1 that's the sign
+    // bit on its own
+    let bias = func.fresh_var(&format!("{}.bias", dest));
+    if k == 1 {
+        instrs.push(IrInstruction::Shr {
+            dest: bias,
+            src: x,
+            amount: 63,
+        });
+    } else {
+        let sign = func.fresh_var(&format!("{}.sign", dest));
+        instrs.push(IrInstruction::Sra {
+            dest: sign,
+            src: x,
+            amount: 63,
+        });
+        instrs.push(IrInstruction::Shr {
+            dest: bias,
+            src: sign,
+            amount: 64 - k,
+        });
+    }
+    let biased = func.fresh_var(&format!("{}.biased", dest));
+    instrs.push(IrInstruction::Add {
+        dest: biased,
+        lhs: x,
+        rhs: bias,
+    });
+    instrs.push(IrInstruction::Sra {
+        dest,
+        src: biased,
+        amount: k,
+    });
+    instrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConstantFoldPass;
+
+    fn lines(func: &IrFunction) -> Vec<String> {
+        func.blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_powers_of_two_become_shifts() {
+        let mut func = IrFunction::parse(
+            "@main(x: int, y: int) {
+.entry:
+  eight = const 8
+  three = const 3
+  a = mul x eight
+  b = mul eight y
+  c = mul x three
+  d = div x eight
+  e = div eight x
+  print a b c d e
+  ret
+}",
+        )
+        .unwrap();
+        reduce_strength(&mut func);
+        bril_ir::verify_ssa(&func).unwrap();
+        assert_eq!(
+            lines(&func)[2..],
+            [
+                "a = shl x 3",
+                "b = shl y 3",
+                "c = mul x three",
+                "d.sign = sra x 63",
+                "d.bias = shr d.sign 61",
+                "d.biased = add x d.bias",
+                "d = sra d.biased 3",
+                // the dividend being a power of two doesn't help
+                "e = div eight x",
+                "print a b c d e",
+                "ret"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shifts_divide_like_div() {
+        // every quotient worked out by folding the shifts, against folding
+        // the `div` itself. Negative dividends that aren't a multiple of
+        // the divisor are where `sra` alone would be one off
+        let dividends = [-9, -8, -7, -1, 0, 1, 7, 9, i64::MIN, i64::MIN + 1, i64::MAX];
+        for x in dividends {
+            for d in [2, 4, 8, 1 << 20, 1 << 62] {
+                let src = format!(
+                    "@main {{
+.entry:
+  x = const {}
+  d = const {}
+  q = div x d
+  print q
+  ret
+}}",
+                    x, d
+                );
+                let mut func = IrFunction::parse(&src).unwrap();
+                reduce_strength(&mut func);
+                assert!(!lines(&func).iter().any(|l| l.contains("div")));
+                ConstantFoldPass::default()
+                    .run_on_function(&mut func, &mut Statistics::default())
+                    .unwrap();
+                let folded = lines(&func);
+                let q = folded.iter().find(|l| l.starts_with("q = ")).unwrap();
+                assert_eq!(*q, format!("q = const {}", x / d), "{} / {}", x, d);
+            }
+        }
+    }
+
+    #[test]
+    fn test_redefined_constants_stay() {
+        // `eight` isn't always 8, and an argument could be anything
+        let mut func = IrFunction::parse(
+            "@main(x: int, c: bool, four: int) {
+.entry:
+  eight = const 8
+  br c .other .done
+.other:
+  eight = const 3
+  jmp .done
+.done:
+  a = mul x eight
+  b = div x four
+  print a b
+  ret
+}",
+        )
+        .unwrap();
+        let before = func.clone();
+        reduce_strength(&mut func);
+        assert_eq!(func, before);
+    }
+}
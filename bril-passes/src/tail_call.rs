@@ -0,0 +1,284 @@
+use crate::pass_manager::{FunctionPass, PassError, Statistics};
+use bril_frontend::Symbol;
+use bril_ir::{BlockID, IrFunction, IrInstruction, PhiSource};
+
+/// Tail Call Elimination, for functions calling themselves
+///
+/// A call to the function it's in, followed straight away by a `ret` of
+/// what it gave back (or a plain `ret`), doesn't need a frame of its own.
+/// Everything the entry block did moves to a new block, which the entry
+/// jumps to, and such a call becomes a jump back to it. A phi there for
+/// each parameter picks the argument the call passed, and since the phis
+/// all read their values at once the arguments can be passed in any
+/// order, `f(b, a)` from `f(a, b)` included.
+pub struct TailCallPass {}
+
+impl FunctionPass for TailCallPass {
+    fn name(&self) -> &str {
+        "TailCallPass"
+    }
+
+    fn run_on_function(
+        &mut self,
+        function: &mut IrFunction,
+        stats: &mut Statistics,
+    ) -> Result<bool, PassError> {
+        let eliminated = eliminate_tail_calls(function);
+        stats.add("tail calls made jumps", eliminated);
+        Ok(eliminated > 0)
+    }
+}
+
+/// Index of the call in `func.blocks[b]` that's a tail call of `func`
+fn tail_call(func: &IrFunction, b: BlockID) -> Option<usize> {
+    let instrs = &func.blocks[b].instrs;
+    instrs.windows(2).position(|pair| match pair {
+        [IrInstruction::Call {
+            target_func, dest, ..
+        }, IrInstruction::Ret { args }] => {
+            *target_func == func.name
+                && match (dest, &args[..]) {
+                    (Some(dest), [ret]) => dest == ret,
+                    (_, []) => true,
+                    _ => false,
+                }
+        }
+        _ => false,
+    })
+}
+
+/// Returns how many calls became jumps
+fn eliminate_tail_calls(func: &mut IrFunction) -> usize {
+    if !(0..func.blocks.len()).any(|b| tail_call(func, b).is_some()) {
+        return 0;
+    }
+    // a parameter given a new value would be the wrong one in the phis,
+    // and a jump back to the entry would need one there
+    let args_redefined = func
+        .blocks
+        .iter()
+        .flat_map(|b| &b.instrs)
+        .any(|i| i.defs().iter().any(|d| func.args.contains(d)));
+    if args_redefined || !func.blocks[0].preds.is_empty() {
+        return 0;
+    }
+
+    // the entry's instructions go on in `header`, the loop's target
+    let entry_label = func.blocks[0].label.clone();
+    let header_label = func.fresh_label(&format!("{}.tail", entry_label));
+    let header = func.add_block(&header_label).unwrap();
+    let entry = &mut func.blocks[0];
+    let instrs = std::mem::take(&mut entry.instrs);
+    let positions = std::mem::take(&mut entry.positions);
+    let origins = std::mem::take(&mut entry.origins);
+    let succs = std::mem::take(&mut entry.succs);
+    for &s in &succs {
+        for p in func.blocks[s].preds.iter_mut().filter(|p| **p == 0) {
+            *p = header;
+        }
+        func.retarget_phi_sources(s, &entry_label, &header_label);
+    }
+    let block = &mut func.blocks[header];
+    block.instrs = instrs;
+    block.positions = positions;
+    block.origins = origins;
+    block.succs = succs;
+    // the entry fell through to the block after it, the header is at the
+    // end and has to jump there
+    if !block.instrs.last().is_some_and(|i| i.is_terminator())
+        && let &[next] = &block.succs[..]
+    {
+        let label = func.blocks[next].label.clone();
+        func.append_instr(header, &IrInstruction::Jmp { label }, None, None);
+    }
+    let jmp = IrInstruction::Jmp {
+        label: header_label.clone(),
+    };
+    func.append_instr(0, &jmp, None, None);
+    func.add_edge(0, header);
+
+    // each parameter's value this time round
+    let params: Vec<Symbol> = func.args.clone();
+    let current: Vec<Symbol> = params.iter().map(|p| func.fresh_var(p.as_ref())).collect();
+    for (cur, typ) in current.iter().zip(func.arg_types.clone()) {
+        func.types.insert(*cur, typ);
+    }
+    for instr in func.blocks.iter_mut().flat_map(|b| &mut b.instrs) {
+        for var in instr.uses_mut() {
+            if let Some(k) = params.iter().position(|p| p == var) {
+                *var = current[k];
+            }
+        }
+    }
+
+    let mut sources: Vec<Vec<PhiSource>> = params
+        .iter()
+        .map(|p| vec![PhiSource::new(&entry_label, Some(*p))])
+        .collect();
+    let mut count = 0;
+    for b in 1..func.blocks.len() {
+        let Some(i) = tail_call(func, b) else {
+            continue;
+        };
+        let block = &mut func.blocks[b];
+        let (pos, origin) = (block.pos(i), block.origin(i));
+        let IrInstruction::Call { args, .. } = &block.instrs[i] else {
+            unreachable!();
+        };
+        for (srcs, arg) in sources.iter_mut().zip(args) {
+            srcs.push(PhiSource::new(&block.label, Some(*arg)));
+        }
+        // anything after the `ret` never ran
+        block.positions.resize(block.instrs.len(), None);
+        block.origins.resize(block.instrs.len(), None);
+        block.instrs.truncate(i);
+        block.positions.truncate(i);
+        block.origins.truncate(i);
+        for s in block.succs.clone() {
+            func.remove_edge(b, s);
+        }
+        func.append_instr(b, &jmp, pos, origin);
+        func.add_edge(b, header);
+        count += 1;
+    }
+
+    for (k, (dest, sources)) in current.into_iter().zip(sources).enumerate() {
+        let phi = IrInstruction::Phi { dest, sources };
+        func.blocks[header].insert_instr(k, phi, None, None);
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swapped_arguments_go_through_phis() {
+        let mut func = IrFunction::parse(
+            "@swap(a: int, b: int, n: int): int {
+.entry:
+  zero = const 0
+  done = le n zero
+  br done .base .rec
+.base:
+  ret a
+.rec:
+  one = const 1
+  m = sub n one
+  r = call @swap b a m
+  ret r
+}",
+        )
+        .unwrap();
+        assert_eq!(eliminate_tail_calls(&mut func), 1);
+        bril_ir::verify_ssa(&func).unwrap();
+        let text = func.to_string();
+        assert!(!text.contains("call"), "{}", text);
+        assert!(text.contains("  jmp .entry.tail\n.entry.tail:"), "{}", text);
+        // the phis read `a.1` and `b.1` from before either changes
+        assert!(
+            text.contains("a.1: int = phi [a, .entry] [b.1, .rec]"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("b.1: int = phi [b, .entry] [a.1, .rec]"),
+            "{}",
+            text
+        );
+        assert!(text.contains("m = sub n.1 one"), "{}", text);
+        assert!(text.contains("  ret a.1\n"), "{}", text);
+    }
+
+    #[test]
+    fn test_entry_falling_through() {
+        let mut func = IrFunction::parse(
+            "@down(n: int): int {
+.entry:
+  zero = const 0
+.check:
+  done = le n zero
+  br done .base .rec
+.base:
+  ret n
+.rec:
+  one = const 1
+  m = sub n one
+  r = call @down m
+  ret r
+}",
+        )
+        .unwrap();
+        assert_eq!(eliminate_tail_calls(&mut func), 1);
+        bril_ir::verify_ssa(&func).unwrap();
+        let header = func.block_index(&"entry.tail".to_string()).unwrap();
+        let lines: Vec<String> = func.blocks[header]
+            .instrs
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(lines.last().unwrap(), "jmp .check");
+    }
+
+    #[test]
+    fn test_void_tail_call() {
+        let mut func = IrFunction::parse(
+            "@count(n: int) {
+.entry:
+  print n
+  zero = const 0
+  done = le n zero
+  br done .exit .rec
+.exit:
+  ret
+.rec:
+  one = const 1
+  m = sub n one
+  call @count m
+  ret
+}",
+        )
+        .unwrap();
+        assert_eq!(eliminate_tail_calls(&mut func), 1);
+        bril_ir::verify_ssa(&func).unwrap();
+        let rec = func.block_index(&"rec".to_string()).unwrap();
+        assert_eq!(
+            func.blocks[rec].instrs.last().unwrap().to_string(),
+            "jmp .entry.tail"
+        );
+        assert_eq!(
+            func.blocks[rec].succs,
+            [func.block_index(&"entry.tail".to_string()).unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_calls_with_work_after_them_stay() {
+        let src = "@fac(n: int): int {
+.entry:
+  one = const 1
+  base = le n one
+  br base .done .rec
+.done:
+  ret one
+.rec:
+  m = sub n one
+  r = call @fac m
+  p = mul n r
+  ret p
+}
+
+@other(n: int): int {
+.entry:
+  r = call @fac n
+  ret r
+}";
+        let mut module = bril_ir::text::parse_module(src).unwrap();
+        for func in &mut module.functions {
+            let before = func.clone();
+            assert_eq!(eliminate_tail_calls(func), 0);
+            assert_eq!(*func, before);
+        }
+    }
+}
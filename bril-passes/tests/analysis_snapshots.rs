@@ -0,0 +1,67 @@
+//! Dominator and liveness dumps for every program in `tests/`, straight
+//! after SSA construction. A change to either analysis shows up here as a
+//! snapshot diff to review (`cargo insta review`).
+
+use bril_frontend::Program;
+use bril_ir::{IrModule, SSAFormation};
+use bril_passes::analysis_printer::Printed;
+use bril_passes::{AnalysisPrinterPass, PassManager};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+// a sink the test can still read once the passes have it
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn analyses(json: &str) -> String {
+    let program: Program = serde_json::from_str(json).expect("test program should parse");
+    let mut module = IrModule::try_from(&program).expect("test program should build a CFG");
+    SSAFormation::try_from(&mut module).expect("test program should go into SSA");
+
+    let sink = Shared::default();
+    let mut pm = PassManager::new();
+    pm.add_pass(AnalysisPrinterPass::with_sink(
+        Printed::Dominators,
+        sink.clone(),
+    ));
+    pm.add_pass(AnalysisPrinterPass::with_sink(
+        Printed::Liveness,
+        sink.clone(),
+    ));
+    let before = module.clone();
+    pm.run(&mut module).expect("the printers should run");
+    assert_eq!(module, before, "printing changed the program");
+
+    let text = sink.0.borrow().clone();
+    String::from_utf8(text).unwrap()
+}
+
+#[test]
+fn snapshot_analyses_of_every_test_program() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests");
+    let mut programs: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    programs.sort();
+    assert!(!programs.is_empty());
+
+    for path in programs {
+        let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+        let json = std::fs::read_to_string(&path).unwrap();
+        insta::assert_snapshot!(name, analyses(&json));
+    }
+}
@@ -0,0 +1,1107 @@
+use anyhow::{bail, Result};
+use bril_frontend::Program;
+use bril_ir::{destruct_ssa, IrInstruction, IrModule, SSAFormation, SsaStyle};
+use bril_passes::{
+    BlockLayoutPass, ConstantFoldPass, ConstantPropagationPass, CopyPropagationPass,
+    DeadCodeRemovalPass, DeadFunctionPass, IndVarPass, InstCombinePass, JumpThreadPass,
+    LoopRotatePass, LoopUnrollPass, PassManager, PassOptions, PassStatistics, PhiSimplifyPass,
+    SimplifyCfgPass, StrengthReductionPass, TailCallPass,
+};
+use riscv_backend::{emit_riscv, select_instructions, MachineFunc, TargetConfig};
+
+/// How much optimization the default pipeline does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    /// No passes at all, straight from SSA to instruction selection
+    O0,
+    /// Unrolling loops with a small constant trip count, constant
+    /// propagation, constant folding, algebraic simplification, induction
+    /// variable simplification, strength reduction, copy propagation, dead
+    /// code removal, tail call elimination, loop rotation, jump threading,
+    /// CFG simplification, trivial phi removal and block layout, then
+    /// dropping the functions `main` never calls
+    #[default]
+    O1,
+    /// The same passes, with the loops that can't be unrolled completely
+    /// going round four copies of their body at a time
+    O2,
+}
+
+impl OptLevel {
+    /// The pass pipeline this level stands for
+    pub fn pipeline(&self) -> PassManager {
+        let mut pm = PassManager::new();
+        if *self != OptLevel::O0 {
+            pm.add_pass(LoopUnrollPass::default());
+            pm.add_pass(ConstantPropagationPass {});
+            pm.add_pass(ConstantFoldPass::default());
+            pm.add_pass(InstCombinePass {});
+            pm.add_pass(IndVarPass {});
+            pm.add_pass(StrengthReductionPass {});
+            pm.add_pass(CopyPropagationPass {});
+            pm.add_pass(DeadCodeRemovalPass {});
+            pm.add_pass(TailCallPass {});
+            pm.add_pass(LoopRotatePass::default());
+            pm.add_pass(JumpThreadPass::default());
+            pm.add_pass(SimplifyCfgPass {});
+            pm.add_pass(PhiSimplifyPass {});
+            pm.add_pass(BlockLayoutPass {});
+            pm.add_module_pass(DeadFunctionPass::new());
+        }
+        let unroll_factor = match self {
+            OptLevel::O2 => 4,
+            _ => 1,
+        };
+        pm.set_options(PassOptions::new().with("unroll.factor", unroll_factor));
+        pm
+    }
+}
+
+/// Everything the pipeline produced, stage by stage
+#[derive(Debug)]
+pub struct CompilationOutput {
+    /// The module after SSA construction and optimization
+    pub ir: IrModule,
+    /// One machine function per IR function, before register allocation
+    pub machine_funcs: Vec<MachineFunc>,
+    /// Final RISC-V assembly text
+    pub asm: String,
+    /// Notes collected along the way (which passes ran, etc.)
+    pub reports: Vec<String>,
+    /// What the passes counted, and how long each took when
+    /// `time_passes` is on
+    pub pass_statistics: PassStatistics,
+}
+
+/// The whole Bril → RISC-V pipeline behind one struct.
+///
+/// Every stage is also reachable on its own (`compile_to_ir`, `optimize`,
+/// `lower`, `emit`) so tests can poke at the boundaries.
+pub struct Compiler {
+    target: TargetConfig,
+    opt_level: OptLevel,
+    // overrides the pipeline of `opt_level` when set
+    custom_passes: Option<PassManager>,
+    verify_each: bool,
+    ssa_style: SsaStyle,
+    collect_stats: bool,
+    time_passes: bool,
+    // `Some(None)` to print after every pass
+    print_after: Option<Option<String>>,
+}
+
+impl Compiler {
+    pub fn new(target: TargetConfig) -> Self {
+        Self {
+            target,
+            opt_level: OptLevel::default(),
+            custom_passes: None,
+            verify_each: false,
+            ssa_style: SsaStyle::default(),
+            collect_stats: false,
+            time_passes: false,
+            print_after: None,
+        }
+    }
+
+    pub fn opt_level(mut self, level: OptLevel) -> Self {
+        self.opt_level = level;
+        self
+    }
+
+    /// Run `passes` instead of the pipeline picked by the opt level
+    pub fn passes(mut self, passes: PassManager) -> Self {
+        self.custom_passes = Some(passes);
+        self
+    }
+
+    /// Check the IR is well formed SSA before the passes and after each one,
+    /// see `PassManager::set_verify_each`
+    pub fn verify_each(mut self, verify_each: bool) -> Self {
+        self.verify_each = verify_each;
+        self
+    }
+
+    /// Which phis SSA construction places, see [`SsaStyle`]
+    pub fn ssa_style(mut self, style: SsaStyle) -> Self {
+        self.ssa_style = style;
+        self
+    }
+
+    /// Add how each pass changed each function's stats to the reports,
+    /// see `PassManager::stats`
+    pub fn collect_stats(mut self, collect_stats: bool) -> Self {
+        self.collect_stats = collect_stats;
+        self
+    }
+
+    /// Time each pass on each function, see `PassManager::statistics`
+    pub fn time_passes(mut self, time_passes: bool) -> Self {
+        self.time_passes = time_passes;
+        self
+    }
+
+    /// Print the IR to stderr after each pass called `filter`, or after
+    /// every pass for None, see `PassManager::print_after_each`
+    pub fn print_after(mut self, filter: Option<String>) -> Self {
+        self.print_after = Some(filter);
+        self
+    }
+
+    pub fn target(&self) -> &TargetConfig {
+        &self.target
+    }
+
+    /// Parse Bril JSON and compile it
+    pub fn compile_json(&mut self, json: &str) -> Result<CompilationOutput> {
+        let mut program = Program::from_json(json)?;
+        program.normalize();
+        self.compile_program(&program)
+    }
+
+    /// Parse Bril text syntax (what `bril2json` takes) and compile it
+    pub fn compile_text(&mut self, src: &str) -> Result<CompilationOutput> {
+        let mut program = bril_frontend::text::parse_program_with_positions(src)?;
+        program.normalize();
+        self.compile_program(&program)
+    }
+
+    pub fn compile_program(&mut self, program: &Program) -> Result<CompilationOutput> {
+        let ir = self.compile_to_ir(program)?;
+        self.compile_ir(ir)
+    }
+
+    /// Resume from an already built (SSA) module, e.g. one read back with
+    /// `bril_ir::text::parse_module`, skipping the frontend
+    pub fn compile_ir(&mut self, mut ir: IrModule) -> Result<CompilationOutput> {
+        // hand-written or reloaded modules haven't had their calls checked,
+        // the backend jumps straight to `target_func`
+        ir.resolve_calls()?;
+        let reports = self.optimize(&mut ir)?;
+        let pass_statistics = self
+            .custom_passes
+            .as_ref()
+            .map(PassManager::statistics)
+            .unwrap_or_default();
+        check_lowerable(&ir)?;
+        let machine_funcs = self.lower(&ir);
+        let asm = self.emit(&machine_funcs);
+
+        Ok(CompilationOutput {
+            ir,
+            machine_funcs,
+            asm,
+            reports,
+            pass_statistics,
+        })
+    }
+
+    /// Link several Bril files (see `Program::link`) and compile the result
+    pub fn compile_linked(&mut self, programs: &[Program]) -> Result<CompilationOutput> {
+        self.compile_program(&Program::link(programs)?)
+    }
+
+    /// Frontend: flat Bril → CFG → SSA
+    pub fn compile_to_ir(&self, program: &Program) -> Result<IrModule> {
+        // imports only resolve against other files, a lone program has to
+        // provide everything itself
+        if !program.imports.is_empty() {
+            let linked = Program::link(std::slice::from_ref(program))?;
+            return self.compile_to_ir(&linked);
+        }
+        let mut ir_mod = IrModule::try_from(program)?;
+        SSAFormation::with_style(&mut ir_mod.functions, self.ssa_style)?;
+        Ok(ir_mod)
+    }
+
+    /// Run the pass pipeline over the module until it settles, returns what
+    /// was run
+    pub fn optimize(&mut self, module: &mut IrModule) -> Result<Vec<String>> {
+        let opt_level = self.opt_level;
+        let pm = self
+            .custom_passes
+            .get_or_insert_with(|| opt_level.pipeline());
+        pm.set_verify_each(self.verify_each);
+        pm.set_collect_stats(self.collect_stats);
+        pm.set_time_passes(self.time_passes);
+        if let Some(filter) = &self.print_after {
+            pm.print_after_each(filter.clone());
+        }
+        let summary = pm.run(module)?;
+        if summary.changes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let ran = summary
+            .changes
+            .iter()
+            .map(|(name, n)| format!("ran {}, changed {} times", name, n));
+        Ok(ran
+            .chain([summary.to_string()])
+            .chain(pm.stats().iter().map(|s| s.to_string()))
+            .collect())
+    }
+
+    /// Out of SSA and instruction selection for every function. The
+    /// module itself keeps its phis
+    pub fn lower(&self, module: &IrModule) -> Vec<MachineFunc> {
+        module
+            .functions
+            .iter()
+            .map(|func| {
+                let mut func = func.clone();
+                destruct_ssa(&mut func);
+                select_instructions(&func)
+            })
+            .collect()
+    }
+
+    /// The module as a Bril program other tools can run, out of SSA like
+    /// `lower` does since the reference interpreters don't take phis
+    pub fn emit_bril(&self, module: &IrModule) -> Result<Program> {
+        let mut module = module.clone();
+        module.functions.iter_mut().for_each(destruct_ssa);
+        Program::try_from(&module)
+    }
+
+    /// Register allocation + assembly emission
+    pub fn emit(&self, machine_funcs: &[MachineFunc]) -> String {
+        emit_riscv(machine_funcs, &self.target)
+    }
+}
+
+/// Extension ops make it through the frontend and the passes, but there's
+/// no code to generate for them. Neither is there for the memory ops, which
+/// would need an allocator to call, or for float arithmetic, which needs
+/// the F/D registers
+fn check_lowerable(module: &IrModule) -> Result<()> {
+    for func in &module.functions {
+        for instr in func.blocks.iter().flat_map(|b| &b.instrs) {
+            let op = match instr {
+                IrInstruction::Opaque { op, .. } => op.as_str(),
+                IrInstruction::Alloc { .. }
+                | IrInstruction::Load { .. }
+                | IrInstruction::Store { .. }
+                | IrInstruction::Free { .. }
+                | IrInstruction::PtrAdd { .. }
+                | IrInstruction::FAdd { .. }
+                | IrInstruction::FSub { .. }
+                | IrInstruction::FMul { .. }
+                | IrInstruction::FDiv { .. }
+                | IrInstruction::FEq { .. }
+                | IrInstruction::FLt { .. }
+                | IrInstruction::FGt { .. }
+                | IrInstruction::FLe { .. }
+                | IrInstruction::FGe { .. } => instr.opcode(),
+                _ => continue,
+            };
+            bail!(
+                "@{}: can't generate code for `{}`, the compiler doesn't support that op",
+                func.name,
+                op
+            );
+        }
+    }
+    Ok(())
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new(TargetConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bril_frontend::Instruction;
+    use std::io::Write;
+
+    #[test]
+    fn test_compile_ir_from_dump_matches_json() {
+        let json = include_str!("../../tests/palindrome.json");
+        let program: Program = serde_json::from_str(json).unwrap();
+
+        let mut compiler = Compiler::default();
+        let from_json = compiler.compile_program(&program).unwrap();
+
+        // dump the SSA module before any passes ran and resume from the text
+        let dump = compiler.compile_to_ir(&program).unwrap().to_string();
+        let ir = bril_ir::text::parse_module(&dump).unwrap();
+        let from_ir = Compiler::default().compile_ir(ir).unwrap();
+
+        assert_eq!(from_ir.asm, from_json.asm);
+    }
+
+    #[test]
+    fn test_programs_read_through_bril_rs() {
+        let json = include_str!("../../tests/palindrome.json");
+        let upstream = bril_rs::load_program_from_read(json.as_bytes());
+        let program = Program::try_from(&upstream).unwrap();
+
+        let out = Compiler::default().compile_program(&program).unwrap();
+        let direct = Compiler::default().compile_json(json).unwrap();
+        assert_eq!(out.asm, direct.asm);
+
+        // the optimized module is in SSA with phis, which bril-rs has no form for
+        let optimized = Program::try_from(&out.ir).unwrap();
+        let err = bril_rs::Program::try_from(&optimized).unwrap_err();
+        assert!(err.to_string().contains("= phi "), "{}", err);
+    }
+
+    #[test]
+    fn test_linked_files_compile_to_one_module() {
+        let main = r#"{
+  "imports": [{"path": "helper.json", "functions": [{"name": "helper", "alias": "inc"}]}],
+  "functions": [{"name": "main", "instrs": [
+    {"op": "const", "dest": "x", "type": "int", "value": 41},
+    {"op": "call", "dest": "y", "type": "int", "funcs": ["inc"], "args": ["x"]},
+    {"op": "print", "args": ["y"]}
+  ]}]
+}"#;
+        let helper = r#"{"functions": [{"name": "helper", "args": [{"name": "n", "type": "int"}],
+  "type": "int", "instrs": [
+    {"op": "const", "dest": "one", "type": "int", "value": 1},
+    {"op": "add", "dest": "r", "type": "int", "args": ["n", "one"]},
+    {"op": "ret", "args": ["r"]}
+  ]}]}"#;
+        let programs: Vec<Program> = [main, helper]
+            .iter()
+            .map(|json| serde_json::from_str(json).unwrap())
+            .collect();
+
+        let output = Compiler::default().compile_linked(&programs).unwrap();
+        assert!(output.asm.contains("\nmain:"));
+        assert!(output.asm.contains("\nhelper:"));
+        assert!(output.asm.contains("jal ra, helper"));
+
+        // on its own the import has nothing to resolve against
+        assert!(Compiler::default().compile_program(&programs[0]).is_err());
+    }
+
+    #[test]
+    fn test_unused_imports_generate_no_code() {
+        let main = r#"{
+  "imports": [{"path": "lib.json", "functions": [{"name": "double"}, {"name": "triple"}]}],
+  "functions": [{"name": "main", "instrs": [
+    {"op": "const", "dest": "x", "type": "int", "value": 21},
+    {"op": "call", "dest": "y", "type": "int", "funcs": ["double"], "args": ["x"]},
+    {"op": "print", "args": ["y"]}
+  ]}]
+}"#;
+        let lib = r#"{"functions": [
+  {"name": "double", "args": [{"name": "n", "type": "int"}], "type": "int", "instrs": [
+    {"op": "add", "dest": "r", "type": "int", "args": ["n", "n"]},
+    {"op": "ret", "args": ["r"]}
+  ]},
+  {"name": "triple", "args": [{"name": "n", "type": "int"}], "type": "int", "instrs": [
+    {"op": "call", "dest": "d", "type": "int", "funcs": ["double"], "args": ["n"]},
+    {"op": "add", "dest": "r", "type": "int", "args": ["d", "n"]},
+    {"op": "ret", "args": ["r"]}
+  ]}
+]}"#;
+        let programs: Vec<Program> = [main, lib]
+            .iter()
+            .map(|json| serde_json::from_str(json).unwrap())
+            .collect();
+
+        let output = Compiler::default().compile_linked(&programs).unwrap();
+        let names: Vec<&str> = output
+            .ir
+            .functions
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(names, ["main", "double"]);
+        assert!(output.asm.contains("\ndouble:"));
+        assert!(!output.asm.contains("triple"), "{}", output.asm);
+
+        // nothing is dropped at O0
+        let output = Compiler::default()
+            .opt_level(OptLevel::O0)
+            .compile_linked(&programs)
+            .unwrap();
+        assert!(output.asm.contains("\ntriple:"));
+    }
+
+    #[test]
+    fn test_tail_recursion_becomes_a_loop() {
+        // factorial with the product passed along, so the call is the last
+        // thing each round does
+        let src = "@main {
+  n: int = const 10;
+  one: int = const 1;
+  f: int = call @fac n one;
+  print f;
+}
+
+@fac(n: int, acc: int): int {
+  zero: int = const 0;
+  done: bool = eq n zero;
+  br done .base .rec;
+.base:
+  ret acc;
+.rec:
+  one: int = const 1;
+  m: int = sub n one;
+  a: int = mul acc n;
+  r: int = call @fac m a;
+  ret r;
+}";
+        let fac_asm = |asm: &str| asm[asm.find("\nfac:").unwrap()..].to_string();
+
+        let output = Compiler::default().compile_text(src).unwrap();
+        let fac = fac_asm(&output.asm);
+        assert!(!fac.contains("jal ra, fac"), "{}", fac);
+        // rotated, so it goes round from the bottom of `.rec`
+        assert!(fac.contains(", rec\n"), "{}", fac);
+        // main's call isn't a tail call, there's a print after it
+        assert!(output.asm.contains("jal ra, fac"));
+
+        let output = Compiler::default()
+            .opt_level(OptLevel::O0)
+            .compile_text(src)
+            .unwrap();
+        assert!(fac_asm(&output.asm).contains("jal ra, fac"));
+    }
+
+    #[test]
+    fn test_powers_of_two_multiply_and_divide_by_shifting() {
+        let src = "@main(x: int) {
+  eight: int = const 8;
+  four: int = const 4;
+  a: int = mul x eight;
+  b: int = div x four;
+  print a b;
+}";
+        let asm = Compiler::default().compile_text(src).unwrap().asm;
+        assert!(asm.contains("slli "), "{}", asm);
+        assert!(asm.contains("srai "), "{}", asm);
+        assert!(asm.contains("srli "), "{}", asm);
+        assert!(!asm.contains("mul ") && !asm.contains("div "), "{}", asm);
+
+        let asm = Compiler::default()
+            .opt_level(OptLevel::O0)
+            .compile_text(src)
+            .unwrap()
+            .asm;
+        assert!(asm.contains("mul ") && asm.contains("div "), "{}", asm);
+    }
+
+    #[test]
+    fn test_emitted_bril_has_only_core_ops() {
+        let src = "@main(x: int) {
+  eight: int = const 8;
+  four: int = const 4;
+  a: int = mul x eight;
+  b: int = div x four;
+  print a b;
+}";
+        let output = Compiler::default().compile_text(src).unwrap();
+        let text = output.ir.to_string();
+        assert!(text.contains(" shl ") && text.contains(" sra "), "{}", text);
+
+        let json = serde_json::to_value(Program::try_from(&output.ir).unwrap()).unwrap();
+        let core = [
+            "const", "id", "add", "sub", "mul", "div", "eq", "lt", "gt", "le", "ge", "print",
+            "ret", "phi",
+        ];
+        for instr in json["functions"][0]["instrs"].as_array().unwrap() {
+            let op = instr["op"].as_str().unwrap();
+            assert!(core.contains(&op), "`{}` isn't core Bril: {}", op, instr);
+        }
+    }
+
+    #[test]
+    fn test_indexing_loop_multiplies_once() {
+        // `i * 8` each time round is an add of 8 to a second counter
+        let src = "@main(n: int) {
+  i: int = const 0;
+  one: int = const 1;
+  eight: int = const 8;
+  sum: int = const 0;
+.head:
+  c: bool = lt i n;
+  br c .body .exit;
+.body:
+  off: int = mul i eight;
+  sum: int = add sum off;
+  i: int = add i one;
+  jmp .head;
+.exit:
+  print sum;
+}";
+        let asm = Compiler::default().compile_text(src).unwrap().asm;
+        assert!(!asm.contains("mul ") && !asm.contains("slli "), "{}", asm);
+
+        let asm = Compiler::default()
+            .opt_level(OptLevel::O0)
+            .compile_text(src)
+            .unwrap()
+            .asm;
+        assert!(asm.contains("mul "), "{}", asm);
+    }
+
+    #[test]
+    fn test_constant_loop_unrolls_and_folds_away() {
+        let src = "@main {
+  i: int = const 0;
+  one: int = const 1;
+  four: int = const 4;
+  sum: int = const 0;
+.head:
+  c: bool = lt i four;
+  br c .body .exit;
+.body:
+  sum: int = add sum i;
+  i: int = add i one;
+  jmp .head;
+.exit:
+  print sum;
+}";
+        let output = Compiler::default().compile_text(src).unwrap();
+        let main = &output.ir.functions[0];
+        assert_eq!(main.blocks.len(), 1, "{}", main);
+        let instrs = &main.blocks[0].instrs;
+        assert_eq!(instrs.len(), 3, "{}", main);
+        assert!(instrs[0].to_string().ends_with(" = const 6"), "{}", main);
+
+        // a bound that isn't known is left to O2
+        let src = src
+            .replace("lt i four", "lt i n")
+            .replace("@main", "@main(n: int)");
+        let output = Compiler::default().compile_text(&src).unwrap();
+        assert!(!output.ir.to_string().contains(".unrolled"));
+        let output = Compiler::default()
+            .opt_level(OptLevel::O2)
+            .compile_text(&src)
+            .unwrap();
+        assert!(output.ir.to_string().contains(".unrolled"), "{}", output.ir);
+    }
+
+    #[test]
+    fn test_unknown_ops_fail_at_codegen() {
+        let json = r#"{"functions": [{"name": "main", "instrs": [
+    {"op": "const", "dest": "x", "type": "int", "value": 1},
+    {"op": "vecadd", "dest": "v", "type": "int", "args": ["x", "x"]},
+    {"op": "print", "args": ["v"]}
+]}]}"#;
+        let program = Program::from_json(json).unwrap();
+        program.validate().unwrap();
+
+        let mut compiler = Compiler::default();
+        let ir = compiler.compile_to_ir(&program).unwrap();
+        assert!(ir.to_string().contains("= opaque vecadd x x"));
+
+        let err = compiler.compile_program(&program).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@main: can't generate code for `vecadd`, the compiler doesn't support that op"
+        );
+    }
+
+    #[test]
+    fn test_emitted_bril_runs_in_brilirs() {
+        let brilirs = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../bril-extern/bril/brilirs/target/release/brilirs"
+        );
+        if !std::path::Path::new(brilirs).exists() {
+            eprintln!("skipped, brilirs isn't built");
+            return;
+        }
+        let src = include_str!("../../bril-extern/bril/benchmarks/core/collatz.bril");
+        let mut compiler = Compiler::default();
+        let output = compiler.compile_text(src).unwrap();
+        assert!(output.ir.to_string().contains(" phi "), "{}", output.ir);
+        let program = compiler.emit_bril(&output.ir).unwrap();
+
+        // as JSON, and as text that brilirs parses itself
+        let run = |input: String, flags: &[&str]| {
+            let mut child = std::process::Command::new(brilirs)
+                .args(flags)
+                .arg("7")
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(input.as_bytes())
+                .unwrap();
+            let run = child.wait_with_output().unwrap();
+            assert!(
+                run.status.success(),
+                "{}",
+                String::from_utf8_lossy(&run.stderr)
+            );
+            String::from_utf8(run.stdout).unwrap()
+        };
+        let expected = include_str!("../../bril-extern/bril/benchmarks/core/collatz.out");
+        assert_eq!(run(serde_json::to_string(&program).unwrap(), &[]), expected);
+        assert_eq!(run(program.to_string(), &["-t"]), expected);
+    }
+
+    #[test]
+    fn test_emitted_bril_text_reads_back() {
+        let src = include_str!("../../bril-extern/bril/benchmarks/core/collatz.bril");
+        let mut compiler = Compiler::default();
+        let output = compiler.compile_text(src).unwrap();
+        // the SSA names, which aren't Bril identifiers
+        assert!(output.ir.to_string().contains('$'), "{}", output.ir);
+
+        let mut program = compiler.emit_bril(&output.ir).unwrap();
+        let text = program.to_string();
+        assert!(!text.contains('$'), "{}", text);
+        let reparsed = bril_frontend::text::parse_program(&text).unwrap();
+
+        // the text has no positions to read back
+        for instr in program.functions.iter_mut().flat_map(|f| &mut f.instrs) {
+            match instr {
+                Instruction::Label { pos, .. } | Instruction::Op { pos, .. } => *pos = None,
+            }
+        }
+        assert_eq!(reparsed, program);
+    }
+
+    #[test]
+    fn test_memory_ops_fail_at_codegen() {
+        let src = "@main {
+  one: int = const 1;
+  p: ptr<int> = alloc one;
+  store p one;
+  x: int = load p;
+  print x;
+  free p;
+}";
+        let err = Compiler::default().compile_text(src).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@main: can't generate code for `alloc`, the compiler doesn't support that op"
+        );
+    }
+
+    #[test]
+    fn test_float_arithmetic_fails_at_codegen() {
+        let src = "@main(x: float) {
+  half: float = const 0.5;
+  bits: int = float2bits half;
+  print bits;
+  y: float = fmul x half;
+  print y;
+}";
+        let err = Compiler::default().compile_text(src).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@main: can't generate code for `fmul`, the compiler doesn't support that op"
+        );
+
+        let src = "@main(x: float) {
+  zero: float = const 0;
+  neg: bool = flt x zero;
+  print neg;
+}";
+        let err = Compiler::default().compile_text(src).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@main: can't generate code for `flt`, the compiler doesn't support that op"
+        );
+    }
+
+    #[test]
+    fn test_void_calls() {
+        let src = "@show(n: int) {
+  print n;
+}
+@main {
+  x: int = const 3;
+  call @show x;
+  ret;
+}";
+        let program = bril_frontend::text::parse_program(src).unwrap();
+        let call = r#"{"op":"call","funcs":["show"],"args":["x"]}"#;
+        assert!(serde_json::to_string(&program).unwrap().contains(call));
+
+        let mut compiler = Compiler::default();
+        let output = compiler.compile_program(&program).unwrap();
+        assert!(output.asm.contains("jal ra, show"));
+        // nothing comes back, so nothing gets moved out of a0
+        let mut after_call = output.asm.lines().skip_while(|l| !l.contains("jal"));
+        assert_eq!(after_call.nth(1), Some("  ret"));
+        let ir_call = output.ir.functions[1].blocks[0]
+            .instrs
+            .iter()
+            .find(|i| matches!(i, IrInstruction::Call { .. }))
+            .unwrap();
+        assert!(ir_call.defs().is_empty());
+
+        // and back out to Bril without a type
+        let ir = compiler.compile_to_ir(&program).unwrap();
+        let back = Program::try_from(&ir).unwrap();
+        assert!(serde_json::to_string(&back).unwrap().contains(call));
+    }
+
+    #[test]
+    fn test_asm_annotated_with_origins() {
+        let src = "@main {
+  x: int = const 6;
+  jmp .next;
+.next:
+  y: int = mul x x;
+  ret y;
+}";
+        let mut compiler = Compiler::new(TargetConfig {
+            annotate_origins: true,
+            ..TargetConfig::default()
+        });
+        let asm = compiler.compile_text(src).unwrap().asm;
+        // the label is instruction 2, and the `jmp` to it goes when the two
+        // blocks are merged. The mul gets folded and `x` is dead after that,
+        // the folded constant still says where it came from
+        let origins: Vec<_> = asm.lines().filter(|l| l.contains("# from")).collect();
+        assert_eq!(origins, ["  # from @main:3", "  # from @main:4"]);
+
+        let plain = Compiler::default().compile_text(src).unwrap().asm;
+        assert!(!plain.contains('#'));
+    }
+
+    #[test]
+    fn test_nops_change_nothing() {
+        let src = include_str!("../../tests/loop.bril");
+        // one in front of every instruction, so never after a terminator
+        let with_nops: String = src
+            .lines()
+            .map(|line| {
+                if line.starts_with("  ") {
+                    format!("  nop;\n{}\n", line)
+                } else {
+                    format!("{}\n", line)
+                }
+            })
+            .collect();
+        let with_nops = with_nops.as_str();
+        assert!(with_nops.matches("nop").count() > 10);
+
+        let ir = Compiler::default()
+            .compile_to_ir(&bril_frontend::text::parse_program(with_nops).unwrap())
+            .unwrap();
+        assert!(ir.to_string().contains("\n  nop\n"));
+
+        let plain = Compiler::default().compile_text(src).unwrap();
+        let nops = Compiler::default().compile_text(with_nops).unwrap();
+        assert_eq!(nops.asm, plain.asm);
+        assert!(!nops.ir.to_string().contains("nop"));
+    }
+
+    /// Drops the entry's last instruction, leaving it without a terminator
+    struct DropRet;
+
+    impl bril_passes::FunctionPass for DropRet {
+        fn name(&self) -> &str {
+            "DropRet"
+        }
+
+        fn run_on_function(
+            &mut self,
+            function: &mut bril_ir::IrFunction,
+            _stats: &mut bril_passes::Statistics,
+        ) -> Result<bool, bril_passes::PassError> {
+            function.blocks[0].instrs.pop();
+            Ok(true)
+        }
+    }
+
+    /// Defines the first name the entry defines a second time, right after
+    struct DuplicateDef;
+
+    impl bril_passes::FunctionPass for DuplicateDef {
+        fn name(&self) -> &str {
+            "DuplicateDef"
+        }
+
+        fn run_on_function(
+            &mut self,
+            function: &mut bril_ir::IrFunction,
+            _stats: &mut bril_passes::Statistics,
+        ) -> Result<bool, bril_passes::PassError> {
+            let entry = &mut function.blocks[0];
+            let Some(i) = entry.instrs.iter().position(|i| !i.defs().is_empty()) else {
+                return Ok(false);
+            };
+            let copy = entry.instrs[i].clone();
+            entry.insert_instr(i + 1, copy, None, None);
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_verify_each_names_the_pass_that_broke_it() {
+        let src = include_str!("../../tests/add.bril");
+        let pipeline = || {
+            let mut pm = PassManager::new();
+            pm.add_pass(DeadCodeRemovalPass {});
+            pm.add_pass(SimplifyCfgPass {});
+            pm
+        };
+        Compiler::default()
+            .passes(pipeline())
+            .verify_each(true)
+            .compile_text(src)
+            .unwrap();
+
+        let mut pm = pipeline();
+        pm.add_pass(DropRet);
+        let err = Compiler::default()
+            .passes(pm)
+            .verify_each(true)
+            .compile_text(src)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@add_test is broken after DropRet:\n  @add_test: block 0: last block doesn't end in br, jmp or ret"
+        );
+    }
+
+    #[test]
+    fn test_verify_each_catches_a_second_def() {
+        let mut pm = PassManager::new();
+        pm.add_pass(DuplicateDef);
+        let err = Compiler::default()
+            .passes(pm)
+            .verify_each(true)
+            .compile_text(include_str!("../../tests/add.bril"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "@add_test is broken after DuplicateDef:\n  @add_test: block 0[1]: `v0` is defined more than once"
+        );
+    }
+
+    #[test]
+    fn test_every_test_program_verifies_after_each_pass() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../tests");
+        let mut checked = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let mut program = Program::from_json(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            program.normalize();
+            // only the passes, some of these have ops there's no code for
+            for level in [OptLevel::O1, OptLevel::O2] {
+                let mut compiler = Compiler::default().opt_level(level).verify_each(true);
+                let mut ir = compiler.compile_to_ir(&program).unwrap();
+                compiler
+                    .optimize(&mut ir)
+                    .unwrap_or_else(|e| panic!("{} at {:?}: {}", path.display(), level, e));
+            }
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn test_copy_propagation_saves_moves() {
+        let mvs = |pm: Option<PassManager>, json: &str| {
+            let mut compiler = Compiler::default();
+            if let Some(pm) = pm {
+                compiler = compiler.passes(pm);
+            }
+            let asm = compiler.compile_json(json).unwrap().asm;
+            asm.lines()
+                .filter(|l| l.trim_start().starts_with("mv "))
+                .count()
+        };
+        let without = || {
+            let mut pm = PassManager::new();
+            pm.add_pass(ConstantPropagationPass {});
+            pm.add_pass(ConstantFoldPass::default());
+            pm.add_pass(DeadCodeRemovalPass {});
+            pm.add_pass(SimplifyCfgPass {});
+            pm
+        };
+
+        // factorial is full of `id`s
+        let factorial = include_str!("../../tests/factorial.json");
+        assert!(mvs(None, factorial) < mvs(Some(without()), factorial));
+        // palindrome has no `id`s, its moves are all phi copies
+        let palindrome = include_str!("../../tests/palindrome.json");
+        assert_eq!(mvs(None, palindrome), mvs(Some(without()), palindrome));
+    }
+
+    #[test]
+    fn test_jumps_to_the_next_block_are_left_out() {
+        let palindrome = include_str!("../../tests/palindrome.json");
+        let output = Compiler::default().compile_json(palindrome).unwrap();
+        let jmps = output
+            .machine_funcs
+            .iter()
+            .flat_map(|f| &f.blocks)
+            .flat_map(|b| &b.instrs)
+            .filter(|i| matches!(i, riscv_backend::MachineInstr::Jmp { .. }))
+            .count();
+        let js = output
+            .asm
+            .lines()
+            .filter(|l| l.trim_start().starts_with("j "))
+            .count();
+        assert!(js < jmps, "{} of {} jumps written", js, jmps);
+    }
+
+    #[test]
+    fn test_rotated_loop_takes_one_branch_each_time_round() {
+        let src = "@main(n: int) {
+  i: int = const 0;
+  one: int = const 1;
+.head:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  print i;
+  i: int = add i one;
+  jmp .head;
+.done:
+  ret;
+}";
+        // each block of the assembly, by its label, with its branches
+        let branches = |asm: &str| {
+            let mut blocks: Vec<(String, Vec<String>)> = Vec::new();
+            for line in asm.lines().map(str::trim) {
+                if let Some(label) = line.strip_prefix('.').and_then(|l| l.strip_suffix(':')) {
+                    blocks.push((label.to_string(), Vec::new()));
+                } else if ["j ", "beqz ", "bnez "].iter().any(|b| line.starts_with(b))
+                    && let Some((_, branches)) = blocks.last_mut()
+                {
+                    branches.push(line.to_string());
+                }
+            }
+            blocks
+        };
+
+        let asm = Compiler::default().compile_text(src).unwrap().asm;
+        let blocks = branches(&asm);
+        // the body goes back to itself, with the test at the bottom
+        let looping: Vec<_> = blocks
+            .iter()
+            .filter(|(label, branches)| {
+                branches.iter().any(|b| b.ends_with(&format!(" {}", label)))
+            })
+            .collect();
+        assert_eq!(looping.len(), 1, "{}", asm);
+        let (_, body_branches) = looping[0];
+        assert_eq!(body_branches.len(), 1, "{}", asm);
+        assert!(body_branches[0].starts_with("bnez "), "{}", asm);
+
+        // unrotated it's a branch out at the top and a jump back at the bottom
+        let asm = Compiler::default()
+            .opt_level(OptLevel::O0)
+            .compile_text(src)
+            .unwrap()
+            .asm;
+        let round: usize = branches(&asm)
+            .iter()
+            .filter(|(label, _)| label == "head" || label == "body")
+            .map(|(_, branches)| branches.len())
+            .sum();
+        assert_eq!(round, 2, "{}", asm);
+    }
+
+    #[test]
+    fn test_inst_combine_shrinks_identities() {
+        let src = "@main(x: int, p: bool) {
+  zero: int = const 0;
+  one: int = const 1;
+  t: bool = const true;
+  a: int = add x zero;
+  b: int = mul one a;
+  c: int = sub b b;
+  q: bool = and t p;
+  r: bool = not q;
+  s: bool = not r;
+  print b c s;
+}";
+        let instrs = |pm: Option<PassManager>| {
+            let mut compiler = Compiler::default();
+            if let Some(pm) = pm {
+                compiler = compiler.passes(pm);
+            }
+            compiler
+                .compile_text(src)
+                .unwrap()
+                .ir
+                .stats()
+                .total()
+                .instrs
+        };
+        let mut without = PassManager::new();
+        without.add_pass(ConstantPropagationPass {});
+        without.add_pass(ConstantFoldPass::default());
+        without.add_pass(CopyPropagationPass {});
+        without.add_pass(DeadCodeRemovalPass {});
+        without.add_pass(SimplifyCfgPass {});
+        assert!(instrs(None) < instrs(Some(without)));
+
+        let ir = Compiler::default().compile_text(src).unwrap().ir;
+        let text = ir.to_string();
+        assert!(text.contains("print x c p"), "{}", text);
+        assert!(!text.contains(" not "), "{}", text);
+    }
+
+    #[test]
+    fn test_folded_branch_leaves_no_phi_copies() {
+        // with `.other` gone both edges into `.d` bring `n`
+        let src = "@main(c: bool, n: int) {
+  x: int = id n;
+  br c .l .r;
+.l:
+  t: bool = const true;
+  br t .d .other;
+.other:
+  x: int = add n n;
+  jmp .d;
+.r:
+  print n;
+  jmp .d;
+.d:
+  print x;
+}";
+        let out = Compiler::default().compile_text(src).unwrap();
+        let text = out.ir.functions[0].to_string();
+        assert!(!text.contains("phi"), "{}", text);
+        assert!(
+            !out.asm.lines().any(|l| l.trim_start().starts_with("mv ")),
+            "{}",
+            out.asm
+        );
+    }
+
+    #[test]
+    fn test_constant_guard_compiles_straight_line() {
+        let src = "@main {
+  debug: bool = const true;
+  br debug .on .off;
+.on:
+  x: int = const 1;
+  print x;
+  jmp .end;
+.off:
+  y: int = const 2;
+  print y;
+.end:
+}";
+        let out = Compiler::default().compile_text(src).unwrap();
+        assert_eq!(out.ir.functions[0].blocks.len(), 1);
+        let asm = &out.asm;
+        for branch in ["beqz", "bnez", "beq ", "bne ", "j "] {
+            assert!(
+                !asm.lines().any(|l| l.trim_start().starts_with(branch)),
+                "{}",
+                asm
+            );
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! The Bril → RISC-V compiler as a library, so it can be embedded in test
+//! harnesses, benchmarks, or anything else that doesn't want to shell out.
+//!
+//! ```
+//! use bril2riscv::{Compiler, OptLevel};
+//! use riscv_backend::TargetConfig;
+//!
+//! let json = r#"{"functions": [{"name": "main", "instrs": [
+//!     {"op": "const", "dest": "a", "type": "int", "value": 2},
+//!     {"op": "const", "dest": "b", "type": "int", "value": 3},
+//!     {"op": "add", "dest": "c", "type": "int", "args": ["a", "b"]},
+//!     {"op": "print", "args": ["c"]},
+//!     {"op": "ret", "args": ["c"]}
+//! ]}]}"#;
+//!
+//! let mut compiler = Compiler::new(TargetConfig::default()).opt_level(OptLevel::O1);
+//! let output = compiler.compile_json(json)?;
+//!
+//! assert_eq!(output.ir.functions.len(), 1);
+//! assert_eq!(output.machine_funcs.len(), 1);
+//! assert!(output.asm.contains("main:"));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The stages can also be driven one at a time:
+//!
+//! ```
+//! use bril2riscv::{Compiler, OptLevel};
+//! use bril_frontend::Program;
+//!
+//! let json = r#"{"functions": [{"name": "main", "instrs": [
+//!     {"op": "const", "dest": "x", "type": "int", "value": 7},
+//!     {"op": "ret", "args": ["x"]}
+//! ]}]}"#;
+//! let program: Program = serde_json::from_str(json)?;
+//!
+//! let mut compiler = Compiler::default().opt_level(OptLevel::O0);
+//! let mut ir = compiler.compile_to_ir(&program)?;
+//! let reports = compiler.optimize(&mut ir)?;
+//! assert!(reports.is_empty()); // O0 runs no passes
+//!
+//! let machine_funcs = compiler.lower(&ir);
+//! let asm = compiler.emit(&machine_funcs);
+//! assert!(asm.contains("li"));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod compiler;
+pub use compiler::CompilationOutput;
+pub use compiler::Compiler;
+pub use compiler::OptLevel;
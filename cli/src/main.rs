@@ -1,36 +1,240 @@
-use anyhow::Result;
-use bril_frontend::Program;
-use bril_ir::{IrModule, SSAFormation};
-use bril_passes::{ConstantFoldPass, ConstantPropagationPass, DeadCodeRemovalPass, PassManager};
+use anyhow::{bail, Context, Result};
+use bril2riscv::{Compiler, OptLevel};
+use bril_frontend::{ParseOptions, Program};
+use bril_ir::{SSAFormation, SsaStyle};
+use bril_passes::PassManager;
+use clap::{Parser, ValueEnum};
+use riscv_backend::TargetConfig;
+use serde_json::json;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 
-use riscv_backend::*;
-//use std::collections::HashMap;
+#[derive(Parser)]
+#[command(about = "Compile Bril programs to RISC-V assembly")]
+struct Args {
+    /// Bril files, `.bril` text or JSON, linked together when there's more
+    /// than one. Left out, JSON is read from stdin, or the bundled
+    /// palindrome demo is compiled when stdin is a terminal
+    input: Vec<PathBuf>,
+
+    /// Resume from a textual IR dump instead, skipping the frontend
+    #[arg(long, value_name = "FILE", conflicts_with = "input")]
+    from_ir: Option<PathBuf>,
+
+    /// Reject JSON fields and ops the compiler doesn't know instead of
+    /// ignoring them
+    #[arg(long)]
+    strict: bool,
+
+    /// Also write the optimized module back out as Bril JSON, e.g. for
+    /// brilirs, or as Bril text when FILE ends in `.bril`
+    #[arg(long, value_name = "FILE")]
+    emit_bril: Option<PathBuf>,
+
+    /// Mark which Bril instruction each piece of the assembly came from,
+    /// as `# from @func:N` with N the instruction's index in the function
+    #[arg(long)]
+    annotate_origins: bool,
+
+    /// Write the final CFG of every function to FILE as Graphviz dot, with
+    /// the dominator tree drawn in dashed
+    #[arg(long, value_name = "FILE")]
+    dump_cfg: Option<PathBuf>,
+
+    /// How hard to optimize: 0 for not at all, 1 for the usual passes, 2
+    /// for those with loops partially unrolled as well
+    #[arg(short = 'O', value_name = "LEVEL", default_value = "1")]
+    opt_level: Level,
+
+    /// Run these passes instead of the ones -O picks, e.g.
+    /// `constprop,fold*2,dce` or `loop(indvars,unroll),simplifycfg`. A pass
+    /// takes options as in `inline<threshold=40>`. `print-doms` and
+    /// `print-liveness` change nothing, they print the analysis to stderr
+    #[arg(long, value_name = "PIPELINE")]
+    passes: Option<String>,
+
+    /// Check the IR is well formed SSA before the optimization passes and
+    /// after each one, stopping at the first pass that breaks it
+    #[arg(long)]
+    verify_each: bool,
+
+    /// Where SSA construction puts phis: `minimal` at every join a def
+    /// reaches, `semi-pruned` only for variables read across blocks,
+    /// `pruned` only where the variable is still live
+    #[arg(long, value_name = "STYLE", default_value = "minimal")]
+    ssa_style: SsaStyle,
+
+    /// Print instruction, block, phi and loop counts of the final module,
+    /// and what each pass changed, as a table or as JSON
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "table")]
+    stats: Option<StatsFormat>,
+
+    /// Print how long each pass took, how much it shrank the code and what
+    /// it counted, as a table on stderr
+    #[arg(long)]
+    time_passes: bool,
+
+    /// Print every function to stderr after each pass, to see where a
+    /// miscompile comes from
+    #[arg(long)]
+    print_after_all: bool,
+
+    /// Same as --print-after-all, only after PASS, by its name or its
+    /// name in --passes, e.g. `ConstantFoldPass` or `fold`
+    #[arg(long, value_name = "PASS", conflicts_with = "print_after_all")]
+    print_after: Option<String>,
+
+    /// Write every pass's time on every function, and what each pass
+    /// counted, to FILE as JSON
+    #[arg(long, value_name = "FILE")]
+    stats_json: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Level {
+    #[value(name = "0")]
+    O0,
+    #[value(name = "1")]
+    O1,
+    #[value(name = "2")]
+    O2,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StatsFormat {
+    Table,
+    Json,
+}
 
 fn main() -> Result<()> {
-    let json_text = include_str!("../../tests/palindrome.json");
-    let bril_prog: Program = serde_json::from_str(&json_text)?;
-    let mut ir_mod: IrModule = IrModule::try_from(&bril_prog)?;
-    let _ = SSAFormation::try_from(&mut ir_mod)?;
-    let mut pm = PassManager::new();
-    pm.add_pass(ConstantPropagationPass {});
-    pm.add_pass(ConstantFoldPass {});
-    pm.add_pass(DeadCodeRemovalPass {});
-    pm.run(&mut ir_mod);
-
-    let mut machine_module = Vec::new();
-    for func in ir_mod.functions.iter() {
-        let mf = select_instructions(func);
-        machine_module.push(mf);
+    let args = Args::parse();
+    let mut compiler = Compiler::new(TargetConfig {
+        annotate_origins: args.annotate_origins,
+        ..TargetConfig::default()
+    })
+    .opt_level(match args.opt_level {
+        Level::O0 => OptLevel::O0,
+        Level::O1 => OptLevel::O1,
+        Level::O2 => OptLevel::O2,
+    })
+    .verify_each(args.verify_each)
+    .ssa_style(args.ssa_style)
+    .collect_stats(args.stats.is_some())
+    .time_passes(args.time_passes || args.stats_json.is_some());
+    if args.print_after_all || args.print_after.is_some() {
+        compiler = compiler.print_after(args.print_after.clone());
+    }
+    if let Some(pipeline) = &args.passes {
+        compiler = compiler.passes(PassManager::from_pipeline(pipeline)?);
+    }
+    let options = ParseOptions {
+        strict: args.strict,
+    };
+
+    let output = if let Some(path) = &args.from_ir {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let ir = bril_ir::text::parse_module(&text)
+            .with_context(|| format!("failed to parse IR from {}", path.display()))?;
+        compiler.compile_ir(ir)?
+    } else {
+        let mut program = if args.input.is_empty() && io::stdin().is_terminal() {
+            let json_text = include_str!("../../tests/palindrome.json");
+            serde_json::from_str(json_text)?
+        } else if args.input.is_empty() {
+            // e.g. `bril2json < prog.bril | cli`
+            options
+                .parse_reader(io::stdin().lock())
+                .context("failed to parse stdin")?
+        } else {
+            let programs = args
+                .input
+                .iter()
+                .map(|path| read_program(path, &options))
+                .collect::<Result<Vec<_>>>()?;
+            Program::link(&programs)?
+        };
+
+        program.normalize();
+
+        // report everything wrong with the program up front
+        if let Err(errors) = program.validate() {
+            for e in &errors {
+                eprintln!("error: {}", e);
+            }
+            bail!("{} problem(s) found, nothing compiled", errors.len());
+        }
+        compiler.compile_program(&program)?
+    };
+
+    if let Some(path) = &args.emit_bril {
+        let program = compiler.emit_bril(&output.ir)?;
+        let text = if path.extension().is_some_and(|ext| ext == "bril") {
+            program.to_string()
+        } else {
+            serde_json::to_string_pretty(&program)? + "\n"
+        };
+        fs::write(path, text).with_context(|| format!("failed to write {}", path.display()))?;
+    }
+
+    if let Some(path) = &args.dump_cfg {
+        let ssa = SSAFormation::analyze(&output.ir.functions)?;
+        let graphs: Vec<String> = output
+            .ir
+            .functions
+            .iter()
+            .map(|func| func.to_dot_with_dominators(&ssa))
+            .collect();
+        fs::write(path, graphs.join("\n"))
+            .with_context(|| format!("failed to write {}", path.display()))?;
+    }
+
+    if args.time_passes {
+        eprint!("{}", output.pass_statistics);
+    }
+    if let Some(path) = &args.stats_json {
+        let json = serde_json::to_string_pretty(&output.pass_statistics)? + "\n";
+        fs::write(path, json).with_context(|| format!("failed to write {}", path.display()))?;
+    }
+
+    match args.stats {
+        Some(StatsFormat::Table) => {
+            println!("\n###### Stats ######");
+            print!("{}", output.ir.stats());
+            for report in &output.reports {
+                println!("{}", report);
+            }
+        }
+        Some(StatsFormat::Json) => {
+            let json = json!({
+                "module": output.ir.stats(),
+                "passes": output.reports,
+            });
+            println!("{}", serde_json::to_string_pretty(&json)?);
+            return Ok(());
+        }
+        None => {}
     }
 
     println!("\n###### SSA IR ######");
-    println!("{:#?}\n", ir_mod);
+    println!("{}", output.ir);
 
     println!("\n###### MachineIR ######");
-    println!("{:#?}\n", machine_module);
+    println!("{:#?}\n", output.machine_funcs);
 
     println!("\n###### Assembly ######");
-    emit_riscv(&machine_module);
+    print!("{}", output.asm);
 
     Ok(())
 }
+
+fn read_program(path: &Path, options: &ParseOptions) -> Result<Program> {
+    if path.extension().is_none_or(|ext| ext != "bril") {
+        return Ok(options.parse_path(path)?);
+    }
+    let src =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    bril_frontend::text::parse_program_with_positions(&src)
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
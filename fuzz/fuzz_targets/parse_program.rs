@@ -0,0 +1,17 @@
+#![no_main]
+
+use bril_frontend::Program;
+use bril_ir::IrModule;
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary bytes → Program → IrModule
+// anything malformed has to come back as an Err, never a panic
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    if let Ok(program) = serde_json::from_str::<Program>(text) {
+        let _ = IrModule::try_from(&program);
+    }
+});
@@ -0,0 +1,206 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use bril_frontend::Program;
+use bril_ir::{IrModule, SSAFormation};
+use libfuzzer_sys::fuzz_target;
+use serde_json::{json, Value};
+
+/// Names are drawn from a tiny pool on purpose, so the generated programs
+/// are full of duplicate labels, dangling jumps and redefined variables
+#[derive(Arbitrary, Debug, Clone, Copy)]
+struct Name(u8);
+
+impl Name {
+    fn var(self) -> String {
+        format!("v{}", self.0 % 8)
+    }
+
+    fn label(self) -> String {
+        format!("L{}", self.0 % 6)
+    }
+
+    fn func(self) -> String {
+        format!("f{}", self.0 % 4)
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinOp {
+    fn opcode(&self) -> &'static str {
+        match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::Eq => "eq",
+            BinOp::Lt => "lt",
+            BinOp::Gt => "gt",
+            BinOp::Le => "le",
+            BinOp::Ge => "ge",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum FuzzInstr {
+    Label(Name),
+    ConstInt {
+        dest: Name,
+        value: i64,
+    },
+    ConstBool {
+        dest: Name,
+        value: bool,
+    },
+    Binary {
+        op: BinOp,
+        dest: Name,
+        lhs: Name,
+        rhs: Name,
+    },
+    Not {
+        dest: Name,
+        args: Vec<Name>,
+    },
+    Id {
+        dest: Name,
+        args: Vec<Name>,
+    },
+    Br {
+        args: Vec<Name>,
+        then_lbl: Name,
+        else_lbl: Name,
+    },
+    Jmp {
+        labels: Vec<Name>,
+    },
+    Call {
+        dest: Option<Name>,
+        funcs: Vec<Name>,
+        args: Vec<Name>,
+    },
+    Ret {
+        args: Vec<Name>,
+    },
+    Print {
+        args: Vec<Name>,
+    },
+    Nop,
+}
+
+#[derive(Arbitrary, Debug)]
+struct FuzzFunction {
+    name: Name,
+    args: Vec<Name>,
+    instrs: Vec<FuzzInstr>,
+}
+
+#[derive(Arbitrary, Debug)]
+struct FuzzProgram {
+    functions: Vec<FuzzFunction>,
+}
+
+fn vars(names: &[Name]) -> Vec<String> {
+    names.iter().map(|n| n.var()).collect()
+}
+
+fn instr_to_json(instr: &FuzzInstr) -> Value {
+    match instr {
+        FuzzInstr::Label(l) => json!({ "label": l.label() }),
+        FuzzInstr::ConstInt { dest, value } => {
+            json!({ "op": "const", "dest": dest.var(), "type": "int", "value": value })
+        }
+        FuzzInstr::ConstBool { dest, value } => {
+            json!({ "op": "const", "dest": dest.var(), "type": "bool", "value": value })
+        }
+        FuzzInstr::Binary { op, dest, lhs, rhs } => json!({
+            "op": op.opcode(),
+            "dest": dest.var(),
+            "type": "int",
+            "args": [lhs.var(), rhs.var()],
+        }),
+        FuzzInstr::Not { dest, args } => {
+            json!({ "op": "not", "dest": dest.var(), "type": "bool", "args": vars(args) })
+        }
+        FuzzInstr::Id { dest, args } => {
+            json!({ "op": "id", "dest": dest.var(), "type": "int", "args": vars(args) })
+        }
+        FuzzInstr::Br {
+            args,
+            then_lbl,
+            else_lbl,
+        } => json!({
+            "op": "br",
+            "args": vars(args),
+            "labels": [then_lbl.label(), else_lbl.label()],
+        }),
+        FuzzInstr::Jmp { labels } => json!({
+            "op": "jmp",
+            "labels": labels.iter().map(|l| l.label()).collect::<Vec<_>>(),
+        }),
+        FuzzInstr::Call { dest, funcs, args } => {
+            let mut call = json!({
+                "op": "call",
+                "type": "int",
+                "funcs": funcs.iter().map(|f| f.func()).collect::<Vec<_>>(),
+                "args": vars(args),
+            });
+            if let Some(d) = dest {
+                call["dest"] = json!(d.var());
+            }
+            call
+        }
+        FuzzInstr::Ret { args } => json!({ "op": "ret", "args": vars(args) }),
+        FuzzInstr::Print { args } => json!({ "op": "print", "args": vars(args) }),
+        FuzzInstr::Nop => json!({ "op": "nop" }),
+    }
+}
+
+fn program_to_json(program: &FuzzProgram) -> Value {
+    let functions: Vec<Value> = program
+        .functions
+        .iter()
+        .map(|f| {
+            let args: Vec<Value> = f
+                .args
+                .iter()
+                .map(|a| json!({ "name": a.var(), "type": "int" }))
+                .collect();
+            json!({
+                "name": f.name.func(),
+                "args": args,
+                "instrs": f.instrs.iter().map(instr_to_json).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+
+    json!({ "functions": functions })
+}
+
+// Syntactically valid but semantically weird programs → IrModule → SSA
+fuzz_target!(|input: FuzzProgram| {
+    let Ok(program) = serde_json::from_value::<Program>(program_to_json(&input)) else {
+        return;
+    };
+
+    if let Ok(mut module) = IrModule::try_from(&program) {
+        let _ = SSAFormation::try_from(&mut module);
+    }
+});
@@ -1,15 +1,18 @@
 use crate::machine_ir::{MachineBlock, MachineFunc, MachineInstr, VReg};
-use bril_frontend::Literal;
+use bril_frontend::{Literal, Symbol};
 use bril_ir::{IrFunction, IrInstruction};
 use std::collections::HashMap;
 
+/// Types don't matter down here: every value, `any` and pointers included, is
+/// one word in an integer register. `IrFunction::type_of` is there for when
+/// floats get their own registers or RV32 needs `lw` over `ld`
 pub fn select_instructions(func: &IrFunction) -> MachineFunc {
     let mut machine_func: MachineFunc = MachineFunc::new(func);
 
-    let mut vreg_mapping: HashMap<String, VReg> = HashMap::new();
+    let mut vreg_mapping: HashMap<Symbol, VReg> = HashMap::new();
     let mut next_vreg = 0;
-    let mut allocate_reg = |name: &String| {
-        *vreg_mapping.entry(name.clone()).or_insert_with(|| {
+    let mut allocate_reg = |name: &Symbol| {
+        *vreg_mapping.entry(*name).or_insert_with(|| {
             let r = VReg::Virtual(next_vreg);
             next_vreg += 1;
             r
@@ -20,16 +23,20 @@ pub fn select_instructions(func: &IrFunction) -> MachineFunc {
         let mut machine_block: MachineBlock = MachineBlock {
             name: block.label.clone(),
             instrs: Vec::new(),
+            origins: Vec::new(),
             succs: block.succs.to_vec(),
         };
 
-        for instr in block.instrs.iter() {
+        for (i, instr) in block.instrs.iter().enumerate() {
             match instr {
                 IrInstruction::Const { dest, value } => {
                     let rd = allocate_reg(dest);
                     let imm = match value {
                         Literal::Int(i) => *i,
                         Literal::Bool(i) => *i as i64,
+                        // no FP registers yet, floats travel as their bit pattern
+                        Literal::Float(f) => f.to_bits() as i64,
+                        Literal::Char(c) => *c as i64,
                     };
                     machine_block.instrs.push(MachineInstr::Li { rd, imm });
                 }
@@ -40,6 +47,25 @@ pub fn select_instructions(func: &IrFunction) -> MachineFunc {
                     machine_block.instrs.push(MachineInstr::Mv { rd, rs1 });
                 }
 
+                // floats already live in integer registers as their bits
+                // (see `Const`), so the casts are plain moves until the F/D
+                // extensions get used and these become fmv.x.d / fmv.d.x
+                IrInstruction::Float2Bits { dest, src }
+                | IrInstruction::Bits2Float { dest, src } => {
+                    let rd = allocate_reg(dest);
+                    let rs1 = allocate_reg(src);
+                    machine_block.instrs.push(MachineInstr::Mv { rd, rs1 });
+                }
+
+                // chars are their codepoint in a register already. int2char
+                // doesn't trap on values that aren't a codepoint, like brili
+                // it's on the program to not do that
+                IrInstruction::Char2Int { dest, src } | IrInstruction::Int2Char { dest, src } => {
+                    let rd = allocate_reg(dest);
+                    let rs1 = allocate_reg(src);
+                    machine_block.instrs.push(MachineInstr::Mv { rd, rs1 });
+                }
+
                 IrInstruction::Add { dest, lhs, rhs } => {
                     let rd = allocate_reg(dest);
                     let rs1 = allocate_reg(lhs);
@@ -80,10 +106,101 @@ pub fn select_instructions(func: &IrFunction) -> MachineFunc {
                         .push(MachineInstr::Div { rd, rs1, rs2 });
                 }
 
+                IrInstruction::Shl { dest, src, amount } => {
+                    let rd = allocate_reg(dest);
+                    let rs1 = allocate_reg(src);
+                    let imm = i64::from(*amount);
+                    machine_block
+                        .instrs
+                        .push(MachineInstr::Slli { rd, rs1, imm });
+                }
+
+                IrInstruction::Shr { dest, src, amount } => {
+                    let rd = allocate_reg(dest);
+                    let rs1 = allocate_reg(src);
+                    let imm = i64::from(*amount);
+                    machine_block
+                        .instrs
+                        .push(MachineInstr::Srli { rd, rs1, imm });
+                }
+
+                IrInstruction::Sra { dest, src, amount } => {
+                    let rd = allocate_reg(dest);
+                    let rs1 = allocate_reg(src);
+                    let imm = i64::from(*amount);
+                    machine_block
+                        .instrs
+                        .push(MachineInstr::Srai { rd, rs1, imm });
+                }
+
+                // chars compare by codepoint, same as ints
+                IrInstruction::Eq { dest, lhs, rhs } | IrInstruction::CEq { dest, lhs, rhs } => {
+                    let rd = allocate_reg(dest);
+                    let rs1 = allocate_reg(lhs);
+                    let rs2 = allocate_reg(rhs);
+
+                    // rd = (lhs ^ rhs) == 0
+                    machine_block
+                        .instrs
+                        .push(MachineInstr::Xor { rd, rs1, rs2 });
+                    machine_block
+                        .instrs
+                        .push(MachineInstr::Seqz { rd, rs1: rd });
+                }
+
+                IrInstruction::Lt { dest, lhs, rhs }
+                | IrInstruction::CLt { dest, lhs, rhs }
+                | IrInstruction::Gt {
+                    dest,
+                    lhs: rhs,
+                    rhs: lhs,
+                }
+                | IrInstruction::CGt {
+                    dest,
+                    lhs: rhs,
+                    rhs: lhs,
+                } => {
+                    let rd = allocate_reg(dest);
+                    let rs1 = allocate_reg(lhs);
+                    let rs2 = allocate_reg(rhs);
+
+                    machine_block
+                        .instrs
+                        .push(MachineInstr::Slt { rd, rs1, rs2 });
+                }
+
+                // a <= b is !(b < a), a >= b is !(a < b)
+                IrInstruction::Ge { dest, lhs, rhs }
+                | IrInstruction::CGe { dest, lhs, rhs }
+                | IrInstruction::Le {
+                    dest,
+                    lhs: rhs,
+                    rhs: lhs,
+                }
+                | IrInstruction::CLe {
+                    dest,
+                    lhs: rhs,
+                    rhs: lhs,
+                } => {
+                    let rd = allocate_reg(dest);
+                    let rs1 = allocate_reg(lhs);
+                    let rs2 = allocate_reg(rhs);
+
+                    machine_block
+                        .instrs
+                        .push(MachineInstr::Slt { rd, rs1, rs2 });
+                    machine_block.instrs.push(MachineInstr::Xori {
+                        rd,
+                        rs1: rd,
+                        imm: 1,
+                    });
+                }
+
                 IrInstruction::Call {
                     dest,
                     target_func,
                     args,
+                    ..
                 } => {
                     for (i, arg) in args.iter().enumerate() {
                         let src_reg = allocate_reg(arg);
@@ -157,15 +274,22 @@ pub fn select_instructions(func: &IrFunction) -> MachineFunc {
                 IrInstruction::Ret { args } => {
                     let mut rd = None;
 
-                    if args.is_empty() {
-                        rd = Some(allocate_reg(&args[0]));
+                    if let Some(ret_val) = args.first() {
+                        rd = Some(allocate_reg(ret_val));
                     }
 
                     machine_block.instrs.push(MachineInstr::Ret { rd });
                 }
 
+                // not even a `nop`, it's there for the tools, not the hardware
+                IrInstruction::Nop => {}
+
                 _ => {}
             }
+
+            // whatever that instruction expanded to came from it
+            let selected = machine_block.instrs.len();
+            machine_block.origins.resize(selected, block.origin(i));
         }
         machine_func.blocks.push(machine_block.clone());
     }
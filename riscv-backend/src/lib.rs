@@ -2,6 +2,8 @@ pub mod instruction_sel;
 pub mod machine_ir;
 pub mod register_alloc;
 pub mod riscv_emission;
+pub mod target;
+pub mod testing;
 
 pub use instruction_sel::select_instructions;
 //pub use machine_ir::MachineBlock;
@@ -10,12 +12,12 @@ pub use instruction_sel::select_instructions;
 pub use machine_ir::*;
 pub use register_alloc::*;
 pub use riscv_emission::emit_riscv;
+pub use target::TargetConfig;
+pub use testing::compile_to_asm_for_test;
 
 // some change
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn it_works() {
         assert_eq!(4, 4);
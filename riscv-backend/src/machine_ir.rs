@@ -1,3 +1,5 @@
+use bril_frontend::InstrId;
+use bril_ir::analysis::liveness::{self, BlockSummary, Liveness};
 use bril_ir::{BlockID, IrFunction};
 use std::collections::HashMap;
 
@@ -22,16 +24,45 @@ impl MachineFunc {
     pub fn block_index(&self, label: &String) -> Option<usize> {
         self.label_to_idx.get(label).copied()
     }
+
+    /// Which registers are live into and out of each block. There are no
+    /// phis down here, out of SSA already made them moves
+    pub fn liveness(&self) -> Liveness<VReg> {
+        let blocks: Vec<BlockSummary<VReg>> = self
+            .blocks
+            .iter()
+            .map(|block| {
+                let mut summary = BlockSummary {
+                    succs: block.succs.clone(),
+                    ..BlockSummary::default()
+                };
+                for instr in &block.instrs {
+                    summary.step(instr.defs(), instr.uses());
+                }
+                summary
+            })
+            .collect();
+        liveness::solve(&blocks)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct MachineBlock {
     pub name: String,
     pub instrs: Vec<MachineInstr>,
+    // the Bril instruction each of `instrs` was selected from, parallel to
+    // `instrs` like `IrBasicBlock::origins`
+    pub origins: Vec<Option<InstrId>>,
     pub succs: Vec<BlockID>,
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+impl MachineBlock {
+    pub fn origin(&self, idx: usize) -> Option<InstrId> {
+        self.origins.get(idx).copied().flatten()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum VReg {
     Virtual(i32),
     // Temp registers
@@ -135,6 +166,22 @@ pub enum MachineInstr {
 
     Div { rd: VReg, rs1: VReg, rs2: VReg },
 
+    // Shifts by an immediate, srli fills with zeros and srai with the sign
+    Slli { rd: VReg, rs1: VReg, imm: i64 },
+
+    Srli { rd: VReg, rs1: VReg, imm: i64 },
+
+    Srai { rd: VReg, rs1: VReg, imm: i64 },
+
+    // Comparison, results are 0 or 1
+    Slt { rd: VReg, rs1: VReg, rs2: VReg },
+
+    Xor { rd: VReg, rs1: VReg, rs2: VReg },
+
+    Xori { rd: VReg, rs1: VReg, imm: i64 },
+
+    Seqz { rd: VReg, rs1: VReg },
+
     // Load & Store
     Li { rd: VReg, imm: i64 },
 
@@ -170,6 +217,13 @@ impl MachineInstr {
             | MachineInstr::Mul { rd, .. }
             | MachineInstr::Sub { rd, .. }
             | MachineInstr::Div { rd, .. }
+            | MachineInstr::Slt { rd, .. }
+            | MachineInstr::Xor { rd, .. }
+            | MachineInstr::Xori { rd, .. }
+            | MachineInstr::Slli { rd, .. }
+            | MachineInstr::Srli { rd, .. }
+            | MachineInstr::Srai { rd, .. }
+            | MachineInstr::Seqz { rd, .. }
             | MachineInstr::Mv { rd, .. }
             | MachineInstr::Li { rd, .. } => {
                 vec![*rd]
@@ -184,11 +238,18 @@ impl MachineInstr {
             | MachineInstr::Mul { rs1, rs2, .. }
             | MachineInstr::Sub { rs1, rs2, .. }
             | MachineInstr::Beq { rs1, rs2, .. }
+            | MachineInstr::Slt { rs1, rs2, .. }
+            | MachineInstr::Xor { rs1, rs2, .. }
             | MachineInstr::Div { rs1, rs2, .. } => {
                 vec![*rs1, *rs2]
             }
 
             MachineInstr::Addi { rs1, .. }
+            | MachineInstr::Xori { rs1, .. }
+            | MachineInstr::Slli { rs1, .. }
+            | MachineInstr::Srli { rs1, .. }
+            | MachineInstr::Srai { rs1, .. }
+            | MachineInstr::Seqz { rs1, .. }
             | MachineInstr::Sw { rs1, .. }
             | MachineInstr::Beqz { rs1, .. }
             | MachineInstr::Mv { rs1, .. } => {
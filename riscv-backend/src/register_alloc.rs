@@ -1,4 +1,5 @@
 use crate::machine_ir::{MachineFunc, VReg};
+use crate::TargetConfig;
 use std::{cmp, collections::HashMap};
 
 /// So far we're going to use Linear Scan for doing register allocation.
@@ -21,7 +22,7 @@ pub struct LiveIntervals {
     pub mark_spilled: bool,
 }
 
-const ALL_REGS: &[VReg] = &[
+pub const ALL_REGS: &[VReg] = &[
     // Temp registers
     VReg::T0,
     VReg::T1,
@@ -60,15 +61,28 @@ const ALL_REGS: &[VReg] = &[
     //VReg::GP,
 ];
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct LinearScan {
     pub live_intervals: HashMap<VReg, LiveIntervals>,
+    /// Registers we're allowed to hand out, taken from the target config
+    pub allocatable_regs: Vec<VReg>,
+}
+
+impl Default for LinearScan {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LinearScan {
     pub fn new() -> Self {
+        Self::with_config(&TargetConfig::default())
+    }
+
+    pub fn with_config(target: &TargetConfig) -> Self {
         Self {
             live_intervals: HashMap::new(),
+            allocatable_regs: target.allocatable_regs.clone(),
         }
     }
 
@@ -106,7 +120,10 @@ impl LinearScan {
                         phy_reg: None,
                     });
 
+                    // a def after the last use still writes the register, e.g.
+                    // a phi's copy at the bottom of a loop for the next time round
                     interval.start = cmp::min(interval.start, *pos);
+                    interval.end = cmp::max(interval.end, *pos);
                 }
 
                 for u in instr.uses() {
@@ -140,10 +157,11 @@ impl LinearScan {
             })
             .collect();
 
-        live_intervals.sort_by_key(|ivl| ivl.start);
+        // break ties on the vreg so the allocation doesn't depend on HashMap order
+        live_intervals.sort_by_key(|ivl| (ivl.start, ivl.vreg));
 
         let mut active_alloc_intervals: Vec<LiveIntervals> = Vec::new();
-        let mut free_regs = ALL_REGS.to_vec();
+        let mut free_regs = self.allocatable_regs.clone();
 
         for curr_iv in live_intervals.iter_mut() {
             active_alloc_intervals.retain(|old_iv| {
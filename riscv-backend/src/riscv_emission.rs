@@ -1,7 +1,9 @@
 use crate::machine_ir::*;
 use crate::register_alloc::{LinearScan, LiveIntervals};
+use crate::TargetConfig;
 use crate::VReg;
 use std::collections::HashMap;
+use std::fmt::{self, Write};
 
 // In case we manual added a register into our system, that hasn't been
 // processed through our live intervals, then we'll nudge the compiler to know
@@ -28,22 +30,45 @@ fn to_phys(v: VReg, map: &HashMap<VReg, LiveIntervals>) -> VReg {
     }
 }
 
-pub fn emit_riscv(module: &[MachineFunc]) {
-    let mut allocator = LinearScan::new();
+/// Allocate registers and render the whole module as RISC-V assembly text
+/// Whether the `beqz` at `i` and the `j` ending the block right after it
+/// are better the other way round, as a `bnez` to where the `j` goes: the
+/// `beqz` goes to the next block and the `j` doesn't, so a block a loop
+/// goes back to from its bottom only takes one branch to get there
+fn branches_on_nonzero(block: &MachineBlock, i: usize, next: Option<&str>) -> bool {
+    match (&block.instrs[i], &block.instrs[i + 1..]) {
+        (MachineInstr::Beqz { label, .. }, [MachineInstr::Jmp { label: then }]) => {
+            next == Some(label.as_str()) && next != Some(then.as_str())
+        }
+        _ => false,
+    }
+}
+
+pub fn emit_riscv(module: &[MachineFunc], target: &TargetConfig) -> String {
+    let mut out = String::new();
+    write_riscv(&mut out, module, target).expect("writing into a String can't fail");
+    out
+}
+
+fn write_riscv(out: &mut String, module: &[MachineFunc], target: &TargetConfig) -> fmt::Result {
+    let mut allocator = LinearScan::with_config(target);
     let func_by_intervals = allocator.run(module);
 
-    println!(".section .text");
-    println!(".p2align 2"); // align to 4-byte boundary
+    writeln!(out, ".section .text")?;
+    writeln!(out, ".p2align 2")?; // align to 4-byte boundary
 
     for func in module.iter() {
-        println!(".globl {}", func.name);
+        writeln!(out, ".globl {}", func.name)?;
     }
 
     for func in module.iter() {
         let mut spill_slots = HashMap::<VReg, usize>::new();
         let mut stack_frame: usize = 0;
         let live_intervals = &func_by_intervals.get(&func.name).unwrap();
-        for (&vreg, ivs) in live_intervals.iter() {
+        // hand out spill slots in vreg order so the frame layout is stable
+        let mut spilled: Vec<(&VReg, &LiveIntervals)> = live_intervals.iter().collect();
+        spilled.sort_by_key(|(vreg, _)| **vreg);
+        for (&vreg, ivs) in spilled {
             if ivs.mark_spilled {
                 spill_slots.insert(vreg, stack_frame);
                 stack_frame += 8;
@@ -51,25 +76,37 @@ pub fn emit_riscv(module: &[MachineFunc]) {
         }
 
         // Prologue
-        println!("\n{}:", func.name); // function label
+        writeln!(out, "\n{}:", func.name)?; // function label
         if stack_frame > 0 {
-            println!("  addi sp, sp, -{}", stack_frame);
+            writeln!(out, "  addi sp, sp, -{}", stack_frame)?;
             // save ra = return address
-            println!("  sd ra, {}(sp)", stack_frame - 8);
+            writeln!(out, "  sd ra, {}(sp)", stack_frame - 8)?;
             // save frame pointer
-            println!("  sd s0, {}(sp)", stack_frame - 16);
-            println!("  mv s0, sp");
+            writeln!(out, "  sd s0, {}(sp)", stack_frame - 16)?;
+            writeln!(out, "  mv s0, sp")?;
         }
 
-        for block in func.blocks.iter() {
-            println!("  .{}:", block.name);
+        for (b, block) in func.blocks.iter().enumerate() {
+            writeln!(out, "  .{}:", block.name)?;
+            // the block after this one is where it goes anyway
+            let next = func.blocks.get(b + 1).map(|next| next.name.as_str());
+
+            let mut last_origin = None;
+            for (i, instr) in block.instrs.iter().enumerate() {
+                let origin = block.origin(i);
+                if let Some(id) = origin
+                    && target.annotate_origins
+                    && origin != last_origin
+                {
+                    writeln!(out, "  # from @{}:{}", func.name, id)?;
+                }
+                last_origin = origin;
 
-            for instr in block.instrs.iter() {
                 // TODO: Add more instructions
                 match instr {
                     MachineInstr::Li { rd, imm } => {
                         let phy_reg = to_phys(*rd, live_intervals);
-                        println!("  li {}, {}", phy_reg.name(), imm);
+                        writeln!(out, "  li {}, {}", phy_reg.name(), imm)?;
                     }
 
                     MachineInstr::Add { rd, rs1, rs2 } => {
@@ -77,7 +114,13 @@ pub fn emit_riscv(module: &[MachineFunc]) {
                         let prs1 = to_phys(*rs1, live_intervals);
                         let prs2 = to_phys(*rs2, live_intervals);
 
-                        println!("  add {}, {}, {}", phy_reg.name(), prs1.name(), prs2.name());
+                        writeln!(
+                            out,
+                            "  add {}, {}, {}",
+                            phy_reg.name(),
+                            prs1.name(),
+                            prs2.name()
+                        )?;
                     }
 
                     MachineInstr::Mul { rd, rs1, rs2 } => {
@@ -85,7 +128,13 @@ pub fn emit_riscv(module: &[MachineFunc]) {
                         let prs1 = to_phys(*rs1, live_intervals);
                         let prs2 = to_phys(*rs2, live_intervals);
 
-                        println!("  mul {}, {}, {}", phy_reg.name(), prs1.name(), prs2.name());
+                        writeln!(
+                            out,
+                            "  mul {}, {}, {}",
+                            phy_reg.name(),
+                            prs1.name(),
+                            prs2.name()
+                        )?;
                     }
 
                     MachineInstr::Sub { rd, rs1, rs2 } => {
@@ -93,7 +142,13 @@ pub fn emit_riscv(module: &[MachineFunc]) {
                         let prs1 = to_phys(*rs1, live_intervals);
                         let prs2 = to_phys(*rs2, live_intervals);
 
-                        println!("  sub {}, {}, {}", phy_reg.name(), prs1.name(), prs2.name());
+                        writeln!(
+                            out,
+                            "  sub {}, {}, {}",
+                            phy_reg.name(),
+                            prs1.name(),
+                            prs2.name()
+                        )?;
                     }
 
                     MachineInstr::Div { rd, rs1, rs2 } => {
@@ -101,47 +156,135 @@ pub fn emit_riscv(module: &[MachineFunc]) {
                         let prs1 = to_phys(*rs1, live_intervals);
                         let prs2 = to_phys(*rs2, live_intervals);
 
-                        println!("  div {}, {}, {}", phy_reg.name(), prs1.name(), prs2.name());
+                        writeln!(
+                            out,
+                            "  div {}, {}, {}",
+                            phy_reg.name(),
+                            prs1.name(),
+                            prs2.name()
+                        )?;
+                    }
+
+                    MachineInstr::Slt { rd, rs1, rs2 } => {
+                        let phy_reg = to_phys(*rd, live_intervals);
+                        let prs1 = to_phys(*rs1, live_intervals);
+                        let prs2 = to_phys(*rs2, live_intervals);
+
+                        writeln!(
+                            out,
+                            "  slt {}, {}, {}",
+                            phy_reg.name(),
+                            prs1.name(),
+                            prs2.name()
+                        )?;
+                    }
+
+                    MachineInstr::Xor { rd, rs1, rs2 } => {
+                        let phy_reg = to_phys(*rd, live_intervals);
+                        let prs1 = to_phys(*rs1, live_intervals);
+                        let prs2 = to_phys(*rs2, live_intervals);
+
+                        writeln!(
+                            out,
+                            "  xor {}, {}, {}",
+                            phy_reg.name(),
+                            prs1.name(),
+                            prs2.name()
+                        )?;
+                    }
+
+                    MachineInstr::Xori { rd, rs1, imm } => {
+                        let phy_reg = to_phys(*rd, live_intervals);
+                        let prs1 = to_phys(*rs1, live_intervals);
+
+                        writeln!(out, "  xori {}, {}, {}", phy_reg.name(), prs1.name(), imm)?;
+                    }
+
+                    MachineInstr::Slli { rd, rs1, imm } => {
+                        let phy_reg = to_phys(*rd, live_intervals);
+                        let prs1 = to_phys(*rs1, live_intervals);
+
+                        writeln!(out, "  slli {}, {}, {}", phy_reg.name(), prs1.name(), imm)?;
+                    }
+
+                    MachineInstr::Srli { rd, rs1, imm } => {
+                        let phy_reg = to_phys(*rd, live_intervals);
+                        let prs1 = to_phys(*rs1, live_intervals);
+
+                        writeln!(out, "  srli {}, {}, {}", phy_reg.name(), prs1.name(), imm)?;
+                    }
+
+                    MachineInstr::Srai { rd, rs1, imm } => {
+                        let phy_reg = to_phys(*rd, live_intervals);
+                        let prs1 = to_phys(*rs1, live_intervals);
+
+                        writeln!(out, "  srai {}, {}, {}", phy_reg.name(), prs1.name(), imm)?;
+                    }
+
+                    MachineInstr::Seqz { rd, rs1 } => {
+                        let phy_reg = to_phys(*rd, live_intervals);
+                        let prs1 = to_phys(*rs1, live_intervals);
+
+                        writeln!(out, "  seqz {}, {}", phy_reg.name(), prs1.name())?;
                     }
 
                     MachineInstr::Mv { rd, rs1 } => {
                         let phy_reg = to_phys(*rd, live_intervals);
                         let prs1 = to_phys(*rs1, live_intervals);
 
-                        println!("  mv {}, {}", phy_reg.name(), prs1.name());
+                        writeln!(out, "  mv {}, {}", phy_reg.name(), prs1.name())?;
                     }
 
                     MachineInstr::Sw { rs1, offset, base } => {
                         let rs = to_phys(*rs1, live_intervals);
                         let base_val = to_phys(*base, live_intervals);
 
-                        println!("  sw {}, {}({})", rs.name(), offset, base_val.name());
+                        writeln!(out, "  sw {}, {}({})", rs.name(), offset, base_val.name())?;
                     }
 
                     MachineInstr::Call { func } => {
-                        println!("  call {}", func);
+                        writeln!(out, "  call {}", func)?;
                     }
 
-                    MachineInstr::Jmp { label } => {
-                        println!("  j {}", label);
+                    // the `beqz` before it went there instead
+                    MachineInstr::Jmp { .. }
+                        if i > 0 && branches_on_nonzero(block, i - 1, next) => {}
+                    MachineInstr::Jmp { label }
+                        if i + 1 < block.instrs.len() || next != Some(label.as_str()) =>
+                    {
+                        writeln!(out, "  j {}", label)?;
                     }
+                    // falls through to it instead
+                    MachineInstr::Jmp { .. } => {}
 
                     MachineInstr::Jal { rd, label } => {
-                        println!("  jal {}, {}", to_phys(*rd, live_intervals).name(), label);
+                        writeln!(
+                            out,
+                            "  jal {}, {}",
+                            to_phys(*rd, live_intervals).name(),
+                            label
+                        )?;
                     }
 
                     MachineInstr::Beqz { rs1, label } => {
-                        //println!("{:#?}", rs1);
+                        //writeln!(out, "{:#?}", rs1)?;
                         let rs = to_phys(*rs1, live_intervals);
-                        println!("  beqz {}, {}", rs.name(), label);
+                        match &block.instrs[i + 1..] {
+                            [MachineInstr::Jmp { label: then }]
+                                if branches_on_nonzero(block, i, next) =>
+                            {
+                                writeln!(out, "  bnez {}, {}", rs.name(), then)?;
+                            }
+                            _ => writeln!(out, "  beqz {}, {}", rs.name(), label)?,
+                        }
                     }
 
                     MachineInstr::Ret { rd } => {
                         if let Some(r) = rd {
                             let phy_reg = to_phys(*r, live_intervals);
-                            println!("  ret {}", phy_reg.name());
+                            writeln!(out, "  ret {}", phy_reg.name())?;
                         } else {
-                            println!("  ret");
+                            writeln!(out, "  ret")?;
                         }
                     }
 
@@ -152,10 +295,11 @@ pub fn emit_riscv(module: &[MachineFunc]) {
 
         if stack_frame > 0 {
             // save ra = return address
-            println!("  ld s0, {}(sp)", stack_frame - 16);
-            println!("  ld ra, {}(sp)", stack_frame - 8);
+            writeln!(out, "  ld s0, {}(sp)", stack_frame - 16)?;
+            writeln!(out, "  ld ra, {}(sp)", stack_frame - 8)?;
             // save frame pointer
-            println!("  addi sp, sp, {}", stack_frame);
+            writeln!(out, "  addi sp, sp, {}", stack_frame)?;
         }
     }
+    Ok(())
 }
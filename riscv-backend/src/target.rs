@@ -0,0 +1,22 @@
+use crate::machine_ir::VReg;
+use crate::register_alloc::ALL_REGS;
+
+/// Backend knobs kept in one place, so tests (and anyone embedding the
+/// backend) can pin them down instead of relying on defaults
+#[derive(Debug, Clone)]
+pub struct TargetConfig {
+    /// Registers the allocator is allowed to hand out
+    pub allocatable_regs: Vec<VReg>,
+    /// Write a `# from @func:id` comment before the instructions each Bril
+    /// instruction turned into, see `bril_frontend::InstrId`
+    pub annotate_origins: bool,
+}
+
+impl Default for TargetConfig {
+    fn default() -> Self {
+        Self {
+            allocatable_regs: ALL_REGS.to_vec(),
+            annotate_origins: false,
+        }
+    }
+}
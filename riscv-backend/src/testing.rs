@@ -0,0 +1,34 @@
+use crate::{emit_riscv, select_instructions, TargetConfig};
+use bril_frontend::Program;
+use bril_ir::{destruct_ssa, IrModule, SSAFormation};
+use bril_passes::{
+    ConstantFoldPass, ConstantPropagationPass, DeadCodeRemovalPass, PassManager, SimplifyCfgPass,
+};
+
+/// Run a Bril JSON program through the whole pipeline with a pinned
+/// `TargetConfig`, so snapshot tests stay one-liners.
+///
+/// Trailing whitespace is stripped from every line, nothing else is touched.
+pub fn compile_to_asm_for_test(json: &str) -> String {
+    let program: Program = serde_json::from_str(json).expect("test program should parse");
+    let mut ir_mod = IrModule::try_from(&program).expect("test program should build a CFG");
+    SSAFormation::try_from(&mut ir_mod).expect("test program should go into SSA");
+
+    let mut pm = PassManager::new();
+    pm.add_pass(ConstantPropagationPass {});
+    pm.add_pass(ConstantFoldPass::default());
+    pm.add_pass(DeadCodeRemovalPass {});
+    pm.add_pass(SimplifyCfgPass {});
+    pm.run(&mut ir_mod).expect("the passes should run");
+
+    for func in &mut ir_mod.functions {
+        destruct_ssa(func);
+    }
+    let machine_module: Vec<_> = ir_mod.functions.iter().map(select_instructions).collect();
+    let asm = emit_riscv(&machine_module, &TargetConfig::default());
+
+    asm.lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
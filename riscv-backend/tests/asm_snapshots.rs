@@ -0,0 +1,55 @@
+//! Assembly snapshots for a small set of programs. Any codegen change shows up
+//! here as a snapshot diff to review (`cargo insta review`).
+
+use riscv_backend::compile_to_asm_for_test;
+
+#[test]
+fn snapshot_straight_line_arithmetic() {
+    let asm = compile_to_asm_for_test(include_str!("../../tests/add.json"));
+    insta::assert_snapshot!("straight_line", asm);
+}
+
+#[test]
+fn snapshot_if_else() {
+    let asm = compile_to_asm_for_test(include_str!("../../tests/condition.json"));
+    insta::assert_snapshot!("if_else", asm);
+}
+
+#[test]
+fn snapshot_loop() {
+    let asm = compile_to_asm_for_test(include_str!("../../tests/loop.json"));
+    insta::assert_snapshot!("loop", asm);
+}
+
+#[test]
+fn snapshot_function_call() {
+    let asm = compile_to_asm_for_test(include_str!("../../tests/factorial.json"));
+    insta::assert_snapshot!("function_call", asm);
+}
+
+#[test]
+fn snapshot_spilling_function() {
+    let asm = compile_to_asm_for_test(include_str!("../../tests/spill.json"));
+    insta::assert_snapshot!("spilling", asm);
+}
+
+#[test]
+fn snapshot_char_comparison() {
+    let asm = compile_to_asm_for_test(include_str!("../../tests/char_compare.json"));
+    insta::assert_snapshot!("char_comparison", asm);
+}
+
+#[test]
+fn snapshot_char_conversions() {
+    let asm = compile_to_asm_for_test(include_str!("../../tests/char_convert.json"));
+    insta::assert_snapshot!("char_conversions", asm);
+}
+
+#[test]
+fn output_is_deterministic() {
+    let json = include_str!("../../tests/palindrome.json");
+    let first = compile_to_asm_for_test(json);
+    for _ in 0..8 {
+        assert_eq!(first, compile_to_asm_for_test(json));
+    }
+}